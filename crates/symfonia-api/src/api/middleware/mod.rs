@@ -3,3 +3,4 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 pub mod authentication;
 pub mod current_user;
+pub mod permission_guard;
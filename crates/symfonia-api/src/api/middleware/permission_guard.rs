@@ -2,16 +2,37 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 use chorus::types::{PermissionFlags, Snowflake};
-use poem::{Endpoint, Middleware, Request};
+use poem::{Endpoint, Middleware, Request, http::StatusCode};
 use sqlx::PgPool;
-use util::entities::User;
+use util::{
+	entities::{Channel, User},
+	errors::{ChannelError, Error, GuildError},
+	util::permissions::{get_channel_permissions, get_guild_permissions},
+};
 
+/// The scope in which the required permissions of a [PermissionGuardMiddleware]
+/// are checked. The id of the guild or channel is read from the `guild_id` or
+/// `channel_id` path parameter respectively.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PermissionCheckType {
-	ChannelView,
+	Guild(PermissionFlags),
+	Channel(PermissionFlags),
 }
 
-pub struct PermissionGuardMiddleware(PermissionCheckType);
+/// Rejects requests of users who lack the declared permissions. On success, the
+/// effective [PermissionFlags] of the user are made available to the endpoint
+/// as request data.
+pub struct PermissionGuardMiddleware(pub PermissionCheckType);
+
+impl PermissionGuardMiddleware {
+	pub fn guild(permissions: PermissionFlags) -> Self {
+		Self(PermissionCheckType::Guild(permissions))
+	}
+
+	pub fn channel(permissions: PermissionFlags) -> Self {
+		Self(PermissionCheckType::Channel(permissions))
+	}
+}
 
 impl<E: Endpoint> Middleware<E> for PermissionGuardMiddleware {
 	type Output = PermissionGuardMiddlewareImpl<E>;
@@ -31,29 +52,119 @@ impl<E: Endpoint> Endpoint for PermissionGuardMiddlewareImpl<E> {
 
 	async fn call(&self, mut req: Request) -> poem::Result<Self::Output> {
 		let db = req.data::<PgPool>().expect("Failed to get database connection");
+		let user =
+			req.data::<User>().ok_or(poem::error::Error::from_status(StatusCode::UNAUTHORIZED))?;
 
-		if let Some(user) = req.data::<User>() {
-			match self.check_type {
-				PermissionCheckType::ChannelView => {
-					let channel_id = req
-						.raw_path_param("channel_id")
-						.map(|s| Snowflake(s.parse().unwrap()))
-						.unwrap();
-					check_channel_permissions(db, channel_id, PermissionFlags::VIEW_CHANNEL)
-						.await?;
-				}
+		let permissions = match self.check_type {
+			PermissionCheckType::Guild(required) => {
+				let guild_id = path_snowflake(&req, "guild_id")
+					.ok_or(Error::Guild(GuildError::InvalidGuild))?;
+				check_guild_permissions(db, guild_id, user.id, required).await?
 			}
-		}
+			PermissionCheckType::Channel(required) => {
+				let channel_id = path_snowflake(&req, "channel_id")
+					.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
+				check_channel_permissions(db, channel_id, user.id, required).await?
+			}
+		};
+		req.set_data(permissions);
 
 		self.ep.call(req).await
 	}
 }
 
+fn path_snowflake(req: &Request, name: &str) -> Option<Snowflake> {
+	req.raw_path_param(name).and_then(|s| s.parse().ok()).map(Snowflake)
+}
+
+async fn check_guild_permissions(
+	db: &PgPool,
+	guild_id: Snowflake,
+	user_id: Snowflake,
+	required: PermissionFlags,
+) -> Result<PermissionFlags, Error> {
+	let permissions = get_guild_permissions(db, guild_id, user_id).await?;
+	if !permissions.contains(required) {
+		return Err(Error::Guild(GuildError::InsufficientPermissions));
+	}
+	Ok(permissions)
+}
+
 async fn check_channel_permissions(
 	db: &PgPool,
 	channel_id: Snowflake,
+	user_id: Snowflake,
+	required: PermissionFlags,
+) -> Result<PermissionFlags, Error> {
+	let channel = Channel::get_by_id(db, channel_id)
+		.await?
+		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
+	let permissions = get_channel_permissions(db, &channel, user_id).await?;
+	require_channel_permissions(permissions, required)
+}
+
+/// Fails with [ChannelError::MissingPermissions] if `permissions` does not
+/// contain all of `required`.
+pub fn require_channel_permissions(
 	permissions: PermissionFlags,
-) -> poem::Result<()> {
-	todo!("Check channel permissions");
-	Ok(())
+	required: PermissionFlags,
+) -> Result<PermissionFlags, Error> {
+	if !permissions.contains(required) {
+		return Err(Error::Channel(ChannelError::MissingPermissions(required - permissions)));
+	}
+	Ok(permissions)
+}
+
+#[cfg(test)]
+mod tests {
+	use chorus::types::PermissionFlags;
+	use poem::error::ResponseError;
+	use util::{
+		errors::{ChannelError, Error},
+		util::permissions::{DM_PERMISSIONS, compute_dm_permissions},
+	};
+
+	use super::require_channel_permissions;
+
+	#[test]
+	fn missing_channel_permissions_are_forbidden() {
+		let permissions = PermissionFlags::VIEW_CHANNEL | PermissionFlags::SEND_MESSAGES;
+
+		for required in [
+			PermissionFlags::READ_MESSAGE_HISTORY,
+			PermissionFlags::MANAGE_MESSAGES,
+			PermissionFlags::MANAGE_CHANNELS,
+			PermissionFlags::MANAGE_WEBHOOKS,
+			PermissionFlags::MANAGE_ROLES,
+			PermissionFlags::CREATE_INSTANT_INVITE,
+			PermissionFlags::ADD_REACTIONS,
+		] {
+			let err =
+				require_channel_permissions(permissions, PermissionFlags::VIEW_CHANNEL | required)
+					.unwrap_err();
+			assert!(
+				matches!(err, Error::Channel(ChannelError::MissingPermissions(p)) if p == required)
+			);
+			assert_eq!(err.status(), poem::http::StatusCode::FORBIDDEN);
+		}
+
+		assert_eq!(
+			require_channel_permissions(permissions, PermissionFlags::SEND_MESSAGES).unwrap(),
+			permissions
+		);
+	}
+
+	#[test]
+	fn unknown_and_foreign_channels_are_not_found() {
+		let err = compute_dm_permissions(false, false).unwrap_err();
+		assert_eq!(err.status(), poem::http::StatusCode::NOT_FOUND);
+		assert_eq!(
+			Error::Channel(ChannelError::InvalidChannel).status(),
+			poem::http::StatusCode::NOT_FOUND
+		);
+
+		let recipient = compute_dm_permissions(true, false).unwrap();
+		assert_eq!(recipient, DM_PERMISSIONS);
+		assert!(require_channel_permissions(recipient, PermissionFlags::MANAGE_CHANNELS).is_err());
+	}
 }
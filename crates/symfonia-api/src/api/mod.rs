@@ -1,9 +1,10 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
+use futures::StreamExt;
 use poem::{
 	EndpointExt, IntoResponse, Route, Server,
-	listener::TcpListener,
+	listener::{AcceptorExt, RustlsCertificate, RustlsConfig, TcpAcceptor},
 	middleware::{Cors, NormalizePath, TrailingSlash},
 	web::Json,
 };
@@ -11,7 +12,13 @@ use reqwest::Method;
 use serde_json::json;
 use sqlx::PgPool;
 use util::{
-	configuration::SymfoniaConfiguration, entities::Config, errors::Error, gateway::ConnectedUsers,
+	configuration::SymfoniaConfiguration,
+	entities::Config,
+	errors::Error,
+	gateway::ConnectedUsers,
+	mail::SharedMailer,
+	shutdown::{self, Shutdown},
+	tls::{self, TlsFiles},
 };
 
 use crate::api::{
@@ -22,11 +29,31 @@ use crate::api::{
 mod middleware;
 mod routes;
 
+/// Starts serving the API in the background. Once `shutdown` is triggered, the
+/// server stops accepting connections and drains the requests in flight; the
+/// returned handle completes when it has stopped.
 pub async fn start_api(
 	db: PgPool,
 	connected_users: ConnectedUsers,
 	config: Config,
-) -> Result<(), Error> {
+	shutdown: Shutdown,
+) -> Result<tokio::task::JoinHandle<()>, Error> {
+	let host = &SymfoniaConfiguration::get().api.cfg.host;
+	let port = SymfoniaConfiguration::get().api.cfg.port;
+	let listener = tokio::net::TcpListener::bind((host.as_str(), port)).await?;
+	serve_api(listener, db, connected_users, config, shutdown).await
+}
+
+/// Like [start_api], but serves the connections of `listener` instead of
+/// binding the configured address.
+pub async fn serve_api(
+	listener: tokio::net::TcpListener,
+	db: PgPool,
+	connected_users: ConnectedUsers,
+	config: Config,
+	shutdown: Shutdown,
+) -> Result<tokio::task::JoinHandle<()>, Error> {
+	let local_addr = listener.local_addr()?;
 	log::info!(target: "symfonia::api::cfg", "Loading configuration");
 
 	if config.sentry.enabled {
@@ -40,6 +67,8 @@ pub async fn start_api(
 		));
 	}
 
+	let mailer = SharedMailer::from_config(&config)?;
+
 	let v9_api = Route::new()
 		.at("/ping", routes::ping::setup_routes())
 		.at("/version", routes::version::setup_routes())
@@ -48,6 +77,7 @@ pub async fn start_api(
 		.data(db)
 		.data(config)
 		.data(connected_users)
+		.data(mailer)
 		.with(NormalizePath::new(TrailingSlash::Trim))
 		.with(Cors::new().allow_methods(&[
 			Method::CONNECT,
@@ -64,21 +94,37 @@ pub async fn start_api(
 
 	log::info!(target: "symfonia::api", "Starting HTTP Server");
 
-	let host = &SymfoniaConfiguration::get().api.cfg.host;
-	let port = SymfoniaConfiguration::get().api.cfg.port;
+	let acceptor = TcpAcceptor::from_tokio(listener)?;
+	let acceptor = match SymfoniaConfiguration::get().api.cfg.tls_files()? {
+		Some(files) => {
+			tls::install_crypto_provider();
+			acceptor.rustls(files.reloads(rustls_config)?.boxed()).boxed()
+		}
+		None => acceptor.boxed(),
+	};
 
-	tokio::task::spawn(async move {
-		// .trim() needs to be called because \n is appended to the .to_string(),
-		// messing up the binding
-		Server::new(TcpListener::bind((host.as_str(), port)))
-			.run(v9_api)
+	let server = tokio::task::spawn(async move {
+		Server::new_with_acceptor(acceptor)
+			.run_with_graceful_shutdown(
+				v9_api,
+				async move { shutdown.triggered().await },
+				Some(shutdown::timeout()),
+			)
 			.await
 			.expect("Failed to start HTTP server");
 		log::info!(target: "symfonia::api", "HTTP Server stopped");
 	});
 
-	log::info!(target: "symfonia::api", "HTTP Server listening on {}", SymfoniaConfiguration::get().api);
-	Ok(())
+	log::info!(target: "symfonia::api", "HTTP Server listening on {local_addr}");
+	Ok(server)
+}
+
+/// The [RustlsConfig] serving `files`. poem only reports invalid certificates
+/// once it is using them, so they are checked here first.
+fn rustls_config(files: &TlsFiles) -> Result<RustlsConfig, Error> {
+	files.server_config()?;
+	let (certificate, key) = files.read()?;
+	Ok(RustlsConfig::new().fallback(RustlsCertificate::new().cert(certificate).key(key)))
 }
 
 fn setup_api_routes() -> Route {
@@ -109,6 +155,10 @@ fn setup_api_routes() -> Route {
 }
 
 async fn custom_error(err: poem::Error) -> impl IntoResponse {
+	if let Some(Error::InvalidFormBody(errors)) = err.downcast_ref::<Error>() {
+		return Json(errors.to_json()).with_status(err.status());
+	}
+
 	Json(json! ({
 		"success": false,
 		"message": err.to_string(),
@@ -2,14 +2,19 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
-use chorus::types::{APIError, AuthError, LoginSchema, jwt};
+use chorus::types::{APIError, AuthError, LoginSchema};
 use poem::{
 	IntoResponse, Request, Response, handler,
 	web::{Data, Json},
 };
 use reqwest::StatusCode;
 use serde_json::json;
-use util::entities::{Config, User};
+use util::{
+	entities::{Config, User},
+	util::token::generate_token,
+};
+
+use super::start_mfa_login;
 
 #[handler]
 pub async fn login(
@@ -17,10 +22,10 @@ pub async fn login(
 	Data(cfg): Data<&Config>,
 	Json(payload): Json<LoginSchema>,
 	req: &Request,
-) -> Result<impl IntoResponse, APIError> {
+) -> poem::Result<impl IntoResponse> {
 	if cfg.login.require_captcha && cfg.security.captcha.enabled {
 		if payload.captcha_key.is_none() {
-			return Err(APIError::Auth(AuthError::InvalidCaptcha));
+			return Err(APIError::Auth(AuthError::InvalidCaptcha).into());
 		}
 
 		let ip = req.remote_addr().to_string();
@@ -30,7 +35,7 @@ pub async fn login(
 	}
 
 	let Some(user) = User::get_user_by_email_or_phone(db, &payload.login, "").await.unwrap() else {
-		return Err(APIError::Auth(AuthError::InvalidLogin));
+		return Err(APIError::Auth(AuthError::InvalidLogin).into());
 	};
 
 	if let Some(hash) = &user.data.hash {
@@ -38,20 +43,18 @@ pub async fn login(
 			Ok(pw_hash) => pw_hash,
 			Err(e) => {
 				log::warn!("Couldn't parse hash for user id {}: {e}", user.id);
-				return Err(AuthError::InvalidLogin.into());
+				return Err(APIError::Auth(AuthError::InvalidLogin).into());
 			}
 		};
 		Argon2::default()
 			.verify_password(payload.password.as_bytes(), &password_hash)
-			.map_err(|_| AuthError::InvalidLogin)?;
+			.map_err(|_| APIError::Auth(AuthError::InvalidLogin))?;
 	}
 
 	if cfg.login.require_verification && !user.verified.unwrap_or_default() {
-		return Err(APIError::Auth(AuthError::InvalidLogin));
+		return Err(APIError::Auth(AuthError::InvalidLogin).into());
 	}
 
-	// TODO: MFA / WebauthN
-
 	if payload.undelete.unwrap_or(false) {
 		if user.disabled.unwrap_or_default() {
 			todo!()
@@ -86,11 +89,15 @@ pub async fn login(
 		}
 	}
 
-	let token = jwt::generate_token(
-		&user.id,
+	if let Some(mfa) = start_mfa_login(db, &user).await? {
+		return Ok(Response::builder().body(mfa.to_string()).into_response());
+	}
+
+	let token = generate_token(
+		user.id,
 		user.email.clone().unwrap_or_default().as_str(),
 		&cfg.security.jwt_secret,
-	);
+	)?;
 
 	Ok(Response::builder()
 		.body(
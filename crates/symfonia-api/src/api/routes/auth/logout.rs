@@ -0,0 +1,35 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+use chorus::types::jwt::Claims;
+use poem::{
+	IntoResponse, handler,
+	web::{Data, Json},
+};
+use serde_json::json;
+use util::{
+	entities::{Config, User},
+	errors::{Error, UserError},
+	util::token::generate_token,
+};
+
+/// Revoke all tokens of the current user, logging them out on all of their
+/// devices. The device making the request receives a new token.
+#[handler]
+pub async fn logout_all(
+	Data(db): Data<&sqlx::PgPool>,
+	Data(cfg): Data<&Config>,
+	Data(claims): Data<&Claims>,
+) -> poem::Result<impl IntoResponse> {
+	let mut user =
+		User::get_by_id(db, claims.id).await?.ok_or(Error::User(UserError::InvalidUser))?;
+	user.revoke_tokens(db).await?;
+
+	let token = generate_token(
+		user.id,
+		user.email.clone().unwrap_or_default().as_str(),
+		&cfg.security.jwt_secret,
+	)?;
+
+	Ok(Json(json!({"token": token})))
+}
@@ -0,0 +1,126 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+use chorus::types::Snowflake;
+use poem::{
+	IntoResponse, handler,
+	web::{Data, Json},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use util::{
+	entities::{Config, SecurityKey, User, WebauthnChallenge},
+	errors::{Error, UserError},
+	util::{token::generate_token, webauthn::webauthn},
+};
+use webauthn_rs::prelude::{PublicKeyCredential, SecurityKeyAuthentication};
+
+#[derive(Debug, Deserialize)]
+pub struct TotpLoginSchema {
+	/// A TOTP code or backup code.
+	pub code: String,
+	/// The ticket `login` responded with.
+	pub ticket: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebauthnLoginSchema {
+	/// The JSON encoded `PublicKeyCredential` the authenticator asserted.
+	pub code: String,
+	/// The ticket `login` responded with.
+	pub ticket: String,
+}
+
+/// Complete a login of a user with MFA enabled, exchanging the ticket of the
+/// password step and a TOTP or backup code for a token. Each ticket can only
+/// be used once, even if the code is wrong.
+#[handler]
+pub async fn login_totp(
+	Data(db): Data<&sqlx::PgPool>,
+	Data(cfg): Data<&Config>,
+	Json(payload): Json<TotpLoginSchema>,
+) -> poem::Result<impl IntoResponse> {
+	let user = User::take_by_mfa_ticket(db, &payload.ticket)
+		.await?
+		.ok_or(Error::User(UserError::InvalidMfaTicket))?;
+	if !user.check_mfa_code(db, &payload.code).await? {
+		return Err(Error::User(UserError::InvalidMfaCode).into());
+	}
+
+	let token = generate_token(
+		user.id,
+		user.email.clone().unwrap_or_default().as_str(),
+		&cfg.security.jwt_secret,
+	)?;
+
+	Ok(Json(json!({"token": token, "settings": user.settings})))
+}
+
+/// Start a login which has to be completed with a second factor, if `user`
+/// enabled MFA. Returns the response telling the client which factors it can
+/// use with `login_totp` or `login_webauthn`.
+pub(super) async fn start_mfa_login(db: &PgPool, user: &User) -> Result<Option<Value>, Error> {
+	let totp = user.mfa_enabled.unwrap_or_default();
+	let webauthn = start_webauthn_login(db, user.id).await?;
+	if !totp && webauthn.is_none() {
+		return Ok(None);
+	}
+	let ticket = user.create_mfa_ticket(db).await?;
+	Ok(Some(json!({
+		"token": null,
+		"mfa": true,
+		"sms": false,
+		"ticket": ticket,
+		"totp": totp,
+		"backup": totp,
+		"webauthn": webauthn
+	})))
+}
+
+/// Start a WebAuthn assertion of the security keys of the user `user_id`, if
+/// they registered any. Returns the JSON encoded options to pass to
+/// `navigator.credentials.get()`.
+async fn start_webauthn_login(db: &PgPool, user_id: Snowflake) -> Result<Option<String>, Error> {
+	let credentials: Vec<_> = SecurityKey::get_by_user_id(db, user_id)
+		.await?
+		.into_iter()
+		.map(|key| key.credential.0)
+		.collect();
+	if credentials.is_empty() {
+		return Ok(None);
+	}
+	let (challenge, authentication) = webauthn()?.start_securitykey_authentication(&credentials)?;
+	WebauthnChallenge::save(db, user_id, WebauthnChallenge::AUTHENTICATION, &authentication)
+		.await?;
+	Ok(Some(serde_json::to_string(&challenge)?))
+}
+
+/// Complete a login of a user with security keys, exchanging the ticket of the
+/// password step and an assertion of one of their keys for a token.
+#[handler]
+pub async fn login_webauthn(
+	Data(db): Data<&sqlx::PgPool>,
+	Data(cfg): Data<&Config>,
+	Json(payload): Json<WebauthnLoginSchema>,
+) -> poem::Result<impl IntoResponse> {
+	let user = User::take_by_mfa_ticket(db, &payload.ticket)
+		.await?
+		.ok_or(Error::User(UserError::InvalidMfaTicket))?;
+	let authentication: SecurityKeyAuthentication =
+		WebauthnChallenge::take(db, user.id, WebauthnChallenge::AUTHENTICATION).await?;
+	let assertion: PublicKeyCredential = serde_json::from_str(&payload.code)
+		.map_err(|e| Error::Custom(format!("Invalid credential: {e}")))?;
+	let result = webauthn()?
+		.finish_securitykey_authentication(&assertion, &authentication)
+		.map_err(Error::from)?;
+	SecurityKey::record_authentication(db, user.id, &result).await?;
+
+	let token = generate_token(
+		user.id,
+		user.email.clone().unwrap_or_default().as_str(),
+		&cfg.security.jwt_secret,
+	)?;
+
+	Ok(Json(json!({"token": token, "settings": user.settings})))
+}
@@ -2,12 +2,31 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 mod login;
+mod logout;
+mod mfa;
 mod register;
+mod reset;
+mod verify;
 
 pub use login::*;
-use poem::{Route, post};
+pub use logout::*;
+pub use mfa::*;
+use poem::{EndpointExt, Route, post};
 pub use register::*;
+pub use reset::*;
+pub use verify::*;
+
+use crate::api::middleware::authentication::AuthenticationMiddleware;
 
 pub fn setup_routes() -> Route {
-	Route::new().at("/login", post(login)).at("/register", post(register))
+	Route::new()
+		.at("/login", post(login))
+		.at("/register", post(register))
+		.at("/mfa/totp", post(login_totp))
+		.at("/mfa/webauthn", post(login_webauthn))
+		.at("/logout-all", post(logout_all).with(AuthenticationMiddleware))
+		.at("/verify", post(verify_email))
+		.at("/verify/resend", post(resend_verification).with(AuthenticationMiddleware))
+		.at("/forgot", post(forgot_password))
+		.at("/reset", post(reset_password))
 }
@@ -1,87 +1,175 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use std::collections::HashSet;
-
-use chorus::types::{APIError, AuthError, RegisterSchema, jwt::generate_token};
+use chorus::types::RegisterSchema;
 use poem::{
-	IntoResponse, Request, handler,
-	web::{Data, Json},
+	IntoResponse, handler,
+	web::{Data, Json, Query},
 };
+use serde::Deserialize;
 use serde_json::json;
 use util::{
-	entities::{Config, Role, User},
+	entities::{Config, Invite, RegistrationToken, User},
+	errors::{Error, FieldErrors, RateLimitError},
 	gateway::ConnectedUsers,
+	mail::SharedMailer,
+	util::{captcha::verify_captcha, register, token::generate_token},
 };
 
+use super::verification_mail;
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterQuery {
+	/// A registration token, which allows registering while new registrations
+	/// are closed or invite only.
+	pub token: Option<String>,
+}
+
 #[handler]
 pub async fn register(
 	Data(db): Data<&sqlx::PgPool>,
 	Data(cfg): Data<&Config>,
+	Data(mailer): Data<&SharedMailer>,
 	Data(connected_users): Data<&ConnectedUsers>,
+	Query(query): Query<RegisterQuery>,
 	Json(payload): Json<RegisterSchema>,
-	req: &Request,
-) -> Result<impl IntoResponse, APIError> {
-	// TODO: Check reg token
+) -> poem::Result<impl IntoResponse> {
+	// The token is only consumed once the registration is known to succeed
+	let token_used = match &query.token {
+		Some(token) => RegistrationToken::is_valid(db, token).await?,
+		None => false,
+	};
+
+	let mut errors = FieldErrors::default();
+	if cfg.register.disabled || !(cfg.register.allow_new_registration || token_used) {
+		errors.add("email", "REGISTRATION_DISABLED", "New user registration is not allowed.");
+		return Err(Error::InvalidFormBody(errors).into());
+	}
+
+	if cfg.register.require_captcha && cfg.security.captcha.enabled {
+		verify_captcha(cfg, payload.captcha_key.as_deref()).await?;
+	}
 
 	if !payload.consent {
-		// TODO: Fail consent
+		errors.add(
+			"consent",
+			"CONSENT_REQUIRED",
+			"You must agree to the Terms of Service and Privacy Policy.",
+		);
+	}
+
+	register::check_username(cfg, &payload.username, &mut errors);
+	register::check_password(cfg, payload.password.as_deref(), &mut errors);
+	register::check_date_of_birth(
+		cfg,
+		payload.date_of_birth,
+		chrono::Utc::now().date_naive(),
+		&mut errors,
+	);
+	let email = register::check_email(cfg, payload.email.as_deref(), &mut errors);
+	if let Some(email) = &email {
+		if User::get_user_by_email_or_phone(db, email, "").await?.is_some() {
+			errors.add("email", "EMAIL_ALREADY_REGISTERED", "Email is already registered.");
+		}
+	}
+
+	if !cfg.register.allow_multiple_accounts {
+		if let Some(fingerprint) = &payload.fingerprint {
+			if User::fingerprint_exists(db, fingerprint).await? {
+				errors.add(
+					"fingerprint",
+					"ACCOUNT_ALREADY_REGISTERED",
+					"An account was already registered from this device.",
+				);
+			}
+		}
 	}
 
-	// TODO: Check registration disabled
-	if let Some(exists) =
-		User::get_user_by_email_or_phone(db, payload.email.as_ref().unwrap().as_str(), "")
-			.await
-			.expect("Failed to check if user exists")
+	let invite = match &payload.invite {
+		Some(code) => {
+			// Expired and used up invites would fail to join after the account
+			// was created, so they do not count as an invite
+			let invite = Invite::get_by_code(db, code)
+				.await?
+				.filter(|invite| invite.is_usable(chrono::Utc::now()));
+			if invite.is_none() {
+				errors.add("invite", "INVITE_INVALID", "Unknown invite.");
+			}
+			invite
+		}
+		None => None,
+	};
+	let guest = email.is_none();
+	if payload.invite.is_none()
+		&& !token_used
+		&& (cfg.register.require_invite || (cfg.register.guests_require_invite && guest))
 	{
-		return Err(APIError::Auth(AuthError::InvalidLogin)); // TODO: Change error
+		errors.add("invite", "INVITE_ONLY", "You need an invite to register.");
 	}
 
-	// TODO: All field checks
+	errors.into_result()?;
+
+	let rate = &cfg.limits.absolute_rate.register;
+	if rate.enabled && !token_used {
+		// The window is configured in milliseconds
+		let since = chrono::Utc::now() - chrono::Duration::milliseconds(rate.window as i64);
+		if User::count_created_since(db, since).await? >= rate.limit as i64 {
+			return Err(Error::RateLimit(RateLimitError::TooManyRegistrations).into());
+		}
+	}
 
+	let mut tx = db.begin().await?;
+	if let Some(token) = query.token.as_deref().filter(|_| token_used) {
+		// Another registration may have used the token since it was checked
+		if !RegistrationToken::consume(&mut tx, token).await? {
+			errors.add("token", "TOKEN_INVALID", "Registration token is invalid or already used.");
+			return Err(Error::InvalidFormBody(errors).into());
+		}
+	}
 	let user = User::create(
-		db,
+		&mut tx,
 		cfg,
-		&payload.username,
+		payload.username.trim(),
 		payload.password,
-		payload.email,
+		email,
 		payload.fingerprint,
 		payload.date_of_birth,
 		false,
 	)
-	.await
-	.expect("Failed to create user");
-
-	let db = db.clone();
-	let connected_users = connected_users.clone();
-	let id = user.id;
-	tokio::spawn(async move {
-		let mut role_user_map = connected_users.role_user_map.lock().await;
-		let role_ids = Role::get_ids_by_user(&db, id)
-			.await
-			.expect("Please report this error to the symfonia developers");
-		// Add the user to each role they are in
-		for role_id in role_ids.iter() {
-			// Check if the role exists in the map
-			let maybe_role_entry = role_user_map.get_mut(role_id);
-			match maybe_role_entry {
-				// Role exists - add user
-				Some(role_entry) => {
-					role_entry.insert(id);
-				}
-				// Role doesn't exist - create role and add user to it
-				None => {
-					role_user_map.insert(*role_id, HashSet::new());
-					role_user_map.get_mut(role_id).unwrap().insert(id);
-				}
-			};
+	.await?;
+	tx.commit().await?;
+
+	if let Some(mut invite) = invite {
+		// The account exists at this point, so a failed join must not fail the
+		// registration
+		if let Err(e) = invite.join(db, &connected_users.role_user_map, &user).await {
+			log::error!(target: "symfonia::auth::register", "Failed to join invite {} for user {}: {e}", invite.code, user.id);
 		}
-	});
+	}
 
-	// TODO: Invite
+	// The account exists at this point, so the mail is sent in the background
+	// and failing to send it does not fail the registration
+	match verification_mail(cfg, &user) {
+		Ok(Some(mail)) => {
+			let mailer = mailer.clone();
+			let user_id = user.id;
+			tokio::spawn(async move {
+				if let Err(e) = mailer.send(mail).await {
+					log::error!(target: "symfonia::auth::register", "Failed to send verification email to user {user_id}: {e}");
+				}
+			});
+		}
+		Ok(None) => (),
+		Err(e) => {
+			log::error!(target: "symfonia::auth::register", "Failed to create the verification email for user {}: {e}", user.id);
+		}
+	}
 
-	let token =
-		generate_token(&user.id, user.email.clone().unwrap().as_str(), &cfg.security.jwt_secret);
+	let token = generate_token(
+		user.id,
+		user.email.clone().unwrap_or_default().as_str(),
+		&cfg.security.jwt_secret,
+	)?;
 
 	Ok(Json(json!({"token": token})))
 }
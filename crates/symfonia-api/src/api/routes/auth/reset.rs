@@ -0,0 +1,104 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+use poem::{
+	IntoResponse, Response, handler,
+	http::StatusCode,
+	web::{Data, Json},
+};
+use serde::Deserialize;
+use serde_json::json;
+use util::{
+	entities::{Config, User},
+	errors::FieldErrors,
+	mail::{Mail, SharedMailer},
+	util::{
+		captcha::verify_captcha,
+		register,
+		token::{EmailTokenPurpose, check_email_token, generate_email_token, generate_token},
+	},
+};
+
+use super::{email_link, start_mfa_login};
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordSchema {
+	/// The email address of the account.
+	pub login: String,
+	pub captcha_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordSchema {
+	/// The token of the link sent by `forgot_password`.
+	pub token: String,
+	pub password: String,
+}
+
+/// Send a link to reset the password to the account with the given address.
+/// Responds the same whether or not such an account exists, so that it does
+/// not reveal which addresses are registered. The email is sent in the
+/// background, as waiting for it would take longer for existing accounts.
+#[handler]
+pub async fn forgot_password(
+	Data(db): Data<&sqlx::PgPool>,
+	Data(cfg): Data<&Config>,
+	Data(mailer): Data<&SharedMailer>,
+	Json(payload): Json<ForgotPasswordSchema>,
+) -> poem::Result<impl IntoResponse> {
+	if cfg.password_reset.require_captcha && cfg.security.captcha.enabled {
+		verify_captcha(cfg, payload.captcha_key.as_deref()).await?;
+	}
+
+	let user = User::get_user_by_email_or_phone(db, &payload.login, "").await?;
+	if let Some((user, email)) = user.and_then(|user| user.email.clone().map(|email| (user, email)))
+	{
+		let token = generate_email_token(
+			user.id,
+			&email,
+			EmailTokenPurpose::ResetPassword,
+			&cfg.security.jwt_secret,
+		)?;
+		let mail = Mail::password_reset(&email, &user.username, &email_link("reset", &token));
+		let mailer = mailer.clone();
+		tokio::spawn(async move {
+			if let Err(e) = mailer.send(mail).await {
+				log::error!(target: "symfonia::auth::reset", "Failed to send password reset email to user {}: {e}", user.id);
+			}
+		});
+	}
+
+	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
+}
+
+/// Set a new password with the token of a password reset link, revoking all
+/// tokens issued before, and log the user in.
+#[handler]
+pub async fn reset_password(
+	Data(db): Data<&sqlx::PgPool>,
+	Data(cfg): Data<&Config>,
+	Json(payload): Json<ResetPasswordSchema>,
+) -> poem::Result<impl IntoResponse> {
+	let mut user = check_email_token(
+		db,
+		&payload.token,
+		EmailTokenPurpose::ResetPassword,
+		&cfg.security.jwt_secret,
+	)
+	.await?;
+
+	let mut errors = FieldErrors::default();
+	register::check_password(cfg, Some(&payload.password), &mut errors);
+	errors.into_result()?;
+	user.set_password(db, &payload.password).await?;
+
+	if let Some(mfa) = start_mfa_login(db, &user).await? {
+		return Ok(Json(mfa));
+	}
+	let token = generate_token(
+		user.id,
+		user.email.clone().unwrap_or_default().as_str(),
+		&cfg.security.jwt_secret,
+	)?;
+	Ok(Json(json!({"token": token})))
+}
@@ -0,0 +1,87 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+use chorus::types::jwt::Claims;
+use poem::{
+	IntoResponse, Response, handler,
+	http::StatusCode,
+	web::{Data, Json},
+};
+use serde::Deserialize;
+use serde_json::json;
+use util::{
+	configuration::SymfoniaConfiguration,
+	entities::{Config, User},
+	errors::{Error, UserError},
+	mail::{Mail, SharedMailer},
+	util::token::{EmailTokenPurpose, check_email_token, generate_email_token, generate_token},
+};
+
+use super::start_mfa_login;
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailSchema {
+	/// The token of the link sent to the user.
+	pub token: String,
+}
+
+/// The link to the page of the client at `path`, carrying `token`.
+pub(super) fn email_link(path: &str, token: &str) -> String {
+	let base = &SymfoniaConfiguration::get().general.mail.link_base_url;
+	format!("{}/{path}#token={token}", base.trim_end_matches('/'))
+}
+
+/// The mail with a link for `user` to verify their email address, if they
+/// have one.
+pub(super) fn verification_mail(cfg: &Config, user: &User) -> Result<Option<Mail>, Error> {
+	let Some(email) = &user.email else {
+		return Ok(None);
+	};
+	let token =
+		generate_email_token(user.id, email, EmailTokenPurpose::Verify, &cfg.security.jwt_secret)?;
+	Ok(Some(Mail::verification(email, &user.username, &email_link("verify", &token))))
+}
+
+/// Verify the email address of the user the token was sent to, and log them
+/// in.
+#[handler]
+pub async fn verify_email(
+	Data(db): Data<&sqlx::PgPool>,
+	Data(cfg): Data<&Config>,
+	Json(payload): Json<VerifyEmailSchema>,
+) -> poem::Result<impl IntoResponse> {
+	let mut user =
+		check_email_token(db, &payload.token, EmailTokenPurpose::Verify, &cfg.security.jwt_secret)
+			.await?;
+	user.set_verified(db).await?;
+
+	if let Some(mfa) = start_mfa_login(db, &user).await? {
+		return Ok(Json(mfa));
+	}
+	let token = generate_token(
+		user.id,
+		user.email.clone().unwrap_or_default().as_str(),
+		&cfg.security.jwt_secret,
+	)?;
+	Ok(Json(json!({"token": token, "user_id": user.id})))
+}
+
+/// Send the current user another verification link.
+#[handler]
+pub async fn resend_verification(
+	Data(db): Data<&sqlx::PgPool>,
+	Data(cfg): Data<&Config>,
+	Data(mailer): Data<&SharedMailer>,
+	Data(claims): Data<&Claims>,
+) -> poem::Result<impl IntoResponse> {
+	let user = User::get_by_id(db, claims.id).await?.ok_or(Error::User(UserError::InvalidUser))?;
+	if user.email.is_none() {
+		return Err(Error::User(UserError::InvalidEmail).into());
+	}
+	if !user.verified.unwrap_or_default()
+		&& let Some(mail) = verification_mail(cfg, &user)?
+	{
+		mailer.send(mail).await?;
+	}
+	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
+}
@@ -6,16 +6,22 @@ use poem::{
 	IntoResponse, handler,
 	web::{Data, Json, Path},
 };
+use serde_json::json;
 use sqlx::PgPool;
 use util::{
 	entities::Channel,
 	errors::{ChannelError, Error},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
 };
 
 #[handler]
 pub async fn create_invite(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(channel_id): Path<Snowflake>,
 	Json(payload): Json<CreateChannelInviteSchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -23,11 +29,17 @@ pub async fn create_invite(
 		.await?
 		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
 
-	// TODO: Check if the user has permission to create an invite
 	// TODO: Check if the channel is a Group DM, and handle recipients
 	// TODO: Check if inviter should be anonymous
 	let invite = channel.create_invite(db, payload, None).await?;
 
+	let mut data = serde_json::to_value(&*invite).map_err(Error::from)?;
+	data["channel_id"] = json!(channel.id);
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_channel_viewers(channel.id);
+	builder.set_message(DispatchEvent::new(DispatchEventType::InviteCreate, data)?);
+	builder.send(db, connected_users).await?;
+
 	Ok(Json(invite.into_inner()))
 }
 
@@ -41,8 +53,6 @@ pub async fn get_invites(
 		.await?
 		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
 
-	// TODO: Check if the user is allowed to see the invites of this channel
-
 	let invites = channel.get_invites(db).await?;
 
 	Ok(Json(invites))
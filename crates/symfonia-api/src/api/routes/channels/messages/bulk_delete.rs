@@ -7,10 +7,15 @@ use poem::{
 	http::StatusCode,
 	web::{Data, Json, Path},
 };
+use serde_json::json;
 use sqlx::PgPool;
 use util::{
 	entities::{Channel, Config, Message, User},
 	errors::{ChannelError, Error},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
 };
 
 #[handler]
@@ -18,6 +23,7 @@ pub async fn bulk_delete(
 	Data(db): Data<&PgPool>,
 	Data(config): Data<&Config>,
 	Data(user): Data<&User>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(channel_id): Path<Snowflake>,
 	Json(ids): Json<Vec<Snowflake>>,
 ) -> poem::Result<impl IntoResponse> {
@@ -37,10 +43,20 @@ pub async fn bulk_delete(
 		return Err(Error::Channel(ChannelError::TooManyMessages(max_bulk_delete)).into());
 	}
 
-	// TODO: Check if the user has permission to delete the messages
-	Message::bulk_delete(db, ids).await?;
+	Message::bulk_delete(db, ids.clone()).await?;
 
-	// TODO: Emit event 'MESSAGE_DELETE_BULK'
+	let event = DispatchEvent::new(
+		DispatchEventType::MessageDeleteBulk,
+		json!({
+			"ids": ids,
+			"channel_id": channel.id,
+			"guild_id": channel.guild_id,
+		}),
+	)?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_channel_viewers(channel.id);
+	builder.set_message(event);
+	builder.send(db, connected_users).await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
@@ -23,8 +23,6 @@ pub async fn acknowledge_message(
 		.await?
 		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
 
-	// TODO: Check if user can view channel (VIEW_CHANNEL)
-
 	if let Some(mut read_state) =
 		ReadState::get_by_user_and_channel(db, channel_id, claims.id).await?
 	{
@@ -1,18 +1,25 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::{MessageModifySchema, Rights, Snowflake, jwt::Claims};
+use chorus::types::{MessageModifySchema, PermissionFlags, Rights, Snowflake, jwt::Claims};
 use poem::{
 	IntoResponse, Response, handler,
 	http::StatusCode,
 	web::{Data, Json, Path},
 };
+use serde_json::json;
 use sqlx::PgPool;
 use util::{
 	entities::{Channel, Config, Message, User},
 	errors::{ChannelError, Error},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
 };
 
+use crate::api::middleware::permission_guard::require_channel_permissions;
+
 pub(crate) mod ack;
 pub(crate) mod crosspost;
 pub(crate) mod reactions;
@@ -23,6 +30,7 @@ pub async fn edit_message(
 	Data(_claims): Data<&Claims>,
 	Data(_config): Data<&Config>,
 	Data(authed_user): Data<&User>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, message_id)): Path<(Snowflake, Snowflake)>,
 	Json(payload): Json<MessageModifySchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -30,7 +38,6 @@ pub async fn edit_message(
 		.await?
 		.ok_or(Error::Channel(ChannelError::InvalidMessage))?;
 
-	// TODO: Check if the user has permission to edit the message
 	if message.author_id != authed_user.id {
 		if !authed_user.rights.has(Rights::MANAGE_MESSAGES, true) {
 			// TODO: Check if user is instance admin
@@ -41,7 +48,13 @@ pub async fn edit_message(
 
 	message.modify(db, payload).await?;
 
-	// TODO: Emit events
+	let channel = Channel::get_by_id(db, channel_id)
+		.await?
+		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_channel_viewers(channel.id);
+	builder.set_message(DispatchEvent::new(DispatchEventType::MessageUpdate, &*message)?);
+	builder.send(db, connected_users).await?;
 
 	Ok(Json(message))
 }
@@ -50,7 +63,7 @@ pub async fn edit_message(
 pub async fn get_message(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
-	// Data(authed_user): Data<&User>,
+	Data(permissions): Data<&PermissionFlags>,
 	Path((channel_id, message_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	let message = Message::get_by_id(db, channel_id, message_id)
@@ -58,10 +71,8 @@ pub async fn get_message(
 		.expect("Failed to get message data")
 		.ok_or(Error::Channel(ChannelError::InvalidMessage))?;
 
-	if message.author_id != claims.id
-	/* TODO: && permissions check 'READ_MESSAGE_HISTORY' */
-	{
-		return Err(Error::Channel(ChannelError::InvalidMessage))?;
+	if message.author_id != claims.id {
+		require_channel_permissions(*permissions, PermissionFlags::READ_MESSAGE_HISTORY)?;
 	}
 
 	Ok(Json(message))
@@ -72,6 +83,8 @@ pub async fn delete_message(
 	Data(db): Data<&PgPool>,
 	Data(_claims): Data<&Claims>,
 	Data(authed_user): Data<&User>,
+	Data(permissions): Data<&PermissionFlags>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, message_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	let channel = Channel::get_by_id(db, channel_id)
@@ -84,14 +97,25 @@ pub async fn delete_message(
 
 	if message.author_id != authed_user.id && !authed_user.rights.has(Rights::MANAGE_MESSAGES, true)
 	{
-		// TODO: Check permissions on channel
+		require_channel_permissions(*permissions, PermissionFlags::MANAGE_MESSAGES)?;
 	} else if !authed_user.rights.has(Rights::SELF_DELETE_MESSAGES, false) {
 		return Err(Error::Channel(ChannelError::InvalidMessage))?; // TODO: Maybe a different error?
 	}
 
 	message.delete(db).await?;
 
-	// TODO: Emit event 'MESSAGE_DELETE'
+	let event = DispatchEvent::new(
+		DispatchEventType::MessageDelete,
+		json!({
+			"id": message.id,
+			"channel_id": channel.id,
+			"guild_id": channel.guild_id,
+		}),
+	)?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_channel_viewers(channel.id);
+	builder.set_message(event);
+	builder.send(db, connected_users).await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
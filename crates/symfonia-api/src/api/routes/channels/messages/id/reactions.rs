@@ -1,22 +1,33 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::{PartialEmoji, Reaction, ReactionQuerySchema, Snowflake, jwt::Claims};
+use chorus::types::{
+	PartialEmoji, PermissionFlags, Reaction, ReactionQuerySchema, Snowflake, jwt::Claims,
+};
 use poem::{
 	IntoResponse, Response, handler,
 	web::{Data, Json, Path, Query},
 };
 use reqwest::StatusCode;
+use serde_json::json;
 use sqlx::PgPool;
 use util::{
 	entities::{Channel, Emoji, GuildMember, Message, User},
 	errors::{ChannelError, Error, GuildError, ReactionError, UserError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
 };
 
+use crate::api::middleware::permission_guard::require_channel_permissions;
+
 #[handler]
 pub async fn add_reaction(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(permissions): Data<&PermissionFlags>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, message_id)): Path<(Snowflake, Snowflake)>,
 	Path((emoji, user_id)): Path<(String, String)>,
 ) -> poem::Result<impl IntoResponse> {
@@ -37,9 +48,9 @@ pub async fn add_reaction(
 	if let Some(emoji_id) = partial_emoji.id {
 		if let Some(external_emoji) = Emoji::get_by_id(db, emoji_id).await? {
 			if message.get_reaction(&partial_emoji).is_none()
-				&& channel.guild_id.map(|id| external_emoji.guild_id.eq(&id)).unwrap_or_default()
+				&& channel.guild_id.map(|id| external_emoji.guild_id.ne(&id)).unwrap_or(true)
 			{
-				// TODO: check permissions 'USE_EXTERNAL_EMOJIS'
+				require_channel_permissions(*permissions, PermissionFlags::USE_EXTERNAL_EMOJIS)?;
 			}
 
 			if let Some(name) = &external_emoji.name {
@@ -49,8 +60,6 @@ pub async fn add_reaction(
 		}
 	}
 
-	// TODO: Check permissions 'ADD_REACTIONS'
-
 	if let Some(reaction) = message.get_reaction_mut(&partial_emoji) {
 		if reaction.user_ids.contains(&claims.id) {
 			// TODO: No error thrown for compatibility with discord, may change in the
@@ -79,30 +88,61 @@ pub async fn add_reaction(
 
 	message.save(db).await?;
 
+	let mut member = None;
 	if let Some(guild_id) = channel.guild_id {
-		let _member = GuildMember::get_by_id(db, claims.id, guild_id)
-			.await?
-			.ok_or(Error::Guild(GuildError::InvalidGuild))?;
-
-		// TODO: emit events 'MESSAGE_REACTION_ADD'
+		member = Some(
+			GuildMember::get_by_id(db, claims.id, guild_id)
+				.await?
+				.ok_or(Error::Guild(GuildError::InvalidGuild))?,
+		);
 	}
 
+	let event = DispatchEvent::new(
+		DispatchEventType::MessageReactionAdd,
+		json!({
+			"user_id": claims.id,
+			"channel_id": channel.id,
+			"message_id": message.id,
+			"guild_id": channel.guild_id,
+			"member": member.as_deref(),
+			"emoji": partial_emoji,
+		}),
+	)?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_channel_viewers(channel.id);
+	builder.set_message(event);
+	builder.send(db, connected_users).await?;
+
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
 
 #[handler]
 pub async fn delete_all_reactions(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, message_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
-	// TODO: Check permissions
-	let mut message = Message::get_by_id(db, channel_id, message_id)
+	let channel = Channel::get_by_id(db, channel_id)
+		.await?
+		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
+	let mut message = Message::get_by_id(db, channel.id, message_id)
 		.await?
 		.ok_or(Error::Channel(ChannelError::InvalidMessage))?;
 
 	message.clear_reactions(db).await?;
 
-	// TODO: Emit event
+	let event = DispatchEvent::new(
+		DispatchEventType::MessageReactionRemoveAll,
+		json!({
+			"channel_id": channel.id,
+			"message_id": message.id,
+			"guild_id": channel.guild_id,
+		}),
+	)?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_channel_viewers(channel.id);
+	builder.set_message(event);
+	builder.send(db, connected_users).await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
@@ -111,6 +151,8 @@ pub async fn delete_all_reactions(
 pub async fn delete_reaction(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(permissions): Data<&PermissionFlags>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, message_id)): Path<(Snowflake, Snowflake)>,
 	Path((emoji, user_id)): Path<(String, String)>,
 ) -> poem::Result<impl IntoResponse> {
@@ -130,12 +172,27 @@ pub async fn delete_reaction(
 	} else {
 		uid = Snowflake(user_id.parse::<u64>().map_err(|_| Error::User(UserError::InvalidUser))?);
 
-		// TODO: Check permissions 'MANAGE_MESSAGES'
+		if uid != claims.id {
+			require_channel_permissions(*permissions, PermissionFlags::MANAGE_MESSAGES)?;
+		}
 	}
 
-	message.remove_reaction(db, partial_emoji).await?;
-
-	// TODO: Emit event
+	message.remove_reaction(db, partial_emoji.clone()).await?;
+
+	let event = DispatchEvent::new(
+		DispatchEventType::MessageReactionRemove,
+		json!({
+			"user_id": uid,
+			"channel_id": channel.id,
+			"message_id": message.id,
+			"guild_id": channel.guild_id,
+			"emoji": partial_emoji,
+		}),
+	)?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_channel_viewers(channel.id);
+	builder.set_message(event);
+	builder.send(db, connected_users).await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
@@ -2,8 +2,8 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 use chorus::types::{
-	GetChannelMessagesSchema, MessageSendSchema, MessageType, Rights, Snowflake, jwt::Claims,
-	types::guild_configuration::GuildFeatures,
+	GetChannelMessagesSchema, MessageSendSchema, MessageType, PermissionFlags, Rights, Snowflake,
+	jwt::Claims, types::guild_configuration::GuildFeatures,
 };
 use poem::{
 	IntoResponse, handler,
@@ -13,8 +13,14 @@ use sqlx::PgPool;
 use util::{
 	entities::{Channel, Config, Guild, Message, User},
 	errors::{ChannelError, Error, GuildError, RateLimitError, UserError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
 };
 
+use crate::api::middleware::permission_guard::require_channel_permissions;
+
 pub mod bulk_delete;
 pub(crate) mod id;
 
@@ -33,10 +39,6 @@ pub async fn get_messages(
 		return Err(Error::Channel(ChannelError::InvalidChannelType).into());
 	}
 
-	// TODO: Check if the user has permission to view the channel (VIEW_CHANNEL)
-	// TODO: Check if the user has permission to read previous messages
-	// (READ_MESSAGE_HISTORY)
-
 	let limit = payload.limit.unwrap_or(50);
 	let mut messages = channel.get_messages(db, payload.anchor, limit).await?;
 
@@ -63,6 +65,8 @@ pub async fn create_message(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
 	Data(config): Data<&Config>,
+	Data(permissions): Data<&PermissionFlags>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(channel_id): Path<Snowflake>,
 	Json(mut payload): Json<MessageSendSchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -76,9 +80,6 @@ pub async fn create_message(
 
 	let user = User::get_by_id(db, claims.id).await?.ok_or(Error::User(UserError::InvalidUser))?;
 
-	// TODO: Check if the user has permission to send messages in the channel
-	// (SEND_MESSAGES)
-
 	if let Some(nonce) = &payload.nonce {
 		if let Some(existing) = Message::get_by_nonce(db, channel_id, claims.id, nonce).await? {
 			return Ok(Json(existing));
@@ -124,7 +125,7 @@ pub async fn create_message(
 	}
 
 	if let Some(reference) = payload.message_reference.as_ref() {
-		// TODO: Check READ_MESSAGE_HISTORY
+		require_channel_permissions(*permissions, PermissionFlags::READ_MESSAGE_HISTORY)?;
 		if let Some(guild_id) = reference.guild_id {
 			let guild = Guild::get_by_id(db, guild_id)
 				.await?
@@ -143,6 +144,10 @@ pub async fn create_message(
 	}
 
 	let message = channel.create_message(db, payload, claims.id).await?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_channel_viewers(channel.id);
+	builder.set_message(DispatchEvent::new(DispatchEventType::MessageCreate, &*message)?);
+	builder.send(db, connected_users).await?;
 
 	Ok(Json(message))
 }
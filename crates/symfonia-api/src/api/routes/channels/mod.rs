@@ -1,16 +1,24 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::{ChannelModifySchema, Snowflake, jwt::Claims};
+use chorus::types::{ChannelModifySchema, ChannelType, PermissionFlags, Snowflake, jwt::Claims};
 use invites::{create_invite, get_invites};
 use poem::{
-	IntoResponse, Route, delete, get, handler, post, put,
+	EndpointExt, IntoResponse, Route, delete, get, handler, post, put,
 	web::{Data, Json, Path},
 };
 use sqlx::PgPool;
 use util::{
-	entities::Channel,
+	entities::{Channel, Recipient},
 	errors::{ChannelError, Error},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
+};
+
+use crate::api::middleware::permission_guard::{
+	PermissionGuardMiddleware, require_channel_permissions,
 };
 
 mod followers;
@@ -24,51 +32,101 @@ mod webhooks;
 
 pub fn setup_routes() -> Route {
 	Route::new()
-		.at("/:channel_id", get(get_channel).delete(delete_channel).patch(modify_channel))
-		.at("/:channel_id/invites", get(get_invites).post(create_invite))
-		.at("/:channel_id/messages", get(messages::get_messages).post(messages::create_message))
-		.at("/:channel_id/messages/bulk_delete", post(messages::bulk_delete::bulk_delete))
+		.at(
+			"/:channel_id",
+			get(get_channel.with(guard(PermissionFlags::VIEW_CHANNEL)))
+				.delete(delete_channel.with(guard(PermissionFlags::empty())))
+				.patch(modify_channel.with(guard(PermissionFlags::MANAGE_CHANNELS))),
+		)
+		.at(
+			"/:channel_id/invites",
+			get(get_invites.with(guard(PermissionFlags::MANAGE_CHANNELS)))
+				.post(create_invite.with(guard(PermissionFlags::CREATE_INSTANT_INVITE))),
+		)
+		.at(
+			"/:channel_id/messages",
+			get(messages::get_messages.with(guard(PermissionFlags::READ_MESSAGE_HISTORY)))
+				.post(messages::create_message.with(guard(PermissionFlags::SEND_MESSAGES))),
+		)
+		.at(
+			"/:channel_id/messages/bulk_delete",
+			post(messages::bulk_delete::bulk_delete.with(guard(PermissionFlags::MANAGE_MESSAGES))),
+		)
 		.at(
 			"/:channel_id/messages/:message_id",
-			get(messages::id::get_message)
-				.delete(messages::id::delete_message)
-				.patch(messages::id::edit_message),
+			get(messages::id::get_message.with(guard(PermissionFlags::VIEW_CHANNEL)))
+				.delete(messages::id::delete_message.with(guard(PermissionFlags::VIEW_CHANNEL)))
+				.patch(messages::id::edit_message.with(guard(PermissionFlags::VIEW_CHANNEL))),
+		)
+		.at(
+			"/:channel_id/messages/:message_id/ack",
+			post(messages::id::ack::acknowledge_message.with(guard(PermissionFlags::VIEW_CHANNEL))),
 		)
-		.at("/:channel_id/messages/:message_id/ack", post(messages::id::ack::acknowledge_message))
 		.at(
 			"/:channel_id/messages/:message_id/crosspost",
-			get(messages::id::crosspost::create_crosspost_message),
+			get(messages::id::crosspost::create_crosspost_message
+				.with(guard(PermissionFlags::SEND_MESSAGES))),
 		)
 		.at(
 			"/:channel_id/messages/:message_id/reactions",
-			delete(messages::id::reactions::delete_all_reactions),
+			delete(
+				messages::id::reactions::delete_all_reactions
+					.with(guard(PermissionFlags::MANAGE_MESSAGES)),
+			),
 		)
 		.at(
 			"/:channel_id/messages/:message_id/reactions/:emoji",
-			get(messages::id::reactions::get_reaction)
-				.delete(messages::id::reactions::delete_reaction),
+			get(messages::id::reactions::get_reaction
+				.with(guard(PermissionFlags::READ_MESSAGE_HISTORY)))
+			.delete(
+				messages::id::reactions::delete_reaction.with(guard(PermissionFlags::VIEW_CHANNEL)),
+			),
 		)
 		.at(
 			"/:channel_id/messages/:message_id/reactions/:emoji/:user_id",
-			put(messages::id::reactions::add_reaction),
+			put(messages::id::reactions::add_reaction.with(guard(
+				PermissionFlags::READ_MESSAGE_HISTORY | PermissionFlags::ADD_REACTIONS,
+			))),
+		)
+		.at(
+			"/:channel_id/pins",
+			get(pins::get_pinned_messages.with(guard(PermissionFlags::READ_MESSAGE_HISTORY))),
 		)
-		.at("/:channel_id/pins", get(pins::get_pinned_messages))
 		.at(
 			"/:channel_id/pins/:message_id",
-			put(pins::add_pinned_message).delete(pins::remove_pinned_message),
+			put(pins::add_pinned_message.with(guard(PermissionFlags::VIEW_CHANNEL)))
+				.delete(pins::remove_pinned_message.with(guard(PermissionFlags::VIEW_CHANNEL))),
+		)
+		.at(
+			"/:channel_id/typing",
+			post(typing::typing_indicator.with(guard(PermissionFlags::SEND_MESSAGES))),
+		)
+		.at(
+			"/:channel_id/webhooks",
+			get(webhooks::get_webhooks.with(guard(PermissionFlags::MANAGE_WEBHOOKS)))
+				.post(webhooks::create_webhook.with(guard(PermissionFlags::MANAGE_WEBHOOKS))),
 		)
-		.at("/:channel_id/webhooks", get(webhooks::get_webhooks).post(webhooks::create_webhook))
-		.at("/:channel_id/followers", post(followers::create_following))
 		.at(
-			"/:channel_id/recipients",
-			put(recipients::add_recipient).delete(recipients::remove_recipient),
+			"/:channel_id/followers",
+			post(followers::create_following.with(guard(PermissionFlags::MANAGE_WEBHOOKS))),
+		)
+		.at(
+			"/:channel_id/recipients/:user_id",
+			put(recipients::add_recipient.with(guard(PermissionFlags::VIEW_CHANNEL)))
+				.delete(recipients::remove_recipient.with(guard(PermissionFlags::VIEW_CHANNEL))),
 		)
 		.at(
 			"/:channel_id/permissions/:overwrite_id",
-			put(permissions::add_overwrite).delete(permissions::remove_overwrite),
+			put(permissions::add_overwrite.with(guard(PermissionFlags::MANAGE_ROLES)))
+				.delete(permissions::remove_overwrite.with(guard(PermissionFlags::MANAGE_ROLES))),
 		)
 }
 
+/// Requires `VIEW_CHANNEL` and `permissions` in the channel of the route.
+fn guard(permissions: PermissionFlags) -> PermissionGuardMiddleware {
+	PermissionGuardMiddleware::channel(PermissionFlags::VIEW_CHANNEL | permissions)
+}
+
 #[handler]
 pub async fn get_channel(
 	Data(db): Data<&PgPool>,
@@ -80,24 +138,47 @@ pub async fn get_channel(
 		.expect("Failed to get channel data")
 		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
 
-	// TODO: Check if the user has permission to read the channel
-
 	Ok(Json(channel.into_inner()))
 }
 
+/// Delete a guild channel, which requires `MANAGE_CHANNELS`. Deleting a DM
+/// closes it for the current user, and deleting a group DM leaves it.
 #[handler]
 pub async fn delete_channel(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
+	Data(permissions): Data<&PermissionFlags>,
 	Path(channel_id): Path<Snowflake>,
 ) -> poem::Result<impl IntoResponse> {
 	let channel = Channel::get_by_id(db, channel_id)
 		.await?
 		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
 
-	// TODO: Check if the user has permission to delete the channel
-	// TODO: Check if the channel is a DM, and handle recipients
-	channel.delete(db).await?;
+	match channel.channel_type {
+		ChannelType::Dm => {
+			let mut recipient = Recipient::get_by_channel_and_user_id(db, channel.id, claims.id)
+				.await?
+				.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
+			recipient.close(db).await?;
+			let mut builder = connected_users.bulk_message_builder();
+			builder.add_user_recipients(&[claims.id]);
+			builder.set_message(DispatchEvent::new(DispatchEventType::ChannelDelete, &*channel)?);
+			builder.send(db, connected_users).await?;
+		}
+		ChannelType::GroupDm => {
+			recipients::leave_group_dm(db, connected_users, &channel, claims.id).await?;
+		}
+		_ => {
+			require_channel_permissions(*permissions, PermissionFlags::MANAGE_CHANNELS)?;
+			let viewers = connected_users.channel_viewers(db, &channel).await?;
+			channel.delete(db).await?;
+			let mut builder = connected_users.bulk_message_builder();
+			builder.add_user_recipients(&viewers);
+			builder.set_message(DispatchEvent::new(DispatchEventType::ChannelDelete, &*channel)?);
+			builder.send(db, connected_users).await?;
+		}
+	}
 
 	Ok(Json(channel.into_inner()))
 }
@@ -106,6 +187,7 @@ pub async fn delete_channel(
 pub async fn modify_channel(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(channel_id): Path<Snowflake>,
 	Json(payload): Json<ChannelModifySchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -113,10 +195,12 @@ pub async fn modify_channel(
 		.await?
 		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
 
-	// TODO: Check if the user has permission to modify the channel
-
 	channel.modify(payload);
 	channel.save(db).await?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_channel_viewers(channel.id);
+	builder.set_message(DispatchEvent::new(DispatchEventType::ChannelUpdate, &*channel)?);
+	builder.send(db, connected_users).await?;
 
 	Ok(Json(channel.into_inner()))
 }
@@ -1,7 +1,11 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::{PermissionOverwrite, PermissionOverwriteType, Snowflake, jwt::Claims};
+use std::collections::HashSet;
+
+use chorus::types::{
+	PermissionFlags, PermissionOverwrite, PermissionOverwriteType, Snowflake, jwt::Claims,
+};
 use poem::{
 	IntoResponse, Response, handler,
 	http::StatusCode,
@@ -11,12 +15,20 @@ use sqlx::PgPool;
 use util::{
 	entities::{Channel, GuildMember, Role},
 	errors::{ChannelError, Error, GuildError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
 };
 
+use crate::api::middleware::permission_guard::require_channel_permissions;
+
 #[handler]
 pub async fn add_overwrite(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(permissions): Data<&PermissionFlags>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, overwrite_id)): Path<(Snowflake, Snowflake)>,
 	Json(payload): Json<PermissionOverwrite>,
 ) -> poem::Result<impl IntoResponse> {
@@ -26,7 +38,8 @@ pub async fn add_overwrite(
 
 	let guild_id = channel.guild_id.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
 
-	// TODO: Check permissions
+	// Users can only allow or deny permissions they have themselves
+	require_channel_permissions(*permissions, payload.allow | payload.deny)?;
 
 	if payload.overwrite_type.eq(&PermissionOverwriteType::Role) {
 		if Role::get_by_id(db, overwrite_id).await?.is_none() {
@@ -38,19 +51,16 @@ pub async fn add_overwrite(
 		return Err(Error::Guild(GuildError::MemberNotFound).into());
 	}
 
-	if let Some(overwrite) = channel
-		.permission_overwrites
-		.as_mut()
-		.and_then(|x| x.iter_mut().find(|x| x.id == overwrite_id))
-	{
-		overwrite.allow &= payload.allow;
-		overwrite.deny &= payload.deny;
+	let viewers = connected_users.channel_viewers(db, &channel).await?;
+	let overwrites = channel.permission_overwrites.get_or_insert_with(Default::default);
+	if let Some(overwrite) = overwrites.iter_mut().find(|x| x.id == overwrite_id) {
+		overwrite.allow = payload.allow;
+		overwrite.deny = payload.deny;
 	} else {
-		channel.permission_overwrites = Some(sqlx::types::Json(vec![payload]));
+		overwrites.push(PermissionOverwrite { id: overwrite_id, ..payload });
 	}
 	channel.save(db).await?;
-
-	// TODO: emit event 'CHANNEL_UPDATE'
+	dispatch_overwrite_change(db, connected_users, &channel, viewers).await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
@@ -59,6 +69,7 @@ pub async fn add_overwrite(
 pub async fn remove_overwrite(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, overwrite_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	let mut channel = Channel::get_by_id(db, channel_id)
@@ -67,14 +78,42 @@ pub async fn remove_overwrite(
 
 	let guild_id = channel.guild_id.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
 
-	// TODO: Check permissions
-
+	let viewers = connected_users.channel_viewers(db, &channel).await?;
 	if let Some(overwrites) = channel.permission_overwrites.as_mut() {
 		overwrites.retain(|x| x.id != overwrite_id);
 	}
 	channel.save(db).await?;
-
-	// TODO: emit event 'CHANNEL_UPDATE'
+	dispatch_overwrite_change(db, connected_users, &channel, viewers).await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
+
+/// Dispatch the saved `channel`, whose overwrites changed, to the users who
+/// could see it before, `viewers`, and to those who can see it now. Users who
+/// lost access get a `CHANNEL_DELETE`, users who gained it a `CHANNEL_CREATE`,
+/// and everyone else a `CHANNEL_UPDATE`.
+async fn dispatch_overwrite_change(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	channel: &Channel,
+	viewers: Vec<Snowflake>,
+) -> Result<(), Error> {
+	let before: HashSet<Snowflake> = viewers.into_iter().collect();
+	let after: HashSet<Snowflake> =
+		connected_users.channel_viewers(db, channel).await?.into_iter().collect();
+	let changes: [(DispatchEventType, Vec<Snowflake>); 3] = [
+		(DispatchEventType::ChannelDelete, before.difference(&after).copied().collect()),
+		(DispatchEventType::ChannelCreate, after.difference(&before).copied().collect()),
+		(DispatchEventType::ChannelUpdate, after.intersection(&before).copied().collect()),
+	];
+	for (event_type, users) in changes {
+		if users.is_empty() {
+			continue;
+		}
+		let mut builder = connected_users.bulk_message_builder();
+		builder.add_user_recipients(&users);
+		builder.set_message(DispatchEvent::new(event_type, &**channel)?);
+		builder.send(db, connected_users).await?;
+	}
+	Ok(())
+}
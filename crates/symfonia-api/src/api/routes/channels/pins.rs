@@ -1,23 +1,33 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::{Snowflake, jwt::Claims};
+use chorus::types::{PermissionFlags, Snowflake, jwt::Claims};
+use chrono::Utc;
 use poem::{
 	IntoResponse, Response, handler,
 	http::StatusCode,
 	web::{Data, Json, Path},
 };
+use serde_json::json;
 use sqlx::PgPool;
 use util::{
-	entities::{Config, Message},
+	entities::{Channel, Config, Message},
 	errors::{ChannelError, Error},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
 };
 
+use crate::api::middleware::permission_guard::require_channel_permissions;
+
 #[handler]
 pub async fn add_pinned_message(
 	Data(db): Data<&PgPool>,
 	Data(config): Data<&Config>,
 	Data(claims): Data<&Claims>,
+	Data(permissions): Data<&PermissionFlags>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, message_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	let mut message = Message::get_by_id(db, channel_id, message_id)
@@ -25,7 +35,7 @@ pub async fn add_pinned_message(
 		.ok_or(Error::Channel(ChannelError::InvalidMessage))?;
 
 	if message.guild_id.is_some() {
-		// TOOD: Check permission 'MANAGE_MESSAGES'
+		require_channel_permissions(*permissions, PermissionFlags::MANAGE_MESSAGES)?;
 	}
 
 	let pinned_count = Message::count_pinned(db, channel_id).await?;
@@ -34,7 +44,7 @@ pub async fn add_pinned_message(
 	}
 
 	message.set_pinned(db, true).await?;
-	// TODO: Emit events 'MESSAGE_UPDATE' AND 'CHANNEL_PINS_UPDATE'
+	dispatch_pins_update(db, connected_users, &message, Some(Utc::now())).await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
@@ -43,6 +53,8 @@ pub async fn add_pinned_message(
 pub async fn remove_pinned_message(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(permissions): Data<&PermissionFlags>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, message_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	let mut message = Message::get_by_id(db, channel_id, message_id)
@@ -50,11 +62,11 @@ pub async fn remove_pinned_message(
 		.ok_or(Error::Channel(ChannelError::InvalidMessage))?;
 
 	if message.guild_id.is_some() {
-		// TOOD: Check permission 'MANAGE_MESSAGES'
+		require_channel_permissions(*permissions, PermissionFlags::MANAGE_MESSAGES)?;
 	}
 
 	message.set_pinned(db, false).await?;
-	// TODO: Emit events 'MESSAGE_UPDATE' AND 'CHANNEL_PINS_UPDATE'
+	dispatch_pins_update(db, connected_users, &message, None).await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
@@ -64,8 +76,38 @@ pub async fn get_pinned_messages(
 	Data(db): Data<&PgPool>,
 	Path(channel_id): Path<Snowflake>,
 ) -> poem::Result<impl IntoResponse> {
-	// TODO: Check permission 'READ_MESSAGE_HISTORY'
 	let messages = Message::get_pinned(db, channel_id).await?;
 
 	Ok(Json(messages))
 }
+
+/// Send `MESSAGE_UPDATE` for the (un)pinned message `message` and
+/// `CHANNEL_PINS_UPDATE` for its channel.
+async fn dispatch_pins_update(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	message: &Message,
+	last_pin_timestamp: Option<chrono::DateTime<Utc>>,
+) -> Result<(), Error> {
+	let channel = Channel::get_by_id(db, message.channel_id)
+		.await?
+		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
+	// Both events go to the same viewers, which are only resolved once
+	let mut viewers = connected_users.bulk_message_builder();
+	viewers.add_user_recipients(&connected_users.channel_viewers(db, &channel).await?);
+
+	let mut builder = viewers.clone();
+	builder.set_message(DispatchEvent::new(DispatchEventType::MessageUpdate, &**message)?);
+	builder.send(db, connected_users).await?;
+
+	let pins_update = DispatchEvent::new(
+		DispatchEventType::ChannelPinsUpdate,
+		json!({
+			"guild_id": channel.guild_id,
+			"channel_id": channel.id,
+			"last_pin_timestamp": last_pin_timestamp,
+		}),
+	)?;
+	viewers.set_message(pins_update);
+	viewers.send(db, connected_users).await
+}
@@ -1,7 +1,7 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::{ChannelType, Snowflake, jwt::Claims};
+use chorus::types::{ChannelType, PermissionFlags, Snowflake, jwt::Claims};
 use poem::{
 	IntoResponse, Response, handler,
 	http::StatusCode,
@@ -10,7 +10,11 @@ use poem::{
 use sqlx::PgPool;
 use util::{
 	entities::{Channel, Recipient, User},
-	errors::{ChannelError, Error},
+	errors::{ChannelError, Error, UserError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{ChannelRecipientChange, DispatchEvent, DispatchEventType},
+	},
 };
 
 #[handler]
@@ -18,6 +22,7 @@ pub async fn add_recipient(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
 	Data(user): Data<&User>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, user_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	let mut channel = Channel::get_by_id(db, channel_id)
@@ -30,7 +35,14 @@ pub async fn add_recipient(
 			channel.recipients.as_ref().map_or(vec![], |v| v.iter().map(|r| r.id).collect());
 		new_recipients.push(user.id);
 
-		let new_dm_channel = Channel::create_dm_channel(db, new_recipients, user.id, None).await?;
+		let mut new_dm_channel =
+			Channel::create_dm_channel(db, new_recipients, user.id, None).await?;
+		new_dm_channel.populate_relations(db).await?;
+		let mut builder = connected_users.bulk_message_builder();
+		builder.add_channel_viewers(new_dm_channel.id);
+		builder
+			.set_message(DispatchEvent::new(DispatchEventType::ChannelCreate, &*new_dm_channel)?);
+		builder.send(db, connected_users).await?;
 
 		Ok(Json(new_dm_channel.into_inner()).into_response())
 	} else {
@@ -41,34 +53,83 @@ pub async fn add_recipient(
 		}
 
 		let recipient = Recipient::create(db, channel_id, user_id).await?;
+		channel.populate_relations(db).await?;
+		let mut builder = connected_users.bulk_message_builder();
+		builder.add_user_recipients(&[user_id]);
+		builder.set_message(DispatchEvent::new(DispatchEventType::ChannelCreate, &*channel)?);
+		builder.send(db, connected_users).await?;
 
-		// TODO: Emit events 'CHANNEL_CREATE' AND 'CHANNEL_RECIPIENT_ADD'
+		let added_user =
+			User::get_by_id(db, user_id).await?.ok_or(Error::User(UserError::InvalidUser))?;
+		let mut builder = connected_users.bulk_message_builder();
+		builder.add_dm_recipients(channel.id);
+		builder.set_message(DispatchEvent::new(
+			DispatchEventType::ChannelRecipientAdd,
+			ChannelRecipientChange { channel_id: channel.id, user: added_user.to_public_user() },
+		)?);
+		builder.send(db, connected_users).await?;
 
 		Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 	}
 }
 
+/// Remove the user `user_id` from a group DM. Only the owner of the group DM
+/// may remove other users.
 #[handler]
 pub async fn remove_recipient(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, user_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
-	let mut channel = Channel::get_by_id(db, channel_id)
+	let channel = Channel::get_by_id(db, channel_id)
 		.await?
 		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
 
-	if channel.channel_type.ne(&ChannelType::GroupDm)
-		&& !(channel.owner_id.map(|u| u == claims.id).unwrap_or_default() || user_id == claims.id)
-	{
-		return Err(Error::Channel(ChannelError::InvalidRecipient).into()); // TODO: Should be a permissions error
+	if channel.channel_type.ne(&ChannelType::GroupDm) {
+		return Err(Error::Channel(ChannelError::InvalidRecipient).into());
+	}
+	if user_id != claims.id && channel.owner_id != Some(claims.id) {
+		return Err(Error::Channel(ChannelError::MissingPermissions(
+			PermissionFlags::MANAGE_CHANNELS,
+		))
+		.into());
 	}
 
-	let recipient = Recipient::get_by_channel_and_user_id(db, channel_id, user_id)
+	leave_group_dm(db, connected_users, &channel, user_id).await?;
+
+	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
+}
+
+/// Remove the user `user_id` from the group DM `channel`. The channel is
+/// deleted once its last recipient left.
+pub(super) async fn leave_group_dm(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	channel: &Channel,
+	user_id: Snowflake,
+) -> Result<(), Error> {
+	let recipient = Recipient::get_by_channel_and_user_id(db, channel.id, user_id)
 		.await?
 		.ok_or(Error::Channel(ChannelError::InvalidRecipient))?;
+	recipient.delete(db).await?;
 
-	// TODO: Emit event?
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_user_recipients(&[user_id]);
+	builder.set_message(DispatchEvent::new(DispatchEventType::ChannelDelete, &**channel)?);
+	builder.send(db, connected_users).await?;
 
-	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
+	if Recipient::get_by_channel_id(db, channel.id).await?.is_empty() {
+		return channel.delete(db).await;
+	}
+
+	let removed_user =
+		User::get_by_id(db, user_id).await?.ok_or(Error::User(UserError::InvalidUser))?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_dm_recipients(channel.id);
+	builder.set_message(DispatchEvent::new(
+		DispatchEventType::ChannelRecipientRemove,
+		ChannelRecipientChange { channel_id: channel.id, user: removed_user.to_public_user() },
+	)?);
+	builder.send(db, connected_users).await
 }
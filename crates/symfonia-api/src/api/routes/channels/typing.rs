@@ -2,21 +2,28 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 use chorus::types::{Snowflake, jwt::Claims};
+use chrono::Utc;
 use poem::{
 	IntoResponse, Response, handler,
 	web::{Data, Path},
 };
 use reqwest::StatusCode;
+use serde_json::json;
 use sqlx::PgPool;
 use util::{
 	entities::{Channel, GuildMember},
 	errors::{ChannelError, Error, GuildError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
 };
 
 #[handler]
 pub async fn typing_indicator(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(channel_id): Path<Snowflake>,
 ) -> poem::Result<impl IntoResponse> {
 	let channel = Channel::get_by_id(db, channel_id)
@@ -32,7 +39,20 @@ pub async fn typing_indicator(
 		.await?
 		.ok_or(Error::Guild(GuildError::MemberNotFound))?;
 
-	// TODO: Emit event 'TYPING_START'
+	let event = DispatchEvent::new(
+		DispatchEventType::TypingStart,
+		json!({
+			"channel_id": channel.id,
+			"guild_id": guild_id,
+			"user_id": claims.id,
+			"timestamp": Utc::now().timestamp(),
+			"member": &*member,
+		}),
+	)?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_channel_viewers(channel.id);
+	builder.set_message(event);
+	builder.send(db, connected_users).await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
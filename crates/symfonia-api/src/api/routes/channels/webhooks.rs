@@ -17,8 +17,6 @@ pub async fn get_webhooks(
 	Data(db): Data<&PgPool>,
 	Path(channel_id): Path<Snowflake>,
 ) -> poem::Result<impl IntoResponse> {
-	// TODO: Check permissions 'MANAGE_WEBHOOKS'
-
 	let channel = Channel::get_by_id(db, channel_id)
 		.await?
 		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
@@ -2,18 +2,24 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 use chorus::types::{
-	BulkGuildBanSchema, GetGuildBansQuery, GuildBanCreateSchema, GuildBansSearchQuery, Snowflake,
-	jwt::Claims,
+	BulkGuildBanSchema, GetGuildBansQuery, GuildBanCreateSchema, GuildBansSearchQuery,
+	PermissionFlags, Snowflake, jwt::Claims,
 };
 use poem::{
 	IntoResponse, Response, handler,
 	web::{Data, Json, Path, Query},
 };
 use reqwest::StatusCode;
+use serde_json::json;
 use sqlx::PgPool;
 use util::{
 	entities::{Guild, GuildBan},
 	errors::{Error, GuildError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
+	util::{hierarchy::MemberRank, permissions::get_guild_permissions},
 };
 
 #[handler]
@@ -61,6 +67,7 @@ pub async fn get_banned_user(
 pub async fn create_ban(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((guild_id, user_id)): Path<(Snowflake, Snowflake)>,
 	Json(payload): Json<GuildBanCreateSchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -71,11 +78,18 @@ pub async fn create_ban(
 		return Err(Error::Guild(GuildError::BanAlreadyExists).into());
 	}
 
-	// TODO: Check permissions, and guild owner status
+	require_ban_members(db, &guild, claims.id).await?;
+	MemberRank::load(db, &guild, claims.id)
+		.await?
+		.ensure_can_ban(&MemberRank::load(db, &guild, user_id).await?)?;
 
-	GuildBan::create(db, guild.id, user_id, claims.id, None).await?; // TODO: Get reason from 'X-Audit-Log-Reason' header
+	let ban = GuildBan::create(db, guild.id, user_id, claims.id, None).await?; // TODO: Get reason from 'X-Audit-Log-Reason' header
 
-	// TODO: Emit events 'GUILD_BAN_ADD' and optionally 'GUILD_MEMBER_REMOVE'
+	// TODO: Emit 'GUILD_MEMBER_REMOVE' once bans remove the user from the guild
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_guild_members(guild.id);
+	builder.set_message(ban_event(DispatchEventType::GuildBanAdd, &ban)?);
+	builder.send(db, connected_users).await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
@@ -84,17 +98,34 @@ pub async fn create_ban(
 pub async fn bulk_ban(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(guild_id): Path<Snowflake>,
 	Json(payload): Json<BulkGuildBanSchema>,
 ) -> poem::Result<impl IntoResponse> {
 	let guild =
 		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
 
-	// TODO: Check permissions, and guild owner status
-
-	let bans = GuildBan::builk_create(db, guild.id, payload.user_ids, claims.id, None).await?; // TODO: Get reason from 'X-Audit-Log
+	require_ban_members(db, &guild, claims.id).await?;
 
-	// TODO: Emit events 'GUILD_BAN_ADD' and optionally 'GUILD_MEMBER_REMOVE'
+	// Users who cannot be banned by the executor are silently left out
+	let rank = MemberRank::load(db, &guild, claims.id).await?;
+	let user_ids = MemberRank::load_many(db, &guild, &payload.user_ids)
+		.await?
+		.into_iter()
+		.filter(|target| rank.can_ban(target))
+		.map(|target| target.user_id)
+		.collect();
+
+	let bans = GuildBan::builk_create(db, guild.id, user_ids, claims.id, None).await?; // TODO: Get reason from 'X-Audit-Log
+
+	// TODO: Emit 'GUILD_MEMBER_REMOVE' once bans remove the users from the guild
+	let mut members = connected_users.bulk_message_builder();
+	members.add_guild_members(guild.id);
+	for ban in bans.iter() {
+		let mut builder = members.clone();
+		builder.set_message(ban_event(DispatchEventType::GuildBanAdd, ban)?);
+		builder.send(db, connected_users).await?;
+	}
 
 	// TODO: This should return a json with banned_users and failed_users
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
@@ -110,7 +141,7 @@ pub async fn search(
 	let guild =
 		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
 
-	// TODO: Check permissions
+	require_ban_members(db, &guild, claims.id).await?;
 
 	let mut bans =
 		GuildBan::find_by_username(db, guild.id, &query.query, query.limit.unwrap_or(10).into())
@@ -128,21 +159,38 @@ pub async fn search(
 pub async fn delete_ban(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((guild_id, user_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	let guild =
 		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
 
-	// TODO: Check permissions, and guild owner status
+	require_ban_members(db, &guild, claims.id).await?;
 
-	let ban = GuildBan::get_by_user(db, guild.id, user_id)
+	let mut ban = GuildBan::get_by_user(db, guild.id, user_id)
 		.await?
 		.ok_or(Error::Guild(GuildError::BanNotFound))?;
-	// TODO: Get public user for event emit
+	ban.populate_relations(db).await?;
+	let event = ban_event(DispatchEventType::GuildBanRemove, &ban)?;
 
 	ban.delete(db).await?;
 
-	// TODO: Emit event 'GUILD_BAN_REMOVE'
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_guild_members(guild.id);
+	builder.set_message(event);
+	builder.send(db, connected_users).await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
+
+async fn require_ban_members(db: &PgPool, guild: &Guild, user_id: Snowflake) -> Result<(), Error> {
+	if !get_guild_permissions(db, guild.id, user_id).await?.contains(PermissionFlags::BAN_MEMBERS) {
+		return Err(Error::Guild(GuildError::InsufficientPermissions));
+	}
+	Ok(())
+}
+
+/// The `GUILD_BAN_ADD` or `GUILD_BAN_REMOVE` event for `ban`.
+fn ban_event(event_type: DispatchEventType, ban: &GuildBan) -> Result<DispatchEvent, Error> {
+	DispatchEvent::new(event_type, json!({"guild_id": ban.guild_id, "user": ban.user}))
+}
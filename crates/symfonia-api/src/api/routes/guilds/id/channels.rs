@@ -13,6 +13,10 @@ use sqlx::PgPool;
 use util::{
 	entities::{Channel, Guild},
 	errors::{Error, GuildError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
 };
 
 #[handler]
@@ -30,6 +34,7 @@ pub async fn get_channels(
 pub async fn create_channel(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(guild_id): Path<Snowflake>,
 	Json(payload): Json<ChannelModifySchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -48,6 +53,11 @@ pub async fn create_channel(
 	)
 	.await?;
 
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_channel_viewers(channel.id);
+	builder.set_message(DispatchEvent::new(DispatchEventType::ChannelCreate, &*channel)?);
+	builder.send(db, connected_users).await?;
+
 	Ok(Json(channel.into_inner()).with_status(StatusCode::CREATED))
 }
 
@@ -55,6 +65,7 @@ pub async fn create_channel(
 pub async fn reorder_channels_route(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(guild_id): Path<Snowflake>,
 	Json(payload): Json<ModifyChannelPositionsSchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -70,7 +81,10 @@ pub async fn reorder_channels_route(
 
 		for channel in channels {
 			channel.save(db).await?;
-			// TODO: emit events
+			let mut builder = connected_users.bulk_message_builder();
+			builder.add_channel_viewers(channel.id);
+			builder.set_message(DispatchEvent::new(DispatchEventType::ChannelUpdate, &*channel)?);
+			builder.send(db, connected_users).await?;
 		}
 	}
 
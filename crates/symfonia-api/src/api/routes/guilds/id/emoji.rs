@@ -7,10 +7,15 @@ use poem::{
 	web::{Data, Json, Path},
 };
 use reqwest::StatusCode;
+use serde_json::json;
 use sqlx::PgPool;
 use util::{
 	entities::{Config, Emoji, Guild},
 	errors::{Error, GuildError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
 };
 
 #[handler]
@@ -53,6 +58,7 @@ pub async fn get_emoji(
 pub async fn create_emoji(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(config): Data<&Config>,
 	Path(guild_id): Path<Snowflake>,
 	Json(payload): Json<EmojiCreateSchema>,
@@ -90,7 +96,7 @@ pub async fn create_emoji(
 	)
 	.await?;
 
-	// TODO: Emit event 'GUILD_EMOJIS_UPDATE'
+	dispatch_emojis_update(db, connected_users, &guild).await?;
 
 	Ok(Json(emoji).with_status(StatusCode::CREATED))
 }
@@ -99,6 +105,7 @@ pub async fn create_emoji(
 pub async fn modify_emoji(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((guild_id, emoji_id)): Path<(Snowflake, Snowflake)>,
 	Json(payload): Json<EmojiModifySchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -123,7 +130,7 @@ pub async fn modify_emoji(
 
 	emoji.save(db).await?;
 
-	// TODO: Emit event 'GUILD_EMOJIS_UPDATE'
+	dispatch_emojis_update(db, connected_users, &guild).await?;
 
 	Ok(Json(emoji))
 }
@@ -132,6 +139,7 @@ pub async fn modify_emoji(
 pub async fn delete_emoji(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((guild_id, emoji_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	let guild =
@@ -148,7 +156,25 @@ pub async fn delete_emoji(
 
 	emoji.delete(db).await?;
 
-	// TODO: Emit event 'GUILD_EMOJIS_UPDATE'
+	dispatch_emojis_update(db, connected_users, &guild).await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
+
+/// Send `GUILD_EMOJIS_UPDATE` with the current emojis of `guild` to its
+/// members.
+async fn dispatch_emojis_update(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	guild: &Guild,
+) -> Result<(), Error> {
+	let emojis = guild.get_emojis(db).await?;
+	let event = DispatchEvent::new(
+		DispatchEventType::GuildEmojisUpdate,
+		json!({"guild_id": guild.id, "emojis": emojis}),
+	)?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_guild_members(guild.id);
+	builder.set_message(event);
+	builder.send(db, connected_users).await
+}
@@ -7,12 +7,20 @@ use poem::{
 	http::StatusCode,
 	web::{Data, Json, Path},
 };
+use serde_json::json;
 use sqlx::PgPool;
 use util::{
-	entities::{Guild, GuildMember, User},
+	entities::{Channel, Guild, GuildMember, User},
 	errors::{Error, GuildError, UserError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
+	util::{hierarchy::MemberRank, permissions::get_guild_permissions},
 };
 
+use crate::api::routes::guilds::dispatch_guild_create;
+
 pub(crate) mod nick;
 pub(crate) mod roles;
 
@@ -41,6 +49,7 @@ pub async fn get_member(
 #[handler]
 pub async fn modify_member(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(authed_user): Data<&User>,
 	Path((guild_id, member_id)): Path<(Snowflake, String)>,
 	Json(payload): Json<ModifyGuildMemberSchema>,
@@ -54,17 +63,17 @@ pub async fn modify_member(
 	let guild =
 		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
 
-	let Some(authed_member) = guild.get_member(db, authed_user.id).await? else {
-		return Err(Error::Guild(GuildError::MemberNotFound).into());
-	};
+	let permissions = get_guild_permissions(db, guild.id, authed_user.id).await?;
+	let authed_rank = MemberRank::load(db, &guild, authed_user.id).await?;
 
 	let mut member =
 		guild.get_member(db, member_id).await?.ok_or(Error::Guild(GuildError::MemberNotFound))?;
 
 	if let Some(nick) = payload.nickname {
-		if !authed_member.permissions.has_permission(PermissionFlags::MANAGE_NICKNAMES) {
+		if !permissions.contains(PermissionFlags::MANAGE_NICKNAMES) {
 			return Err(Error::Guild(GuildError::InsufficientPermissions).into());
 		}
+		authed_rank.ensure_can_moderate(&MemberRank::load(db, &guild, member_id).await?)?;
 
 		if nick.is_empty() {
 			member.nick = None;
@@ -80,17 +89,24 @@ pub async fn modify_member(
 	}
 
 	if let Some(roles) = payload.roles {
-		if !authed_member.permissions.has_permission(PermissionFlags::MANAGE_ROLES) {
+		if !permissions.contains(PermissionFlags::MANAGE_ROLES) {
 			return Err(Error::Guild(GuildError::InsufficientPermissions).into());
 		}
 
+		for role_id in roles.iter().filter(|id| !member.roles.contains(id)) {
+			let role =
+				guild.get_role(db, *role_id).await?.ok_or(Error::Guild(GuildError::InvalidRole))?;
+			authed_rank.ensure_can_manage_role(role.position.to_uint())?;
+		}
+
 		member.roles.extend(roles);
 		member.roles.dedup();
 	}
 
 	member.save(db).await?;
-
-	// TODO: Emit event 'GUILD_MEMBER_UPDATE'
+	connected_users.publish_member_list_change(guild.id).await;
+	dispatch_member_event(db, connected_users, DispatchEventType::GuildMemberUpdate, &mut member)
+		.await?;
 
 	Ok(Json(member.into_inner()))
 }
@@ -98,6 +114,7 @@ pub async fn modify_member(
 #[handler]
 pub async fn join_guild(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(authed_user): Data<&User>,
 	Path((guild_id, member_id)): Path<(Snowflake, String)>,
 ) -> poem::Result<impl IntoResponse> {
@@ -118,7 +135,19 @@ pub async fn join_guild(
 
 	guild.populate_relations(db).await?;
 
-	guild.add_member(db, member_id).await?;
+	guild.add_member(db, &connected_users.role_user_map, member_id).await?;
+	connected_users.publish_member_list_change(guild.id).await;
+
+	let mut member =
+		guild.get_member(db, member_id).await?.ok_or(Error::Guild(GuildError::MemberNotFound))?;
+	dispatch_member_event(db, connected_users, DispatchEventType::GuildMemberAdd, &mut member)
+		.await?;
+	guild.channels = Channel::get_by_guild_id(db, guild.id)
+		.await?
+		.into_iter()
+		.map(Channel::into_inner)
+		.collect();
+	dispatch_guild_create(db, connected_users, &guild, member_id).await?;
 
 	Ok(Json(guild.into_inner()))
 }
@@ -126,6 +155,7 @@ pub async fn join_guild(
 #[handler]
 pub async fn remove_member(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(authed_user): Data<&User>,
 	Path((guild_id, member_id)): Path<(Snowflake, String)>,
 ) -> poem::Result<impl IntoResponse> {
@@ -142,17 +172,50 @@ pub async fn remove_member(
 	let guild =
 		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
 
-	let our_member = guild
-		.get_member(db, authed_user.id)
-		.await?
-		.ok_or(Error::Guild(GuildError::MemberNotFound))?;
-	if our_member.permissions.has_permission(PermissionFlags::KICK_MEMBERS) {
-		return Err(Error::Guild(GuildError::InsufficientPermissions).into());
+	if member_id != authed_user.id {
+		let permissions = get_guild_permissions(db, guild.id, authed_user.id).await?;
+		if !permissions.contains(PermissionFlags::KICK_MEMBERS) {
+			return Err(Error::Guild(GuildError::InsufficientPermissions).into());
+		}
+
+		let our_rank = MemberRank::load(db, &guild, authed_user.id).await?;
+		our_rank.ensure_can_moderate(&MemberRank::load(db, &guild, member_id).await?)?;
 	}
 
 	let member =
 		guild.get_member(db, member_id).await?.ok_or(Error::Guild(GuildError::MemberNotFound))?;
 
-	member.delete(db).await?;
+	// The removed member is told about its removal as well
+	let member_ids = GuildMember::get_member_ids(db, guild.id).await?;
+	member.delete(db, &connected_users.role_user_map).await?;
+	connected_users.publish_member_list_change(guild.id).await;
+
+	let user = User::get_by_id(db, member_id).await?.ok_or(Error::User(UserError::InvalidUser))?;
+	let event = DispatchEvent::new(
+		DispatchEventType::GuildMemberRemove,
+		json!({"guild_id": guild.id, "user": user.to_public_user()}),
+	)?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_user_recipients(&member_ids);
+	builder.set_message(event);
+	builder.send(db, connected_users).await?;
+
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
+
+/// Send the `GUILD_MEMBER_ADD` or `GUILD_MEMBER_UPDATE` event for `member` to
+/// the members of its guild.
+pub(super) async fn dispatch_member_event(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	event_type: DispatchEventType,
+	member: &mut GuildMember,
+) -> Result<(), Error> {
+	member.populate_relations(db).await?;
+	let mut data = serde_json::to_value(&**member)?;
+	data["guild_id"] = json!(member.guild_id);
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_guild_members(member.guild_id);
+	builder.set_message(DispatchEvent::new(event_type, data)?);
+	builder.send(db, connected_users).await
+}
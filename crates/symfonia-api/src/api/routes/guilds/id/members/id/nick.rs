@@ -10,11 +10,14 @@ use sqlx::PgPool;
 use util::{
 	entities::Guild,
 	errors::{Error, GuildError},
+	gateway::{ConnectedUsers, dispatchevent::DispatchEventType},
+	util::{hierarchy::MemberRank, permissions::get_guild_permissions},
 };
 
 #[handler]
 pub async fn change_nickname(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(claims): Data<&Claims>,
 	Path((guild_id, member_id)): Path<(Snowflake, String)>,
 	Json(payload): Json<ModifyCurrentGuildMemberSchema>,
@@ -24,22 +27,37 @@ pub async fn change_nickname(
 
 	let mut authed_member =
 		guild.get_member(db, claims.id).await?.ok_or(Error::Guild(GuildError::MemberNotFound))?;
+	let permissions = get_guild_permissions(db, guild.id, claims.id).await?;
 
-	if member_id.eq("@me")
-		&& authed_member.permissions.has_permission(PermissionFlags::CHANGE_NICKNAME)
-	{
+	if member_id.eq("@me") && permissions.contains(PermissionFlags::CHANGE_NICKNAME) {
 		authed_member.nick = payload.nickname;
-	} else if authed_member.permissions.has_permission(PermissionFlags::MANAGE_NICKNAMES) {
-		let snowflake = Snowflake(member_id.parse::<u64>().unwrap());
+	} else if permissions.contains(PermissionFlags::MANAGE_NICKNAMES) {
+		let snowflake = if member_id.eq("@me") {
+			claims.id
+		} else {
+			Snowflake(member_id.parse::<u64>().map_err(|_| poem::http::StatusCode::BAD_REQUEST)?)
+		};
 		authed_member = guild
 			.get_member(db, snowflake)
 			.await?
 			.ok_or(Error::Guild(GuildError::MemberNotFound))?;
+
+		let actor = MemberRank::load(db, &guild, claims.id).await?;
+		actor.ensure_can_moderate(&MemberRank::load(db, &guild, snowflake).await?)?;
+
 		authed_member.nick = payload.nickname;
 	} else {
 		return Err(Error::Guild(GuildError::InsufficientPermissions).into());
 	}
 	authed_member.save(db).await?;
+	connected_users.publish_member_list_change(guild.id).await;
+	super::dispatch_member_event(
+		db,
+		connected_users,
+		DispatchEventType::GuildMemberUpdate,
+		&mut authed_member,
+	)
+	.await?;
 
 	Ok(Json(authed_member.into_inner()))
 }
@@ -11,32 +11,42 @@ use sqlx::PgPool;
 use util::{
 	entities::{Guild, User},
 	errors::{Error, GuildError},
+	gateway::{ConnectedUsers, dispatchevent::DispatchEventType},
+	util::{hierarchy::MemberRank, permissions::get_guild_permissions},
 };
 
 #[handler]
 pub async fn add_role(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(authed_user): Data<&User>,
 	Path((guild_id, member_id, role_id)): Path<(Snowflake, Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	let guild =
 		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
 
-	let authed_member = guild
-		.get_member(db, authed_user.id)
-		.await?
-		.ok_or(Error::Guild(GuildError::MemberNotFound))?;
-
-	if !authed_member.permissions.has_permission(PermissionFlags::MANAGE_ROLES) {
+	let permissions = get_guild_permissions(db, guild.id, authed_user.id).await?;
+	if !permissions.contains(PermissionFlags::MANAGE_ROLES) {
 		return Err(Error::Guild(GuildError::InsufficientPermissions).into());
 	}
 
 	let mut member =
 		guild.get_member(db, member_id).await?.ok_or(Error::Guild(GuildError::MemberNotFound))?;
 
-	guild.get_role(db, role_id).await?.ok_or(Error::Guild(GuildError::InvalidRole))?;
+	let role = guild.get_role(db, role_id).await?.ok_or(Error::Guild(GuildError::InvalidRole))?;
+	MemberRank::load(db, &guild, authed_user.id)
+		.await?
+		.ensure_can_manage_role(role.position.to_uint())?;
 
-	member.add_role(db, role_id).await?;
+	member.add_role(db, &connected_users.role_user_map, role_id).await?;
+	connected_users.publish_member_list_change(guild.id).await;
+	super::dispatch_member_event(
+		db,
+		connected_users,
+		DispatchEventType::GuildMemberUpdate,
+		&mut member,
+	)
+	.await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
@@ -44,27 +54,35 @@ pub async fn add_role(
 #[handler]
 pub async fn remove_role(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(authed_user): Data<&User>,
 	Path((guild_id, member_id, role_id)): Path<(Snowflake, Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	let guild =
 		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
 
-	let authed_member = guild
-		.get_member(db, authed_user.id)
-		.await?
-		.ok_or(Error::Guild(GuildError::MemberNotFound))?;
-
-	if !authed_member.permissions.has_permission(PermissionFlags::MANAGE_ROLES) {
+	let permissions = get_guild_permissions(db, guild.id, authed_user.id).await?;
+	if !permissions.contains(PermissionFlags::MANAGE_ROLES) {
 		return Err(Error::Guild(GuildError::InsufficientPermissions).into());
 	}
 
 	let mut member =
 		guild.get_member(db, member_id).await?.ok_or(Error::Guild(GuildError::MemberNotFound))?;
 
-	guild.get_role(db, role_id).await?.ok_or(Error::Guild(GuildError::InvalidRole))?;
+	let role = guild.get_role(db, role_id).await?.ok_or(Error::Guild(GuildError::InvalidRole))?;
+	MemberRank::load(db, &guild, authed_user.id)
+		.await?
+		.ensure_can_manage_role(role.position.to_uint())?;
 
-	member.remove_role(db, role_id).await?;
+	member.remove_role(db, &connected_users.role_user_map, role_id).await?;
+	connected_users.publish_member_list_change(guild.id).await;
+	super::dispatch_member_event(
+		db,
+		connected_users,
+		DispatchEventType::GuildMemberUpdate,
+		&mut member,
+	)
+	.await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
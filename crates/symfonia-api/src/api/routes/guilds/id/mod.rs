@@ -13,10 +13,15 @@ use poem::{
 	http::StatusCode,
 	web::{Data, Json, Path},
 };
+use serde_json::json;
 use sqlx::PgPool;
 use util::{
 	entities::{Channel, Guild, GuildMember, Role, User},
 	errors::{ChannelError, Error, GuildError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
 };
 
 mod audit_log;
@@ -65,6 +70,7 @@ pub async fn get_guild(
 pub async fn modify_guild(
 	Data(db): Data<&PgPool>,
 	Data(authed_user): Data<&User>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(guild_id): Path<Snowflake>,
 	Json(payload): Json<GuildModifySchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -175,7 +181,10 @@ pub async fn modify_guild(
 
 	guild.save(db).await?;
 
-	// TODO: Emit event 'GUILD_UPDATE'
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_guild_members(guild.id);
+	builder.set_message(DispatchEvent::new(DispatchEventType::GuildUpdate, &*guild)?);
+	builder.send(db, connected_users).await?;
 
 	Ok(Json(guild.into_inner()))
 }
@@ -184,6 +193,7 @@ pub async fn modify_guild(
 pub async fn delete_guild(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(guild_id): Path<Snowflake>,
 ) -> poem::Result<impl IntoResponse> {
 	let guild =
@@ -191,9 +201,18 @@ pub async fn delete_guild(
 
 	// TODO: Check if the user is the owner of the guild
 
-	guild.delete(db).await?;
-
-	// TODO: Emit event 'GUILD_DELETE'
+	// The members are gone once the guild is deleted
+	let member_ids = GuildMember::get_member_ids(db, guild.id).await?;
+	guild.delete(db, &connected_users.role_user_map).await?;
+
+	let event = DispatchEvent::new(
+		DispatchEventType::GuildDelete,
+		json!({"id": guild_id, "unavailable": false}),
+	)?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_user_recipients(&member_ids);
+	builder.set_message(event);
+	builder.send(db, connected_users).await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
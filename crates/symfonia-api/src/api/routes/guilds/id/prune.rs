@@ -1,16 +1,21 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::{GuildPruneQuerySchema, GuildPruneResult, Snowflake};
+use chorus::types::{GuildPruneQuerySchema, GuildPruneResult, PermissionFlags, Snowflake};
 use poem::{
 	IntoResponse, handler,
 	web::{Data, Json, Path, Query},
 };
+use serde_json::json;
 use sqlx::PgPool;
-use sqlx_pg_uint::PgU16;
 use util::{
-	entities::{Config, Guild, Role, User},
+	entities::{Config, Guild, GuildMember, User},
 	errors::{Error, GuildError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
+	util::{hierarchy::MemberRank, permissions::get_guild_permissions},
 };
 
 #[handler]
@@ -24,27 +29,13 @@ pub async fn prune_members_dry_run(
 	let guild =
 		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
 
-	let me = guild
-		.get_member(db, authed_user.id)
-		.await?
-		.ok_or(Error::Guild(GuildError::MemberNotFound))?;
-
-	let my_highest = async {
-		let mut roles = vec![];
-		for role_id in me.roles.iter() {
-			let role = Role::get_by_id(db, *role_id)
-				.await?
-				.ok_or(Error::Guild(GuildError::InvalidRole))?;
-
-			roles.push(role);
-		}
-
-		let zero = PgU16::new(0);
-		let highest =
-			roles.iter().max_by_key(|r| &r.position).map(|r| &r.position).unwrap_or(&zero);
-		<Result<u16, Error>>::Ok(highest.to_uint())
+	let permissions = get_guild_permissions(db, guild.id, authed_user.id).await?;
+	if !permissions.contains(PermissionFlags::KICK_MEMBERS) {
+		return Err(Error::Guild(GuildError::InsufficientPermissions).into());
 	}
-	.await?;
+
+	// Only members below our own top role can be pruned
+	let my_highest = MemberRank::load(db, &guild, authed_user.id).await?.manageable_below();
 
 	let members = guild
 		.calculate_inactive_members(db, query.days, query.include_roles, my_highest.into())
@@ -56,6 +47,7 @@ pub async fn prune_members_dry_run(
 #[handler]
 pub async fn prune_members(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(authed_user): Data<&User>,
 	Data(config): Data<&Config>,
 	Path(guild_id): Path<Snowflake>,
@@ -64,36 +56,38 @@ pub async fn prune_members(
 	let guild =
 		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
 
-	let me = guild
-		.get_member(db, authed_user.id)
-		.await?
-		.ok_or(Error::Guild(GuildError::MemberNotFound))?;
-
-	let my_highest = async {
-		let mut roles = vec![];
-		for role_id in &me.roles {
-			let role = Role::get_by_id(db, *role_id)
-				.await?
-				.ok_or(Error::Guild(GuildError::InvalidRole))?;
-
-			roles.push(role);
-		}
-
-		let zero = PgU16::new(0);
-		let highest =
-			roles.iter().max_by_key(|r| &r.position).map(|r| &r.position).unwrap_or(&zero);
-		<Result<u16, Error>>::Ok(highest.to_uint())
+	let permissions = get_guild_permissions(db, guild.id, authed_user.id).await?;
+	if !permissions.contains(PermissionFlags::KICK_MEMBERS) {
+		return Err(Error::Guild(GuildError::InsufficientPermissions).into());
 	}
-	.await?;
+
+	// Only members below our own top role can be pruned
+	let my_highest = MemberRank::load(db, &guild, authed_user.id).await?.manageable_below();
 
 	let members = guild
 		.calculate_inactive_members(db, query.days, query.include_roles, my_highest.into())
 		.await?;
 
 	let total_count = members.len();
-	for member in members {
-		// TODO: Emit events?  Maybe write a special query for this?
-		member.delete(db).await?;
+	// The pruned members are told about their removal as well
+	let member_ids = GuildMember::get_member_ids(db, guild.id).await?;
+	let mut events = Vec::with_capacity(total_count);
+	for mut member in members {
+		// TODO: Maybe write a special query for this?
+		member.populate_relations(db).await?;
+		events.push(DispatchEvent::new(
+			DispatchEventType::GuildMemberRemove,
+			json!({"guild_id": guild.id, "user": member.user}),
+		)?);
+		member.delete(db, &connected_users.role_user_map).await?;
+	}
+	connected_users.publish_member_list_change(guild.id).await;
+	let mut recipients = connected_users.bulk_message_builder();
+	recipients.add_user_recipients(&member_ids);
+	for event in events {
+		let mut builder = recipients.clone();
+		builder.set_message(event);
+		builder.send(db, connected_users).await?;
 	}
 
 	Ok(Json(GuildPruneResult {
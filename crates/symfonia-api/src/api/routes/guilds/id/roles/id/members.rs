@@ -11,11 +11,13 @@ use sqlx::PgPool;
 use util::{
 	entities::Guild,
 	errors::{Error, GuildError},
+	gateway::ConnectedUsers,
 };
 
 #[handler]
 pub async fn bulk_assign_roles(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(claims): Data<&Claims>,
 	Path((guild_id, role_id)): Path<(Snowflake, Snowflake)>,
 	Json(member_ids): Json<Vec<Snowflake>>,
@@ -43,9 +45,9 @@ pub async fn bulk_assign_roles(
 			.ok_or(Error::Guild(GuildError::MemberNotFound))?;
 		member.populate_relations(db).await?;
 		if member.roles.contains(&role_id) {
-			member.remove_role(db, role_id).await?;
+			member.remove_role(db, &connected_users.role_user_map, role_id).await?;
 		} else {
-			member.add_role(db, role_id).await?;
+			member.add_role(db, &connected_users.role_user_map, role_id).await?;
 		}
 	}
 
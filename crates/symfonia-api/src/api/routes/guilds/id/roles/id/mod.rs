@@ -7,10 +7,16 @@ use poem::{
 	http::StatusCode,
 	web::{Data, Json, Path},
 };
+use serde_json::json;
 use sqlx::PgPool;
 use util::{
 	entities::Guild,
 	errors::{Error, GuildError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
+	util::{hierarchy::MemberRank, permissions::get_guild_permissions},
 };
 
 pub(crate) mod member_ids;
@@ -37,24 +43,34 @@ pub async fn get_role(
 #[handler]
 pub async fn delete_role(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(claims): Data<&Claims>,
 	Path((guild_id, role_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	let guild =
 		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
 
-	let authed_member =
-		guild.get_member(db, claims.id).await?.ok_or(Error::Guild(GuildError::MemberNotFound))?;
-
-	if authed_member.permissions.has_permission(PermissionFlags::MANAGE_ROLES) {
+	let permissions = get_guild_permissions(db, guild.id, claims.id).await?;
+	if !permissions.contains(PermissionFlags::MANAGE_ROLES) {
 		return Err(Error::Guild(GuildError::InsufficientPermissions).into());
 	}
 
 	let role = guild.get_role(db, role_id).await?.ok_or(Error::Guild(GuildError::RoleNotFound))?;
-
-	role.delete(db).await?;
-
-	// TODO: Emit event 'GUILD_ROLE_DELETE'
+	MemberRank::load(db, &guild, claims.id)
+		.await?
+		.ensure_can_manage_role(role.position.to_uint())?;
+
+	role.delete(db, &connected_users.role_user_map).await?;
+	connected_users.publish_member_list_change(guild.id).await;
+
+	let event = DispatchEvent::new(
+		DispatchEventType::GuildRoleDelete,
+		json!({"guild_id": guild.id, "role_id": role_id}),
+	)?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_guild_members(guild.id);
+	builder.set_message(event);
+	builder.send(db, connected_users).await?;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
@@ -62,6 +78,7 @@ pub async fn delete_role(
 #[handler]
 pub async fn modify_role(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(claims): Data<&Claims>,
 	Path((guild_id, role_id)): Path<(Snowflake, Snowflake)>,
 	Json(payload): Json<RoleCreateModifySchema>,
@@ -69,16 +86,17 @@ pub async fn modify_role(
 	let guild =
 		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
 
-	let authed_member =
-		guild.get_member(db, claims.id).await?.ok_or(Error::Guild(GuildError::MemberNotFound))?;
-
-	if !authed_member.permissions.has_permission(PermissionFlags::MANAGE_ROLES) {
+	let permissions = get_guild_permissions(db, guild.id, claims.id).await?;
+	if !permissions.contains(PermissionFlags::MANAGE_ROLES) {
 		return Err(Error::Guild(GuildError::InsufficientPermissions).into());
 	}
 
 	let mut role =
 		guild.get_role(db, role_id).await?.ok_or(Error::Guild(GuildError::RoleNotFound))?;
 
+	let rank = MemberRank::load(db, &guild, claims.id).await?;
+	rank.ensure_can_manage_role(role.position.to_uint())?;
+
 	if let Some(name) = payload.name {
 		role.name = name;
 	}
@@ -100,6 +118,8 @@ pub async fn modify_role(
 	}
 
 	if let Some(position) = payload.position {
+		// Roles can't be moved to or above the member's own top role
+		rank.ensure_can_manage_role(position as u16)?;
 		role.position = (position as u16).into();
 	}
 
@@ -117,8 +137,16 @@ pub async fn modify_role(
 	}
 
 	role.save(db).await?;
-
-	// TODO: Emit event 'GUILD_ROLE_UPDATE'
+	connected_users.publish_member_list_change(guild.id).await;
+
+	let event = DispatchEvent::new(
+		DispatchEventType::GuildRoleUpdate,
+		json!({"guild_id": guild.id, "role": &*role}),
+	)?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_guild_members(guild.id);
+	builder.set_message(event);
+	builder.send(db, connected_users).await?;
 
 	Ok(Json(role))
 }
@@ -6,11 +6,16 @@ use poem::{
 	IntoResponse, handler,
 	web::{Data, Json, Path},
 };
+use serde_json::json;
 use sqlx::PgPool;
 use util::{
 	SharedEventPublisherMap,
 	entities::{Config, Guild, Role, User},
 	errors::{Error, GuildError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
 };
 
 pub(crate) mod id;
@@ -37,6 +42,7 @@ pub async fn get_roles(
 #[handler]
 pub async fn create_role(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(publisher_map): Data<&SharedEventPublisherMap>,
 	Data(authed_user): Data<&User>,
 	Data(config): Data<&Config>,
@@ -48,7 +54,7 @@ pub async fn create_role(
 
 	let role_count = guild.count_roles(db).await?;
 
-	if config.limits.guild.max_roles as i32 >= role_count {
+	if role_count >= config.limits.guild.max_roles as i32 {
 		return Err(
 			Error::Guild(GuildError::RoleLimitReached(config.limits.guild.max_roles)).into()
 		);
@@ -59,6 +65,7 @@ pub async fn create_role(
 	let role = Role::create(
 		db,
 		publisher_map.clone(),
+		&connected_users.role_user_map,
 		None,
 		guild.id,
 		&name,
@@ -72,8 +79,16 @@ pub async fn create_role(
 		None,
 	)
 	.await?;
+	connected_users.publish_member_list_change(guild.id).await;
 
-	// TODO: Emit event 'GUILD_ROLE_CREATE'
+	let event = DispatchEvent::new(
+		DispatchEventType::GuildRoleCreate,
+		json!({"guild_id": guild.id, "role": &*role}),
+	)?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_guild_members(guild.id);
+	builder.set_message(event);
+	builder.send(db, connected_users).await?;
 
 	Ok(Json(role.into_inner()))
 }
@@ -81,6 +96,7 @@ pub async fn create_role(
 #[handler]
 pub async fn update_position(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(authed_user): Data<&User>,
 	Data(config): Data<&Config>,
 	Path(guild_id): Path<Snowflake>,
@@ -107,6 +123,16 @@ pub async fn update_position(
 
 	role.position = payload.position.into();
 	role.save(db).await?;
+	connected_users.publish_member_list_change(guild.id).await;
+
+	let event = DispatchEvent::new(
+		DispatchEventType::GuildRoleUpdate,
+		json!({"guild_id": guild.id, "role": &*role}),
+	)?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_guild_members(guild.id);
+	builder.set_message(event);
+	builder.send(db, connected_users).await?;
 
 	let mut roles = guild.get_roles(db).await?;
 	roles.sort_by(|a, b| a.position.cmp(&b.position));
@@ -1,7 +1,7 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::{GuildCreateSchema, jwt::Claims};
+use chorus::types::{GuildCreateSchema, Snowflake, jwt::Claims};
 use poem::{
 	IntoResponse, Route, get, handler, patch, post, put,
 	web::{Data, Json},
@@ -11,6 +11,10 @@ use util::{
 	SharedEventPublisherMap,
 	entities::{Config, Guild, User},
 	errors::{Error, UserError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
 };
 
 mod id;
@@ -114,6 +118,7 @@ pub fn setup_routes() -> Route {
 pub async fn create_guild(
 	Data(db): Data<&PgPool>,
 	Data(publisher_map): Data<&SharedEventPublisherMap>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(cfg): Data<&Config>,
 	Data(claims): Data<&Claims>,
 	Json(payload): Json<GuildCreateSchema>,
@@ -131,6 +136,7 @@ pub async fn create_guild(
 	let guild = Guild::create(
 		db,
 		publisher_map.clone(),
+		&connected_users.role_user_map,
 		cfg,
 		&guild_name,
 		payload.icon,
@@ -138,6 +144,21 @@ pub async fn create_guild(
 		&payload.channels.unwrap_or_default(),
 	)
 	.await?;
+	dispatch_guild_create(db, connected_users, &guild, claims.id).await?;
 
 	Ok(Json(guild))
 }
+
+/// Send `GUILD_CREATE` for `guild` to the user `user_id`, who just joined it.
+/// `guild` has to have its roles and channels loaded.
+pub(crate) async fn dispatch_guild_create(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	guild: &Guild,
+	user_id: Snowflake,
+) -> Result<(), Error> {
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_user_recipients(&[user_id]);
+	builder.set_message(DispatchEvent::new(DispatchEventType::GuildCreate, &**guild)?);
+	builder.send(db, connected_users).await
+}
@@ -13,6 +13,7 @@ use util::{
 	SharedEventPublisherMap,
 	entities::{Config, Guild, GuildTemplate, User},
 	errors::{Error, GuildError},
+	gateway::ConnectedUsers,
 };
 
 #[handler]
@@ -82,6 +83,7 @@ pub async fn get_template(
 pub async fn create_guild_from_template(
 	Data(db): Data<&PgPool>,
 	Data(publisher_map): Data<&SharedEventPublisherMap>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(authed_user): Data<&User>,
 	Data(config): Data<&Config>,
 	Path(code): Path<String>,
@@ -119,13 +121,14 @@ pub async fn create_guild_from_template(
 		db,
 		config,
 		publisher_map.clone(),
+		&connected_users.role_user_map,
 		authed_user.id,
 		&template,
 		&payload.name,
 	)
 	.await?;
 
-	guild.add_member(db, authed_user.id).await?;
+	guild.add_member(db, &connected_users.role_user_map, authed_user.id).await?;
 
 	Ok(Json(json!({
 		"id": guild.id,
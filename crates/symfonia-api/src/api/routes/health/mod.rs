@@ -1,8 +1,22 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use poem::{IntoResponse, Route, get, handler, http::StatusCode, web::Data};
+use chorus::types::Rights;
+use poem::{
+	EndpointExt, IntoResponse, Route, get, handler,
+	http::StatusCode,
+	web::{Data, Json},
+};
 use sqlx::PgPool;
+use util::{
+	entities::User,
+	errors::{Error, UserError},
+	gateway::ConnectedUsers,
+};
+
+use crate::api::middleware::{
+	authentication::AuthenticationMiddleware, current_user::CurrentUserMiddleware,
+};
 
 #[handler]
 pub async fn healthz(Data(db): Data<&PgPool>) -> poem::Result<impl IntoResponse> {
@@ -13,6 +27,29 @@ pub async fn healthz(Data(db): Data<&PgPool>) -> poem::Result<impl IntoResponse>
 	Ok(StatusCode::OK)
 }
 
+/// Report the differences between the in-memory Role->User map of the gateway
+/// and the database. Only available to operators. Responds with `503` if roles
+/// were changed during the comparison.
+#[handler]
+pub async fn role_user_map_drift(
+	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
+	Data(authed_user): Data<&User>,
+) -> poem::Result<impl IntoResponse> {
+	if !authed_user.rights.has(Rights::OPERATOR, false) {
+		return Err(Error::User(UserError::MissingRights(Rights::OPERATOR)).into());
+	}
+
+	let drift = connected_users
+		.role_user_map_drift(db)
+		.await?
+		.ok_or(poem::Error::from_status(StatusCode::SERVICE_UNAVAILABLE))?;
+	Ok(Json(drift))
+}
+
 pub fn setup_routes() -> Route {
-	Route::new().at("/healthz", get(healthz)).at("/readyz", get(healthz))
+	Route::new().at("/healthz", get(healthz)).at("/readyz", get(healthz)).at(
+		"/role-user-map",
+		get(role_user_map_drift).with(AuthenticationMiddleware).with(CurrentUserMiddleware),
+	)
 }
@@ -6,10 +6,15 @@ use poem::{
 	IntoResponse, Route, get, handler,
 	web::{Data, Json, Path},
 };
+use serde_json::json;
 use sqlx::PgPool;
 use util::{
 	entities::{Channel, Invite, User},
 	errors::{ChannelError, Error, InviteError, UserError},
+	gateway::{
+		ConnectedUsers,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	},
 };
 
 pub fn setup_routes() -> Route {
@@ -31,6 +36,7 @@ pub async fn get_invite(
 #[handler]
 pub async fn accept_invite(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(claims): Data<&Claims>,
 	Path(invite_code): Path<String>,
 ) -> poem::Result<impl IntoResponse> {
@@ -40,7 +46,7 @@ pub async fn accept_invite(
 
 	let user = User::get_by_id(db, claims.id).await?.ok_or(Error::User(UserError::InvalidUser))?;
 
-	invite.join(db, &user).await?;
+	invite.join(db, &connected_users.role_user_map, &user).await?;
 
 	Ok(Json(invite.into_inner()))
 }
@@ -49,16 +55,20 @@ pub async fn accept_invite(
 pub async fn delete_invite(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(invite_code): Path<String>,
 ) -> poem::Result<impl IntoResponse> {
 	let mut invite = Invite::get_by_code(db, &invite_code)
 		.await?
 		.ok_or(Error::Invite(InviteError::InvalidInvite))?;
 
+	let mut channel = None;
 	if let Some(channel_id) = invite.channel_id {
-		let channel = Channel::get_by_id(db, channel_id)
-			.await?
-			.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
+		channel = Some(
+			Channel::get_by_id(db, channel_id)
+				.await?
+				.ok_or(Error::Channel(ChannelError::InvalidChannel))?,
+		);
 		// TODO: Check if the user has permission to delete an invite
 		// TODO: Check if the channel is a Group DM, and handle recipients
 		// TODO: Check if inviter should be anonymous
@@ -68,5 +78,20 @@ pub async fn delete_invite(
 
 	invite.delete(db).await?;
 
+	if let Some(channel) = channel {
+		let event = DispatchEvent::new(
+			DispatchEventType::InviteDelete,
+			json!({
+				"channel_id": channel.id,
+				"guild_id": channel.guild_id,
+				"code": invite.code,
+			}),
+		)?;
+		let mut builder = connected_users.bulk_message_builder();
+		builder.add_channel_viewers(channel.id);
+		builder.set_message(event);
+		builder.send(db, connected_users).await?;
+	}
+
 	Ok(Json(invite.into_inner()))
 }
@@ -0,0 +1,133 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+use chorus::types::jwt::Claims;
+use poem::{
+	IntoResponse, Route, handler, post,
+	web::{Data, Json},
+};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::PgPool;
+use util::{
+	entities::{BackupCode, Config, User},
+	errors::{Error, UserError},
+	util::{mfa, token::generate_token},
+};
+
+pub fn setup_routes() -> Route {
+	Route::new()
+		.at("/totp/enable", post(enable_totp))
+		.at("/totp/disable", post(disable_totp))
+		.at("/codes", post(get_backup_codes))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EnableTotpSchema {
+	pub password: String,
+	/// The base32 encoded secret the client generated and showed to the user.
+	pub secret: String,
+	/// A code of the authenticator app, proving that it received the secret.
+	pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisableTotpSchema {
+	/// A TOTP code or backup code.
+	pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BackupCodesSchema {
+	pub password: String,
+	#[serde(default)]
+	pub regenerate: bool,
+}
+
+/// Enable TOTP for the current user. Existing tokens are revoked, and the
+/// response contains a new token together with fresh backup codes, if the
+/// instance generates them.
+#[handler]
+pub async fn enable_totp(
+	Data(db): Data<&PgPool>,
+	Data(cfg): Data<&Config>,
+	Data(claims): Data<&Claims>,
+	Json(payload): Json<EnableTotpSchema>,
+) -> poem::Result<impl IntoResponse> {
+	let mut user =
+		User::get_by_id(db, claims.id).await?.ok_or(Error::User(UserError::InvalidUser))?;
+	if user.mfa_enabled.unwrap_or_default() {
+		return Err(Error::User(UserError::MfaAlreadyEnabled).into());
+	}
+	user.check_password(&payload.password)?;
+	mfa::validate_totp_secret(&payload.secret)?;
+	let Some(step) = mfa::verify_totp(&payload.secret, &payload.code, None)? else {
+		return Err(Error::User(UserError::InvalidMfaCode).into());
+	};
+
+	user.enable_totp(db, &payload.secret, step).await?;
+	let backup_codes = if cfg.security.two_factor.generate_backup_codes {
+		BackupCode::regenerate(db, user.id, cfg.security.mfa_backup_code_count).await?
+	} else {
+		Vec::new()
+	};
+	user.revoke_tokens(db).await?;
+	let token = generate_token(
+		user.id,
+		user.email.clone().unwrap_or_default().as_str(),
+		&cfg.security.jwt_secret,
+	)?;
+
+	Ok(Json(json!({"token": token, "backup_codes": backup_codes})))
+}
+
+/// Disable TOTP for the current user, after checking a TOTP code or backup
+/// code. Existing tokens are revoked, and the response contains a new token.
+#[handler]
+pub async fn disable_totp(
+	Data(db): Data<&PgPool>,
+	Data(cfg): Data<&Config>,
+	Data(claims): Data<&Claims>,
+	Json(payload): Json<DisableTotpSchema>,
+) -> poem::Result<impl IntoResponse> {
+	let mut user =
+		User::get_by_id(db, claims.id).await?.ok_or(Error::User(UserError::InvalidUser))?;
+	if !user.check_mfa_code(db, &payload.code).await? {
+		return Err(Error::User(UserError::InvalidMfaCode).into());
+	}
+
+	user.disable_totp(db).await?;
+	user.revoke_tokens(db).await?;
+	let token = generate_token(
+		user.id,
+		user.email.clone().unwrap_or_default().as_str(),
+		&cfg.security.jwt_secret,
+	)?;
+
+	Ok(Json(json!({"token": token})))
+}
+
+/// List the backup codes of the current user, or replace them with new ones
+/// if `regenerate` is set. Only new codes are listed with the code itself, as
+/// just hashes of the codes are stored.
+#[handler]
+pub async fn get_backup_codes(
+	Data(db): Data<&PgPool>,
+	Data(cfg): Data<&Config>,
+	Data(claims): Data<&Claims>,
+	Json(payload): Json<BackupCodesSchema>,
+) -> poem::Result<impl IntoResponse> {
+	let user = User::get_by_id(db, claims.id).await?.ok_or(Error::User(UserError::InvalidUser))?;
+	user.check_password(&payload.password)?;
+	if !user.mfa_enabled.unwrap_or_default() {
+		return Err(Error::User(UserError::MfaNotEnabled).into());
+	}
+
+	let backup_codes = if payload.regenerate {
+		BackupCode::regenerate(db, user.id, cfg.security.mfa_backup_code_count).await?
+	} else {
+		BackupCode::get_by_user_id(db, user.id).await?
+	};
+
+	Ok(Json(json!({"backup_codes": backup_codes})))
+}
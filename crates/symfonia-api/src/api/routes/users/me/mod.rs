@@ -1,7 +1,9 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
+mod mfa;
 mod settings;
+mod webauthn;
 
 use chorus::types::jwt::Claims;
 use poem::{
@@ -16,7 +18,11 @@ use util::{
 };
 
 pub fn setup_routes() -> Route {
-	Route::new().at("/", get(get_data)).at("/settings", get(get_settings).patch(update_settings))
+	Route::new()
+		.at("/", get(get_data))
+		.at("/settings", get(get_settings).patch(update_settings))
+		.nest("/mfa", mfa::setup_routes())
+		.nest("/mfa/webauthn", webauthn::setup_routes())
 }
 
 #[handler]
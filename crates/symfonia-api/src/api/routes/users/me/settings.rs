@@ -10,6 +10,7 @@ use sqlx::PgPool;
 use util::{
 	entities::User,
 	errors::{Error, UserError},
+	gateway::{ConnectedUsers, presence::Status},
 };
 
 #[handler]
@@ -26,11 +27,19 @@ pub async fn get_settings(
 pub async fn update_settings(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Json(settings): Json<UserSettings>,
 ) -> poem::Result<impl IntoResponse> {
 	let mut user =
 		User::get_by_id(db, claims.id).await?.ok_or(Error::User(UserError::InvalidUser))?;
 
+	let status = serde_json::to_value(&settings)
+		.map_err(Error::from)?
+		.get("status")
+		.and_then(|status| status.as_str())
+		.map(Status::parse);
+	connected_users.set_settings_status(db, claims.id, status).await;
+
 	user.settings = util::entities::UserSettings::consume(settings, user.settings_index.to_uint());
 	// TODO: user.settings.update(db).await.map_err(Error::Sqlx)?;
 
@@ -0,0 +1,164 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+use chorus::types::{Snowflake, jwt::Claims};
+use poem::{
+	IntoResponse, Response, Route, get, handler,
+	http::StatusCode,
+	patch, post,
+	web::{Data, Json, Path},
+};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::PgPool;
+use util::{
+	entities::{SecurityKey, User, WebauthnChallenge},
+	errors::{Error, UserError},
+	util::webauthn::{user_handle, webauthn},
+};
+use webauthn_rs::prelude::{RegisterPublicKeyCredential, SecurityKeyRegistration};
+
+pub fn setup_routes() -> Route {
+	Route::new()
+		.at("/credentials", get(get_security_keys))
+		.at("/credentials/begin", post(begin_registration))
+		.at("/credentials/finish", post(finish_registration))
+		.at("/credentials/:key_id", patch(rename_security_key).delete(delete_security_key))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BeginRegistrationSchema {
+	pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FinishRegistrationSchema {
+	pub name: String,
+	/// The JSON encoded `PublicKeyCredential` created by the authenticator.
+	pub credential: String,
+}
+
+/// Confirms a change to the security keys of the current user, with either
+/// their password or a TOTP code or backup code.
+#[derive(Debug, Deserialize)]
+pub struct ConfirmIdentitySchema {
+	pub password: Option<String>,
+	pub code: Option<String>,
+}
+
+impl ConfirmIdentitySchema {
+	async fn check(&self, db: &PgPool, user: &User) -> Result<(), Error> {
+		match (&self.password, &self.code) {
+			(Some(password), _) => user.check_password(password),
+			(None, Some(code)) if user.check_mfa_code(db, code).await? => Ok(()),
+			(None, Some(_)) => Err(UserError::InvalidMfaCode.into()),
+			(None, None) => Err(UserError::InvalidPassword.into()),
+		}
+	}
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameSecurityKeySchema {
+	pub name: String,
+	#[serde(flatten)]
+	pub confirmation: ConfirmIdentitySchema,
+}
+
+/// List the security keys of the current user.
+#[handler]
+pub async fn get_security_keys(
+	Data(db): Data<&PgPool>,
+	Data(claims): Data<&Claims>,
+) -> poem::Result<impl IntoResponse> {
+	let keys = SecurityKey::get_by_user_id(db, claims.id).await?;
+	Ok(Json(keys.iter().map(SecurityKey::to_public).collect::<Vec<_>>()))
+}
+
+/// Start registering a security key for the current user. Responds with the
+/// JSON encoded options to pass to `navigator.credentials.create()`.
+#[handler]
+pub async fn begin_registration(
+	Data(db): Data<&PgPool>,
+	Data(claims): Data<&Claims>,
+	Json(payload): Json<BeginRegistrationSchema>,
+) -> poem::Result<impl IntoResponse> {
+	let user = User::get_by_id(db, claims.id).await?.ok_or(Error::User(UserError::InvalidUser))?;
+	user.check_password(&payload.password)?;
+
+	let registered = SecurityKey::get_by_user_id(db, user.id)
+		.await?
+		.into_iter()
+		.map(|key| key.credential.cred_id().clone())
+		.collect();
+	let (challenge, registration) = webauthn()?
+		.start_securitykey_registration(
+			user_handle(user.id),
+			&user.username,
+			&user.username,
+			Some(registered),
+			None,
+			None,
+		)
+		.map_err(Error::from)?;
+	WebauthnChallenge::save(db, user.id, WebauthnChallenge::REGISTRATION, &registration).await?;
+
+	Ok(Json(json!({"challenge": serde_json::to_string(&challenge).map_err(Error::from)?})))
+}
+
+/// Finish registering a security key, with the credential the authenticator
+/// created from the options of `begin_registration`.
+#[handler]
+pub async fn finish_registration(
+	Data(db): Data<&PgPool>,
+	Data(claims): Data<&Claims>,
+	Json(payload): Json<FinishRegistrationSchema>,
+) -> poem::Result<impl IntoResponse> {
+	let registration: SecurityKeyRegistration =
+		WebauthnChallenge::take(db, claims.id, WebauthnChallenge::REGISTRATION).await?;
+	let credential: RegisterPublicKeyCredential = serde_json::from_str(&payload.credential)
+		.map_err(|e| Error::Custom(format!("Invalid credential: {e}")))?;
+	let credential = webauthn()?
+		.finish_securitykey_registration(&credential, &registration)
+		.map_err(Error::from)?;
+
+	let key = SecurityKey::create(db, claims.id, &payload.name, credential).await?;
+	Ok(Json(key.to_public()))
+}
+
+/// Rename a security key of the current user, after checking their password
+/// or a TOTP code or backup code.
+#[handler]
+pub async fn rename_security_key(
+	Data(db): Data<&PgPool>,
+	Data(claims): Data<&Claims>,
+	Path(key_id): Path<Snowflake>,
+	Json(payload): Json<RenameSecurityKeySchema>,
+) -> poem::Result<impl IntoResponse> {
+	let user = User::get_by_id(db, claims.id).await?.ok_or(Error::User(UserError::InvalidUser))?;
+	payload.confirmation.check(db, &user).await?;
+
+	let mut key = SecurityKey::get_by_id(db, key_id, claims.id)
+		.await?
+		.ok_or(Error::User(UserError::SecurityKeyNotFound))?;
+	key.rename(db, &payload.name).await?;
+	Ok(Json(key.to_public()))
+}
+
+/// Delete a security key of the current user, after checking their password
+/// or a TOTP code or backup code.
+#[handler]
+pub async fn delete_security_key(
+	Data(db): Data<&PgPool>,
+	Data(claims): Data<&Claims>,
+	Path(key_id): Path<Snowflake>,
+	Json(payload): Json<ConfirmIdentitySchema>,
+) -> poem::Result<impl IntoResponse> {
+	let user = User::get_by_id(db, claims.id).await?.ok_or(Error::User(UserError::InvalidUser))?;
+	payload.check(db, &user).await?;
+
+	let key = SecurityKey::get_by_id(db, key_id, claims.id)
+		.await?
+		.ok_or(Error::User(UserError::SecurityKeyNotFound))?;
+	key.delete(db).await?;
+	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
+}
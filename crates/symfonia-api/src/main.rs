@@ -2,6 +2,35 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-fn main() {
-	println!("Hello, world!");
+//! Runs the HTTP API on its own. Events are published to the event bus of the
+//! configuration, to be delivered by the gateway processes.
+
+use symfonia_api::api::start_api;
+use util::{
+	entities::Config,
+	shutdown::{self, Shutdown},
+	startup::{self, CliArgs, Component},
+};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
+	CliArgs::parse_or_default().init_configuration();
+	let _handle = startup::init_logging()?;
+
+	if !Component::Api.enabled() {
+		return Err("The API is disabled in the configuration".into());
+	}
+	log::info!(target: "symfonia::api", "Starting up the Symfonia API");
+	startup::warn_if_events_stay_in_process(Component::Api);
+
+	let db = startup::connect_database(Component::Api).await?;
+	startup::prepare_database(&db).await?;
+
+	let config = Config::init(db.pool()).await.unwrap_or_default();
+	let connected_users = startup::connected_users(db.pool()).await?;
+
+	let shutdown = Shutdown::default();
+	let server = start_api(db.pool().to_owned(), connected_users, config, shutdown.clone()).await?;
+	shutdown::run_until_signal(&shutdown, vec![server]).await;
+	Ok(())
 }
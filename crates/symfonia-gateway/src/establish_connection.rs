@@ -4,31 +4,46 @@
 
 use std::sync::Arc;
 
-use chorus::types::{GatewayHeartbeat, GatewayHello, GatewayReady};
+use chorus::types::{GatewayHeartbeat, GatewayHello, GatewayResume, Snowflake};
 use futures::{SinkExt, StreamExt};
 use log::{debug, trace};
 use serde_json::json;
 use sqlx::PgPool;
-use tokio::{net::TcpStream, sync::Mutex, task::JoinHandle};
+use tokio::{
+	net::TcpStream,
+	sync::{Mutex, broadcast},
+	task::JoinHandle,
+};
 use tokio_tungstenite::{
-	accept_async,
+	accept_hdr_async,
 	tungstenite::{
 		Message,
+		handshake::server::{Request, Response},
 		protocol::{CloseFrame, frame::coding::CloseCode},
 	},
 };
 use util::{
-	entities::Config,
+	configuration::SymfoniaConfiguration,
+	entities::{Config, Session},
 	errors::{Error, GatewayError, UserError},
-	gateway::{GatewayPayload, NewWebSocketConnection, WebSocketConnection, event::Event},
+	gateway::{
+		DisconnectInfo, GatewayPayload, NewWebSocketConnection, ReplayBuffer, WebSocketConnection,
+		event::Event,
+		intents::GatewayIntents,
+		transport::{ConnectionOptions, Transport},
+	},
+	tls::{self, MaybeTlsStream, TlsAcceptor},
 	util::token::check_token,
 };
 
 use super::ConnectedUsers;
 use crate::{
-	gateway_task::{self},
+	RESUME_RECONNECT_WINDOW_SECONDS,
+	gateway_task::{self, sequence_dispatch},
 	heartbeat::HeartbeatHandler,
+	presence::connect_client,
 	ready::create_ready,
+	session::end_session,
 };
 
 /// Internal use only state struct to pass around data to the
@@ -39,6 +54,12 @@ struct State {
 	config: Config,
 	connected_users: ConnectedUsers,
 	sequence_number: Arc<Mutex<u64>>,
+	/// Dispatches sent to the session. Replaced by the buffer of the previous
+	/// connection when a session is resumed.
+	replay_buffer: Arc<Mutex<ReplayBuffer>>,
+	/// The intents of the session. Replaced by the intents of the previous
+	/// connection when a session is resumed.
+	intents: GatewayIntents,
 	/// Receiver for heartbeat messages. The `HeartbeatHandler` will receive
 	/// messages from this channel.
 	heartbeat_receive: tokio::sync::broadcast::Receiver<GatewayHeartbeat>,
@@ -57,14 +78,46 @@ struct State {
 /// contains a [Weak] reference to the new [GatewayUser].
 pub(super) async fn establish_connection(
 	stream: TcpStream,
+	tls_acceptor: Option<TlsAcceptor>,
 	db: PgPool,
 	config: Config,
 	connected_users: ConnectedUsers,
 ) -> Result<NewWebSocketConnection, Error> {
 	trace!(target: "symfonia::gateway::establish_connection::establish_connection", "Beginning process to establish connection (handshake)");
+	let stream = match tls_acceptor {
+		Some(tls_acceptor) => tls::accept(&tls_acceptor, stream).await?,
+		None => MaybeTlsStream::Plain(stream),
+	};
 	// Accept the connection and split it into its sender and receiver halves.
-	let ws_stream = accept_async(stream).await?.split();
-	let connection = WebSocketConnection::new(ws_stream.0, ws_stream.1);
+	// The query string of the request holds the connection options of the client.
+	let mut query = None;
+	let ws_stream = accept_hdr_async(stream, |request: &Request, response: Response| {
+		query = request.uri().query().map(|query| query.to_string());
+		Ok(response)
+	})
+	.await?
+	.split();
+	let options = match ConnectionOptions::from_query(query.as_deref()) {
+		Ok(options) => options,
+		Err(e) => {
+			log::debug!(target: "symfonia::gateway::establish_connection", "Client requested invalid connection options: {e}");
+			let connection =
+				WebSocketConnection::new(ws_stream.0, ws_stream.1, Transport::default());
+			let code = match e {
+				GatewayError::InvalidApiVersion(_) => 4012,
+				_ => 4002,
+			};
+			connection.sender.send(Message::Close(Some(CloseFrame {
+				code: CloseCode::Library(code),
+				reason: e.to_string().into(),
+			})))?;
+			connection.kill_send.send(());
+			return Err(e.into());
+		}
+	};
+	trace!(target: "symfonia::gateway::establish_connection::establish_connection", "Connection options: {options:?}");
+	let transport = Transport::new(&options)?;
+	let connection = WebSocketConnection::new(ws_stream.0, ws_stream.1, transport);
 	trace!(target: "symfonia::gateway::establish_connection::establish_connection", "Sending hello message");
 	// Hello message
 	match connection.sender.send(Message::Text(json!(GatewayHello::default()).to_string().into())) {
@@ -85,7 +138,8 @@ pub(super) async fn establish_connection(
 	// and handle.
 	let (message_send, message_receive) = tokio::sync::broadcast::channel::<GatewayHeartbeat>(4);
 
-	let sequence_number = Arc::new(Mutex::new(0u64)); // TODO: Actually use this, as in: Increment it when needed. Currently, this is not being done.
+	// Sequence number of the last dispatch sent to the client.
+	let sequence_number = Arc::new(Mutex::new(0u64));
 
 	// Used to inform the `HeartbeatHandler` task of the session_id of the client,
 	// if we receive it after a heartbeat handler task has been spawned.
@@ -97,6 +151,8 @@ pub(super) async fn establish_connection(
 		config: config.clone(),
 		connected_users: connected_users.clone(),
 		sequence_number: sequence_number.clone(),
+		replay_buffer: Arc::new(Mutex::new(ReplayBuffer::default())),
+		intents: GatewayIntents::default(),
 		heartbeat_receive: message_receive.resubscribe(),
 		heartbeat_send: message_send.clone(),
 		session_id_send: session_id_send.clone(),
@@ -178,17 +234,7 @@ async fn finish_connecting(
 					// would also allow us to use more appropriate channel types such as
 					// `oneshot` for the session_id_receive channel. However, I don't see that
 					// this is needed at the moment.
-					heartbeat_handler_handle = Some(tokio::spawn({
-						let mut heartbeat_handler = HeartbeatHandler::new(
-							state.connection.clone(),
-							state.heartbeat_receive.resubscribe(),
-							state.sequence_number.clone(),
-							state.session_id_receive.resubscribe(),
-						);
-						async move {
-							heartbeat_handler.run().await;
-						}
-					}))
+					heartbeat_handler_handle = Some(spawn_heartbeat_handler(&state))
 				}
 				Some(_) => {
 					state.heartbeat_send.send(heartbeat);
@@ -217,82 +263,300 @@ async fn finish_connecting(
 					return Err(UserError::InvalidToken.into());
 				}
 			};
-			log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Getting gateway_user");
-			let gateway_user = state.connected_users.get_user_or_new(claims.id);
-			log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Creating main gateway task handle");
-			let main_task_handle = tokio::spawn(gateway_task::gateway_task(
-				state.connection.clone(),
-				gateway_user.lock().await.inbox.resubscribe(),
-				state.heartbeat_send.clone(),
-				state.sequence_number.clone(),
-				state.connected_users.clone(),
+			state.intents = match check_intents(
+				&state.db,
 				claims.id,
-			));
-			log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Creating gateway_client");
-			let gateway_client = state
-                .connected_users
-                .new_client(
-                    gateway_user.clone(),
-                    state.connection.clone(),
-                    main_task_handle,
-                    match heartbeat_handler_handle {
-                        Some(handle) => handle,
-                        None => tokio::spawn({
-                            log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "No heartbeat_handler yet. Creating one...");
-                            let mut heartbeat_handler = HeartbeatHandler::new(
-                                state.connection.clone(),
-                                state.heartbeat_receive.resubscribe(),
-                                state.sequence_number.clone(),
-                                state.session_id_receive.resubscribe(),
-                            );
-                            async move {
-                                heartbeat_handler.run().await;
-                            }
-                        }),
-                    },
-                    &identify.event_data.as_ref().unwrap().token,
-                    state.sequence_number.clone(),
-                )
-                .await;
-			match state.session_id_send.send(identify.event_data.unwrap().token) {
-				Ok(_) => (),
-				Err(_) => {
-					log::error!(target: "symfonia::gateway::establish_connection::finish_connecting", "Failed to send session_id to heartbeat handler");
+				identify.event_data.as_ref().unwrap().intents,
+			)
+			.await
+			{
+				Ok(intents) => intents,
+				Err(Error::Gateway(e)) => {
+					log::debug!(target: "symfonia::gateway::establish_connection::finish_connecting", "Client identified with invalid intents: {e}");
+					let code = match e {
+						GatewayError::DisallowedIntents => 4014,
+						_ => 4013,
+					};
 					state.connection.sender.send(Message::Close(Some(CloseFrame {
-						code: CloseCode::Library(4000),
-						reason: "Internal server error".into(),
+						code: CloseCode::Library(code),
+						reason: e.to_string().into(),
 					})));
 					state.connection.kill_send.send(()).expect("Failed to send kill signal");
-					return Err(GatewayError::Internal.into());
+					return Err(e.into());
 				}
-			}
-			let formatted_payload = GatewayPayload::<GatewayReady> {
+				Err(e) => return Err(e),
+			};
+			let session_id = Snowflake::generate();
+			let session = Session::create(
+				&state.db,
+				claims.id,
+				session_id,
+				SymfoniaConfiguration::get().general.node_id,
+				identify.event_data.as_ref().unwrap(),
+			)
+			.await?;
+			// The new client is not yet subscribed to the inbox of the user and learns
+			// about its sessions through READY instead.
+			state.connected_users.dispatch_sessions_replace(&state.db, claims.id).await?;
+			let session_id = session_id.to_string();
+			// READY has to be the first dispatch of the session, so the inbox is only
+			// processed once it was sent. Subscribing before READY is created ensures
+			// that no dispatch happening in the meantime is lost.
+			let inbox = subscribe_inbox(&state, claims.id).await;
+			connect_client(&state.db, &state.connected_users, &session).await?;
+			let formatted_payload = GatewayPayload::<serde_json::Value> {
 				op_code: 0,
-				event_data: Some(create_ready(claims.id, &state.db).await?),
+				event_data: Some(
+					create_ready(claims.id, &session_id, &state.db, &state.connected_users).await?,
+				),
 				sequence_number: None,
 				event_name: Some("READY".to_string()),
 			};
-			state
-				.connection
-				.sender
-				.send(Message::Text(json!(formatted_payload).to_string().into()))?;
+			let ready =
+				sequence_dispatch(formatted_payload, &state.sequence_number, &state.replay_buffer)
+					.await;
+			state.connection.sender.send(Message::Text(ready.into()))?;
+			let new_connection = start_session(
+				&state,
+				heartbeat_handler_handle.take(),
+				claims.id,
+				&session_id,
+				inbox,
+			)
+			.await?;
 			log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Done!");
-			return Ok(NewWebSocketConnection {
-				user: gateway_user,
-				client: gateway_client.clone(),
-			});
+			return Ok(new_connection);
 		} else if let Event::Resume(resume) = event {
 			log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Received resume payload");
-			log::warn!(target: "symfonia::gateway::establish_connection::finish_connecting", "Resuming connections is not yet implemented. Telling client to identify instead.");
-			state.connection.sender.send(Message::Close(Some(CloseFrame {
-				code: CloseCode::from(4000),
-				reason: "Resuming connections is not yet implemented. Please identify instead."
-					.into(),
-			})))?;
-			state.connection.kill_send.send(()).expect("Failed to send kill signal");
+			let Some(resume) = resume.event_data else {
+				send_invalid_session(&state.connection, false)?;
+				continue;
+			};
+			match resume_session(&mut state, &resume).await? {
+				Some((user_id, missed_payloads)) => {
+					let inbox = subscribe_inbox(&state, user_id).await;
+					log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Replaying {} missed dispatches", missed_payloads.len());
+					for payload in missed_payloads.into_iter() {
+						state.connection.sender.send(Message::Text(payload.into()))?;
+					}
+					let resumed = GatewayPayload::<()> {
+						op_code: 0,
+						event_data: None,
+						sequence_number: None,
+						event_name: Some("RESUMED".to_string()),
+					};
+					let resumed =
+						sequence_dispatch(resumed, &state.sequence_number, &state.replay_buffer)
+							.await;
+					state.connection.sender.send(Message::Text(resumed.into()))?;
+					let new_connection = start_session(
+						&state,
+						heartbeat_handler_handle.take(),
+						user_id,
+						&resume.session_id,
+						inbox,
+					)
+					.await?;
+					// Session ids are snowflakes, as the session could not have been resumed
+					// otherwise.
+					let session_id =
+						Snowflake(resume.session_id.parse::<u64>().unwrap_or_default());
+					Session::move_to_node(
+						&state.db,
+						session_id,
+						SymfoniaConfiguration::get().general.node_id,
+					)
+					.await?;
+					if let Some(session) = Session::get_by_session_id(&state.db, session_id).await?
+					{
+						connect_client(&state.db, &state.connected_users, &session).await?;
+					}
+					log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Resumed session");
+					return Ok(new_connection);
+				}
+				None => continue,
+			}
 		} else {
 			debug!(target: "symfonia::gateway::establish_connection::finish_connecting", "Message could not be decoded as resume, heartbeat or identify: {}", raw_message);
 			return Err(GatewayError::UnexpectedMessage("Received payload other than Heartbeat, Identify or Resume before the connection was established".to_string()).into());
 		}
 	}
 }
+
+/// Validates a resume request. If the session can be resumed, the sequence
+/// number and replay buffer of the previous connection are carried over to
+/// `state`, and the id of the user is returned together with the dispatches
+/// the client missed. Otherwise, the client is sent an `INVALID_SESSION`.
+async fn resume_session(
+	state: &mut State,
+	resume: &GatewayResume,
+) -> Result<Option<(Snowflake, Vec<String>)>, Error> {
+	let claims = match check_token(&state.db, &resume.token, &state.config.security.jwt_secret)
+		.await
+	{
+		Ok(claims) => claims,
+		Err(_) => {
+			log::trace!(target: "symfonia::gateway::establish_connection::resume_session", "Failed to verify token");
+			state.connection.sender.send(Message::Close(Some(CloseFrame {
+				code: CloseCode::Library(4004),
+				reason: "The token you sent in your resume payload is incorrect.".into(),
+			})));
+			state.connection.kill_send.send(()).expect("Failed to send kill signal");
+			return Err(UserError::InvalidToken.into());
+		}
+	};
+	let Ok(sequence) = resume.seq.parse::<u64>() else {
+		send_invalid_session(&state.connection, false)?;
+		return Ok(None);
+	};
+	let disconnect_info = match state.connected_users.take_resumable_session(&resume.session_id) {
+		Some(disconnect_info) => Some(disconnect_info),
+		// Sessions of gateways which shut down are saved to the database.
+		None => DisconnectInfo::take_saved(&state.db, &resume.session_id).await?,
+	};
+	let Some(disconnect_info) = disconnect_info else {
+		// The previous connection of the session might not have been noticed as
+		// closed yet, in which case the client may try again shortly.
+		let still_connected =
+			state.connected_users.is_session_connected(claims.id, &resume.session_id).await;
+		send_invalid_session(&state.connection, still_connected)?;
+		return Ok(None);
+	};
+	if disconnect_info.user_id != claims.id {
+		// Do not let other users invalidate the session by guessing its id.
+		state.connected_users.add_resumable_session(disconnect_info);
+		send_invalid_session(&state.connection, false)?;
+		return Ok(None);
+	}
+	if disconnect_info.has_expired(RESUME_RECONNECT_WINDOW_SECONDS as u64) {
+		end_session(&state.db, &state.connected_users, &disconnect_info).await;
+		send_invalid_session(&state.connection, false)?;
+		return Ok(None);
+	}
+	let missed_payloads = disconnect_info
+		.replay_buffer
+		.lock()
+		.await
+		.events_after(sequence, disconnect_info.disconnected_at_sequence);
+	let Some(missed_payloads) = missed_payloads else {
+		log::debug!(target: "symfonia::gateway::establish_connection::resume_session", "Cannot replay dispatches after sequence number {sequence}");
+		end_session(&state.db, &state.connected_users, &disconnect_info).await;
+		send_invalid_session(&state.connection, false)?;
+		return Ok(None);
+	};
+	*state.sequence_number.lock().await = disconnect_info.disconnected_at_sequence;
+	state.replay_buffer = disconnect_info.replay_buffer;
+	state.intents = disconnect_info.intents;
+	Ok(Some((claims.id, missed_payloads)))
+}
+
+/// Parses the intents of an identify payload and checks that the user
+/// `user_id` is allowed to use the privileged intents among them.
+async fn check_intents(
+	db: &PgPool,
+	user_id: Snowflake,
+	intents: Option<i32>,
+) -> Result<GatewayIntents, Error> {
+	let allowed = GatewayIntents::allowed_privileged(db, user_id).await?;
+	let Some(intents) = intents else {
+		// Clients which do not send intents receive all events they may receive.
+		return Ok(GatewayIntents::all().difference(GatewayIntents::PRIVILEGED) | allowed);
+	};
+	let intents = GatewayIntents::from_identify(intents)?;
+	if !allowed.contains(intents & GatewayIntents::PRIVILEGED) {
+		return Err(GatewayError::DisallowedIntents.into());
+	}
+	Ok(intents)
+}
+
+/// Subscribes to the inbox of the user, for [start_session].
+async fn subscribe_inbox(state: &State, user_id: Snowflake) -> broadcast::Receiver<Event> {
+	state.connected_users.get_user_or_new(user_id).lock().await.inbox.resubscribe()
+}
+
+/// Spawns the tasks of an identified or resumed session and registers the
+/// [GatewayClient](util::gateway::GatewayClient) of the connection. `READY` or
+/// `RESUMED` must have been sent already, as the dispatches of `inbox` are
+/// sent from here on.
+async fn start_session(
+	state: &State,
+	heartbeat_handler_handle: Option<JoinHandle<()>>,
+	user_id: Snowflake,
+	session_id: &str,
+	inbox: broadcast::Receiver<Event>,
+) -> Result<NewWebSocketConnection, Error> {
+	log::trace!(target: "symfonia::gateway::establish_connection::start_session", "Getting gateway_user");
+	let gateway_user = state.connected_users.get_user_or_new(user_id);
+	log::trace!(target: "symfonia::gateway::establish_connection::start_session", "Creating main gateway task handle");
+	let main_task_handle = tokio::spawn(gateway_task::gateway_task(
+		state.connection.clone(),
+		inbox,
+		state.heartbeat_send.clone(),
+		state.sequence_number.clone(),
+		state.replay_buffer.clone(),
+		state.connected_users.clone(),
+		state.db.clone(),
+		user_id,
+		session_id.to_string(),
+		state.intents,
+	));
+	log::trace!(target: "symfonia::gateway::establish_connection::start_session", "Creating gateway_client");
+	let heartbeat_handler_handle = match heartbeat_handler_handle {
+		Some(handle) => handle,
+		None => {
+			log::trace!(target: "symfonia::gateway::establish_connection::start_session", "No heartbeat_handler yet. Creating one...");
+			spawn_heartbeat_handler(state)
+		}
+	};
+	let gateway_client = state
+		.connected_users
+		.new_client(
+			gateway_user.clone(),
+			state.connection.clone(),
+			main_task_handle,
+			heartbeat_handler_handle,
+			session_id,
+			state.sequence_number.clone(),
+			state.replay_buffer.clone(),
+			state.intents,
+		)
+		.await;
+	match state.session_id_send.send(session_id.to_string()) {
+		Ok(_) => (),
+		Err(_) => {
+			log::error!(target: "symfonia::gateway::establish_connection::start_session", "Failed to send session_id to heartbeat handler");
+			state.connection.sender.send(Message::Close(Some(CloseFrame {
+				code: CloseCode::Library(4000),
+				reason: "Internal server error".into(),
+			})));
+			state.connection.kill_send.send(()).expect("Failed to send kill signal");
+			return Err(GatewayError::Internal.into());
+		}
+	}
+	Ok(NewWebSocketConnection { user: gateway_user, client: gateway_client })
+}
+
+fn spawn_heartbeat_handler(state: &State) -> JoinHandle<()> {
+	let mut heartbeat_handler = HeartbeatHandler::new(
+		state.connection.clone(),
+		state.heartbeat_receive.resubscribe(),
+		state.sequence_number.clone(),
+		state.session_id_receive.resubscribe(),
+	);
+	tokio::spawn(async move {
+		heartbeat_handler.run().await;
+	})
+}
+
+/// Sends an `INVALID_SESSION` payload. `resumable` tells the client whether it
+/// may try to resume the session again, or has to identify instead.
+fn send_invalid_session(connection: &WebSocketConnection, resumable: bool) -> Result<(), Error> {
+	let payload = GatewayPayload::<bool> {
+		op_code: 9,
+		event_data: Some(resumable),
+		sequence_number: None,
+		event_name: None,
+	};
+	connection.sender.send(Message::Text(json!(payload).to_string().into()))?;
+	Ok(())
+}
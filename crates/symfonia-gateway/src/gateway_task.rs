@@ -6,7 +6,9 @@ use std::sync::Arc;
 
 use chorus::types::{GatewayHeartbeat, Snowflake};
 use log::debug;
+use serde::{Serialize, de::DeserializeOwned};
 use serde_json::json;
+use sqlx::PgPool;
 use tokio::sync::Mutex;
 use tokio_tungstenite::tungstenite::{
 	Message,
@@ -14,22 +16,49 @@ use tokio_tungstenite::tungstenite::{
 };
 use util::{
 	errors::{Error, GatewayError},
-	gateway::{WebSocketConnection, event::Event},
+	gateway::{
+		DisconnectInfo, GatewayPayload, ReplayBuffer, WebSocketConnection, event::Event,
+		intents::GatewayIntents,
+	},
 };
 
 use super::ConnectedUsers;
+use crate::{
+	guild_subscriptions::guild_subscriptions,
+	presence::{disconnect_client, update_client},
+	request_guild_members::{RequestContext, request_guild_members},
+	session::end_session,
+};
 
 /// Handles all messages a client sends to the gateway post-handshake.
+#[allow(clippy::too_many_arguments)]
 pub(super) async fn gateway_task(
 	mut connection: WebSocketConnection,
 	inbox: tokio::sync::broadcast::Receiver<Event>,
 	heartbeat_send: tokio::sync::broadcast::Sender<GatewayHeartbeat>,
 	last_sequence_number: Arc<Mutex<u64>>,
+	replay_buffer: Arc<Mutex<ReplayBuffer>>,
 	connected_users: ConnectedUsers,
+	db: PgPool,
 	user_id: Snowflake,
+	session_id: String,
+	intents: GatewayIntents,
 ) {
 	log::trace!(target: "symfonia::gateway::gateway_task", "Started a new gateway task!");
-	let inbox_processor = tokio::spawn(process_inbox(connection.clone(), inbox.resubscribe()));
+	// Events for this session only, as opposed to the inbox shared by all sessions
+	// of the user.
+	let (session_outbox, session_inbox) = tokio::sync::broadcast::channel(100);
+	let inbox_processor = tokio::spawn(process_inbox(
+		connection.clone(),
+		inbox.resubscribe(),
+		session_inbox,
+		last_sequence_number.clone(),
+		replay_buffer.clone(),
+		user_id,
+		intents,
+	));
+	// Clients closing the connection normally end their session for good.
+	let mut resumable = true;
 
 	/*
 	Before we can respond to any gateway event we receive, we need to figure out what kind of event
@@ -43,11 +72,24 @@ pub(super) async fn gateway_task(
 			_ = connection.kill_receive.recv() => {
 				// Since callsites handle closing the connection, we don't need to do that here.
 				// Perform cleanup and return
-				let mut store_lock = connected_users.store.write();
-				store_lock.users.remove(&user_id);
-				store_lock.inboxes.remove(&user_id);
-				// TODO(bitfl0wer) Add the user to the disconnected sessions
-				drop(store_lock);
+				disconnect_client(&db, &connected_users, user_id, &session_id).await;
+				connected_users.unsubscribe_member_lists(&session_id);
+				let disconnected_at_sequence = *last_sequence_number.lock().await;
+				// The user stays registered for their other sessions, if any.
+				let parent = connected_users.remove_client(user_id, &session_id).await;
+				let disconnect_info = DisconnectInfo::new(
+					session_id,
+					user_id,
+					disconnected_at_sequence,
+					replay_buffer,
+					intents,
+					parent,
+				);
+				if resumable {
+					connected_users.add_resumable_session(disconnect_info);
+				} else {
+					end_session(&db, &connected_users, &disconnect_info).await;
+				}
 				return;
 			},
 			message_result = connection.receiver.recv() => {
@@ -60,12 +102,58 @@ pub(super) async fn gateway_task(
 					Message::Text(_) => {
 						log::trace!(target: "symfonia::gateway::gateway_task", "Received raw message {:?}", message_of_unknown_type);
 						let event = unwrap_event(Event::try_from(message_of_unknown_type), connection.clone(), connection.kill_send.clone());
+						if let Event::RequestGuildMembers(request) = event {
+							let context = RequestContext {
+								connection: connection.clone(),
+								db: db.clone(),
+								connected_users: connected_users.clone(),
+								user_id,
+								intents,
+								sequence_number: last_sequence_number.clone(),
+								replay_buffer: replay_buffer.clone(),
+							};
+							// Large guilds take a while to chunk, which must not block heartbeats.
+							tokio::spawn(async move {
+								if let Err(e) = request_guild_members(context, request).await {
+									log::error!(target: "symfonia::gateway::gateway_task", "Failed to answer Request Guild Members: {e}");
+								}
+							});
+							continue;
+						}
+						if let Event::GuildSubscriptions(request) = event {
+							let task = guild_subscriptions(
+								db.clone(),
+								connected_users.clone(),
+								user_id,
+								session_id.clone(),
+								session_outbox.clone(),
+								request,
+							);
+							// Loading the member list of a large guild must not block heartbeats.
+							tokio::spawn(async move {
+								if let Err(e) = task.await {
+									log::error!(target: "symfonia::gateway::gateway_task", "Failed to subscribe to guild member list: {e}");
+								}
+							});
+							continue;
+						}
+						if let Event::PresenceUpdate(update) = event {
+							if let Some(update) = update.event_data {
+								if let Err(e) = update_client(&db, &connected_users, user_id, &session_id, &update).await {
+									log::error!(target: "symfonia::gateway::gateway_task", "Failed to update presence: {e}");
+								}
+							}
+							continue;
+						}
 						handle_event(event, connection.clone(), heartbeat_send.clone());
 					},
 					Message::Close(close_frame) => {
 						// Closing is initiated by the client - we don't need to send a
 						// close message back.
 						debug!("Client is closing connection. Signaling gateway_task to shut down");
+						if let Some(frame) = close_frame {
+							resumable = !matches!(frame.code, CloseCode::Normal | CloseCode::Away);
+						}
 						connection.kill_send.send(());
 					},
 					_ => continue
@@ -155,34 +243,81 @@ fn unwrap_event(
 	}
 }
 
-/// Process events triggered by the HTTP API.
+/// Assigns the next sequence number of a session to a dispatch payload and
+/// records the payload in the session's [ReplayBuffer]. Returns the serialized
+/// payload.
+pub(super) async fn sequence_dispatch<T: Serialize + DeserializeOwned>(
+	mut payload: GatewayPayload<T>,
+	sequence_number: &Mutex<u64>,
+	replay_buffer: &Mutex<ReplayBuffer>,
+) -> String {
+	let mut sequence = sequence_number.lock().await;
+	*sequence += 1;
+	payload.sequence_number = Some(*sequence);
+	let serialized = json!(payload).to_string();
+	replay_buffer.lock().await.push(*sequence, serialized.clone());
+	serialized
+}
+
+/// Process events triggered by the HTTP API, sent either to all sessions of
+/// the user through `inbox` or to this session only through `session_inbox`.
+/// Dispatches the client has not subscribed to through its `intents` are
+/// dropped before they are assigned a sequence number.
 async fn process_inbox(
 	mut connection: WebSocketConnection,
 	mut inbox: tokio::sync::broadcast::Receiver<Event>,
+	mut session_inbox: tokio::sync::broadcast::Receiver<Event>,
+	sequence_number: Arc<Mutex<u64>>,
+	replay_buffer: Arc<Mutex<ReplayBuffer>>,
+	user_id: Snowflake,
+	intents: GatewayIntents,
 ) {
 	loop {
-		tokio::select! {
+		let event = tokio::select! {
 			_ = connection.kill_receive.recv() => {
 				return;
 			}
-			event = inbox.recv() => {
-				match event {
-					Ok(event) => {
-						let send_result = connection.sender.send(Message::Text(json!(event).to_string().into()));
-						match send_result {
-							Ok(_) => (), // TODO: Increase sequence number here
-							Err(_) => {
-								debug!("Failed to send event to WebSocket. Closing connection and killing tasks");
-								connection.sender.send(Message::Close(Some(CloseFrame { code: CloseCode::Library(4000), reason: "WebSocket error".into() })));
-								connection.kill_send.send(()).expect("Failed to send kill_send");
-							},
+			event = inbox.recv() => event,
+			event = session_inbox.recv() => event,
+		};
+		match event {
+			Ok(event) => {
+				let payload = match &event {
+					Event::Dispatch(dispatch) => match dispatch
+						.event_type()
+						.and_then(|event_type| Ok((event_type, dispatch.to_payload()?)))
+					{
+						Ok((event_type, mut payload)) => {
+							if !intents.filter(event_type, &mut payload, user_id) {
+								continue;
+							}
+							sequence_dispatch(payload, &sequence_number, &replay_buffer).await
 						}
-					}
+						Err(e) => {
+							log::error!(target: "symfonia::gateway::gateway_task::process_inbox", "Failed to serialize dispatch event: {e}");
+							continue;
+						}
+					},
+					_ => json!(event).to_string(),
+				};
+				let send_result = connection.sender.send(Message::Text(payload.into()));
+				match send_result {
+					Ok(_) => (),
 					Err(_) => {
-						return;
+						debug!(
+							"Failed to send event to WebSocket. Closing connection and killing tasks"
+						);
+						connection.sender.send(Message::Close(Some(CloseFrame {
+							code: CloseCode::Library(4000),
+							reason: "WebSocket error".into(),
+						})));
+						connection.kill_send.send(()).expect("Failed to send kill_send");
 					}
 				}
 			}
+			Err(_) => {
+				return;
+			}
 		}
 	}
 }
@@ -0,0 +1,42 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chorus::types::Snowflake;
+use sqlx::PgPool;
+use tokio::sync::broadcast::Sender;
+use util::{
+	errors::Error,
+	gateway::{
+		ConnectedUsers, GatewayPayload, event::Event, member_list::GuildSubscriptionsRequest,
+	},
+};
+
+use crate::request_guild_members::is_member;
+
+/// Handles an op 14 Guild Subscriptions by subscribing the session to the
+/// requested ranges of the member lists of the requested channels. The ranges
+/// are sent to the session through `outbox`, as are later changes to them.
+/// Requests for guilds the user is not a member of are ignored, as are channels
+/// they cannot view.
+pub(super) async fn guild_subscriptions(
+	db: PgPool,
+	connected_users: ConnectedUsers,
+	user_id: Snowflake,
+	session_id: String,
+	outbox: Sender<Event>,
+	request: GatewayPayload<GuildSubscriptionsRequest>,
+) -> Result<(), Error> {
+	let Some(request) = request.event_data else {
+		return Ok(());
+	};
+	if !is_member(&db, user_id, request.guild_id).await? {
+		log::debug!(target: "symfonia::gateway::guild_subscriptions", "User {user_id} subscribed to guild {} without being a member", request.guild_id);
+		return Ok(());
+	}
+	// Requests without channels only subscribe to typing or activities.
+	if request.channel_ranges().is_empty() {
+		return Ok(());
+	}
+	connected_users.subscribe_member_lists(&db, user_id, &session_id, outbox, &request).await
+}
@@ -108,9 +108,6 @@ impl HeartbeatHandler {
 	/// ```
 	pub(super) async fn run(&mut self) {
 		trace!(target: "symfonia::gateway::heartbeat_handler", "Heartbeat handler started");
-		// TODO: On death of this task, create and store disconnect info in gateway
-		// client object
-		let sequence = 0u64;
 		loop {
 			// When receiving heartbeats, we need to consider the following cases:
 			// - Heartbeat sequence number is correct
@@ -118,6 +115,8 @@ impl HeartbeatHandler {
 			//   sent before the heartbeat was received
 			// - Heartbeat sequence number is way off, likely because the connection has
 			//   high latency or is unstable
+			// - Heartbeat sequence number is ahead of ours, which means that the client
+			//   claims to have received a dispatch we never sent
 			//
 			// I would consider "way off" to be a difference of more than or equal to 3.
 			tokio::select! {
@@ -128,45 +127,25 @@ impl HeartbeatHandler {
 				Ok(heartbeat) = self.message_receive.recv() => {
 					trace!("Received heartbeat message in heartbeat_handler");
 					if let Some(received_sequence_number) = heartbeat.d {
-						let sequence = self.sequence_number.lock().await;
-						// TODO: As long as sequence numbers are not increased server-side, this code
-						// is not useful.
-						/* match Self::compare_sequence_numbers(*sequence, received_sequence_number) {
-							SequenceNumberComparison::Correct => {
-								self.send_ack().await;
-							}
+						let sequence = *self.sequence_number.lock().await;
+						match Self::compare_sequence_numbers(sequence, received_sequence_number) {
+							SequenceNumberComparison::Correct => (),
 							SequenceNumberComparison::SlightlyOff(diff) => {
 								trace!(target: "symfonia::gateway::heartbeat_handler", "Received heartbeat sequence number is slightly off by {}. This may be due to latency or a new packet being sent before the current one got received.", diff);
-								self.send_ack().await;
 							}
 							SequenceNumberComparison::WayOff(diff) => {
-								// TODO: We could potentially send a heartbeat to the client, prompting it to send a new heartbeat.
-								// This would require more logic though.
-								trace!(target: "symfonia::gateway::heartbeat_handler", "Received heartbeat sequence number is way off by {}. This may be due to latency.", diff);
-								match self.connection.sender.send(Message::Text(json!(GatewayReconnect::default()).to_string())) {
-									Ok(_) => (),
-									Err(e) => {
-										trace!("Failed to send reconnect message in heartbeat_handler. Stopping gateway_task and heartbeat_handler");
-										self.connection.kill_send.send(()).expect("Failed to send kill signal in heartbeat_handler");
-									}
-								};
+								debug!(target: "symfonia::gateway::heartbeat_handler", "Received heartbeat sequence number is way off by {}. This may be due to latency.", diff);
+							}
+							SequenceNumberComparison::Ahead(diff) => {
+								debug!(target: "symfonia::gateway::heartbeat_handler", "Received heartbeat sequence number is {} ahead of the last dispatched sequence number. Closing connection", diff);
+								self.connection.sender.send(Message::Close(Some(CloseFrame { code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Library(4007), reason: "Invalid seq".into() })));
 								self.connection.kill_send.send(()).expect("Failed to send kill signal in heartbeat_handler");
+								break;
 							}
-						} */
+						}
 					}
 					self.last_heartbeat = std::time::Instant::now();
-					match self.connection.sender.send(Message::Text(
-						json!(GatewayHeartbeatAck::default()).to_string().into(),
-					)) {
-						Ok(_) => (),
-						Err(_) => {
-							trace!("Failed to send heartbeat ack in heartbeat_handler. Stopping gateway_task and heartbeat_handler");
-							self.connection.sender.send(Message::Close(Some(CloseFrame { code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Library(4000), reason: "WebSocket error".into() })));
-							self.connection.kill_send.send(()).expect("Failed to send kill signal in heartbeat_handler");
-						},
-					}
-
-					;
+					self.send_ack().await;
 				}
 				else => {
 					// TODO: We could potentially send a heartbeat if we haven't received one in ~40 seconds,
@@ -183,15 +162,17 @@ impl HeartbeatHandler {
 		}
 	}
 
-	/// Compares two sequence numbers and returns a comparison result of type
-	/// [SequenceNumberComparison].
-	fn compare_sequence_numbers(one: u64, two: u64) -> SequenceNumberComparison {
-		let max = std::cmp::max(one, two);
-		let min = std::cmp::min(one, two);
-		match max - min {
+	/// Compares the sequence number of the last dispatch sent to the client
+	/// with the sequence number the client sent in its heartbeat and returns a
+	/// comparison result of type [SequenceNumberComparison].
+	fn compare_sequence_numbers(sent: u64, received: u64) -> SequenceNumberComparison {
+		if received > sent {
+			return SequenceNumberComparison::Ahead(received - sent);
+		}
+		match sent - received {
 			0 => SequenceNumberComparison::Correct,
-			1..=2 => SequenceNumberComparison::SlightlyOff(max - min),
-			_ => SequenceNumberComparison::WayOff(max - min),
+			1..=2 => SequenceNumberComparison::SlightlyOff(sent - received),
+			_ => SequenceNumberComparison::WayOff(sent - received),
 		}
 	}
 
@@ -207,6 +188,13 @@ impl HeartbeatHandler {
 				trace!(
 					"Failed to send heartbeat ack in heartbeat_handler. Stopping gateway_task and heartbeat_handler"
 				);
+				self.connection.sender.send(Message::Close(Some(CloseFrame {
+					code:
+						tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Library(
+							4000,
+						),
+					reason: "WebSocket error".into(),
+				})));
 				self.connection
 					.kill_send
 					.send(())
@@ -217,6 +205,7 @@ impl HeartbeatHandler {
 }
 
 /// Granular comparison of two sequence numbers.
+#[derive(Debug, PartialEq, Eq)]
 enum SequenceNumberComparison {
 	/// The sequence numbers are identical.
 	Correct,
@@ -224,4 +213,21 @@ enum SequenceNumberComparison {
 	SlightlyOff(u64),
 	// The sequence numbers have a difference of 3 or more.
 	WayOff(u64),
+	/// The client claims to have received a sequence number that was never
+	/// sent.
+	Ahead(u64),
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn compares_sequence_numbers() {
+		use SequenceNumberComparison::*;
+		assert_eq!(HeartbeatHandler::compare_sequence_numbers(5, 5), Correct);
+		assert_eq!(HeartbeatHandler::compare_sequence_numbers(5, 3), SlightlyOff(2));
+		assert_eq!(HeartbeatHandler::compare_sequence_numbers(5, 1), WayOff(4));
+		assert_eq!(HeartbeatHandler::compare_sequence_numbers(5, 6), Ahead(1));
+	}
 }
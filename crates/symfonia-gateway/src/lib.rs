@@ -1,13 +1,18 @@
 mod establish_connection;
 mod gateway_task;
+mod guild_subscriptions;
 mod heartbeat;
+mod presence;
 mod ready;
+mod request_guild_members;
+mod session;
 
 static RESUME_RECONNECT_WINDOW_SECONDS: u8 = 90;
 static DEFAULT_GATEWAY_BIND: &str = "0.0.0.0:3003";
 
-use std::{collections::HashMap, thread::sleep, time::Duration};
+use std::{collections::HashMap, time::Duration};
 
+use futures::{StreamExt, stream};
 use log::info;
 use sqlx::PgPool;
 use tokio::net::TcpListener;
@@ -15,7 +20,9 @@ use util::{
 	configuration::SymfoniaConfiguration,
 	entities::Config,
 	errors::Error,
-	gateway::{ConnectedUsers, ResumableClientsStore},
+	gateway::{ConnectedUsers, DisconnectInfo, ResumableClientsStore},
+	shutdown::Shutdown,
+	tls::{self, TlsAcceptor, TlsFiles},
 };
 
 // This Source Code Form is subject to the terms of the Mozilla Public
@@ -59,93 +66,158 @@ Handling a connection involves the following steps:
 Handling disconnects and session resumes is for late
 */
 
+/// Runs the gateway until `shutdown` is triggered. All connected clients are
+/// then asked to reconnect, and their sessions are saved so that they can be
+/// resumed on another node.
+///
+/// The sessions this node left behind the last time it ran are deleted before
+/// any connections are accepted.
 pub async fn start_gateway(
 	db: PgPool,
 	connected_users: ConnectedUsers,
 	config: Config,
+	shutdown: Shutdown,
+) -> Result<(), Error> {
+	let port = SymfoniaConfiguration::get().gateway.cfg.port;
+	let host = &SymfoniaConfiguration::get().gateway.cfg.host;
+	let listener = TcpListener::bind((host.as_str(), port)).await?;
+	session::end_stale_sessions(
+		&db,
+		&connected_users,
+		SymfoniaConfiguration::get().general.node_id,
+	)
+	.await;
+	serve_gateway(listener, db, connected_users, config, shutdown).await
+}
+
+/// Like [start_gateway], but serves the connections of `listener` instead of
+/// binding the configured address.
+pub async fn serve_gateway(
+	listener: TcpListener,
+	db: PgPool,
+	connected_users: ConnectedUsers,
+	config: Config,
+	shutdown: Shutdown,
 ) -> Result<(), Error> {
 	// TODO(bitfl0wer): Add log messages throughout the method for debugging the
 	// gateway
 	info!(target: "symfonia::gateway", "Starting gateway server");
+	let local_addr = listener.local_addr()?;
+
+	// Certificates are replaced whenever they are reloaded. Without TLS, no
+	// certificates are ever received.
+	let mut tls_configs = match SymfoniaConfiguration::get().gateway.cfg.tls_files()? {
+		Some(files) => {
+			tls::install_crypto_provider();
+			files.reloads(TlsFiles::server_config)?.boxed()
+		}
+		None => stream::pending().boxed(),
+	};
+	let mut tls_acceptor = match SymfoniaConfiguration::get().gateway.cfg.tls {
+		true => tls_configs.next().await.map(TlsAcceptor::from),
+		false => None,
+	};
 
-	let port = SymfoniaConfiguration::get().gateway.cfg.port;
-	let host = &SymfoniaConfiguration::get().gateway.cfg.host;
-	// .trim() needs to be called because \n is appended to the .to_string(),
-	// messing up the binding
-	let try_socket = TcpListener::bind((host.as_str(), port)).await;
-	let listener = try_socket.expect("Failed to bind to address");
-
-	info!(target: "symfonia::gateway", "Gateway server listening on {host}:{port}");
+	info!(target: "symfonia::gateway", "Gateway server listening on {local_addr}");
 
 	let resumeable_clients: ResumableClientsStore = HashMap::new();
 	let connected_users_clone = connected_users.clone();
-	tokio::task::spawn(async { purge_expired_disconnects(connected_users_clone).await });
-	while let Ok((stream, _)) = listener.accept().await {
+	let db_clone = db.clone();
+	tokio::task::spawn(async { purge_expired_disconnects(connected_users_clone, db_clone).await });
+	// Events published by the HTTP API, possibly on another node.
+	tokio::task::spawn(connected_users.clone().deliver_bus_messages(db.clone()));
+	loop {
+		let stream = tokio::select! {
+			accepted = listener.accept() => match accepted {
+				Ok((stream, _)) => stream,
+				Err(_) => break,
+			},
+			Some(tls_config) = tls_configs.next() => {
+				tls_acceptor = Some(TlsAcceptor::from(tls_config));
+				continue;
+			}
+			_ = shutdown.triggered() => break,
+		};
 		log::trace!(target: "symfonia::gateway", "New connection received");
-		let connection_result =
-			match tokio::task::spawn(establish_connection::establish_connection(
-				stream,
-				db.clone(),
-				config.clone(),
-				connected_users.clone(),
-			))
-			.await
-			{
-				Ok(result) => result,
-				Err(e) => {
-					log::debug!(target: "symfonia::gateway::establish_connection", "User gateway task died: {e}");
-					continue;
-				}
-			};
-		match connection_result {
-			Ok(_) => (),
-			Err(e) => {
+		// Connections are established in their own task, so that a client which
+		// is slow to complete its handshake does not hold up the others.
+		let connection = establish_connection::establish_connection(
+			stream,
+			tls_acceptor.clone(),
+			db.clone(),
+			config.clone(),
+			connected_users.clone(),
+		);
+		tokio::task::spawn(async move {
+			if let Err(e) = connection.await {
 				log::debug!(target: "symfonia::gateway::establish_connection", "User gateway connection could not be established: {e}");
-				continue;
 			}
-		}
+		});
 	}
+	info!(target: "symfonia::gateway", "Gateway server stopped accepting connections");
+	let reconnected = connected_users.reconnect_all_clients().await;
+	let saved = connected_users.save_resumable_sessions(&db).await;
+	info!(target: "symfonia::gateway", "Asked {reconnected} clients to reconnect, saved {saved} resumable sessions");
 	Ok(())
 }
 
+/// Run [start_gateway] in the background, logging the error it stops with, if
+/// any.
+pub fn spawn_gateway(
+	db: PgPool,
+	connected_users: ConnectedUsers,
+	config: Config,
+	shutdown: Shutdown,
+) -> tokio::task::JoinHandle<()> {
+	tokio::spawn(async move {
+		if let Err(e) = start_gateway(db, connected_users, config, shutdown).await {
+			log::error!(target: "symfonia::gateway", "Gateway server stopped: {e}");
+		}
+	})
+}
+
 /// A disconnected, resumable session can only be resumed within
 /// `RESUME_RECONNECT_WINDOW_SECONDS` seconds after a disconnect occurs.
-/// Sessions that can be resumed are stored in a `Map`. The purpose of this
-/// method is to periodically throw out expired sessions from that map.
-async fn purge_expired_disconnects(connected_users: ConnectedUsers) {
+/// Sessions that can be resumed are stored in a `Map`, or in the database if
+/// they were saved by a gateway shutting down. The purpose of this method is
+/// to periodically throw out expired sessions from both.
+async fn purge_expired_disconnects(connected_users: ConnectedUsers, db: PgPool) {
 	let mut minutely_log_timer = 0;
 	let mut removed_elements_last_minute: u128 = 0;
+	let mut interval = tokio::time::interval(Duration::from_secs(5));
 	loop {
-		sleep(Duration::from_secs(5));
+		interval.tick().await;
 		// log::trace!(target: "symfonia::gateway::purge_expired_disconnects", "Removing
 		// stale disconnected sessions from list of resumeable sessions");
-		let current_unix_timestamp = std::time::SystemTime::now()
-			.duration_since(std::time::SystemTime::UNIX_EPOCH)
-			.expect("Check the clock/time settings on the host machine")
-			.as_secs();
 		let mut to_remove = Vec::new();
 		let mut _inner = connected_users.inner();
 		let read_lock = _inner.read();
 		for (disconnected_session_id, disconnected_session) in
 			read_lock.resumeable_clients_store.iter()
 		{
-			// TODO(bitfl0wer): What are we calculating here? At least, this should be
-			// commented
-			if current_unix_timestamp - disconnected_session.disconnected_at_sequence
-				> RESUME_RECONNECT_WINDOW_SECONDS as u64
-			{
+			if disconnected_session.has_expired(RESUME_RECONNECT_WINDOW_SECONDS as u64) {
 				to_remove.push(disconnected_session_id.clone());
 			}
 		}
 		drop(read_lock);
-		let len = to_remove.len();
-		removed_elements_last_minute =
-			removed_elements_last_minute.checked_add(len as u128).unwrap_or(u128::MAX);
 		let mut write_lock = _inner.write();
-		for session_id in to_remove.iter() {
-			write_lock.resumeable_clients_store.remove(session_id);
-		}
+		let mut expired = to_remove
+			.iter()
+			.filter_map(|session_id| write_lock.resumeable_clients_store.remove(session_id))
+			.collect::<Vec<_>>();
 		drop(write_lock);
+		match DisconnectInfo::take_expired_saved(&db, RESUME_RECONNECT_WINDOW_SECONDS as u64).await
+		{
+			Ok(saved) => expired.extend(saved),
+			Err(e) => {
+				log::error!(target: "symfonia::gateway::purge_expired_disconnects", "Failed to purge saved sessions: {e}");
+			}
+		}
+		removed_elements_last_minute =
+			removed_elements_last_minute.checked_add(expired.len() as u128).unwrap_or(u128::MAX);
+		for disconnect_info in expired.iter() {
+			session::end_session(&db, &connected_users, disconnect_info).await;
+		}
 		minutely_log_timer += 1;
 		if minutely_log_timer == 12 {
 			log::debug!(target: "symfonia::gateway::purge_expired_disconnects", "Removed {} stale sessions in the last 60 seconds", removed_elements_last_minute);
@@ -154,90 +226,3 @@ async fn purge_expired_disconnects(connected_users: ConnectedUsers) {
 		}
 	}
 }
-
-/// Tells every user-/client specific tokio task spawned by the symfonia binary
-/// to yield so that the server may shut down in an orderly fashion.
-///
-/// ## #\[allow(clippy::await_holding_lock)]
-///
-/// We hold the lock of `inner.write()` across an await point
-/// `user_mutex.lock().await`. The lock is held across the await point right
-/// before shutting down the application. It should be okay to do this. The
-/// inevitable shutdown of the application should guarantee no contention.
-///
-/// TODO: This is currently unused. We cannot use this, as the future created by
-/// this function is not `Send`. This is because of the whole "holding mutex
-/// across await" thing. We need to find a better solution for this.
-#[allow(clippy::await_holding_lock)]
-pub async fn tokio_task_killer(connected_users: ConnectedUsers) {
-	exit_signal_detected().await;
-	log::debug!("Exit signal detected!");
-	let inner = connected_users.inner();
-	let users = &mut inner.write().users;
-	for (_, user_mutex) in users.iter() {
-		let mut user = user_mutex.lock().await;
-		user.kill().await;
-	}
-}
-
-/// Detects when an exit signal is sent by the operating system. The future will
-/// complete when an exit signal is detected.
-async fn exit_signal_detected() {
-	#[cfg(all(unix, windows))]
-	{
-		panic!("Unsupported platform; How did you get here?");
-	}
-
-	#[cfg(unix)]
-	{
-		// All these signals should shut down an application on UNIX-like systems
-		use tokio::signal::unix::{SignalKind, signal};
-		let mut sig_alarm = signal(SignalKind::alarm()).unwrap();
-		let mut sig_hangup = signal(SignalKind::hangup()).unwrap();
-		let mut sig_interrupt = signal(SignalKind::interrupt()).unwrap();
-		let mut sig_pipe = signal(SignalKind::interrupt()).unwrap();
-		let mut sig_quit = signal(SignalKind::quit()).unwrap();
-		let mut sig_terminate = signal(SignalKind::terminate()).unwrap();
-		let mut sig_user_defined1 = signal(SignalKind::user_defined1()).unwrap();
-		let mut sig_user_defined2 = signal(SignalKind::user_defined2()).unwrap();
-		let ctrl_c = tokio::signal::ctrl_c();
-
-		tokio::select! {
-			// If we receive any of these signals, yield
-			_ = sig_alarm.recv() => (),
-			_ = sig_hangup.recv() => (),
-			_ = sig_interrupt.recv() => (),
-			_ = sig_pipe.recv() => (),
-			_ = sig_quit.recv() => (),
-			_ = sig_terminate.recv() => (),
-			_ = sig_user_defined1.recv() => (),
-			_ = sig_user_defined2.recv() => (),
-			event = ctrl_c => event.expect("Failed to listen to CTRL-c event"),
-		}
-	}
-
-	#[cfg(windows)]
-	{
-		// All these signals should shut down an application on Windows
-		use tokio::signal::windows::{ctrl_break, ctrl_close, ctrl_logoff, ctrl_shutdown};
-		let mut sig_break = ctrl_break().unwrap();
-		let ctrl_c = tokio::signal::ctrl_c();
-		let mut sig_close = ctrl_close().unwrap();
-		let mut sig_logoff = ctrl_logoff().unwrap();
-		let mut sig_shutdown = ctrl_shutdown().unwrap();
-
-		tokio::select! {
-			// If we receive any of these signals, yield
-			_ = sig_break.recv() => (),
-			event = ctrl_c => event.expect("Failed to listen to CTRL-c event"),
-			_ = sig_close.recv() => (),
-			_ = sig_logoff.recv() => (),
-			_ = sig_shutdown.recv() => (),
-		}
-	}
-
-	#[cfg(not(any(unix, windows)))]
-	{
-		panic!("Unsupported platform");
-	}
-}
@@ -2,6 +2,36 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-fn main() {
-	println!("Hello, world!");
+//! Runs the gateway on its own. Events published to the event bus of the
+//! configuration, e.g. by API processes, are delivered to the users connected
+//! to this process.
+
+use symfonia_gateway::spawn_gateway;
+use util::{
+	entities::Config,
+	shutdown::{self, Shutdown},
+	startup::{self, CliArgs, Component},
+};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
+	CliArgs::parse_or_default().init_configuration();
+	let _handle = startup::init_logging()?;
+
+	if !Component::Gateway.enabled() {
+		return Err("The gateway is disabled in the configuration".into());
+	}
+	log::info!(target: "symfonia::gateway", "Starting up the Symfonia gateway");
+	startup::warn_if_events_stay_in_process(Component::Gateway);
+
+	let db = startup::connect_database(Component::Gateway).await?;
+	startup::prepare_database(&db).await?;
+
+	let config = Config::init(db.pool()).await.unwrap_or_default();
+	let connected_users = startup::connected_users(db.pool()).await?;
+
+	let shutdown = Shutdown::default();
+	let server = spawn_gateway(db.pool().to_owned(), connected_users, config, shutdown.clone());
+	shutdown::run_until_signal(&shutdown, vec![server]).await;
+	Ok(())
 }
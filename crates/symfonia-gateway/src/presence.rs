@@ -0,0 +1,101 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chorus::types::{Snowflake, UpdatePresence};
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use util::{
+	entities::{Session, User, UserSettings},
+	errors::{Error, UserError},
+	gateway::{
+		ConnectedUsers,
+		presence::{ClientPresence, ClientType, Status, UserPresence},
+	},
+};
+
+/// Registers the presence of a client which identified or resumed the session
+/// `session`, publishes it to the other processes, and dispatches the presence
+/// of the user if it changed.
+pub(super) async fn connect_client(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	session: &Session,
+) -> Result<(), Error> {
+	let user =
+		User::get_by_id(db, session.user_id).await?.ok_or(Error::User(UserError::InvalidUser))?;
+	let settings_status = UserSettings::get_status_by_user_id(db, session.user_id)
+		.await?
+		.as_deref()
+		.map(Status::parse);
+	let client_type = ClientType::from_properties(&serde_json::from_str(&session.client_info)?);
+	let activities = match session.activities.as_deref() {
+		Some(activities) => serde_json::from_str(activities)?,
+		None => json!([]),
+	};
+	let client = ClientPresence::from_update(
+		&json!({ "status": session.status, "activities": activities }),
+		client_type,
+	);
+	let session_token = session.session_id.to_string();
+	let changed = connected_users.presences.write().set_client(
+		UserPresence::new(user.to_public_user(), settings_status),
+		&session_token,
+		client,
+	);
+	connected_users.publish_client_presence(session.user_id, &session_token).await;
+	if changed {
+		connected_users.dispatch_presence_update(db, session.user_id).await?;
+	}
+	Ok(())
+}
+
+/// Handles an op 3 Presence Update of a connected client. The new presence is
+/// stored with the session, so that it survives resuming.
+pub(super) async fn update_client(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	user_id: Snowflake,
+	session_id: &str,
+	update: &UpdatePresence,
+) -> Result<(), Error> {
+	let update = serde_json::to_value(update)?;
+	let changed = connected_users.presences.write().update_client(user_id, session_id, &update);
+	connected_users.publish_client_presence(user_id, session_id).await;
+	if let Ok(session_id) = session_id.parse::<u64>() {
+		let client = ClientPresence::from_update(&update, ClientType::Web);
+		let activities = Value::Array(client.activities).to_string();
+		Session::update_presence(
+			db,
+			Snowflake(session_id),
+			client.status.as_str(),
+			Some(activities),
+		)
+		.await?;
+	}
+	if changed {
+		connected_users.dispatch_presence_update(db, user_id).await?;
+	}
+	Ok(())
+}
+
+/// Removes the presence of a disconnected client. If this was the last client
+/// of the user, they are dispatched as offline. The disconnect is published to
+/// the other processes. Errors are only logged, as there is no client left to
+/// report them to.
+pub(super) async fn disconnect_client(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	user_id: Snowflake,
+	session_id: &str,
+) {
+	let changed = connected_users.presences.write().remove_client(user_id, session_id);
+	connected_users.publish_client_disconnected(user_id, session_id).await;
+	if !changed {
+		connected_users.presences.write().prune(user_id);
+		return;
+	}
+	if let Err(e) = connected_users.dispatch_presence_update(db, user_id).await {
+		log::error!(target: "symfonia::gateway::presence::disconnect_client", "Failed to dispatch PRESENCE_UPDATE: {e}");
+	}
+}
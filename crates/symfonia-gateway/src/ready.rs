@@ -4,17 +4,24 @@
 
 use std::collections::HashMap;
 
-use chorus::types::{
-	ClientInfo, GatewayReady, ReadState, Session, Snowflake, UserNote, VersionedReadStateOrEntries,
-};
-use serde_json::json;
+use chorus::types::{GatewayReady, ReadState, Snowflake, UserNote, VersionedReadStateOrEntries};
+use serde_json::Value;
 use sqlx::PgPool;
 use util::{
-	entities::{Channel, Guild, Note, Relationship, User},
+	entities::{Channel, Guild, Note, Relationship, Session, User},
 	errors::Error,
+	gateway::ConnectedUsers,
 };
 
-pub async fn create_ready(user_id: Snowflake, db: &PgPool) -> Result<GatewayReady, Error> {
+/// Creates the `READY` payload of a session. Besides the fields of
+/// [GatewayReady], the payload includes the presences of online friends and,
+/// for each guild, of its online members.
+pub async fn create_ready(
+	user_id: Snowflake,
+	session_id: &str,
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+) -> Result<Value, Error> {
 	let user = match User::get_by_id(db, user_id).await? {
 		Some(uwuser) => uwuser,
 		None => {
@@ -32,6 +39,13 @@ pub async fn create_ready(user_id: Snowflake, db: &PgPool) -> Result<GatewayRead
 		});
 	}
 
+	let mut guild_presences = Vec::with_capacity(guilds.len());
+	for guild in guilds.iter() {
+		guild_presences.push(connected_users.guild_presences(db, guild.id).await?);
+	}
+	let friend_ids = Relationship::get_friend_ids(user_id, db).await?;
+	let friend_presences = connected_users.presences.read().presences_of(friend_ids, None)?;
+
 	let relationships = Relationship::get_all_by_id(user_id, db)
 		.await?
 		.into_iter()
@@ -52,29 +66,23 @@ pub async fn create_ready(user_id: Snowflake, db: &PgPool) -> Result<GatewayRead
 		notes.insert(note.target_id, note.content);
 	}
 
-	// TODO: The session ID needs to be stored in the database and also removed on
-	// session disconnect. This is a temporary solution.
-	let session_id = Snowflake::generate().to_string();
-
-	// TODO: This is also just temporary.
-	let session = Session {
-		activities: None,
-		client_info: ClientInfo::default(),
-		session_id: session_id.clone(),
-		status: "Testing symfonia".to_string(),
-	};
+	let sessions = Session::get_by_user_id(db, user_id)
+		.await?
+		.iter()
+		.map(|session| session.to_inner())
+		.collect::<Vec<_>>();
 
 	// TODO: There are a lot of missing fields here. Ideally, all of the fields
 	// should be populated with the correct data.
 	let ready = GatewayReady {
 		user: user.clone().to_inner(),
 		guilds,
-		session_id,
+		session_id: session_id.to_string(),
 		user_settings: Some(user.settings.into_inner()),
 		relationships,
 		private_channels,
 		notes,
-		sessions: Some([session].into()),
+		sessions: Some(sessions),
 		// Note: Discord.com now just sends Entries, while Spacebar sends VersionedReadState
 		read_state: VersionedReadStateOrEntries::Versioned(ReadState {
 			entries: Default::default(),
@@ -83,6 +91,13 @@ pub async fn create_ready(user_id: Snowflake, db: &PgPool) -> Result<GatewayRead
 		}),
 		..Default::default()
 	};
-	log::debug!(target: "symfonia::gateway::ready::create_ready", "Created READY json payload: {:#?}", json!(ready));
+	let mut ready = serde_json::to_value(ready)?;
+	if let Some(guilds) = ready.get_mut("guilds").and_then(Value::as_array_mut) {
+		for (guild, presences) in guilds.iter_mut().zip(guild_presences) {
+			guild["presences"] = serde_json::to_value(presences)?;
+		}
+	}
+	ready["presences"] = serde_json::to_value(friend_presences)?;
+	log::debug!(target: "symfonia::gateway::ready::create_ready", "Created READY json payload: {:#?}", ready);
 	Ok(ready)
 }
@@ -0,0 +1,250 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{collections::HashSet, sync::Arc};
+
+use chorus::types::{
+	GatewayRequestGuildMembers, GuildMember as ChorusGuildMember, GuildMembersChunk,
+	PresenceUpdate, Snowflake,
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::Message;
+use util::{
+	entities::GuildMember,
+	errors::{Error, GuildError},
+	gateway::{
+		ConnectedUsers, GatewayPayload, ReplayBuffer, WebSocketConnection, intents::GatewayIntents,
+	},
+};
+
+use crate::gateway_task::sequence_dispatch;
+
+/// Maximum number of members sent in a single `GUILD_MEMBERS_CHUNK`.
+const MAX_CHUNK_SIZE: usize = 1000;
+/// Maximum number of members returned for a query, and of `user_ids` per
+/// request.
+const MAX_QUERY_LIMIT: u64 = 100;
+
+/// An op 8 request, as sent by clients. Both `guild_id` and `user_ids` may be
+/// either a single snowflake or a list of snowflakes.
+#[derive(Debug, Deserialize)]
+struct MemberRequest {
+	guild_id: OneOrMany<Snowflake>,
+	query: Option<String>,
+	#[serde(default)]
+	limit: u64,
+	#[serde(default)]
+	presences: bool,
+	user_ids: Option<OneOrMany<Snowflake>>,
+	nonce: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+	One(T),
+	Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+	fn into_vec(self) -> Vec<T> {
+		match self {
+			Self::One(value) => vec![value],
+			Self::Many(values) => values,
+		}
+	}
+}
+
+/// The state of a session needed to answer op 8 requests.
+pub(super) struct RequestContext {
+	pub(super) connection: WebSocketConnection,
+	pub(super) db: PgPool,
+	pub(super) connected_users: ConnectedUsers,
+	pub(super) user_id: Snowflake,
+	pub(super) intents: GatewayIntents,
+	pub(super) sequence_number: Arc<Mutex<u64>>,
+	pub(super) replay_buffer: Arc<Mutex<ReplayBuffer>>,
+}
+
+/// Answers an op 8 Request Guild Members with `GUILD_MEMBERS_CHUNK`
+/// dispatches. Requests for guilds the user is not a member of are ignored.
+pub(super) async fn request_guild_members(
+	context: RequestContext,
+	request: GatewayPayload<GatewayRequestGuildMembers>,
+) -> Result<(), Error> {
+	let Some(request) = request.event_data else {
+		return Ok(());
+	};
+	let request: MemberRequest = serde_json::from_value(serde_json::to_value(request)?)?;
+	// Presences can only be requested with the GUILD_PRESENCES intent.
+	let presences = request.presences && context.intents.contains(GatewayIntents::GUILD_PRESENCES);
+	let user_ids = request.user_ids.map(OneOrMany::into_vec);
+	for guild_id in request.guild_id.into_vec() {
+		if !is_member(&context.db, context.user_id, guild_id).await? {
+			log::debug!(target: "symfonia::gateway::request_guild_members", "User {} requested members of guild {guild_id} without being a member", context.user_id);
+			continue;
+		}
+		let (members, not_found) = match &user_ids {
+			Some(user_ids) => get_by_user_ids(&context.db, guild_id, user_ids).await?,
+			None => {
+				let query = request.query.as_deref().unwrap_or_default();
+				if query.is_empty() && !context.intents.contains(GatewayIntents::GUILD_MEMBERS) {
+					log::debug!(target: "symfonia::gateway::request_guild_members", "Listing the members of a guild requires the GUILD_MEMBERS intent");
+					continue;
+				}
+				(get_by_query(&context.db, guild_id, query, request.limit).await?, Vec::new())
+			}
+		};
+		send_chunks(&context, guild_id, members, not_found, presences, request.nonce.clone())
+			.await?;
+	}
+	Ok(())
+}
+
+pub(super) async fn is_member(
+	db: &PgPool,
+	user_id: Snowflake,
+	guild_id: Snowflake,
+) -> Result<bool, Error> {
+	match GuildMember::get_by_id(db, user_id, guild_id).await {
+		Ok(member) => Ok(member.is_some()),
+		Err(Error::Guild(GuildError::MemberNotFound)) => Ok(false),
+		Err(e) => Err(e),
+	}
+}
+
+/// Looks up the members with the ids `user_ids`. Returns the members found,
+/// and the ids of the users which are not members of the guild.
+async fn get_by_user_ids(
+	db: &PgPool,
+	guild_id: Snowflake,
+	user_ids: &[Snowflake],
+) -> Result<(Vec<GuildMember>, Vec<Snowflake>), Error> {
+	let user_ids = &user_ids[..user_ids.len().min(MAX_QUERY_LIMIT as usize)];
+	let members = GuildMember::get_by_user_ids(db, guild_id, user_ids).await?;
+	let not_found = not_found(user_ids, &members);
+	Ok((members, not_found))
+}
+
+/// The ids among `user_ids` which are not the id of one of `members`.
+fn not_found(user_ids: &[Snowflake], members: &[GuildMember]) -> Vec<Snowflake> {
+	let found = members.iter().map(|member| member.id).collect::<HashSet<_>>();
+	user_ids.iter().filter(|user_id| !found.contains(user_id)).copied().collect()
+}
+
+/// Looks up the members whose name starts with `query`. An empty `query`
+/// matches all members, in which case a `limit` of 0 returns every member of
+/// the guild. Any other `limit` is capped at [MAX_QUERY_LIMIT].
+async fn get_by_query(
+	db: &PgPool,
+	guild_id: Snowflake,
+	query: &str,
+	limit: u64,
+) -> Result<Vec<GuildMember>, Error> {
+	if !query.is_empty() {
+		let limit = limit.clamp(1, MAX_QUERY_LIMIT) as u16;
+		return GuildMember::search(db, guild_id, query, limit).await;
+	}
+	let limit = if limit == 0 { usize::MAX } else { limit.min(MAX_QUERY_LIMIT) as usize };
+	let mut members: Vec<GuildMember> = Vec::new();
+	loop {
+		let page_size = (limit - members.len()).min(MAX_CHUNK_SIZE) as u16;
+		let after = members.last().map(|member| member.id);
+		let mut page =
+			GuildMember::get_by_guild_id_with_users(db, guild_id, page_size, after).await?;
+		let is_last_page = page.len() < page_size as usize;
+		members.append(&mut page);
+		if is_last_page || members.len() >= limit {
+			return Ok(members);
+		}
+	}
+}
+
+/// Sends `members` to the client, split into chunks of at most
+/// [MAX_CHUNK_SIZE] members. At least one chunk is sent, even if no members
+/// were found.
+async fn send_chunks(
+	context: &RequestContext,
+	guild_id: Snowflake,
+	members: Vec<GuildMember>,
+	not_found: Vec<Snowflake>,
+	presences: bool,
+	nonce: Option<String>,
+) -> Result<(), Error> {
+	let members: Vec<ChorusGuildMember> =
+		members.into_iter().map(GuildMember::into_inner).collect();
+	let chunks: Vec<&[ChorusGuildMember]> =
+		if members.is_empty() { vec![&[]] } else { members.chunks(MAX_CHUNK_SIZE).collect() };
+	let chunk_count = chunks.len() as u16;
+	for (chunk_index, chunk) in chunks.into_iter().enumerate() {
+		let presences = match presences {
+			true => Some(get_presences(&context.connected_users, guild_id, chunk)?),
+			false => None,
+		};
+		let payload = GatewayPayload {
+			op_code: 0,
+			event_data: Some(GuildMembersChunk {
+				guild_id,
+				members: chunk.to_vec(),
+				chunk_index: chunk_index as u16,
+				chunk_count,
+				// The ids not found are only sent once, with the first chunk.
+				not_found: (chunk_index == 0 && !not_found.is_empty()).then(|| not_found.clone()),
+				presences,
+				nonce: nonce.clone(),
+			}),
+			sequence_number: None,
+			event_name: Some("GUILD_MEMBERS_CHUNK".to_string()),
+		};
+		let payload =
+			sequence_dispatch(payload, &context.sequence_number, &context.replay_buffer).await;
+		context.connection.sender.send(Message::Text(payload.into()))?;
+	}
+	Ok(())
+}
+
+/// The presences of the online members among `members`, as other users see
+/// them. Offline and invisible members are omitted.
+fn get_presences(
+	connected_users: &ConnectedUsers,
+	guild_id: Snowflake,
+	members: &[ChorusGuildMember],
+) -> Result<Vec<PresenceUpdate>, Error> {
+	let user_ids = members.iter().filter_map(|member| member.user.as_ref()).map(|user| user.id);
+	connected_users.presences.read().presences_of(user_ids, Some(guild_id))
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	#[test]
+	fn guild_ids_and_user_ids_may_be_single_snowflakes() {
+		let request: MemberRequest = serde_json::from_value(json!({
+			"guild_id": "1",
+			"user_ids": "2",
+			"limit": 0,
+		}))
+		.unwrap();
+		assert_eq!(request.guild_id.into_vec(), [Snowflake(1)]);
+		assert_eq!(request.user_ids.unwrap().into_vec(), [Snowflake(2)]);
+
+		let request: MemberRequest = serde_json::from_value(json!({
+			"guild_id": ["1", "3"],
+			"query": "ab",
+			"limit": 10,
+			"presences": true,
+		}))
+		.unwrap();
+		assert_eq!(request.guild_id.into_vec(), [Snowflake(1), Snowflake(3)]);
+		assert_eq!(request.query.as_deref(), Some("ab"));
+		assert_eq!(request.limit, 10);
+		assert!(request.presences);
+		assert!(request.user_ids.is_none());
+	}
+}
@@ -0,0 +1,62 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chorus::types::Snowflake;
+use sqlx::PgPool;
+use util::{
+	entities::Session,
+	gateway::{ConnectedUsers, DisconnectInfo},
+};
+
+/// Ends a session that can no longer be resumed: The session is deleted from
+/// the database and the remaining clients of the user receive a
+/// `SESSIONS_REPLACE`. Errors are only logged, as there is no client left to
+/// report them to.
+pub(super) async fn end_session(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	disconnect_info: &DisconnectInfo,
+) {
+	let Ok(session_id) = disconnect_info.session_token.parse::<u64>() else {
+		log::warn!(target: "symfonia::gateway::session::end_session", "Session id {} is not a snowflake", disconnect_info.session_token);
+		return;
+	};
+	match Session::delete_by_session_id(db, Snowflake(session_id)).await {
+		Ok(true) => (),
+		Ok(false) => return,
+		Err(e) => {
+			log::error!(target: "symfonia::gateway::session::end_session", "Failed to delete session {session_id}: {e}");
+			return;
+		}
+	}
+	if let Err(e) = connected_users.dispatch_sessions_replace(db, disconnect_info.user_id).await {
+		log::error!(target: "symfonia::gateway::session::end_session", "Failed to dispatch SESSIONS_REPLACE: {e}");
+	}
+}
+
+/// Deletes the sessions this gateway node left behind when it stopped without
+/// saving them, e.g. because it crashed. Their users' connected clients on
+/// other nodes receive a `SESSIONS_REPLACE`. Errors are only logged, as they
+/// should not keep the gateway from starting.
+pub(super) async fn end_stale_sessions(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	node_id: u64,
+) {
+	let user_ids = match Session::delete_stale(db, node_id).await {
+		Ok(user_ids) => user_ids,
+		Err(e) => {
+			log::error!(target: "symfonia::gateway::session::end_stale_sessions", "Failed to delete stale sessions: {e}");
+			return;
+		}
+	};
+	if !user_ids.is_empty() {
+		log::info!(target: "symfonia::gateway::session::end_stale_sessions", "Deleted the stale sessions of {} users", user_ids.len());
+	}
+	for user_id in user_ids {
+		if let Err(e) = connected_users.dispatch_sessions_replace(db, user_id).await {
+			log::error!(target: "symfonia::gateway::session::end_stale_sessions", "Failed to dispatch SESSIONS_REPLACE: {e}");
+		}
+	}
+}
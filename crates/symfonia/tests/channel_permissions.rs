@@ -0,0 +1,247 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks that channel routes respond with `404` to users who cannot see a
+//! channel and with `403` to users lacking a permission, that DMs and group
+//! DMs are closed or left instead of deleted, and that changing overwrites
+//! creates or deletes channels for the users who gain or lose access.
+//!
+//! Run with `cargo test -- --ignored`, against the database in `DATABASE_URL`.
+
+mod common;
+
+use chorus::types::Snowflake;
+use common::{TestServers, expect_dispatch};
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+use util::entities::{Channel, Guild, Recipient};
+
+/// GUILDS
+const INTENTS: u64 = 1;
+
+/// Register a user with a name starting with `prefix`, returning their token
+/// and id.
+async fn register(servers: &TestServers, prefix: &str) -> (String, Snowflake) {
+	let token = servers.register(prefix).await;
+	let user: Value = servers
+		.http
+		.get(format!("{}/users/@me", servers.api_url))
+		.header("Authorization", &token)
+		.send()
+		.await
+		.expect("Failed to send a request")
+		.json()
+		.await
+		.expect("The current user is not json");
+	let id = user["id"].as_str().expect("User has no id").parse().unwrap();
+	(token, Snowflake(id))
+}
+
+/// Send a `method` request to `path` of the API as the user with `token`,
+/// returning the status.
+async fn status(
+	servers: &TestServers,
+	method: reqwest::Method,
+	path: &str,
+	token: &str,
+) -> StatusCode {
+	servers
+		.http
+		.request(method, format!("{}{path}", servers.api_url))
+		.header("Authorization", token)
+		.send()
+		.await
+		.expect("Failed to send a request")
+		.status()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn guild_channels_require_membership_and_permissions() {
+	let servers = TestServers::start().await;
+	let (owner, _) = register(&servers, "chperm_owner").await;
+	let (member, member_id) = register(&servers, "chperm_member").await;
+
+	let guild: Value = servers
+		.http
+		.post(format!("{}/guilds", servers.api_url))
+		.header("Authorization", &owner)
+		.json(&json!({"name": "Channel Permissions"}))
+		.send()
+		.await
+		.unwrap()
+		.json()
+		.await
+		.unwrap();
+	let guild_id = guild["id"].as_str().expect("Guild has no id").to_string();
+	let channel: Value = servers
+		.http
+		.post(format!("{}/guilds/{guild_id}/channels", servers.api_url))
+		.header("Authorization", &owner)
+		.json(&json!({"name": "guarded", "type": 0}))
+		.send()
+		.await
+		.unwrap()
+		.json()
+		.await
+		.unwrap();
+	let channel_path = format!("/channels/{}", channel["id"].as_str().unwrap());
+
+	// Users outside of the guild cannot see the channel at all
+	assert_eq!(
+		status(&servers, reqwest::Method::GET, &channel_path, &member).await,
+		StatusCode::NOT_FOUND
+	);
+	assert_eq!(
+		status(&servers, reqwest::Method::DELETE, &channel_path, &member).await,
+		StatusCode::NOT_FOUND
+	);
+
+	let guild =
+		Guild::get_by_id(&servers.db, Snowflake(guild_id.parse().unwrap())).await.unwrap().unwrap();
+	guild
+		.add_member(&servers.db, &servers.connected_users.role_user_map, member_id)
+		.await
+		.expect("Failed to add the member");
+
+	// Members see the channel, but cannot delete it without MANAGE_CHANNELS
+	assert_eq!(
+		status(&servers, reqwest::Method::GET, &channel_path, &member).await,
+		StatusCode::OK
+	);
+	assert_eq!(
+		status(&servers, reqwest::Method::DELETE, &channel_path, &member).await,
+		StatusCode::FORBIDDEN
+	);
+	assert_eq!(
+		status(&servers, reqwest::Method::DELETE, &channel_path, &owner).await,
+		StatusCode::OK
+	);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn private_channels_are_closed_or_left() {
+	let servers = TestServers::start().await;
+	let (alice, alice_id) = register(&servers, "chperm_alice").await;
+	let (bob, bob_id) = register(&servers, "chperm_bob").await;
+	let (carol, carol_id) = register(&servers, "chperm_carol").await;
+
+	let dm = Channel::create_dm_channel(&servers.db, vec![bob_id], alice_id, None).await.unwrap();
+	let dm_path = format!("/channels/{}", dm.id);
+	assert_eq!(
+		status(&servers, reqwest::Method::DELETE, &dm_path, &carol).await,
+		StatusCode::NOT_FOUND
+	);
+	// Closing a DM does not require MANAGE_CHANNELS, and keeps it for the
+	// other recipient
+	assert_eq!(status(&servers, reqwest::Method::DELETE, &dm_path, &bob).await, StatusCode::OK);
+	let recipient =
+		Recipient::get_by_channel_and_user_id(&servers.db, dm.id, bob_id).await.unwrap().unwrap();
+	assert!(recipient.closed);
+	assert_eq!(status(&servers, reqwest::Method::GET, &dm_path, &alice).await, StatusCode::OK);
+
+	let group = Channel::create_dm_channel(&servers.db, vec![bob_id, carol_id], alice_id, None)
+		.await
+		.unwrap();
+	let group_path = format!("/channels/{}", group.id);
+	// Only the owner may remove other recipients
+	assert_eq!(
+		status(
+			&servers,
+			reqwest::Method::DELETE,
+			&format!("{group_path}/recipients/{carol_id}"),
+			&bob
+		)
+		.await,
+		StatusCode::FORBIDDEN
+	);
+	assert_eq!(status(&servers, reqwest::Method::DELETE, &group_path, &bob).await, StatusCode::OK);
+	assert_eq!(
+		status(&servers, reqwest::Method::GET, &group_path, &bob).await,
+		StatusCode::NOT_FOUND
+	);
+	assert_eq!(
+		status(
+			&servers,
+			reqwest::Method::DELETE,
+			&format!("{group_path}/recipients/{carol_id}"),
+			&alice
+		)
+		.await,
+		StatusCode::NO_CONTENT
+	);
+	assert_eq!(
+		status(&servers, reqwest::Method::GET, &group_path, &carol).await,
+		StatusCode::NOT_FOUND
+	);
+	assert_eq!(status(&servers, reqwest::Method::GET, &group_path, &alice).await, StatusCode::OK);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn overwrites_create_and_delete_channels_for_users_gaining_or_losing_access() {
+	let servers = TestServers::start().await;
+	let (owner, _) = register(&servers, "chperm_ow_owner").await;
+	let (member, member_id) = register(&servers, "chperm_ow_member").await;
+
+	let guild: Value = servers
+		.http
+		.post(format!("{}/guilds", servers.api_url))
+		.header("Authorization", &owner)
+		.json(&json!({"name": "Overwrites"}))
+		.send()
+		.await
+		.unwrap()
+		.json()
+		.await
+		.unwrap();
+	let guild_id = guild["id"].as_str().expect("Guild has no id").to_string();
+	let channel: Value = servers
+		.http
+		.post(format!("{}/guilds/{guild_id}/channels", servers.api_url))
+		.header("Authorization", &owner)
+		.json(&json!({"name": "hidden", "type": 0}))
+		.send()
+		.await
+		.unwrap()
+		.json()
+		.await
+		.unwrap();
+	let channel_id = channel["id"].as_str().expect("Channel has no id").to_string();
+	let guild =
+		Guild::get_by_id(&servers.db, Snowflake(guild_id.parse().unwrap())).await.unwrap().unwrap();
+	guild
+		.add_member(&servers.db, &servers.connected_users.role_user_map, member_id)
+		.await
+		.expect("Failed to add the member");
+	let mut owner_gateway = servers.connect(&owner, INTENTS).await;
+	let mut member_gateway = servers.connect(&member, INTENTS).await;
+
+	// Denying VIEW_CHANNEL to @everyone hides the channel from the member only
+	let overwrite_path =
+		format!("{}/channels/{channel_id}/permissions/{guild_id}", servers.api_url);
+	let response = servers
+		.http
+		.put(&overwrite_path)
+		.header("Authorization", &owner)
+		.json(&json!({"id": guild_id, "type": 0, "allow": "0", "deny": "1024"}))
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+	let deleted = expect_dispatch(&mut member_gateway, "CHANNEL_DELETE").await;
+	assert_eq!(deleted["id"], channel_id);
+	let updated = expect_dispatch(&mut owner_gateway, "CHANNEL_UPDATE").await;
+	assert_eq!(updated["id"], channel_id);
+
+	// Removing the overwrite shows it to the member again
+	let response =
+		servers.http.delete(&overwrite_path).header("Authorization", &owner).send().await.unwrap();
+	assert_eq!(response.status(), StatusCode::NO_CONTENT);
+	let created = expect_dispatch(&mut member_gateway, "CHANNEL_CREATE").await;
+	assert_eq!(created["id"], channel_id);
+	let updated = expect_dispatch(&mut owner_gateway, "CHANNEL_UPDATE").await;
+	assert_eq!(updated["id"], channel_id);
+}
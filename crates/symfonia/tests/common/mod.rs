@@ -0,0 +1,200 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Servers and clients shared by the integration tests.
+//!
+//! Each test starts its own API and gateway on ephemeral ports, against the
+//! database in `DATABASE_URL`, and registers users with names unique to the
+//! run, so that the tests can run concurrently and be run again.
+#![allow(dead_code)]
+
+use std::{path::PathBuf, sync::Once, time::Duration};
+
+use chorus::types::Snowflake;
+use futures::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use symfonia_api::api::serve_api;
+use symfonia_gateway::serve_gateway;
+use tokio::{
+	net::{TcpListener, TcpStream},
+	task::JoinHandle,
+	time::timeout,
+};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async, tungstenite::Message};
+use util::{
+	configuration::SymfoniaConfiguration, entities::Config, errors::Error, gateway::ConnectedUsers,
+	shutdown::Shutdown,
+};
+
+/// A password satisfying the default registration policy.
+pub const PASSWORD: &str = "Correct Horse Battery 42";
+
+pub type Gateway = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+static CONFIGURATION: Once = Once::new();
+
+/// An API and a gateway serving one test.
+pub struct TestServers {
+	pub db: PgPool,
+	pub http: reqwest::Client,
+	/// The base URL of the API, e.g. `http://127.0.0.1:41234/api`.
+	pub api_url: String,
+	pub gateway_url: String,
+	/// The users connected to the gateway, shared with the API.
+	pub connected_users: ConnectedUsers,
+	/// Stops both servers once triggered.
+	pub shutdown: Shutdown,
+	pub api: JoinHandle<()>,
+	pub gateway: JoinHandle<Result<(), Error>>,
+}
+
+impl TestServers {
+	pub async fn start() -> Self {
+		Self::start_with(Config::default()).await
+	}
+
+	/// Start the servers with the instance configuration `config`. The
+	/// registration rate limit is lifted, as all runs share the database.
+	pub async fn start_with(mut config: Config) -> Self {
+		CONFIGURATION.call_once(|| {
+			SymfoniaConfiguration::init(&PathBuf::from("../../symfonia.toml"));
+		});
+		config.limits.absolute_rate.register.enabled = false;
+
+		let db = PgPool::connect(&std::env::var("DATABASE_URL").expect("DATABASE_URL is not set"))
+			.await
+			.expect("Failed to connect to the database");
+		sqlx::migrate!("../util/migrations").run(&db).await.expect("Failed to run migrations");
+
+		let shutdown = Shutdown::default();
+		let connected_users = ConnectedUsers::default();
+		connected_users.init_role_user_map(&db).await.expect("Failed to init role user map");
+		tokio::spawn(connected_users.clone().replicate_state());
+
+		let api_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let api_url = format!("http://{}/api", api_listener.local_addr().unwrap());
+		let api = serve_api(
+			api_listener,
+			db.clone(),
+			connected_users.clone(),
+			config.clone(),
+			shutdown.clone(),
+		)
+		.await
+		.expect("Failed to start the API");
+
+		let gateway_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let gateway_url =
+			format!("ws://{}/?v=9&encoding=json", gateway_listener.local_addr().unwrap());
+		let gateway = tokio::spawn(serve_gateway(
+			gateway_listener,
+			db.clone(),
+			connected_users.clone(),
+			config,
+			shutdown.clone(),
+		));
+
+		Self {
+			db,
+			http: reqwest::Client::new(),
+			api_url,
+			gateway_url,
+			connected_users,
+			shutdown,
+			api,
+			gateway,
+		}
+	}
+
+	/// A username starting with `prefix` which no earlier run registered.
+	pub fn unique_username(prefix: &str) -> String {
+		format!("{prefix}_{:x}", u64::from(Snowflake::default()))
+	}
+
+	/// A valid registration of a user with a unique name starting with
+	/// `prefix`.
+	pub fn registration(prefix: &str) -> Value {
+		let username = Self::unique_username(prefix);
+		json!({
+			"username": username,
+			"password": PASSWORD,
+			"email": format!("{username}@symfonia.test"),
+			"consent": true,
+			"date_of_birth": "2000-01-01",
+		})
+	}
+
+	/// Register a user with a unique name starting with `prefix`, returning
+	/// its token.
+	pub async fn register(&self, prefix: &str) -> String {
+		let response: Value = self
+			.http
+			.post(format!("{}/auth/register", self.api_url))
+			.json(&Self::registration(prefix))
+			.send()
+			.await
+			.expect("Failed to register")
+			.json()
+			.await
+			.expect("Register did not respond with json");
+		response["token"].as_str().expect("Register did not respond with a token").to_string()
+	}
+
+	/// Connect to the gateway and identify with `token` and `intents`,
+	/// returning once `READY` was received.
+	pub async fn connect(&self, token: &str, intents: u64) -> Gateway {
+		let mut gateway = self.identify(token, intents).await;
+		expect_dispatch(&mut gateway, "READY").await;
+		gateway
+	}
+
+	/// Connect to the gateway and send an identify payload, without waiting for
+	/// the response.
+	pub async fn identify(&self, token: &str, intents: u64) -> Gateway {
+		let (mut gateway, _) = connect_async(&self.gateway_url).await.expect("Failed to connect");
+		let hello = next_payload(&mut gateway).await;
+		assert_eq!(hello["op"], 10);
+		gateway
+			.send(Message::Text(
+				json!({
+					"op": 2,
+					"d": {
+						"token": token,
+						"properties": {"os": "linux", "browser": "symfonia-tests", "device": ""},
+						"intents": intents,
+					},
+				})
+				.to_string()
+				.into(),
+			))
+			.await
+			.expect("Failed to identify");
+		gateway
+	}
+}
+
+/// The next text message of `gateway`, as json.
+pub async fn next_payload(gateway: &mut Gateway) -> Value {
+	loop {
+		let message = timeout(Duration::from_secs(5), gateway.next())
+			.await
+			.expect("Timed out waiting for a gateway message")
+			.expect("Gateway connection closed")
+			.expect("Failed to receive a gateway message");
+		if let Message::Text(text) = message {
+			return serde_json::from_str(&text).expect("Gateway sent invalid json");
+		}
+	}
+}
+
+/// Skip payloads until the dispatch `event_name` arrives, and return its data.
+pub async fn expect_dispatch(gateway: &mut Gateway, event_name: &str) -> Value {
+	loop {
+		let payload = next_payload(gateway).await;
+		if payload["op"] == 0 && payload["t"] == event_name {
+			return payload["d"].clone();
+		}
+	}
+}
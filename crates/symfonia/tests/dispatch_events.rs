@@ -0,0 +1,96 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks that mutations through the HTTP API are published as dispatch
+//! events to the clients connected to the gateway.
+//!
+//! Run with `cargo test -- --ignored`, against the database in `DATABASE_URL`.
+
+mod common;
+
+use common::{TestServers, expect_dispatch};
+use serde_json::{Value, json};
+
+/// GUILDS | GUILD_MESSAGES | GUILD_MESSAGE_TYPING
+const INTENTS: u64 = 1 | (1 << 9) | (1 << 11);
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn rest_mutations_are_dispatched_to_the_gateway() {
+	let servers = TestServers::start().await;
+	let http = &servers.http;
+	let api_url = &servers.api_url;
+	let token = servers.register("dispatch").await;
+	let mut gateway = servers.connect(&token, INTENTS).await;
+
+	let guild: Value = http
+		.post(format!("{api_url}/guilds"))
+		.header("Authorization", &token)
+		.json(&json!({"name": "Dispatch Events"}))
+		.send()
+		.await
+		.unwrap()
+		.json()
+		.await
+		.unwrap();
+	let guild_id = guild["id"].as_str().expect("Guild has no id").to_string();
+	let guild_create = expect_dispatch(&mut gateway, "GUILD_CREATE").await;
+	assert_eq!(guild_create["id"], guild_id);
+
+	let channel: Value = http
+		.post(format!("{api_url}/guilds/{guild_id}/channels"))
+		.header("Authorization", &token)
+		.json(&json!({"name": "events", "type": 0}))
+		.send()
+		.await
+		.unwrap()
+		.json()
+		.await
+		.unwrap();
+	let channel_id = channel["id"].as_str().expect("Channel has no id").to_string();
+	let created = expect_dispatch(&mut gateway, "CHANNEL_CREATE").await;
+	assert_eq!(created["id"], channel_id);
+
+	let message: Value = http
+		.post(format!("{api_url}/channels/{channel_id}/messages"))
+		.header("Authorization", &token)
+		.json(&json!({"content": "hello gateway"}))
+		.send()
+		.await
+		.unwrap()
+		.json()
+		.await
+		.unwrap();
+	let created = expect_dispatch(&mut gateway, "MESSAGE_CREATE").await;
+	assert_eq!(created["id"], message["id"]);
+	assert_eq!(created["content"], "hello gateway");
+
+	http.post(format!("{api_url}/channels/{channel_id}/typing"))
+		.header("Authorization", &token)
+		.send()
+		.await
+		.unwrap();
+	let typing = expect_dispatch(&mut gateway, "TYPING_START").await;
+	assert_eq!(typing["channel_id"], channel_id);
+
+	http.post(format!("{api_url}/guilds/{guild_id}/roles"))
+		.header("Authorization", &token)
+		.json(&json!({"name": "dispatched"}))
+		.send()
+		.await
+		.unwrap();
+	let role_create = expect_dispatch(&mut gateway, "GUILD_ROLE_CREATE").await;
+	assert_eq!(role_create["guild_id"], guild_id);
+	assert_eq!(role_create["role"]["name"], "dispatched");
+
+	let message_id = message["id"].as_str().unwrap();
+	http.delete(format!("{api_url}/channels/{channel_id}/messages/{message_id}"))
+		.header("Authorization", &token)
+		.send()
+		.await
+		.unwrap();
+	let deleted = expect_dispatch(&mut gateway, "MESSAGE_DELETE").await;
+	assert_eq!(deleted["id"], message_id);
+	assert_eq!(deleted["channel_id"], channel_id);
+}
@@ -0,0 +1,100 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks that events published to the PostgreSQL event bus reach the
+//! subscribers of all nodes connected to the same database.
+//!
+//! Runs against the database in `DATABASE_URL`. Run with
+//! `cargo test -- --ignored`.
+
+use std::time::Duration;
+
+use chorus::types::Snowflake;
+use serde_json::json;
+use sqlx::PgPool;
+use tokio::{sync::broadcast, time::timeout};
+use util::gateway::{
+	dispatchevent::{DispatchEvent, DispatchEventType},
+	event::Event,
+	event_bus::{BusMessage, EventBus, PgEventBus},
+	presence::Status,
+};
+
+async fn connect() -> PgPool {
+	let db = PgPool::connect(&std::env::var("DATABASE_URL").expect("DATABASE_URL is not set"))
+		.await
+		.expect("Failed to connect to the database");
+	sqlx::migrate!("../util/migrations").run(&db).await.expect("Failed to run migrations");
+	db
+}
+
+fn message(recipients: u64) -> BusMessage {
+	BusMessage::Dispatch {
+		recipients: (1..=recipients).map(Snowflake).collect(),
+		event: Event::Dispatch(
+			DispatchEvent::new(
+				DispatchEventType::MessageDelete,
+				json!({"id": "10", "channel_id": "20"}),
+			)
+			.expect("Failed to create the event"),
+		),
+	}
+}
+
+async fn receive(messages: &mut broadcast::Receiver<BusMessage>) -> BusMessage {
+	timeout(Duration::from_secs(5), messages.recv())
+		.await
+		.expect("Timed out waiting for a message")
+		.expect("Failed to receive a message")
+}
+
+/// The number of recipients of the next message, which has to be a dispatch.
+async fn receive_dispatch(messages: &mut broadcast::Receiver<BusMessage>) -> usize {
+	match receive(messages).await {
+		BusMessage::Dispatch { recipients, .. } => recipients.len(),
+		message => panic!("Expected a dispatch, got {message:?}"),
+	}
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "requires a PostgreSQL database"]
+async fn messages_reach_all_nodes() {
+	let db = connect().await;
+	let api_node = PgEventBus::connect(db.clone(), 1).await.expect("Failed to connect node 1");
+	let gateway_node = PgEventBus::connect(db, 2).await.expect("Failed to connect node 2");
+	let mut own_messages = api_node.subscribe();
+	let mut other_messages = gateway_node.subscribe();
+
+	// Small enough to be sent with NOTIFY itself
+	api_node.publish(message(3)).await.expect("Failed to publish a small message");
+	// Too large for NOTIFY, stored in the database instead
+	api_node.publish(message(2000)).await.expect("Failed to publish a large message");
+	api_node
+		.publish(BusMessage::MemberListChanged { guild_id: Snowflake(30) })
+		.await
+		.expect("Failed to publish a member list change");
+	api_node
+		.publish(BusMessage::SettingsStatusChanged {
+			user_id: Snowflake(40),
+			status: Some(Status::Idle),
+		})
+		.await
+		.expect("Failed to publish a settings status");
+
+	for messages in [&mut own_messages, &mut other_messages] {
+		assert_eq!(receive_dispatch(messages).await, 3);
+		assert_eq!(receive_dispatch(messages).await, 2000);
+		assert!(matches!(
+			receive(messages).await,
+			BusMessage::MemberListChanged { guild_id: Snowflake(30) }
+		));
+		assert!(matches!(
+			receive(messages).await,
+			BusMessage::SettingsStatusChanged {
+				user_id: Snowflake(40),
+				status: Some(Status::Idle)
+			}
+		));
+	}
+}
@@ -0,0 +1,70 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks that shutting down stops the API, asks gateway clients to reconnect,
+//! and saves their sessions so that they can be resumed on another node.
+//!
+//! Run with `cargo test -- --ignored`, against the database in `DATABASE_URL`.
+
+mod common;
+
+use std::time::Duration;
+
+use common::TestServers;
+use futures::StreamExt;
+use serde_json::Value;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::{Message, protocol::frame::coding::CloseCode};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn shutdown_saves_gateway_sessions() {
+	let servers = TestServers::start().await;
+	let token = servers.register("shutdown").await;
+	let mut gateway = servers.identify(&token, 0).await;
+
+	let mut session_id = None;
+	let mut reconnect_requested = false;
+	loop {
+		let message = timeout(Duration::from_secs(5), gateway.next())
+			.await
+			.expect("Timed out waiting for a gateway message")
+			.expect("Gateway connection ended without a close frame")
+			.expect("Failed to receive a gateway message");
+		match message {
+			Message::Text(text) => {
+				let payload: Value =
+					serde_json::from_str(&text).expect("Gateway sent invalid json");
+				if payload["t"] == "READY" {
+					session_id = payload["d"]["session_id"].as_str().map(str::to_string);
+					servers.shutdown.trigger();
+				}
+				reconnect_requested |= payload["op"] == 7;
+			}
+			Message::Close(frame) => {
+				let frame = frame.expect("Close frame has no code");
+				assert_eq!(frame.code, CloseCode::Library(4000));
+				break;
+			}
+			_ => (),
+		}
+	}
+	assert!(reconnect_requested, "Gateway did not send RECONNECT");
+
+	timeout(Duration::from_secs(10), servers.gateway)
+		.await
+		.expect("Gateway did not stop")
+		.unwrap()
+		.expect("Gateway stopped with an error");
+	timeout(Duration::from_secs(10), servers.api).await.expect("API did not stop").unwrap();
+
+	let session_id = session_id.expect("READY did not contain a session id");
+	let saved: i64 =
+		sqlx::query_scalar("SELECT COUNT(*) FROM resumable_sessions WHERE session_id = $1")
+			.bind(&session_id)
+			.fetch_one(&servers.db)
+			.await
+			.unwrap();
+	assert_eq!(saved, 1, "Session was not saved");
+}
@@ -0,0 +1,130 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks that TOTP codes and backup codes are only accepted once, and that
+//! changes to security keys have to be confirmed.
+//!
+//! Run with `cargo test -- --ignored`, against the database in `DATABASE_URL`.
+
+mod common;
+
+use common::{PASSWORD, TestServers};
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const SECRET: &str = "JBSWY3DPEHPK3PXP";
+
+/// Post `body` to `path` of the API, returning the status and the json
+/// response.
+async fn post(
+	servers: &TestServers,
+	path: &str,
+	token: Option<&str>,
+	body: Value,
+) -> (StatusCode, Value) {
+	let mut request = servers.http.post(format!("{}{path}", servers.api_url)).json(&body);
+	if let Some(token) = token {
+		request = request.header("Authorization", token);
+	}
+	let response = request.send().await.expect("Failed to send a request");
+	let status = response.status();
+	(status, response.json().await.unwrap_or(Value::Null))
+}
+
+/// Log in as `email` and complete the login with the MFA code `code`.
+async fn login_with(servers: &TestServers, email: &str, code: &str) -> StatusCode {
+	let (status, login) =
+		post(servers, "/auth/login", None, json!({"login": email, "password": PASSWORD})).await;
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(login["mfa"], true);
+	let ticket = login["ticket"].as_str().expect("Login did not respond with a ticket");
+	let (status, _) =
+		post(servers, "/auth/mfa/totp", None, json!({"code": code, "ticket": ticket})).await;
+	status
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn mfa_codes_are_accepted_once() {
+	let servers = TestServers::start().await;
+	let registration = TestServers::registration("mfa");
+	let email = registration["email"].as_str().unwrap().to_string();
+	let (status, registered) = post(&servers, "/auth/register", None, registration).await;
+	assert_eq!(status, StatusCode::OK);
+	let token = registered["token"].as_str().unwrap();
+
+	let totp = TOTP::new_unchecked(
+		Algorithm::SHA1,
+		6,
+		0,
+		30,
+		Secret::Encoded(SECRET.into()).to_bytes().unwrap(),
+	);
+	let code = totp.generate_current().unwrap();
+	let (status, enabled) = post(
+		&servers,
+		"/users/@me/mfa/totp/enable",
+		Some(token),
+		json!({"password": PASSWORD, "secret": SECRET, "code": code}),
+	)
+	.await;
+	assert_eq!(status, StatusCode::OK);
+	let token = enabled["token"].as_str().unwrap();
+	let backup_code = enabled["backup_codes"][0]["code"]
+		.as_str()
+		.expect("Enabling TOTP did not respond with backup codes")
+		.to_string();
+
+	// The code which enabled TOTP is used up
+	assert!(!login_with(&servers, &email, &code).await.is_success());
+
+	assert_eq!(login_with(&servers, &email, &backup_code).await, StatusCode::OK);
+	assert!(!login_with(&servers, &email, &backup_code).await.is_success());
+
+	// Only hashes of the backup codes are stored
+	let (status, codes) = post(
+		&servers,
+		"/users/@me/mfa/codes",
+		Some(token),
+		json!({"password": PASSWORD, "regenerate": false}),
+	)
+	.await;
+	assert_eq!(status, StatusCode::OK);
+	let codes = codes["backup_codes"].as_array().unwrap();
+	assert!(!codes.is_empty());
+	assert!(codes.iter().all(|code| code.get("code").is_none()));
+	assert!(codes.iter().any(|code| code["consumed"] == true));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn security_key_changes_require_confirmation() {
+	let servers = TestServers::start().await;
+	let token = servers.register("webauthn").await;
+	let key_url = format!("{}/users/@me/mfa/webauthn/credentials/1", servers.api_url);
+	let delete = |body: Value| {
+		servers.http.delete(&key_url).header("Authorization", &token).json(&body).send()
+	};
+
+	let response = delete(json!({})).await.expect("Failed to send a request");
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+	let response = delete(json!({"password": "wrong"})).await.expect("Failed to send a request");
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+	let response = delete(json!({"code": "123456"})).await.expect("Failed to send a request");
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+	// Only a confirmed request gets to look up the key
+	let response = delete(json!({"password": PASSWORD})).await.expect("Failed to send a request");
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+	let response = servers
+		.http
+		.patch(&key_url)
+		.header("Authorization", &token)
+		.json(&json!({"name": "renamed"}))
+		.send()
+		.await
+		.expect("Failed to send a request");
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,87 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks that password resets require a captcha if configured, and that new
+//! passwords have to satisfy the password policy.
+//!
+//! Run with `cargo test -- --ignored`, against the database in `DATABASE_URL`.
+
+mod common;
+
+use chorus::types::Snowflake;
+use common::{PASSWORD, TestServers};
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+use util::{
+	entities::Config,
+	util::token::{EmailTokenPurpose, generate_email_token},
+};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn forgot_password_requires_a_captcha() {
+	let mut config = Config::default();
+	config.password_reset.require_captcha = true;
+	config.security.captcha.enabled = true;
+	let servers = TestServers::start_with(config).await;
+
+	let response = servers
+		.http
+		.post(format!("{}/auth/forgot", servers.api_url))
+		.json(&json!({"login": "nobody@symfonia.test"}))
+		.send()
+		.await
+		.expect("Failed to send a request");
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn reset_passwords_follow_the_password_policy() {
+	let config = Config::default();
+	let jwt_secret = config.security.jwt_secret.clone();
+	let servers = TestServers::start_with(config).await;
+	let registration = TestServers::registration("reset");
+	let email = registration["email"].as_str().unwrap().to_string();
+	let registered: Value = servers
+		.http
+		.post(format!("{}/auth/register", servers.api_url))
+		.json(&registration)
+		.send()
+		.await
+		.unwrap()
+		.json()
+		.await
+		.unwrap();
+	let user: Value = servers
+		.http
+		.get(format!("{}/users/@me", servers.api_url))
+		.header("Authorization", registered["token"].as_str().unwrap())
+		.send()
+		.await
+		.unwrap()
+		.json()
+		.await
+		.unwrap();
+	let user_id = Snowflake(user["id"].as_str().unwrap().parse().unwrap());
+	let reset_token =
+		generate_email_token(user_id, &email, EmailTokenPurpose::ResetPassword, &jwt_secret)
+			.unwrap();
+
+	let reset = |password: &str| {
+		servers
+			.http
+			.post(format!("{}/auth/reset", servers.api_url))
+			.json(&json!({"token": reset_token, "password": password}))
+			.send()
+	};
+	let response = reset("short").await.expect("Failed to send a request");
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+	let response: Value = response.json().await.unwrap();
+	assert_eq!(response["code"], 50035);
+	assert!(response["errors"].get("password").is_some());
+
+	let response = reset(&format!("{PASSWORD} again")).await.expect("Failed to send a request");
+	assert_eq!(response.status(), StatusCode::OK);
+}
@@ -0,0 +1,93 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks the errors of `POST /auth/register` and the use of registration
+//! tokens.
+//!
+//! Run with `cargo test -- --ignored`, against the database in `DATABASE_URL`.
+
+mod common;
+
+use common::TestServers;
+use reqwest::StatusCode;
+use serde_json::Value;
+use util::entities::Config;
+
+/// Register with `body`, returning the status and the json response.
+async fn register(servers: &TestServers, query: &str, body: &Value) -> (StatusCode, Value) {
+	let response = servers
+		.http
+		.post(format!("{}/auth/register{query}", servers.api_url))
+		.json(body)
+		.send()
+		.await
+		.expect("Failed to register");
+	let status = response.status();
+	(status, response.json().await.expect("Register did not respond with json"))
+}
+
+fn codes(response: &Value, field: &str) -> Vec<String> {
+	response["errors"][field]["_errors"]
+		.as_array()
+		.unwrap_or_else(|| panic!("No errors for {field} in {response}"))
+		.iter()
+		.map(|error| error["code"].as_str().unwrap().to_string())
+		.collect()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn invalid_fields_are_reported_together() {
+	let servers = TestServers::start().await;
+	let mut body = TestServers::registration("fields");
+	body["password"] = "short".into();
+	body["consent"] = false.into();
+	body["date_of_birth"] = "2020-01-01".into();
+	body["invite"] = "not-an-invite".into();
+
+	let (status, response) = register(&servers, "", &body).await;
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+	assert_eq!(response["code"], 50035);
+	assert_eq!(response["message"], "Invalid Form Body");
+	assert!(codes(&response, "password").contains(&"BASE_TYPE_MIN_LENGTH".to_string()));
+	assert_eq!(codes(&response, "consent"), ["CONSENT_REQUIRED"]);
+	assert_eq!(codes(&response, "date_of_birth"), ["DATE_OF_BIRTH_UNDERAGE"]);
+	assert_eq!(codes(&response, "invite"), ["INVITE_INVALID"]);
+	assert!(response["errors"].get("username").is_none());
+	assert!(response["errors"].get("email").is_none());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn registration_tokens_are_used_by_successful_registrations_only() {
+	let mut config = Config::default();
+	config.register.allow_new_registration = false;
+	let servers = TestServers::start_with(config).await;
+	let token = TestServers::unique_username("token");
+	sqlx::query(
+		"INSERT INTO valid_registration_tokens (token, created_at, expires_at) VALUES ($1, \
+		 NOW() AT TIME ZONE 'UTC', (NOW() AT TIME ZONE 'UTC') + INTERVAL '1 hour')",
+	)
+	.bind(&token)
+	.execute(&servers.db)
+	.await
+	.unwrap();
+	let query = format!("?token={token}");
+
+	let (status, _) = register(&servers, "", &TestServers::registration("closed")).await;
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+
+	let mut invalid = TestServers::registration("invalid");
+	invalid["consent"] = false.into();
+	let (status, _) = register(&servers, &query, &invalid).await;
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+
+	let (status, response) = register(&servers, &query, &TestServers::registration("token")).await;
+	assert_eq!(status, StatusCode::OK, "{response}");
+	assert!(response["token"].is_string());
+
+	let (status, response) = register(&servers, &query, &TestServers::registration("reuse")).await;
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+	assert_eq!(codes(&response, "email"), ["REGISTRATION_DISABLED"]);
+}
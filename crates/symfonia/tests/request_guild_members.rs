@@ -0,0 +1,171 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks the `GUILD_MEMBERS_CHUNK`s sent in response to op 8 Request Guild
+//! Members, by `user_ids`, by `query` and by `limit`, and the presences sent
+//! with them.
+//!
+//! Run with `cargo test -- --ignored`, against the database in `DATABASE_URL`.
+
+mod common;
+
+use chorus::types::Snowflake;
+use common::{Gateway, TestServers, expect_dispatch};
+use futures::SinkExt;
+use serde_json::{Value, json};
+use tokio_tungstenite::tungstenite::Message;
+use util::entities::Guild;
+
+/// GUILDS | GUILD_MEMBERS
+const INTENTS: u64 = 1 | (1 << 1);
+/// GUILDS | GUILD_MEMBERS | GUILD_PRESENCES
+const PRESENCE_INTENTS: u64 = INTENTS | (1 << 8);
+
+/// The id of the user with `token`, and their username.
+async fn current_user(servers: &TestServers, token: &str) -> (Snowflake, String) {
+	let user: Value = servers
+		.http
+		.get(format!("{}/users/@me", servers.api_url))
+		.header("Authorization", token)
+		.send()
+		.await
+		.expect("Failed to send a request")
+		.json()
+		.await
+		.expect("The current user is not json");
+	let id = user["id"].as_str().expect("User has no id").parse().unwrap();
+	(Snowflake(id), user["username"].as_str().unwrap().to_string())
+}
+
+/// Create a guild owned by the user with `owner` and add the users
+/// `member_ids` to it, returning its id.
+async fn create_guild(servers: &TestServers, owner: &str, member_ids: &[Snowflake]) -> String {
+	let guild: Value = servers
+		.http
+		.post(format!("{}/guilds", servers.api_url))
+		.header("Authorization", owner)
+		.json(&json!({"name": "Member Requests"}))
+		.send()
+		.await
+		.unwrap()
+		.json()
+		.await
+		.unwrap();
+	let guild_id = guild["id"].as_str().expect("Guild has no id").to_string();
+	let guild =
+		Guild::get_by_id(&servers.db, Snowflake(guild_id.parse().unwrap())).await.unwrap().unwrap();
+	for user_id in member_ids {
+		guild
+			.add_member(&servers.db, &servers.connected_users.role_user_map, *user_id)
+			.await
+			.expect("Failed to add a member");
+	}
+	guild_id
+}
+
+/// Send an op 8 request with the data `request` and return the first chunk of
+/// the response.
+async fn request_chunk(gateway: &mut Gateway, request: Value) -> Value {
+	gateway
+		.send(Message::Text(json!({"op": 8, "d": request}).to_string().into()))
+		.await
+		.expect("Failed to request guild members");
+	expect_dispatch(gateway, "GUILD_MEMBERS_CHUNK").await
+}
+
+/// Send an op 8 request with the data `request` and return the ids of the
+/// members and the `not_found` ids of the first chunk of the response.
+async fn request_members(gateway: &mut Gateway, request: Value) -> (Vec<String>, Value) {
+	let chunk = request_chunk(gateway, request).await;
+	let mut ids = chunk["members"]
+		.as_array()
+		.expect("Chunk has no members")
+		.iter()
+		.map(|member| member["user"]["id"].as_str().unwrap().to_string())
+		.collect::<Vec<_>>();
+	ids.sort();
+	(ids, chunk["not_found"].clone())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn members_are_requested_by_ids_query_and_limit() {
+	let servers = TestServers::start().await;
+	let owner = servers.register("members_owner").await;
+	let alice = servers.register("members_alice").await;
+	let bob = servers.register("members_bob").await;
+	let (owner_id, _) = current_user(&servers, &owner).await;
+	let (alice_id, alice_name) = current_user(&servers, &alice).await;
+	let (bob_id, _) = current_user(&servers, &bob).await;
+	let stranger = Snowflake::default();
+
+	let guild_id = create_guild(&servers, &owner, &[alice_id, bob_id]).await;
+	let mut gateway = servers.connect(&owner, INTENTS).await;
+
+	let (ids, not_found) = request_members(
+		&mut gateway,
+		json!({"guild_id": guild_id, "user_ids": [alice_id, stranger], "limit": 0}),
+	)
+	.await;
+	assert_eq!(ids, [alice_id.to_string()]);
+	assert_eq!(not_found, json!([stranger]));
+
+	let (ids, _) = request_members(
+		&mut gateway,
+		json!({"guild_id": guild_id, "query": alice_name, "limit": 10}),
+	)
+	.await;
+	assert_eq!(ids, [alice_id.to_string()]);
+
+	let (ids, _) =
+		request_members(&mut gateway, json!({"guild_id": guild_id, "query": "", "limit": 2})).await;
+	assert_eq!(ids.len(), 2);
+
+	let (ids, _) =
+		request_members(&mut gateway, json!({"guild_id": guild_id, "query": "", "limit": 0})).await;
+	let mut all = [owner_id, alice_id, bob_id].map(|id| id.to_string());
+	all.sort();
+	assert_eq!(ids, all);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn invisible_members_have_no_presence() {
+	let servers = TestServers::start().await;
+	let owner = servers.register("presences_owner").await;
+	let alice = servers.register("presences_alice").await;
+	let bob = servers.register("presences_bob").await;
+	let (alice_id, _) = current_user(&servers, &alice).await;
+	let (bob_id, _) = current_user(&servers, &bob).await;
+	let guild_id = create_guild(&servers, &owner, &[alice_id, bob_id]).await;
+
+	let mut owner_gateway = servers.connect(&owner, PRESENCE_INTENTS).await;
+	let mut alice_gateway = servers.connect(&alice, INTENTS).await;
+	let _bob_gateway = servers.connect(&bob, INTENTS).await;
+	alice_gateway
+		.send(Message::Text(
+			json!({"op": 3, "d": {"status": "invisible", "since": null, "activities": [], "afk": false}})
+				.to_string()
+				.into(),
+		))
+		.await
+		.expect("Failed to update the presence");
+	// Invisible users are seen as offline
+	loop {
+		let presence = expect_dispatch(&mut owner_gateway, "PRESENCE_UPDATE").await;
+		if presence["user"]["id"] == alice_id.to_string() && presence["status"] == "offline" {
+			break;
+		}
+	}
+
+	let chunk = request_chunk(
+		&mut owner_gateway,
+		json!({"guild_id": guild_id, "user_ids": [alice_id, bob_id], "presences": true}),
+	)
+	.await;
+	let presences = chunk["presences"].as_array().expect("Chunk has no presences");
+	assert_eq!(presences.len(), 1);
+	assert_eq!(presences[0]["user"]["id"], bob_id.to_string());
+	assert_eq!(presences[0]["status"], "online");
+}
@@ -0,0 +1,112 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks that identifying creates a session on the node of the gateway, that
+//! the stale sessions of a node are deleted unless they were saved to be
+//! resumed, and that closing one session of a user keeps their others going.
+//!
+//! Run with `cargo test -- --ignored`, against the database in `DATABASE_URL`.
+
+mod common;
+
+use std::sync::{Arc, Weak};
+
+use chorus::types::Snowflake;
+use common::{TestServers, expect_dispatch};
+use serde_json::{Value, json};
+use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, frame::coding::CloseCode};
+use util::{
+	configuration::SymfoniaConfiguration,
+	entities::Session,
+	gateway::{DisconnectInfo, ReplayBuffer, intents::GatewayIntents},
+};
+
+/// Identify with `token`, returning the id of the new session and its user.
+async fn identify(servers: &TestServers, token: &str) -> (Snowflake, Snowflake) {
+	let mut gateway = servers.identify(token, 0).await;
+	let ready = expect_dispatch(&mut gateway, "READY").await;
+	let session_id = ready["session_id"].as_str().expect("READY has no session id");
+	let user_id = ready["user"]["id"].as_str().expect("READY has no user");
+	(Snowflake(session_id.parse().unwrap()), Snowflake(user_id.parse().unwrap()))
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn stale_sessions_are_deleted_unless_resumable() {
+	let servers = TestServers::start().await;
+	let token = servers.register("sessions").await;
+	let (session_id, user_id) = identify(&servers, &token).await;
+	let (resumable_id, _) = identify(&servers, &token).await;
+
+	let session = Session::get_by_session_id(&servers.db, session_id)
+		.await
+		.unwrap()
+		.expect("Identifying did not create a session");
+	assert_eq!(session.user_id, user_id);
+	assert_eq!(session.node_id.to_uint(), SymfoniaConfiguration::get().general.node_id);
+
+	// Pretend both sessions were left behind by a node no other test uses, and
+	// that one of them was saved to be resumed.
+	let node_id = Snowflake::generate().0;
+	for id in [session_id, resumable_id] {
+		Session::move_to_node(&servers.db, id, node_id).await.unwrap();
+	}
+	let saved = DisconnectInfo::new(
+		resumable_id.to_string(),
+		user_id,
+		1,
+		Arc::new(Mutex::new(ReplayBuffer::default())),
+		GatewayIntents::empty(),
+		Weak::new(),
+	);
+	saved.save(&servers.db).await.expect("Failed to save the session");
+
+	let user_ids = Session::delete_stale(&servers.db, node_id).await.unwrap();
+	assert_eq!(user_ids, [user_id]);
+	assert!(Session::get_by_session_id(&servers.db, session_id).await.unwrap().is_none());
+	assert!(Session::get_by_session_id(&servers.db, resumable_id).await.unwrap().is_some());
+	assert!(Session::delete_stale(&servers.db, node_id).await.unwrap().is_empty());
+
+	DisconnectInfo::take_saved(&servers.db, &saved.session_token).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "requires a PostgreSQL database"]
+async fn closing_a_session_keeps_the_other_sessions_of_the_user() {
+	let servers = TestServers::start().await;
+	let token = servers.register("two_sessions").await;
+	let mut closing = servers.connect(&token, 1).await;
+	let mut staying = servers.identify(&token, 1).await;
+	let ready = expect_dispatch(&mut staying, "READY").await;
+	let session_id = ready["session_id"].as_str().expect("READY has no session id").to_string();
+
+	closing
+		.close(Some(CloseFrame { code: CloseCode::Normal, reason: "".into() }))
+		.await
+		.expect("Failed to close the session");
+	// The remaining session learns that it is the only one left
+	loop {
+		let sessions = expect_dispatch(&mut staying, "SESSIONS_REPLACE").await;
+		let sessions = sessions.as_array().expect("SESSIONS_REPLACE is no list");
+		if sessions.len() == 1 {
+			assert_eq!(sessions[0]["session_id"], session_id);
+			break;
+		}
+	}
+
+	let guild: Value = servers
+		.http
+		.post(format!("{}/guilds", servers.api_url))
+		.header("Authorization", &token)
+		.json(&json!({"name": "Still Connected"}))
+		.send()
+		.await
+		.unwrap()
+		.json()
+		.await
+		.unwrap();
+	let guild_create = expect_dispatch(&mut staying, "GUILD_CREATE").await;
+	assert_eq!(guild_create["id"], guild["id"]);
+}
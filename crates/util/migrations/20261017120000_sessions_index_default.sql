@@ -0,0 +1,6 @@
+ALTER TABLE sessions
+    ALTER COLUMN index SET DEFAULT nextval('sessions_index_seq');
+
+ALTER SEQUENCE sessions_index_seq OWNED BY sessions.index;
+
+CREATE UNIQUE INDEX IF NOT EXISTS sessions_session_id_idx ON sessions (session_id);
@@ -0,0 +1,8 @@
+create table if not exists event_bus_payloads
+(
+    id         bigserial primary key,
+    payload    jsonb                    not null,
+    created_at timestamp with time zone not null default now()
+);
+
+create index if not exists event_bus_payloads_created_at_idx on event_bus_payloads (created_at);
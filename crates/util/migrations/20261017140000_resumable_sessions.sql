@@ -0,0 +1,11 @@
+create table if not exists resumable_sessions
+(
+    session_id      text primary key,
+    user_id         numeric(20, 0)           not null constraint chk_user_id_range check (user_id >= 0 AND user_id <= 18446744073709551615),
+    sequence        bigint                   not null,
+    disconnected_at timestamp with time zone not null,
+    intents         bigint                   not null,
+    replay_buffer   jsonb                    not null
+);
+
+create index if not exists resumable_sessions_disconnected_at_idx on resumable_sessions (disconnected_at);
@@ -0,0 +1,22 @@
+-- WebAuthn credential ids are opaque byte strings of up to 1023 bytes, stored
+-- hex encoded. The full credential is needed to verify assertions, and
+-- signature counters are unsigned 32 bit integers.
+alter table security_keys alter column key_id type text using key_id::text;
+alter table security_keys drop column public_key;
+alter table security_keys add column credential jsonb not null;
+alter table security_keys alter column counter type bigint;
+
+create unique index if not exists security_keys_key_id_uindex on security_keys (key_id);
+create index if not exists security_keys_user_id_idx on security_keys (user_id);
+
+create table if not exists webauthn_challenges
+(
+    user_id    numeric(20, 0)           not null constraint chk_user_id_range check (user_id >= 0 AND user_id <= 18446744073709551615),
+    kind       varchar(32)              not null,
+    state      jsonb                    not null,
+    created_at timestamp with time zone not null default now(),
+    primary key (user_id, kind),
+    constraint webauthn_challenges_user_id_fk
+        foreign key (user_id) references users (id)
+            on delete cascade
+);
@@ -0,0 +1,6 @@
+-- The time step of the last TOTP code accepted for a user, so that every
+-- code is only accepted once.
+alter table users add column if not exists totp_last_step bigint null;
+
+-- Backup codes are stored as SHA-256 hashes of the codes.
+update backup_codes set code = encode(sha256(convert_to(code, 'UTF8')), 'hex');
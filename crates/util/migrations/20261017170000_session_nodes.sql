@@ -0,0 +1,5 @@
+-- The gateway node a session is connected to, so that a node which restarts
+-- can delete the sessions it left behind.
+alter table sessions add column if not exists node_id numeric(20, 0) not null default 0 constraint chk_node_id_range check (node_id >= 0 AND node_id <= 18446744073709551615);
+
+create index if not exists sessions_node_id_idx on sessions (node_id);
@@ -8,12 +8,13 @@ use std::{
 	path::PathBuf,
 	str::FromStr,
 	sync::OnceLock,
+	time::Duration,
 };
 
 use serde::{Deserialize, Serialize};
 use serde_with::{DisplayFromStr, serde_as};
 
-use crate::errors::Error;
+use crate::{errors::Error, tls::TlsFiles};
 
 const TLS_CONFIG_DISABLE: &str = "disable";
 const TLS_CONFIG_ALLOW: &str = "allow";
@@ -36,24 +37,66 @@ pub struct ComponentConfiguration {
 	pub enabled: bool,
 	pub port: u16,
 	pub host: String,
+	/// Whether to serve HTTPS or WSS, using `tls_certificate` and `tls_key`.
 	pub tls: bool,
+	/// Path to the PEM encoded certificate chain served if `tls` is enabled.
+	#[serde(default)]
+	pub tls_certificate: Option<PathBuf>,
+	/// Path to the PEM encoded private key of `tls_certificate`.
+	#[serde(default)]
+	pub tls_key: Option<PathBuf>,
 	pub database: DatabaseConfigurationOverrides,
 }
 
+impl ComponentConfiguration {
+	/// The certificate and key to serve, or [None] if TLS is disabled.
+	pub fn tls_files(&self) -> Result<Option<TlsFiles>, Error> {
+		if !self.tls {
+			return Ok(None);
+		}
+		match (&self.tls_certificate, &self.tls_key) {
+			(Some(certificate), Some(key)) => {
+				Ok(Some(TlsFiles { certificate: certificate.clone(), key: key.clone() }))
+			}
+			_ => Err(Error::Custom(
+				"`tls` is enabled, but `tls_certificate` or `tls_key` is not set".to_string(),
+			)),
+		}
+	}
+}
+
+/// Settings of the database pool of a single component. Connection settings
+/// left out are taken from the [DatabaseConfiguration] in `[general.database]`.
 #[serde_as]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct DatabaseConfigurationOverrides {
+	/// Maximum number of connections kept open by the pool.
 	pub max_connections: u32,
+	/// Number of connections kept open by the pool, even when idle.
+	#[serde(default)]
+	pub min_connections: u32,
+	/// Seconds to wait for a connection of the pool to become available,
+	/// before failing. Defaults to [DEFAULT_ACQUIRE_TIMEOUT].
+	#[serde(default)]
+	pub acquire_timeout_secs: Option<u64>,
+	/// Milliseconds after which the server aborts a statement. Statements are
+	/// not aborted if unset, or set to `0`.
+	#[serde(default)]
+	pub statement_timeout_ms: Option<u64>,
 	pub user: Option<String>,
 	pub password: Option<String>,
 	pub host: Option<String>,
-	pub port: Option<u32>,
+	pub port: Option<u16>,
 	#[serde(default)]
-	#[serde_as(as = "DisplayFromStr")]
-	pub tls: TlsConfig,
+	#[serde_as(as = "Option<DisplayFromStr>")]
+	pub tls: Option<TlsConfig>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// How long a component waits for a database connection to become available,
+/// unless `acquire_timeout_secs` is set.
+pub const DEFAULT_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
 /// TLS configuration modes. Also called `sslconfig` by PostgreSQL. See <https://www.postgresql.org/docs/current/libpq-ssl.html#:~:text=32.1.%C2%A0SSL%20Mode-,descriptions,-sslmode>
 /// for the security implications of this choice.
 pub enum TlsConfig {
@@ -84,11 +127,11 @@ impl TryFrom<&str> for TlsConfig {
 	fn try_from(value: &str) -> Result<Self, Self::Error> {
 		match value.to_lowercase().as_str() {
 			TLS_CONFIG_DISABLE => Ok(Self::Disable),
-			TLS_CONFIG_ALLOW => Ok(Self::Disable),
-			TLS_CONFIG_PREFER => Ok(Self::Disable),
-			TLS_CONFIG_REQUIRE => Ok(Self::Disable),
-			"verifyca" | TLS_CONFIG_VERIFY_CA | "verify-ca" => Ok(Self::Disable),
-			"verifyfull" | TLS_CONFIG_VERIFY_FULL | "verify-full" => Ok(Self::Disable),
+			TLS_CONFIG_ALLOW => Ok(Self::Allow),
+			TLS_CONFIG_PREFER => Ok(Self::Prefer),
+			TLS_CONFIG_REQUIRE => Ok(Self::Require),
+			"verifyca" | TLS_CONFIG_VERIFY_CA | "verify-ca" => Ok(Self::VerifyCa),
+			"verifyfull" | TLS_CONFIG_VERIFY_FULL | "verify-full" => Ok(Self::VerifyFull),
 			other => Err(format!(r#""{}" is not a valid TlsConfig variant"#, other).into()),
 		}
 	}
@@ -119,10 +162,92 @@ impl FromStr for TlsConfig {
 pub struct GeneralConfiguration {
 	pub log_level: LogLevel,
 	pub node_id: u64,
+	/// How dispatch events travel from the nodes causing them to the gateway
+	/// nodes delivering them.
+	#[serde(default)]
+	pub event_bus: EventBusKind,
+	/// Seconds to wait for in-flight requests and gateway sessions to wind
+	/// down when shutting down, before exiting regardless.
+	#[serde(default = "default_shutdown_timeout_secs")]
+	pub shutdown_timeout_secs: u64,
+	#[serde(default)]
+	pub tokens: TokenConfiguration,
+	#[serde(default)]
+	pub mail: MailConfiguration,
 	#[serde(rename = "database")]
 	pub database_configuration: DatabaseConfiguration,
 }
 
+fn default_shutdown_timeout_secs() -> u64 {
+	30
+}
+
+/// How access tokens are signed, and for how long they stay valid.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TokenConfiguration {
+	/// Seconds after which an issued token expires.
+	pub lifetime_secs: u64,
+	/// The JWT signature algorithm. The `HS*` algorithms sign with the
+	/// `jwtSecret` of the instance configuration; all others sign with
+	/// `private_key` and verify with `public_key`.
+	pub algorithm: jsonwebtoken::Algorithm,
+	/// PEM encoded private key, required by asymmetric algorithms.
+	pub private_key: Option<PathBuf>,
+	/// PEM encoded public key, required by asymmetric algorithms.
+	pub public_key: Option<PathBuf>,
+}
+
+impl Default for TokenConfiguration {
+	fn default() -> Self {
+		Self {
+			lifetime_secs: 60 * 60 * 24 * 7,
+			algorithm: jsonwebtoken::Algorithm::HS256,
+			private_key: None,
+			public_key: None,
+		}
+	}
+}
+
+/// How emails sent to users look, and where their links lead. The transport
+/// is configured in the `email` section of the instance configuration.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct MailConfiguration {
+	/// The sender of all emails.
+	pub from: String,
+	/// The address of the client, which links in emails lead to. The token of
+	/// a link is passed in the fragment, e.g. `/verify#token=...`.
+	pub link_base_url: String,
+	/// File emails are appended to instead of being sent, if no SMTP host is
+	/// configured. Emails are only logged if this is unset as well.
+	pub sink: Option<PathBuf>,
+}
+
+impl Default for MailConfiguration {
+	fn default() -> Self {
+		Self {
+			from: "Symfonia <noreply@localhost>".to_string(),
+			link_base_url: "http://localhost:3000".to_string(),
+			sink: None,
+		}
+	}
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+/// The [EventBus](crate::gateway::event_bus::EventBus) implementation used by
+/// this node.
+pub enum EventBusKind {
+	/// Events are only delivered within this process. Only suitable if the API
+	/// and gateway run in a single process on a single node.
+	#[default]
+	InProcess,
+	/// Events are delivered to all nodes connected to the same database, using
+	/// PostgreSQL `LISTEN`/`NOTIFY`.
+	Postgres,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum LogLevel {
 	Error = 0,
@@ -156,6 +281,27 @@ impl Display for GatewayConfiguration {
 pub struct ApiConfiguration {
 	#[serde(flatten)]
 	pub cfg: ComponentConfiguration,
+	/// WebAuthn security keys can only be used if this is set.
+	#[serde(default)]
+	pub webauthn: Option<WebauthnConfiguration>,
+}
+
+/// The relying party WebAuthn credentials are bound to. Credentials stop
+/// working if `rp_id` changes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WebauthnConfiguration {
+	/// The domain of the instance, e.g. `example.com`.
+	pub rp_id: String,
+	/// The origin clients are served from, e.g. `https://example.com`. Must be
+	/// `rp_id` or one of its subdomains.
+	pub origin: String,
+	/// The name authenticators show for the instance.
+	#[serde(default = "default_rp_name")]
+	pub rp_name: String,
+}
+
+fn default_rp_name() -> String {
+	"Symfonia".to_string()
 }
 
 impl Display for ApiConfiguration {
@@ -226,6 +372,70 @@ impl Display for DatabaseConfiguration {
 	}
 }
 
+impl DatabaseConfiguration {
+	/// The settings of a component's pool, with `overrides` taking precedence
+	/// over this configuration.
+	pub fn with_overrides(&self, overrides: &DatabaseConfigurationOverrides) -> PoolConfiguration {
+		PoolConfiguration {
+			host: overrides.host.clone().unwrap_or_else(|| self.host.clone()),
+			port: overrides.port.unwrap_or(self.port),
+			username: overrides.user.clone().unwrap_or_else(|| self.username.clone()),
+			password: overrides.password.clone().unwrap_or_else(|| self.password.clone()),
+			database: self.database.clone(),
+			tls: overrides.tls.unwrap_or(self.tls),
+			max_connections: overrides.max_connections,
+			min_connections: overrides.min_connections,
+			acquire_timeout: overrides
+				.acquire_timeout_secs
+				.map(Duration::from_secs)
+				.unwrap_or(DEFAULT_ACQUIRE_TIMEOUT),
+			statement_timeout: overrides
+				.statement_timeout_ms
+				.filter(|ms| *ms > 0)
+				.map(Duration::from_millis),
+		}
+	}
+}
+
+/// The effective settings of a component's database pool, see
+/// [DatabaseConfiguration::with_overrides].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolConfiguration {
+	pub host: String,
+	pub port: u16,
+	pub username: String,
+	pub password: String,
+	pub database: String,
+	pub tls: TlsConfig,
+	pub max_connections: u32,
+	pub min_connections: u32,
+	pub acquire_timeout: Duration,
+	pub statement_timeout: Option<Duration>,
+}
+
+impl Display for PoolConfiguration {
+	/// Formats the settings without the password, so that they can be logged.
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"postgresql://{}@{}:{}/{} (tls: {}, connections: {}..={}, acquire timeout: {:?}, \
+			 statement timeout: ",
+			self.username,
+			self.host,
+			self.port,
+			self.database,
+			self.tls,
+			self.min_connections,
+			self.max_connections,
+			self.acquire_timeout
+		)?;
+		match self.statement_timeout {
+			Some(timeout) => write!(f, "{timeout:?})"),
+			None => f.write_str("none)"),
+		}
+	}
+}
+
 #[cfg(test)]
 #[allow(clippy::unwrap_used)]
 mod test {
@@ -245,4 +455,36 @@ mod test {
 			.unwrap()
 		);
 	}
+
+	#[test]
+	fn overrides_take_precedence_over_general_settings() {
+		let general = DatabaseConfiguration {
+			host: "localhost".to_string(),
+			port: 5432,
+			username: "symfonia".to_string(),
+			password: "hunter2".to_string(),
+			database: "symfonia".to_string(),
+			tls: TlsConfig::from_str("verify-full").unwrap(),
+		};
+		let overrides = DatabaseConfigurationOverrides {
+			max_connections: 5,
+			statement_timeout_ms: Some(0),
+			user: Some("gateway".to_string()),
+			port: Some(6432),
+			tls: Some(TlsConfig::Prefer),
+			..Default::default()
+		};
+
+		let pool = general.with_overrides(&overrides);
+		assert_eq!(
+			(pool.host.as_str(), pool.port, pool.username.as_str(), pool.password.as_str()),
+			("localhost", 6432, "gateway", "hunter2")
+		);
+		assert_eq!(pool.tls, TlsConfig::Prefer);
+		assert_eq!(general.tls, TlsConfig::VerifyFull);
+		assert_eq!(pool.max_connections, 5);
+		assert_eq!(pool.acquire_timeout, DEFAULT_ACQUIRE_TIMEOUT);
+		assert_eq!(pool.statement_timeout, None);
+		assert!(!pool.to_string().contains("hunter2"));
+	}
 }
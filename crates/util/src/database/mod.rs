@@ -8,7 +8,10 @@ use secrecy::{
 	SecretBox,
 	zeroize::{Zeroize, ZeroizeOnDrop},
 };
-use sqlx::{PgPool, Row, postgres::PgConnectOptions};
+use sqlx::{
+	PgPool, Row,
+	postgres::{PgConnectOptions, PgPoolOptions},
+};
 
 mod seed_config;
 pub use seed_config::*;
@@ -20,8 +23,11 @@ pub struct Connection {
 }
 
 impl Connection {
-	pub async fn new(options: PgConnectOptions) -> Result<Self, sqlx::Error> {
-		let pool = PgPool::connect_with(options).await?;
+	pub async fn new(
+		options: PgConnectOptions,
+		pool_options: PgPoolOptions,
+	) -> Result<Self, sqlx::Error> {
+		let pool = pool_options.connect_with(options).await?;
 		Ok(Self { pool })
 	}
 
@@ -52,7 +52,9 @@ impl Application {
 		create_bot_user: bool,
 	) -> Result<Self, Error> {
 		let bot_user_id = if create_bot_user {
-			let bot_user = User::create(db, cfg, name, None, None, None, None, true).await?;
+			let bot_user =
+				User::create(&mut *db.acquire().await?, cfg, name, None, None, None, None, true)
+					.await?;
 
 			Some(bot_user.id.to_owned())
 		} else {
@@ -102,6 +104,17 @@ impl Application {
 			.map_err(Error::Sqlx)
 	}
 
+	pub async fn get_by_bot_user_id(
+		db: &PgPool,
+		bot_user_id: Snowflake,
+	) -> Result<Option<Self>, Error> {
+		sqlx::query_as("SELECT * FROM applications WHERE bot_user_id = $1")
+			.bind(bot_user_id)
+			.fetch_optional(db)
+			.await
+			.map_err(Error::Sqlx)
+	}
+
 	pub async fn get_owner(&self, db: &PgPool) -> Result<User, Error> {
 		let u = User::get_by_id(db, self.owner_id).await?.unwrap(); // Unwrap the option since this should absolutely never fail
 		Ok(u)
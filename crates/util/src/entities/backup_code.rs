@@ -0,0 +1,128 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chorus::types::Snowflake;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use crate::errors::Error;
+
+/// The number of random bytes of a backup code, which is shown hex encoded.
+const BACKUP_CODE_BYTES: usize = 10;
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize, Deserialize)]
+/// A single-use code which can be used instead of a TOTP code, in case a user
+/// loses access to their authenticator.
+///
+/// Only a hash of the code is stored, so the code itself is only known right
+/// after it was generated.
+pub struct BackupCode {
+	#[serde(skip)]
+	pub id: Snowflake,
+	pub user_id: Snowflake,
+	#[sqlx(skip)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub code: Option<String>,
+	#[sqlx(rename = "code")]
+	#[serde(skip)]
+	pub code_hash: String,
+	pub consumed: bool,
+	#[serde(skip)]
+	pub expired: bool,
+}
+
+/// The hex encoded SHA-256 hash of the backup code `code`, ignoring case and
+/// the spaces and dashes a user may type. Backup codes are random enough to
+/// not need a salt or a slow hash.
+fn hash_code(code: &str) -> String {
+	let code = code.replace([' ', '-'], "").to_lowercase();
+	hex::encode(Sha256::digest(code.as_bytes()))
+}
+
+impl BackupCode {
+	/// Expire all backup codes of the user `user_id`, and generate `count` new
+	/// ones, which are only ever returned here.
+	pub async fn regenerate(
+		db: &PgPool,
+		user_id: Snowflake,
+		count: u16,
+	) -> Result<Vec<Self>, Error> {
+		let mut transaction = db.begin().await?;
+		sqlx::query("UPDATE backup_codes SET expired = true WHERE user_id = $1")
+			.bind(user_id)
+			.execute(&mut *transaction)
+			.await?;
+
+		let mut codes = Vec::with_capacity(count as usize);
+		for _ in 0..count {
+			let plain = hex::encode(rand::thread_rng().r#gen::<[u8; BACKUP_CODE_BYTES]>());
+			let code = Self {
+				id: Snowflake::default(),
+				user_id,
+				code_hash: hash_code(&plain),
+				code: Some(plain),
+				consumed: false,
+				expired: false,
+			};
+			sqlx::query(
+				"INSERT INTO backup_codes (id, code, consumed, expired, user_id) VALUES ($1, $2, false, false, $3)",
+			)
+			.bind(code.id)
+			.bind(&code.code_hash)
+			.bind(user_id)
+			.execute(&mut *transaction)
+			.await?;
+			codes.push(code);
+		}
+		transaction.commit().await?;
+		Ok(codes)
+	}
+
+	/// The backup codes of the user `user_id` which have not expired, including
+	/// those which have been consumed. The codes themselves are not known.
+	pub async fn get_by_user_id(db: &PgPool, user_id: Snowflake) -> Result<Vec<Self>, Error> {
+		sqlx::query_as("SELECT * FROM backup_codes WHERE user_id = $1 AND NOT expired")
+			.bind(user_id)
+			.fetch_all(db)
+			.await
+			.map_err(Error::Sqlx)
+	}
+
+	/// Consume the backup code `code` of the user `user_id`. Returns whether
+	/// the code was valid; every code is only accepted once.
+	pub async fn consume(db: &PgPool, user_id: Snowflake, code: &str) -> Result<bool, Error> {
+		let consumed = sqlx::query(
+			"UPDATE backup_codes SET consumed = true WHERE user_id = $1 AND code = $2 AND NOT consumed AND NOT expired",
+		)
+		.bind(user_id)
+		.bind(hash_code(code))
+		.execute(db)
+		.await?;
+		Ok(consumed.rows_affected() > 0)
+	}
+
+	/// Expire all backup codes of the user `user_id`.
+	pub async fn expire_all(db: &PgPool, user_id: Snowflake) -> Result<(), Error> {
+		sqlx::query("UPDATE backup_codes SET expired = true WHERE user_id = $1")
+			.bind(user_id)
+			.execute(db)
+			.await?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn hashes_ignore_formatting() {
+		let hash = hash_code("0123456789abcdef0123");
+		assert_eq!(hash.len(), 64);
+		assert_eq!(hash_code("01234-56789 ABCDEF-0123"), hash);
+		assert_ne!(hash_code("0123456789abcdef0124"), hash);
+	}
+}
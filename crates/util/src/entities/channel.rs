@@ -101,12 +101,13 @@ impl Channel {
 				name,
 				nsfw: Some(nsfw),
 				guild_id,
+				parent_id,
 				..Default::default()
 			},
 			..Default::default()
 		};
 
-		sqlx::query("INSERT INTO channels (id, type, name, nsfw, guild_id, parent_id, flags, permission_overwrites, default_thread_rate_limit_per_user, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, NOW())")
+		sqlx::query("INSERT INTO channels (id, type, name, nsfw, guild_id, parent_id, flags, permission_overwrites, default_thread_rate_limit_per_user, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, NOW())")
             .bind(channel.id)
             .bind(channel.channel_type)
             .bind(&channel.name)
@@ -137,7 +138,7 @@ impl Channel {
 
 		unique_recipients.push(creator_id);
 
-		let channel = Channel::create(
+		let mut channel = Channel::create(
 			db,
 			dm_type,
 			name,
@@ -152,10 +153,18 @@ impl Channel {
 		)
 		.await?;
 
-		for recipient in unique_recipients {
-			Recipient::create(db, recipient, channel.id).await?;
+		if channel.channel_type == ChannelType::GroupDm {
+			// The creator of a group DM owns it, and may manage its recipients
+			sqlx::query("UPDATE channels SET owner_id = $1 WHERE id = $2")
+				.bind(creator_id)
+				.bind(channel.id)
+				.execute(db)
+				.await?;
+			channel.owner_id = Some(creator_id);
+		}
 
-			// TODO: Emit event 'CHANNEL_CREATE'
+		for recipient in unique_recipients {
+			Recipient::create(db, channel.id, recipient).await?;
 		}
 
 		Ok(channel)
@@ -175,7 +184,7 @@ impl Channel {
 	}
 
 	pub async fn get_by_id(db: &PgPool, id: Snowflake) -> Result<Option<Self>, Error> {
-		sqlx::query_as("SELECT * FROM channels WHERE id = ?")
+		sqlx::query_as("SELECT * FROM channels WHERE id = $1")
 			.bind(id)
 			.fetch_optional(db)
 			.await
@@ -183,7 +192,7 @@ impl Channel {
 	}
 
 	pub async fn get_by_guild_id(db: &PgPool, guild_id: Snowflake) -> Result<Vec<Self>, Error> {
-		sqlx::query_as("SELECT * FROM channels WHERE guild_id = ?")
+		sqlx::query_as("SELECT * FROM channels WHERE guild_id = $1")
 			.bind(guild_id)
 			.fetch_all(db)
 			.await
@@ -248,7 +257,7 @@ impl Channel {
 	}
 
 	pub async fn delete(&self, db: &PgPool) -> Result<(), Error> {
-		sqlx::query("DELETE FROM channels WHERE id = ?")
+		sqlx::query("DELETE FROM channels WHERE id = $1")
 			.bind(self.id)
 			.execute(db)
 			.await
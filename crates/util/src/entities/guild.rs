@@ -2,7 +2,10 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::ops::{Deref, DerefMut};
+use std::{
+	collections::HashMap,
+	ops::{Deref, DerefMut},
+};
 
 use chorus::types::{
 	ChannelType, NSFWLevel, PermissionFlags, PremiumTier, Snowflake, SystemChannelFlags,
@@ -10,13 +13,14 @@ use chorus::types::{
 };
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, PgPool, QueryBuilder, Row};
-use sqlx_pg_uint::PgU16;
+use sqlx_pg_uint::{PgU16, PgU32};
 
 use super::*;
 use crate::{
 	SharedEventPublisherMap,
 	entities::{Channel, Config, Emoji, GuildMember, GuildTemplate, Invite, Role, Sticker, User},
 	errors::{Error, GuildError, UserError},
+	gateway::{SharedRoleUserMap, role_user_map::RoleUserMapChange},
 };
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize, sqlx::FromRow)]
@@ -52,6 +56,7 @@ impl Guild {
 	pub async fn create(
 		db: &PgPool,
 		shared_event_publisher_map: SharedEventPublisherMap,
+		role_user_map: &SharedRoleUserMap,
 		cfg: &Config,
 		name: &str,
 		icon: Option<String>,
@@ -90,7 +95,7 @@ impl Guild {
 		};
 		shared_event_publisher_map.write().insert(guild.id, guild.publisher.clone());
 
-		sqlx::query("INSERT INTO guilds (id, afk_timeout, default_message_notifications, explicit_content_filter, features, icon, max_members, max_presences, max_video_channel_users, name, owner_id, region, system_channel_flags, preferred_locale, welcome_screen, large, premium_tier, unavailable, widget_enabled, nsfw) VALUES (?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,0,?,0,0,?)")
+		sqlx::query("INSERT INTO guilds (id, afk_timeout, default_message_notifications, explicit_content_filter, features, icon, max_members, max_presences, max_video_channel_users, name, owner_id, region, system_channel_flags, preferred_locale, welcome_screen, large, premium_tier, unavailable, widget_enabled, nsfw) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, false, $16, false, false, $17)")
             .bind(guild.id)
             .bind(guild.afk_timeout)
             .bind(guild.default_message_notifications)
@@ -115,6 +120,7 @@ impl Guild {
 		let everyone = Role::create(
 			db,
 			shared_event_publisher_map,
+			role_user_map,
 			Some(guild.id),
 			guild.id,
 			"@everyone",
@@ -132,7 +138,7 @@ impl Guild {
 
 		let user = User::get_by_id(db, owner_id).await?.unwrap();
 
-		user.add_to_guild(db, guild.id).await?;
+		user.add_to_guild(db, role_user_map, guild.id).await?;
 		guild.owner = Some(true);
 
 		guild.roles = vec![everyone.into_inner()];
@@ -186,6 +192,7 @@ impl Guild {
 		db: &PgPool,
 		cfg: &Config,
 		shared_event_publisher_map: SharedEventPublisherMap,
+		role_user_map: &SharedRoleUserMap,
 		owner_id: Snowflake,
 		template: &GuildTemplate,
 		name: &str,
@@ -194,11 +201,21 @@ impl Guild {
 			return Err(Error::Guild(GuildError::NoSourceGuild));
 		};
 
-		Self::create(db, shared_event_publisher_map, cfg, name, None, owner_id, &g.channels).await
+		Self::create(
+			db,
+			shared_event_publisher_map,
+			role_user_map,
+			cfg,
+			name,
+			None,
+			owner_id,
+			&g.channels,
+		)
+		.await
 	}
 
 	pub async fn get_by_id(db: &PgPool, id: Snowflake) -> Result<Option<Self>, Error> {
-		sqlx::query_as("SELECT * FROM guilds WHERE id = ?")
+		sqlx::query_as("SELECT * FROM guilds WHERE id = $1")
 			.bind(id)
 			.fetch_optional(db)
 			.await
@@ -223,13 +240,12 @@ impl Guild {
 	}
 
 	pub async fn has_member(&self, db: &PgPool, user_id: Snowflake) -> Result<bool, Error> {
-		sqlx::query_as("SELECT * FROM guild_members WHERE guild_id = ? AND user_id =?")
+		sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM members WHERE guild_id = $1 AND id = $2)")
 			.bind(self.id)
 			.bind(user_id)
-			.fetch_optional(db)
+			.fetch_one(db)
 			.await
 			.map_err(Error::Sqlx)
-			.map(|r: Option<GuildMember>| r.is_some())
 	}
 
 	pub async fn get_role(&self, db: &PgPool, id: Snowflake) -> Result<Option<Role>, Error> {
@@ -280,11 +296,16 @@ impl Guild {
 	}
 
 	/// Shorthand for `GuildMember::create()`
-	pub async fn add_member(&self, db: &PgPool, user_id: Snowflake) -> Result<(), Error> {
+	pub async fn add_member(
+		&self,
+		db: &PgPool,
+		role_user_map: &SharedRoleUserMap,
+		user_id: Snowflake,
+	) -> Result<(), Error> {
 		let user =
 			User::get_by_id(db, user_id).await?.ok_or(Error::User(UserError::InvalidUser))?;
 
-		let member = GuildMember::create(db, &user, self).await?;
+		let member = GuildMember::create(db, role_user_map, &user, self).await?;
 
 		Ok(())
 	}
@@ -300,40 +321,36 @@ impl Guild {
 		let cutoff = now - chrono::Duration::days(days as i64);
 		let min_snowflake = Snowflake::from(cutoff.and_utc().timestamp() as u64);
 
-		if roles.is_empty() {
-			sqlx::query_as("SELECT gm.* FROM guild_members gm JOIN member_roles mr ON gm.index = mr.index JOIN roles r ON r.id = mr.role_id WHERE gm.guild_id = ? AND (gm.last_message_id < ? OR gm.last_message_id IS NULL) AND r.position < ?")
-                .bind(self.id)
-                .bind(min_snowflake)
-                .bind(highest_role)
-                .fetch_all(db)
-                .await
-                .map_err(Error::Sqlx)
-		} else {
-			let mut builder = QueryBuilder::new(
-				"SELECT gm.* FROM members gm JOIN member_roles mr ON gm.index = mr.index JOIN roles r ON r.id = mr.role_id WHERE gm.guild_id = ? AND (gm.last_message_id < ? OR gm.last_message_id IS NULL) AND r.position < ? AND mr.role_id IN (",
-			);
+		// Members are only pruned if all of their roles are below `highest_role`,
+		// and the owner is never pruned.
+		let mut builder = QueryBuilder::new("SELECT gm.* FROM members gm WHERE gm.guild_id = ");
+		builder.push_bind(self.id);
+		builder.push(" AND (gm.last_message_id < ");
+		builder.push_bind(min_snowflake);
+		builder.push(" OR gm.last_message_id IS NULL) AND gm.id IS DISTINCT FROM ");
+		builder.push_bind(self.owner_id);
+		builder.push(
+			" AND NOT EXISTS (SELECT 1 FROM member_roles mr JOIN roles r ON r.id = mr.role_id WHERE mr.index = gm.index AND r.position >= ",
+		);
+		builder.push_bind(highest_role);
+		builder.push(")");
 
+		if !roles.is_empty() {
+			builder.push(
+				" AND EXISTS (SELECT 1 FROM member_roles mr WHERE mr.index = gm.index AND mr.role_id IN (",
+			);
 			let mut separated = builder.separated(", ");
 			for role in &roles {
 				separated.push_bind(role);
 			}
-			separated.push_unseparated(") ");
-
-			let query = builder.build();
-			Ok(query
-				.bind(self.id)
-				.bind(min_snowflake)
-				.bind(highest_role)
-				.fetch_all(db)
-				.await?
-				.iter_mut()
-				.flat_map(|row| GuildMember::from_row(row))
-				.collect())
+			separated.push_unseparated("))");
 		}
+
+		builder.build_query_as().fetch_all(db).await.map_err(Error::Sqlx)
 	}
 
 	pub async fn save(&self, db: &PgPool) -> Result<(), Error> {
-		sqlx::query("UPDATE guilds SET afk_timeout =?, default_message_notifications =?, explicit_content_filter =?, features =?, icon =?, max_members =?, max_presences =?, max_video_channel_users =?, name =?, owner_id =?, region =?, system_channel_flags =?, preferred_locale =?, welcome_screen =?, large =?, premium_tier =?, unavailable =?, widget_enabled =?, nsfw =?, public_updates_channel_id =?, rules_channel_id =? WHERE id =?")
+		sqlx::query("UPDATE guilds SET afk_timeout = $1, default_message_notifications = $2, explicit_content_filter = $3, features = $4, icon = $5, max_members = $6, max_presences = $7, max_video_channel_users = $8, name = $9, owner_id = $10, region = $11, system_channel_flags = $12, preferred_locale = $13, welcome_screen = $14, large = $15, premium_tier = $16, unavailable = $17, widget_enabled = $18, nsfw = $19, public_updates_channel_id = $20, rules_channel_id = $21 WHERE id = $22")
             .bind(self.afk_timeout)
             .bind(self.default_message_notifications)
             .bind(self.explicit_content_filter)
@@ -361,13 +378,19 @@ impl Guild {
             .map_err(Error::Sqlx)
 	}
 
-	pub async fn delete(self, db: &PgPool) -> Result<(), Error> {
-		sqlx::query("DELETE FROM guilds WHERE id =?")
-			.bind(self.id)
-			.execute(db)
-			.await
-			.map_err(Error::Sqlx)
-			.map(|_| ())
+	/// Delete the guild, along with its roles and members.
+	pub async fn delete(self, db: &PgPool, role_user_map: &SharedRoleUserMap) -> Result<(), Error> {
+		let mut tx = db.begin().await?;
+		let role_ids: Vec<Snowflake> =
+			sqlx::query_scalar("SELECT id FROM roles WHERE guild_id = $1")
+				.bind(self.id)
+				.fetch_all(&mut *tx)
+				.await?;
+		sqlx::query("DELETE FROM guilds WHERE id = $1").bind(self.id).execute(&mut *tx).await?;
+		tx.commit().await?;
+
+		role_user_map.apply(role_ids.into_iter().map(RoleUserMapChange::RemoveRole)).await;
+		Ok(())
 	}
 
 	pub async fn search_members(
@@ -427,7 +450,7 @@ impl GuildBan {
 			.await?
 			.ok_or(Error::Guild(GuildError::MemberNotFound))?;
 
-		sqlx::query("INSERT INTO guild_bans (id, guild_id, user_id, executor_id, reason, ip) VALUES (?,?,?,?,?,'127.0.0.1')") // TODO: Do something to get the users IP
+		sqlx::query("INSERT INTO bans (id, guild_id, user_id, executor_id, reason, ip) VALUES ($1, $2, $3, $4, $5, '127.0.0.1')") // TODO: Do something to get the users IP
             .bind(ban_id)
             .bind(guild_id)
             .bind(user_id)
@@ -454,8 +477,11 @@ impl GuildBan {
 		executing_user_id: Snowflake,
 		reason: impl Into<Option<String>>,
 	) -> Result<Vec<GuildBan>, Error> {
+		if user_ids.is_empty() {
+			return Ok(Vec::new());
+		}
 		let mut query_builder = QueryBuilder::new(
-			"INSERT INTO guild_bans (id, guild_id, user_id, executor_id, reason, ip) VALUES ",
+			"INSERT INTO bans (id, guild_id, user_id, executor_id, reason, ip) VALUES ",
 		);
 		let mut rows = user_ids
 			.into_iter()
@@ -484,11 +510,15 @@ impl GuildBan {
 
 		query.execute(db).await?;
 
+		let user_ids = rows.iter().map(|row| row.user_id).collect::<Vec<_>>();
+		let users = User::get_by_id_list(db, &user_ids, None, PgU32::from(user_ids.len() as u32))
+			.await?
+			.into_iter()
+			.map(|user| (user.id, user.to_public_user()))
+			.collect::<HashMap<_, _>>();
 		for row in rows.iter_mut() {
-			row.user = User::get_by_id(db, row.user_id)
-				.await?
-				.ok_or(Error::User(UserError::InvalidUser))?
-				.to_public_user();
+			row.user =
+				users.get(&row.user_id).cloned().ok_or(Error::User(UserError::InvalidUser))?;
 		}
 
 		Ok(rows)
@@ -502,7 +532,7 @@ impl GuildBan {
 	}
 
 	pub async fn get_by_id(db: &PgPool, id: Snowflake) -> Result<Option<GuildBan>, Error> {
-		sqlx::query_as("SELECT * FROM guild_bans WHERE id = ?")
+		sqlx::query_as("SELECT * FROM bans WHERE id = $1")
 			.bind(id)
 			.fetch_optional(db)
 			.await
@@ -517,11 +547,9 @@ impl GuildBan {
 		limit: Option<u16>,
 	) -> Result<Vec<GuildBan>, Error> {
 		let limit = limit.map(PgU16::from);
-		sqlx::query_as("SELECT * FROM bans WHERE (user_id < ? OR ? IS NULL) AND (user_id > ? OR ? IS NULL) AND guild_id = ? LIMIT IFNULL(?, 1000)")
-            .bind(before)
+		sqlx::query_as("SELECT * FROM bans WHERE (user_id < $1 OR $1 IS NULL) AND (user_id > $2 OR $2 IS NULL) AND guild_id = $3 ORDER BY user_id LIMIT COALESCE($4, 1000)")
             .bind(before)
             .bind(after)
-            .bind(after)
             .bind(guild_id)
             .bind(limit)
             .fetch_all(db)
@@ -534,7 +562,7 @@ impl GuildBan {
 		guild_id: Snowflake,
 		user_id: Snowflake,
 	) -> Result<Option<Self>, Error> {
-		sqlx::query_as("SELECT * FROM guild_bans WHERE user_id = ? AND guild_id = ?")
+		sqlx::query_as("SELECT * FROM bans WHERE user_id = $1 AND guild_id = $2")
 			.bind(user_id)
 			.bind(guild_id)
 			.fetch_optional(db)
@@ -548,7 +576,7 @@ impl GuildBan {
 		search_term: &str,
 		limit: PgU16,
 	) -> Result<Vec<Self>, Error> {
-		sqlx::query_as("SELECT b.* FROM bans b JOIN members m ON b.user_id = m.id AND b.guild_id = m.guild_id JOIN users u ON b.user_id = u.id WHERE u.username LIKE ? AND b.guild_id = ? LIMIT ?")
+		sqlx::query_as("SELECT b.* FROM bans b JOIN users u ON b.user_id = u.id WHERE u.username LIKE $1 AND b.guild_id = $2 LIMIT $3")
             .bind(format!("%{}%", search_term))
             .bind(guild_id)
             .bind(limit)
@@ -558,7 +586,7 @@ impl GuildBan {
 	}
 
 	pub async fn delete(self, db: &PgPool) -> Result<(), Error> {
-		sqlx::query("DELETE FROM guild_bans WHERE id = ?")
+		sqlx::query("DELETE FROM bans WHERE id = $1")
 			.bind(self.id)
 			.execute(db)
 			.await
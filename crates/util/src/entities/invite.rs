@@ -5,7 +5,7 @@
 use std::ops::{Deref, DerefMut};
 
 use chorus::types::{CreateChannelInviteSchema, InviteType, Snowflake};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
@@ -14,6 +14,7 @@ use sqlx_pg_uint::{PgU8, PgU32};
 use crate::{
 	entities::{Channel, Guild, User},
 	errors::{ChannelError, Error, GuildError},
+	gateway::SharedRoleUserMap,
 };
 
 #[derive(Debug, Deserialize, Serialize, sqlx::FromRow)]
@@ -102,7 +103,7 @@ impl Invite {
 		code, type, temporary, uses, max_uses, max_age, created_at, expires_at, guild_id, channel_id, inviter_id, target_user_id, target_user_type, vanity_url, flags
 		 */
 
-		sqlx::query("INSERT INTO invites (code, type, temporary, uses, max_uses, max_age, created_at, expires_at, guild_id, channel_id, inviter_id, target_user_id, target_user_type, vanity_url, flags) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+		sqlx::query("INSERT INTO invites (code, type, temporary, uses, max_uses, max_age, created_at, expires_at, guild_id, channel_id, inviter_id, target_user_id, target_user_type, vanity_url, flags) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)")
             .bind(random_code)
             .bind(invite.invite_type)
             .bind(invite.temporary)
@@ -143,7 +144,7 @@ impl Invite {
 			vanity_url: Some(true),
 		};
 
-		sqlx::query("INSERT INTO invites (code, type, temporary, uses, max_uses, max_age, created_at, expires_at, guild_id, channel_id, inviter_id, target_user_id, target_user_type, vanity_url, flags) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+		sqlx::query("INSERT INTO invites (code, type, temporary, uses, max_uses, max_age, created_at, expires_at, guild_id, channel_id, inviter_id, target_user_id, target_user_type, vanity_url, flags) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)")
             .bind(code)
             .bind(invite.invite_type)
             .bind(invite.temporary)
@@ -166,7 +167,7 @@ impl Invite {
 	}
 
 	pub async fn get_by_code(db: &PgPool, code: &str) -> Result<Option<Self>, Error> {
-		let invite: Option<Self> = sqlx::query_as("SELECT * FROM invites WHERE code = ?")
+		let invite: Option<Self> = sqlx::query_as("SELECT * FROM invites WHERE code = $1")
 			.bind(code)
 			.fetch_optional(db)
 			.await
@@ -179,7 +180,7 @@ impl Invite {
 		let guild =
 			Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
 
-		let mut invites = sqlx::query_as("SELECT * FROM invites WHERE guild_id = ?")
+		let mut invites = sqlx::query_as("SELECT * FROM invites WHERE guild_id = $1")
 			.bind(guild_id)
 			.fetch_all(db)
 			.await
@@ -196,7 +197,7 @@ impl Invite {
 		db: &PgPool,
 		guild_id: Snowflake,
 	) -> Result<Option<Self>, Error> {
-		sqlx::query_as("SELECT * FROM invites WHERE guild_id = ? AND vanity_url = 1")
+		sqlx::query_as("SELECT * FROM invites WHERE guild_id = $1 AND vanity_url")
 			.bind(guild_id)
 			.fetch_optional(db)
 			.await
@@ -204,7 +205,7 @@ impl Invite {
 	}
 
 	pub async fn get_by_channel(db: &PgPool, channel_id: Snowflake) -> Result<Vec<Self>, Error> {
-		sqlx::query_as("SELECT * FROM invites WHERE channel_id = ?")
+		sqlx::query_as("SELECT * FROM invites WHERE channel_id = $1")
 			.bind(channel_id)
 			.fetch_all(db)
 			.await
@@ -212,7 +213,7 @@ impl Invite {
 	}
 
 	pub async fn delete(&self, db: &PgPool) -> Result<(), Error> {
-		sqlx::query("DELETE FROM invites WHERE code = ?")
+		sqlx::query("DELETE FROM invites WHERE code = $1")
 			.bind(&self.code)
 			.execute(db)
 			.await
@@ -220,13 +221,19 @@ impl Invite {
 			.map_err(Error::Sqlx)
 	}
 
-	pub async fn join(&mut self, db: &PgPool, user: &User) -> Result<(), Error> {
+	pub async fn join(
+		&mut self,
+		db: &PgPool,
+		role_user_map: &SharedRoleUserMap,
+		user: &User,
+	) -> Result<(), Error> {
 		if let Some(invite_type) = self.invite_type {
 			match invite_type {
 				InviteType::Guild => {
 					// TODO: Track what invite code a user used?
 					user.add_to_guild(
 						db,
+						role_user_map,
 						self.guild_id.ok_or(Error::Guild(GuildError::InvalidGuild))?,
 					)
 					.await?;
@@ -248,9 +255,18 @@ impl Invite {
 		Ok(())
 	}
 
+	/// Whether the invite can still be joined at `now`, i.e. it has neither
+	/// expired nor been used up.
+	pub fn is_usable(&self, now: DateTime<Utc>) -> bool {
+		let expired = self.expires_at.is_some_and(|expires_at| expires_at <= now);
+		let max_uses = self.max_uses.clone().map_or(0, |max_uses| max_uses.to_uint() as u32);
+		let uses = self.uses.clone().map_or(0, |uses| uses.to_uint());
+		!expired && (max_uses == 0 || uses < max_uses)
+	}
+
 	pub async fn increase_uses(&mut self, db: &PgPool) -> Result<(), Error> {
 		self.uses = self.uses.as_mut().map(|uses| PgU32::from(uses.to_uint() + 1));
-		sqlx::query("UPDATE invites SET uses = ? WHERE code = ?")
+		sqlx::query("UPDATE invites SET uses = $1 WHERE code = $2")
 			.bind(&self.uses)
 			.bind(&self.code)
 			.execute(db)
@@ -273,7 +289,7 @@ impl Invite {
 	}
 
 	pub async fn set_code(&mut self, db: &PgPool, code: &str) -> Result<(), Error> {
-		sqlx::query("UPDATE invites SET code = ? WHERE code = ?")
+		sqlx::query("UPDATE invites SET code = $1 WHERE code = $2")
 			.bind(code)
 			.bind(&self.code)
 			.execute(db)
@@ -284,8 +300,7 @@ impl Invite {
 	}
 
 	pub async fn save(&self, db: &PgPool) -> Result<(), Error> {
-		sqlx::query("UPDATE invites SET type = ?, temporary = ?, uses = ?, max_uses = ?, max_age = ?, created_at = ?, expires_at = ?, guild_id = ?, channel_id = ?, inviter_id = ?, target_user_id = ?, target_user_type = ?, vanity_url = ?, flags = ? WHERE code = ?")
-            .bind(&self.code)
+		sqlx::query("UPDATE invites SET type = $1, temporary = $2, uses = $3, max_uses = $4, max_age = $5, created_at = $6, expires_at = $7, guild_id = $8, channel_id = $9, inviter_id = $10, target_user_id = $11, target_user_type = $12, vanity_url = $13, flags = $14 WHERE code = $15")
             .bind(self.invite_type)
             .bind(self.temporary)
             .bind(self.uses.as_ref().unwrap_or(&0.into()))
@@ -296,6 +311,10 @@ impl Invite {
             .bind(self.guild_id)
             .bind(self.channel_id)
             .bind(self.inviter_id)
+            .bind(self.target_user_id)
+            .bind(self.target_type)
+            .bind(self.vanity_url)
+            .bind(self.flags)
             .bind(&self.code)
             .execute(db)
             .await
@@ -307,3 +326,34 @@ impl Invite {
 		self.inner
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn invite(expires_at: Option<DateTime<Utc>>, uses: u32, max_uses: Option<u8>) -> Invite {
+		Invite {
+			inner: chorus::types::Invite {
+				expires_at,
+				uses: Some(PgU32::from(uses)),
+				max_uses: max_uses.map(PgU8::from),
+				..Default::default()
+			},
+			channel_id: None,
+			inviter_id: None,
+			target_user_id: None,
+			vanity_url: None,
+		}
+	}
+
+	#[test]
+	fn expired_and_used_up_invites_are_unusable() {
+		let now = Utc::now();
+		assert!(invite(None, 100, None).is_usable(now));
+		assert!(invite(None, 100, Some(0)).is_usable(now));
+		assert!(invite(Some(now + chrono::Duration::hours(1)), 4, Some(5)).is_usable(now));
+
+		assert!(!invite(Some(now), 0, None).is_usable(now));
+		assert!(!invite(None, 5, Some(5)).is_usable(now));
+	}
+}
@@ -2,16 +2,23 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::ops::{Deref, DerefMut};
+use std::{
+	collections::HashMap,
+	ops::{Deref, DerefMut},
+};
 
 use chorus::types::{Snowflake, UserGuildSettingsUpdate};
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, Row};
-use sqlx_pg_uint::{PgU16, PgU64};
+use sqlx_pg_uint::{PgU16, PgU32, PgU64};
 
 use crate::{
 	entities::{Guild, User},
 	errors::{Error, GuildError, UserError},
+	gateway::{
+		SharedRoleUserMap,
+		role_user_map::{RoleAssignment, RoleUserMapChange},
+	},
 };
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, FromRow)]
@@ -42,7 +49,13 @@ impl DerefMut for GuildMember {
 }
 
 impl GuildMember {
-	pub async fn create(db: &sqlx::PgPool, user: &User, guild: &Guild) -> Result<Self, Error> {
+	/// Add `user` to `guild`, giving them the `@everyone` role.
+	pub async fn create(
+		db: &sqlx::PgPool,
+		role_user_map: &SharedRoleUserMap,
+		user: &User,
+		guild: &Guild,
+	) -> Result<Self, Error> {
 		// TODO: check if user is banned
 		// TODO: Check max guild count
 
@@ -73,12 +86,13 @@ impl GuildMember {
 			last_message_id: None,
 		};
 
-		let res = sqlx::query("INSERT INTO members (id, guild_id, joined_at, deaf, mute, pending, settings, bio) VALUES (?, ?, NOW(), 0, 0, 0, ?, ?) RETURNING members_index_seq")
+		let mut tx = db.begin().await?;
+		let res = sqlx::query("INSERT INTO members (id, guild_id, joined_at, deaf, mute, pending, settings, bio) VALUES ($1, $2, NOW(), false, false, false, $3, $4) RETURNING index")
             .bind(user.id)
             .bind(guild.id)
             .bind(sqlx::types::Json(UserGuildSettingsUpdate::default()))
             .bind(user.bio.clone().unwrap_or_default())
-            .fetch_one(db)
+            .fetch_one(&mut *tx)
             .await
             .map_err(Error::from)?;
 
@@ -86,11 +100,19 @@ impl GuildMember {
 
 		member.index = index.clone();
 
-		sqlx::query("INSERT INTO member_roles (index, role_id) VALUES (?,?)")
+		sqlx::query("INSERT INTO member_roles (index, role_id) VALUES ($1, $2)")
 			.bind(index)
 			.bind(guild.id)
-			.execute(db)
+			.execute(&mut *tx)
 			.await?;
+		tx.commit().await?;
+
+		role_user_map
+			.apply([RoleUserMapChange::Assign(RoleAssignment {
+				role_id: guild.id,
+				user_id: user.id,
+			})])
+			.await;
 
 		Ok(member)
 	}
@@ -101,7 +123,7 @@ impl GuildMember {
 		guild_id: Snowflake,
 	) -> Result<Option<Self>, Error> {
 		let mut member: Self =
-			sqlx::query_as("SELECT * FROM members WHERE id = ? AND guild_id = ?")
+			sqlx::query_as("SELECT * FROM members WHERE id = $1 AND guild_id = $2")
 				.bind(id)
 				.bind(guild_id)
 				.fetch_optional(db)
@@ -118,6 +140,8 @@ impl GuildMember {
 		Ok(Some(member))
 	}
 
+	/// Retrieve up to `limit` members of a guild, ordered by their user id and
+	/// starting after the user id `after`.
 	pub async fn get_by_guild_id(
 		db: &sqlx::PgPool,
 		guild_id: Snowflake,
@@ -125,21 +149,76 @@ impl GuildMember {
 		after: Option<Snowflake>,
 	) -> Result<Vec<Self>, Error> {
 		let limit = PgU16::from(limit);
-		sqlx::query_as("SELECT * FROM members WHERE guild_id = ? WHERE id > IFNULL(?, 0) LIMIT ?")
+		sqlx::query_as("SELECT * FROM members WHERE guild_id = $1 AND id > $2 ORDER BY id LIMIT $3")
 			.bind(guild_id)
+			.bind(after.unwrap_or(Snowflake(0)))
 			.bind(limit)
-			.bind(after)
 			.fetch_all(db)
 			.await
 			.map_err(Error::from)
 	}
 
+	/// Like [Self::get_by_guild_id], with the users of the members populated.
+	/// The users of all members are loaded at once, with a single query.
+	pub async fn get_by_guild_id_with_users(
+		db: &sqlx::PgPool,
+		guild_id: Snowflake,
+		limit: u16,
+		after: Option<Snowflake>,
+	) -> Result<Vec<Self>, Error> {
+		let members = Self::get_by_guild_id(db, guild_id, limit, after).await?;
+		Self::with_users(db, members).await
+	}
+
+	/// The members of a guild among the users `user_ids`, with their users
+	/// populated. Users who are not members of the guild are skipped. The
+	/// members and their users are loaded with one query each.
+	pub async fn get_by_user_ids(
+		db: &sqlx::PgPool,
+		guild_id: Snowflake,
+		user_ids: &[Snowflake],
+	) -> Result<Vec<Self>, Error> {
+		if user_ids.is_empty() {
+			return Ok(Vec::new());
+		}
+		let mut query_builder = sqlx::QueryBuilder::new("SELECT * FROM members WHERE guild_id = ");
+		query_builder.push_bind(guild_id);
+		query_builder.push(" AND id IN (");
+		let mut separated = query_builder.separated(", ");
+		for user_id in user_ids {
+			separated.push_bind(user_id);
+		}
+		separated.push_unseparated(") ORDER BY id");
+		let members = query_builder.build_query_as::<Self>().fetch_all(db).await?;
+		Self::with_users(db, members).await
+	}
+
+	/// Populate the users of `members`, loading all of them with one query.
+	async fn with_users(db: &sqlx::PgPool, members: Vec<Self>) -> Result<Vec<Self>, Error> {
+		let user_ids = members.iter().map(|member| member.id).collect::<Vec<_>>();
+		let mut users: HashMap<Snowflake, User> =
+			User::get_by_id_list(db, &user_ids, None, PgU32::from(user_ids.len() as u32))
+				.await?
+				.into_iter()
+				.map(|user| (user.id, user))
+				.collect();
+		members
+			.into_iter()
+			.map(|mut member| {
+				let user = users.remove(&member.id).ok_or(Error::User(UserError::InvalidUser))?;
+				member.user = Some(user.to_public_user());
+				member.user_data = user;
+				Ok(member)
+			})
+			.collect()
+	}
+
 	pub async fn get_by_role_id(
 		db: &sqlx::PgPool,
 		guild_id: Snowflake,
 		role_id: Snowflake,
 	) -> Result<Vec<Self>, Error> {
-		sqlx::query_as("SELECT gm.* FROM members gm JOIN member_roles mr ON mr.index = gm.index WHERE mr.role_id = ? AND gm.guild_id = ?")
+		sqlx::query_as("SELECT gm.* FROM members gm JOIN member_roles mr ON mr.index = gm.index WHERE mr.role_id = $1 AND gm.guild_id = $2")
             .bind(role_id)
             .bind(guild_id)
             .fetch_all(db)
@@ -148,13 +227,15 @@ impl GuildMember {
 	}
 
 	pub async fn get_by_user_id(db: &sqlx::PgPool, user_id: Snowflake) -> Result<Vec<Self>, Error> {
-		sqlx::query_as("SELECT * FROM members WHERE id = ?")
+		sqlx::query_as("SELECT * FROM members WHERE id = $1")
 			.bind(user_id)
 			.fetch_all(db)
 			.await
 			.map_err(Error::from)
 	}
 
+	/// Search for up to `limit` members of a guild whose nickname or username
+	/// starts with `query`, ignoring case.
 	pub async fn search(
 		db: &sqlx::PgPool,
 		guild_id: Snowflake,
@@ -162,20 +243,61 @@ impl GuildMember {
 		limit: u16,
 	) -> Result<Vec<Self>, Error> {
 		let limit = PgU16::from(limit);
-		let mut members: Vec<Self> =
-			sqlx::query_as("SELECT * FROM members WHERE guild_id = ? AND name LIKE ? LIMIT ?")
-				.bind(guild_id)
-				.bind(format!("%{}%", query))
-				.bind(limit)
-				.fetch_all(db)
-				.await
-				.map_err(Error::from)?;
+		let pattern =
+			format!("{}%", query.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+		let members: Vec<Self> = sqlx::query_as(
+			"SELECT m.* FROM members m JOIN users u ON u.id = m.id WHERE m.guild_id = $1 AND (m.nick ILIKE $2 OR u.username ILIKE $2) ORDER BY m.id LIMIT $3",
+		)
+		.bind(guild_id)
+		.bind(pattern)
+		.bind(limit)
+		.fetch_all(db)
+		.await
+		.map_err(Error::from)?;
+
+		Self::with_users(db, members).await
+	}
 
-		for member in members.iter_mut() {
-			member.populate_relations(db).await?;
-		}
+	/// Retrieve the user IDs of all members of a guild.
+	pub async fn get_member_ids(
+		db: &sqlx::PgPool,
+		guild_id: Snowflake,
+	) -> Result<Vec<Snowflake>, Error> {
+		sqlx::query_scalar("SELECT id FROM members WHERE guild_id = $1")
+			.bind(guild_id)
+			.fetch_all(db)
+			.await
+			.map_err(Error::from)
+	}
 
-		Ok(members)
+	/// Retrieve the members of all guilds the user `user_id` is a member of, as
+	/// pairs of guild ID and user ID. The user themselves is not included.
+	pub async fn get_co_member_ids(
+		db: &sqlx::PgPool,
+		user_id: Snowflake,
+	) -> Result<Vec<(Snowflake, Snowflake)>, Error> {
+		sqlx::query_as(
+			"SELECT guild_id, id FROM members WHERE guild_id IN (SELECT guild_id FROM members WHERE id = $1) AND id <> $1",
+		)
+		.bind(user_id)
+		.fetch_all(db)
+		.await
+		.map_err(Error::from)
+	}
+
+	/// Retrieve the roles of all members of a guild, as pairs of user ID and
+	/// role ID.
+	pub async fn get_role_ids_by_guild_id(
+		db: &sqlx::PgPool,
+		guild_id: Snowflake,
+	) -> Result<Vec<(Snowflake, Snowflake)>, Error> {
+		sqlx::query_as(
+			"SELECT m.id, mr.role_id FROM member_roles mr JOIN members m ON m.index = mr.index WHERE m.guild_id = $1",
+		)
+		.bind(guild_id)
+		.fetch_all(db)
+		.await
+		.map_err(Error::from)
 	}
 
 	pub async fn populate_relations(&mut self, db: &sqlx::PgPool) -> Result<(), Error> {
@@ -188,7 +310,7 @@ impl GuildMember {
 	}
 
 	pub async fn count(db: &sqlx::PgPool) -> Result<i32, Error> {
-		sqlx::query("SELECT COUNT(*) FROM members")
+		sqlx::query("SELECT COUNT(*)::int FROM members")
 			.fetch_one(db)
 			.await
 			.map_err(Error::from)
@@ -196,7 +318,7 @@ impl GuildMember {
 	}
 
 	pub async fn count_by_role(db: &sqlx::PgPool, role_id: Snowflake) -> Result<i32, Error> {
-		sqlx::query("SELECT COUNT(*) FROM member_roles WHERE role_id = ?")
+		sqlx::query("SELECT COUNT(*)::int FROM member_roles WHERE role_id = $1")
 			.bind(role_id)
 			.fetch_one(db)
 			.await
@@ -205,7 +327,7 @@ impl GuildMember {
 	}
 
 	pub async fn count_by_user_id(db: &sqlx::PgPool, user_id: Snowflake) -> Result<i32, Error> {
-		sqlx::query("SELECT COUNT(*) FROM members WHERE id = ?")
+		sqlx::query("SELECT COUNT(*)::int FROM members WHERE id = $1")
 			.bind(user_id)
 			.fetch_one(db)
 			.await
@@ -213,17 +335,35 @@ impl GuildMember {
 			.map_err(Error::from)
 	}
 
-	pub async fn delete(self, db: &sqlx::PgPool) -> Result<(), Error> {
-		sqlx::query("DELETE FROM members WHERE id =?")
+	/// Remove the member from their guild, along with all of their roles.
+	pub async fn delete(
+		self,
+		db: &sqlx::PgPool,
+		role_user_map: &SharedRoleUserMap,
+	) -> Result<(), Error> {
+		let mut tx = db.begin().await?;
+		let role_ids: Vec<Snowflake> =
+			sqlx::query_scalar("SELECT role_id FROM member_roles WHERE index = $1")
+				.bind(&self.index)
+				.fetch_all(&mut *tx)
+				.await?;
+		sqlx::query("DELETE FROM members WHERE id = $1 AND guild_id = $2")
 			.bind(self.id)
-			.execute(db)
-			.await
-			.map_err(Error::from)
-			.map(|_| ())
+			.bind(self.guild_id)
+			.execute(&mut *tx)
+			.await?;
+		tx.commit().await?;
+
+		role_user_map
+			.apply(role_ids.into_iter().map(|role_id| {
+				RoleUserMapChange::Unassign(RoleAssignment { role_id, user_id: self.id })
+			}))
+			.await;
+		Ok(())
 	}
 
 	pub async fn save(&self, db: &sqlx::PgPool) -> Result<(), Error> {
-		sqlx::query("UPDATE members SET settings = ?, nick = ?, deaf = ?, mute = ?, pending = ?, last_message_id = ?, avatar = ?, flags = ?, permissions = ? WHERE id = ?") //banner = ?, bio = ?, theme_colors = ?,
+		sqlx::query("UPDATE members SET settings = $1, nick = $2, deaf = $3, mute = $4, pending = $5, last_message_id = $6, avatar = $7, flags = $8, permissions = $9 WHERE id = $10 AND guild_id = $11") //banner = ?, bio = ?, theme_colors = ?,
             .bind(&self.settings)
             .bind(&self.nick)
             .bind(self.deaf)
@@ -237,6 +377,7 @@ impl GuildMember {
             .bind(self.flags)
             .bind(&self.permissions)
             .bind(self.id)
+            .bind(self.guild_id)
             .execute(db)
             .await
             .map(|_| ())
@@ -257,17 +398,38 @@ impl GuildMember {
 			.and_then(|r| r.ok_or(Error::User(UserError::InvalidUser)))
 	}
 
-	pub async fn add_role(&mut self, db: &sqlx::PgPool, role_id: Snowflake) -> Result<(), Error> {
+	pub async fn add_role(
+		&mut self,
+		db: &sqlx::PgPool,
+		role_user_map: &SharedRoleUserMap,
+		role_id: Snowflake,
+	) -> Result<(), Error> {
 		if self.roles.contains(&role_id) {
 			return Ok(());
 		}
 
-		self.roles.push(role_id);
-		sqlx::query("INSERT INTO member_roles (index, role_id) VALUES (?,?)")
-			.bind(&self.index)
+		let mut tx = db.begin().await?;
+		// The role has to belong to the guild of the member, and must not be
+		// deleted before the assignment is committed
+		sqlx::query("SELECT id FROM roles WHERE id = $1 AND guild_id = $2 FOR SHARE")
 			.bind(role_id)
-			.execute(db)
-			.await?;
+			.bind(self.guild_id)
+			.fetch_optional(&mut *tx)
+			.await?
+			.ok_or(Error::Guild(GuildError::RoleNotFound))?;
+		sqlx::query(
+			"INSERT INTO member_roles (index, role_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+		)
+		.bind(&self.index)
+		.bind(role_id)
+		.execute(&mut *tx)
+		.await?;
+		tx.commit().await?;
+
+		role_user_map
+			.apply([RoleUserMapChange::Assign(RoleAssignment { role_id, user_id: self.id })])
+			.await;
+		self.roles.push(role_id);
 
 		Ok(())
 	}
@@ -275,18 +437,25 @@ impl GuildMember {
 	pub async fn remove_role(
 		&mut self,
 		db: &sqlx::PgPool,
+		role_user_map: &SharedRoleUserMap,
 		role_id: Snowflake,
 	) -> Result<(), Error> {
 		if !self.roles.contains(&role_id) {
 			return Ok(());
 		}
 
-		self.roles.retain(|r| r != &role_id);
-		sqlx::query("DELETE FROM member_roles WHERE index =? AND role_id =?")
+		let mut tx = db.begin().await?;
+		sqlx::query("DELETE FROM member_roles WHERE index = $1 AND role_id = $2")
 			.bind(&self.index)
 			.bind(role_id)
-			.execute(db)
+			.execute(&mut *tx)
 			.await?;
+		tx.commit().await?;
+
+		role_user_map
+			.apply([RoleUserMapChange::Unassign(RoleAssignment { role_id, user_id: self.id })])
+			.await;
+		self.roles.retain(|r| r != &role_id);
 
 		Ok(())
 	}
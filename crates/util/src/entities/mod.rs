@@ -2,7 +2,9 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub use application::*;
 pub use audit_log::*;
+pub use backup_code::*;
 pub use channel::*;
 pub use config::*;
 pub use emoji::*;
@@ -14,8 +16,11 @@ pub use message::*;
 pub use note::*;
 pub use read_state::*;
 pub use recipient::*;
+pub use registration_token::*;
 pub use relationship::*;
 pub use role::*;
+pub use security_key::*;
+pub use session::*;
 pub use sticker::*;
 pub use user::*;
 pub use user_settings::*;
@@ -27,6 +32,7 @@ use crate::SharedEventPublisher;
 mod application;
 mod attachment;
 mod audit_log;
+mod backup_code;
 mod channel;
 mod config;
 mod emoji;
@@ -38,8 +44,11 @@ mod message;
 mod note;
 mod read_state;
 mod recipient;
+mod registration_token;
 mod relationship;
 mod role;
+mod security_key;
+mod session;
 mod sticker;
 mod template;
 mod user;
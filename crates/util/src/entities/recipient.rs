@@ -28,7 +28,7 @@ impl Recipient {
 		user_id: Snowflake,
 	) -> Result<Self, Error> {
 		let id = Snowflake::default();
-		sqlx::query("INSERT INTO recipients (id, channel_id, user_id) VALUES (?,?,?)")
+		sqlx::query("INSERT INTO recipients (id, channel_id, user_id) VALUES ($1, $2, $3)")
 			.bind(id)
 			.bind(channel_id)
 			.bind(user_id)
@@ -52,7 +52,7 @@ impl Recipient {
 	}
 
 	pub async fn get_by_id(db: &sqlx::PgPool, id: Snowflake) -> Result<Option<Self>, Error> {
-		sqlx::query_as("SELECT * FROM recipients WHERE id = ?")
+		sqlx::query_as("SELECT * FROM recipients WHERE id = $1")
 			.bind(id)
 			.fetch_optional(db)
 			.await
@@ -63,7 +63,7 @@ impl Recipient {
 		db: &sqlx::PgPool,
 		channel_id: Snowflake,
 	) -> Result<Vec<Self>, Error> {
-		sqlx::query_as("SELECT * FROM recipients WHERE channel_id = ?")
+		sqlx::query_as("SELECT * FROM recipients WHERE channel_id = $1")
 			.bind(channel_id)
 			.fetch_all(db)
 			.await
@@ -71,7 +71,7 @@ impl Recipient {
 	}
 
 	pub async fn get_by_user_id(db: &sqlx::PgPool, user_id: Snowflake) -> Result<Vec<Self>, Error> {
-		sqlx::query_as("SELECT * FROM recipients WHERE user_id = ?")
+		sqlx::query_as("SELECT * FROM recipients WHERE user_id = $1")
 			.bind(user_id)
 			.fetch_all(db)
 			.await
@@ -83,7 +83,7 @@ impl Recipient {
 		channel_id: Snowflake,
 		user_id: Snowflake,
 	) -> Result<Option<Self>, Error> {
-		sqlx::query_as("SELECT * FROM recipients WHERE channel_id = ? AND user_id = ?")
+		sqlx::query_as("SELECT * FROM recipients WHERE channel_id = $1 AND user_id = $2")
 			.bind(channel_id)
 			.bind(user_id)
 			.fetch_optional(db)
@@ -91,8 +91,19 @@ impl Recipient {
 			.map_err(Error::from)
 	}
 
+	/// Close the channel for this recipient, hiding it from their private
+	/// channels without leaving it.
+	pub async fn close(&mut self, db: &sqlx::PgPool) -> Result<(), Error> {
+		sqlx::query("UPDATE recipients SET closed = true WHERE id = $1")
+			.bind(self.id)
+			.execute(db)
+			.await?;
+		self.closed = true;
+		Ok(())
+	}
+
 	pub async fn delete(self, db: &sqlx::PgPool) -> Result<(), Error> {
-		sqlx::query("DELETE FROM recipients WHERE id = ?")
+		sqlx::query("DELETE FROM recipients WHERE id = $1")
 			.bind(self.id)
 			.execute(db)
 			.await
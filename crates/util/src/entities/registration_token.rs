@@ -0,0 +1,40 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use sqlx::{PgConnection, PgPool};
+
+use crate::errors::Error;
+
+/// A single-use token an administrator hands out to let someone register while
+/// new registrations are closed.
+pub struct RegistrationToken;
+
+impl RegistrationToken {
+	/// Whether `token` is a valid, unexpired token. The token stays valid, see
+	/// [RegistrationToken::consume].
+	pub async fn is_valid(db: &PgPool, token: &str) -> Result<bool, Error> {
+		sqlx::query_scalar(
+			"SELECT EXISTS (SELECT 1 FROM valid_registration_tokens WHERE token = $1 \
+			 AND expires_at > (NOW() AT TIME ZONE 'UTC'))",
+		)
+		.bind(token)
+		.fetch_one(db)
+		.await
+		.map_err(Error::Sqlx)
+	}
+
+	/// Remove `token`, and return whether it was a valid, unexpired token. Run
+	/// this in the transaction creating the account, so that the token is only
+	/// used up by a registration which succeeds.
+	pub async fn consume(conn: &mut PgConnection, token: &str) -> Result<bool, Error> {
+		let consumed: Option<String> = sqlx::query_scalar(
+			"DELETE FROM valid_registration_tokens WHERE token = $1 \
+			 AND expires_at > (NOW() AT TIME ZONE 'UTC') RETURNING token",
+		)
+		.bind(token)
+		.fetch_optional(conn)
+		.await?;
+		Ok(consumed.is_some())
+	}
+}
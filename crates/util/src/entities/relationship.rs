@@ -77,6 +77,15 @@ impl Relationship {
 			.await
 			.map_err(Error::from)
 	}
+
+	/// Retrieve the IDs of all friends of a user
+	pub async fn get_friend_ids(id: Snowflake, db: &PgPool) -> Result<Vec<Snowflake>, Error> {
+		sqlx::query_scalar("SELECT to_id from relationships WHERE from_id = $1 AND type = 1")
+			.bind(id)
+			.fetch_all(db)
+			.await
+			.map_err(Error::from)
+	}
 }
 
 // TODO: move to symfonia again
@@ -11,7 +11,11 @@ use sqlx::{PgPool, Row};
 use sqlx_pg_uint::PgU64;
 
 use super::*;
-use crate::{SharedEventPublisherMap, eq_shared_event_publisher, errors::Error};
+use crate::{
+	SharedEventPublisherMap, eq_shared_event_publisher,
+	errors::Error,
+	gateway::{SharedRoleUserMap, role_user_map::RoleUserMapChange},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Role {
@@ -49,6 +53,7 @@ impl Role {
 	pub async fn create(
 		db: &PgPool,
 		shared_event_publisher_map: SharedEventPublisherMap,
+		role_user_map: &SharedRoleUserMap,
 		id: Option<Snowflake>,
 		guild_id: Snowflake,
 		name: &str,
@@ -78,8 +83,7 @@ impl Role {
 			guild_id: guild_id.to_owned(),
 			publisher: SharedEventPublisher::default(),
 		};
-		shared_event_publisher_map.write().insert(role.id, role.publisher.clone());
-		sqlx::query("INSERT INTO roles (id, guild_id, name, color, hoist, managed, mentionable, permissions, position, icon, unicode_emoji) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+		sqlx::query("INSERT INTO roles (id, guild_id, name, color, hoist, managed, mentionable, permissions, position, icon, unicode_emoji) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)")
             .bind(role.id)
             .bind(role.guild_id)
             .bind(&role.name)
@@ -93,12 +97,14 @@ impl Role {
             .bind(&role.unicode_emoji)
             .execute(db)
             .await?;
+		shared_event_publisher_map.write().insert(role.id, role.publisher.clone());
+		role_user_map.apply([RoleUserMapChange::InsertRole(role.id)]).await;
 
 		Ok(role)
 	}
 
 	pub async fn get_by_id(db: &PgPool, id: Snowflake) -> Result<Option<Self>, Error> {
-		sqlx::query_as("SELECT * FROM roles WHERE id = ?")
+		sqlx::query_as("SELECT * FROM roles WHERE id = $1")
 			.bind(id)
 			.fetch_optional(db)
 			.await
@@ -106,7 +112,7 @@ impl Role {
 	}
 
 	pub async fn get_by_guild(db: &PgPool, guild_id: Snowflake) -> Result<Vec<Self>, Error> {
-		sqlx::query_as("SELECT * FROM roles WHERE guild_id = ?")
+		sqlx::query_as("SELECT * FROM roles WHERE guild_id = $1")
 			.bind(guild_id)
 			.fetch_all(db)
 			.await
@@ -150,7 +156,7 @@ impl Role {
 	}
 
 	pub async fn count_by_guild(db: &PgPool, guild_id: Snowflake) -> Result<i32, Error> {
-		sqlx::query("SELECT COUNT(*) FROM roles WHERE guild_id = ?")
+		sqlx::query("SELECT COUNT(*)::int FROM roles WHERE guild_id = $1")
 			.bind(guild_id)
 			.fetch_one(db)
 			.await
@@ -159,7 +165,7 @@ impl Role {
 	}
 
 	pub async fn save(&self, db: &PgPool) -> Result<(), Error> {
-		sqlx::query("UPDATE roles SET name = ?, color = ?, hoist = ?, managed = ?, mentionable = ?, permissions = ?, position = ?, icon = ?, unicode_emoji = ? WHERE id = ?")
+		sqlx::query("UPDATE roles SET name = $1, color = $2, hoist = $3, managed = $4, mentionable = $5, permissions = $6, position = $7, icon = $8, unicode_emoji = $9 WHERE id = $10")
             .bind(&self.name)
             .bind(self.color)
             .bind(self.hoist)
@@ -176,13 +182,22 @@ impl Role {
             .map_err(Error::Sqlx)
 	}
 
-	pub async fn delete(&self, db: &PgPool) -> Result<(), Error> {
-		sqlx::query("DELETE FROM roles WHERE id = ?")
+	/// Delete the role, taking it away from all members which have it.
+	pub async fn delete(
+		&self,
+		db: &PgPool,
+		role_user_map: &SharedRoleUserMap,
+	) -> Result<(), Error> {
+		let mut tx = db.begin().await?;
+		sqlx::query("DELETE FROM member_roles WHERE role_id = $1")
 			.bind(self.id)
-			.execute(db)
-			.await
-			.map(|_| ())
-			.map_err(Error::Sqlx)
+			.execute(&mut *tx)
+			.await?;
+		sqlx::query("DELETE FROM roles WHERE id = $1").bind(self.id).execute(&mut *tx).await?;
+		tx.commit().await?;
+
+		role_user_map.apply([RoleUserMapChange::RemoveRole(self.id)]).await;
+		Ok(())
 	}
 
 	pub fn into_inner(self) -> chorus::types::RoleObject {
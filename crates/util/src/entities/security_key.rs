@@ -0,0 +1,198 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chorus::types::Snowflake;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use sqlx::{PgPool, types::Json};
+use webauthn_rs::prelude::{self as webauthn, AuthenticationResult, CredentialID};
+
+use crate::{
+	errors::{Error, UserError},
+	util::{mfa::MFA_TICKET_LIFETIME, webauthn::check_counter},
+};
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+/// A WebAuthn security key a user registered as a second factor.
+pub struct SecurityKey {
+	pub id: Snowflake,
+	pub user_id: Snowflake,
+	/// The hex encoded credential id.
+	pub key_id: String,
+	pub credential: Json<webauthn::SecurityKey>,
+	/// The signature counter of the last accepted assertion.
+	pub counter: i64,
+	pub name: String,
+}
+
+/// The hex encoding of `id`, as stored in `key_id`.
+fn encode_key_id(id: &CredentialID) -> String {
+	hex::encode(id.as_ref() as &[u8])
+}
+
+impl SecurityKey {
+	/// Store the key `credential` registered by the user `user_id`.
+	pub async fn create(
+		db: &PgPool,
+		user_id: Snowflake,
+		name: &str,
+		credential: webauthn::SecurityKey,
+	) -> Result<Self, Error> {
+		let key = Self {
+			id: Snowflake::default(),
+			user_id,
+			key_id: encode_key_id(credential.cred_id()),
+			credential: Json(credential),
+			counter: 0,
+			name: name.to_string(),
+		};
+		sqlx::query(
+			"INSERT INTO security_keys (id, user_id, key_id, credential, counter, name) VALUES ($1, $2, $3, $4, $5, $6)",
+		)
+		.bind(key.id)
+		.bind(key.user_id)
+		.bind(&key.key_id)
+		.bind(&key.credential)
+		.bind(key.counter)
+		.bind(&key.name)
+		.execute(db)
+		.await?;
+		Self::update_webauthn_enabled(db, user_id).await?;
+		Ok(key)
+	}
+
+	pub async fn get_by_id(
+		db: &PgPool,
+		id: Snowflake,
+		user_id: Snowflake,
+	) -> Result<Option<Self>, Error> {
+		sqlx::query_as("SELECT * FROM security_keys WHERE id = $1 AND user_id = $2")
+			.bind(id)
+			.bind(user_id)
+			.fetch_optional(db)
+			.await
+			.map_err(Error::Sqlx)
+	}
+
+	pub async fn get_by_user_id(db: &PgPool, user_id: Snowflake) -> Result<Vec<Self>, Error> {
+		sqlx::query_as("SELECT * FROM security_keys WHERE user_id = $1 ORDER BY id")
+			.bind(user_id)
+			.fetch_all(db)
+			.await
+			.map_err(Error::Sqlx)
+	}
+
+	pub async fn rename(&mut self, db: &PgPool, name: &str) -> Result<(), Error> {
+		sqlx::query("UPDATE security_keys SET name = $1 WHERE id = $2")
+			.bind(name)
+			.bind(self.id)
+			.execute(db)
+			.await?;
+		self.name = name.to_string();
+		Ok(())
+	}
+
+	pub async fn delete(self, db: &PgPool) -> Result<(), Error> {
+		sqlx::query("DELETE FROM security_keys WHERE id = $1").bind(self.id).execute(db).await?;
+		Self::update_webauthn_enabled(db, self.user_id).await
+	}
+
+	/// Record a successful assertion of one of the keys of the user `user_id`.
+	/// Fails if the key is unknown, or if its signature counter did not
+	/// advance, which means the key may have been cloned.
+	pub async fn record_authentication(
+		db: &PgPool,
+		user_id: Snowflake,
+		result: &AuthenticationResult,
+	) -> Result<(), Error> {
+		let mut key: Self =
+			sqlx::query_as("SELECT * FROM security_keys WHERE user_id = $1 AND key_id = $2")
+				.bind(user_id)
+				.bind(encode_key_id(result.cred_id()))
+				.fetch_optional(db)
+				.await?
+				.ok_or(UserError::SecurityKeyNotFound)?;
+		check_counter(key.counter as u32, result.counter())?;
+		key.credential.update_credential(result);
+
+		// Another assertion of the same key may have been accepted meanwhile
+		let updated = sqlx::query(
+			"UPDATE security_keys SET counter = $1, credential = $2 WHERE id = $3 AND counter = $4",
+		)
+		.bind(result.counter() as i64)
+		.bind(&key.credential)
+		.bind(key.id)
+		.bind(key.counter)
+		.execute(db)
+		.await?;
+		if updated.rows_affected() == 0 {
+			return Err(UserError::SecurityKeyCounterRegressed.into());
+		}
+		Ok(())
+	}
+
+	/// The public representation of this key, without its credential.
+	pub fn to_public(&self) -> Value {
+		json!({"id": self.id, "name": self.name, "type": 1})
+	}
+
+	async fn update_webauthn_enabled(db: &PgPool, user_id: Snowflake) -> Result<(), Error> {
+		sqlx::query(
+			"UPDATE users SET webauthn_enabled = EXISTS (SELECT 1 FROM security_keys WHERE user_id = $1) WHERE id = $1",
+		)
+		.bind(user_id)
+		.execute(db)
+		.await?;
+		Ok(())
+	}
+}
+
+/// The state of a WebAuthn ceremony of a user, kept between its begin and
+/// finish requests. Each user has at most one pending ceremony per kind.
+pub struct WebauthnChallenge;
+
+impl WebauthnChallenge {
+	pub const REGISTRATION: &str = "registration";
+	pub const AUTHENTICATION: &str = "authentication";
+
+	/// Store `state` as the pending ceremony `kind` of the user `user_id`,
+	/// replacing an earlier one.
+	pub async fn save<T: Serialize + Sync>(
+		db: &PgPool,
+		user_id: Snowflake,
+		kind: &str,
+		state: &T,
+	) -> Result<(), Error> {
+		sqlx::query(
+			"INSERT INTO webauthn_challenges (user_id, kind, state, created_at) VALUES ($1, $2, $3, NOW()) \
+			 ON CONFLICT (user_id, kind) DO UPDATE SET state = $3, created_at = NOW()",
+		)
+		.bind(user_id)
+		.bind(kind)
+		.bind(Json(state))
+		.execute(db)
+		.await?;
+		Ok(())
+	}
+
+	/// Remove the pending ceremony `kind` of the user `user_id` and return its
+	/// state, unless it is older than [MFA_TICKET_LIFETIME].
+	pub async fn take<T: DeserializeOwned + Send + Unpin + 'static>(
+		db: &PgPool,
+		user_id: Snowflake,
+		kind: &str,
+	) -> Result<T, Error> {
+		let state: Option<Json<T>> = sqlx::query_scalar(
+			"DELETE FROM webauthn_challenges WHERE user_id = $1 AND kind = $2 \
+			 RETURNING CASE WHEN created_at > NOW() - make_interval(secs => $3) THEN state END",
+		)
+		.bind(user_id)
+		.bind(kind)
+		.bind(MFA_TICKET_LIFETIME.as_secs_f64())
+		.fetch_optional(db)
+		.await?
+		.flatten();
+		state.map(|state| state.0).ok_or(UserError::WebauthnChallengeNotFound.into())
+	}
+}
@@ -0,0 +1,202 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chorus::types::{Activity, ClientInfo, GatewayIdentifyPayload, Snowflake};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use sqlx_pg_uint::PgU64;
+
+use crate::errors::Error;
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+/// A gateway session of a user. A session is created when a client identifies
+/// and deleted once it can no longer be resumed.
+pub struct Session {
+	pub index: PgU64,
+	pub user_id: Snowflake,
+	pub session_id: Snowflake,
+	/// The gateway node the client of the session is connected to.
+	pub node_id: PgU64,
+	/// JSON serialized activities of the session, if any.
+	pub activities: Option<String>,
+	/// JSON serialized connection properties the client identified with.
+	pub client_info: String,
+	pub status: String,
+}
+
+impl Session {
+	/// Create a new session for a client that identified with `identify` on the
+	/// gateway node `node_id`.
+	pub async fn create(
+		db: &PgPool,
+		user_id: Snowflake,
+		session_id: Snowflake,
+		node_id: u64,
+		identify: &GatewayIdentifyPayload,
+	) -> Result<Self, Error> {
+		let client_info = serde_json::to_string(&identify.properties)?;
+		let presence = serde_json::to_value(&identify.presence)?;
+		let status = presence.get("status").and_then(|status| status.as_str()).unwrap_or("online");
+		let activities = presence
+			.get("activities")
+			.filter(|activities| !activities.is_null())
+			.map(|activities| activities.to_string());
+		sqlx::query_as(
+			"INSERT INTO sessions (user_id, session_id, node_id, activities, client_info, status) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+		)
+		.bind(user_id)
+		.bind(session_id)
+		.bind(PgU64::from(node_id))
+		.bind(activities)
+		.bind(client_info)
+		.bind(status)
+		.fetch_one(db)
+		.await
+		.map_err(Error::Sqlx)
+	}
+
+	pub async fn get_by_session_id(
+		db: &PgPool,
+		session_id: Snowflake,
+	) -> Result<Option<Self>, Error> {
+		sqlx::query_as("SELECT * FROM sessions WHERE session_id = $1")
+			.bind(session_id)
+			.fetch_optional(db)
+			.await
+			.map_err(Error::Sqlx)
+	}
+
+	/// Retrieve all sessions of a user, oldest first.
+	pub async fn get_by_user_id(db: &PgPool, user_id: Snowflake) -> Result<Vec<Self>, Error> {
+		sqlx::query_as("SELECT * FROM sessions WHERE user_id = $1 ORDER BY index")
+			.bind(user_id)
+			.fetch_all(db)
+			.await
+			.map_err(Error::Sqlx)
+	}
+
+	/// Delete the session with the given `session_id`. Returns whether a
+	/// session was deleted.
+	pub async fn delete_by_session_id(db: &PgPool, session_id: Snowflake) -> Result<bool, Error> {
+		sqlx::query("DELETE FROM sessions WHERE session_id = $1")
+			.bind(session_id)
+			.execute(db)
+			.await
+			.map(|result| result.rows_affected() > 0)
+			.map_err(Error::Sqlx)
+	}
+
+	/// Record that the client of the session `session_id` resumed it on the
+	/// gateway node `node_id`.
+	pub async fn move_to_node(
+		db: &PgPool,
+		session_id: Snowflake,
+		node_id: u64,
+	) -> Result<(), Error> {
+		sqlx::query("UPDATE sessions SET node_id = $1 WHERE session_id = $2")
+			.bind(PgU64::from(node_id))
+			.bind(session_id)
+			.execute(db)
+			.await
+			.map(|_| ())
+			.map_err(Error::Sqlx)
+	}
+
+	/// Delete the sessions the gateway node `node_id` left behind when it
+	/// stopped, except for those saved to be resumed on another node. Only to
+	/// be called by the node `node_id` before it accepts connections. Returns
+	/// the ids of the users whose sessions were deleted.
+	pub async fn delete_stale(db: &PgPool, node_id: u64) -> Result<Vec<Snowflake>, Error> {
+		sqlx::query_scalar(
+			"WITH deleted AS (DELETE FROM sessions WHERE node_id = $1 \
+			 AND session_id::text NOT IN (SELECT session_id FROM resumable_sessions) \
+			 RETURNING user_id) \
+			 SELECT DISTINCT user_id FROM deleted WHERE user_id IS NOT NULL",
+		)
+		.bind(PgU64::from(node_id))
+		.fetch_all(db)
+		.await
+		.map_err(Error::Sqlx)
+	}
+
+	/// Store the status and activities of the session, as last set by its
+	/// client.
+	pub async fn update_presence(
+		db: &PgPool,
+		session_id: Snowflake,
+		status: &str,
+		activities: Option<String>,
+	) -> Result<(), Error> {
+		sqlx::query("UPDATE sessions SET status = $1, activities = $2 WHERE session_id = $3")
+			.bind(status)
+			.bind(activities)
+			.bind(session_id)
+			.execute(db)
+			.await
+			.map(|_| ())
+			.map_err(Error::Sqlx)
+	}
+
+	pub fn to_inner(&self) -> chorus::types::Session {
+		let properties =
+			serde_json::from_str::<serde_json::Value>(&self.client_info).unwrap_or_default();
+		let client_info = serde_json::from_value::<ClientInfo>(json!({
+			"client": properties.get("browser").and_then(|browser| browser.as_str()).unwrap_or_default(),
+			"os": properties.get("os").and_then(|os| os.as_str()).unwrap_or_default(),
+			"version": 0,
+		}))
+		.unwrap_or_default();
+		chorus::types::Session {
+			activities: self
+				.activities
+				.as_deref()
+				.and_then(|activities| serde_json::from_str::<Vec<Activity>>(activities).ok()),
+			client_info,
+			session_id: self.session_id.to_string(),
+			status: self.status.clone(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn session(client_info: &str, activities: Option<&str>) -> Session {
+		Session {
+			index: PgU64::from(1),
+			user_id: Snowflake(1),
+			session_id: Snowflake(2),
+			node_id: PgU64::from(0),
+			activities: activities.map(str::to_string),
+			client_info: client_info.to_string(),
+			status: "idle".to_string(),
+		}
+	}
+
+	#[test]
+	fn to_inner_reads_the_connection_properties() {
+		let inner =
+			session(r#"{"os": "linux", "browser": "firefox", "device": ""}"#, None).to_inner();
+		assert_eq!(inner.client_info.client, "firefox");
+		assert_eq!(inner.client_info.os, "linux");
+		assert_eq!(inner.session_id, "2");
+		assert_eq!(inner.status, "idle");
+		assert!(inner.activities.is_none());
+	}
+
+	#[test]
+	fn to_inner_tolerates_invalid_json() {
+		let inner = session("not json", Some("not json either")).to_inner();
+		assert_eq!(inner.client_info.client, "");
+		assert!(inner.activities.is_none());
+	}
+
+	#[test]
+	fn to_inner_reads_the_activities() {
+		let inner = session("{}", Some("[]")).to_inner();
+		assert_eq!(inner.activities.map(|activities| activities.len()), Some(0));
+	}
+}
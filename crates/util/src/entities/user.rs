@@ -8,21 +8,23 @@ use std::{
 };
 
 use argon2::{
-	Argon2,
+	Argon2, PasswordHash, PasswordVerifier,
 	password_hash::{self, PasswordHasher, SaltString},
 };
 use bigdecimal::BigDecimal;
 use chorus::types::{PublicUser, Rights, Snowflake, UserData};
-use chrono::{NaiveDate, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value, from_str};
-use sqlx::{FromRow, PgPool, Row};
+use sqlx::{FromRow, PgConnection, PgPool, Row};
 use sqlx_pg_uint::{PgU32, PgU64};
 
 use super::*;
 use crate::{
-	entities::{Config, Guild, GuildMember, UserSettings},
-	errors::{Error, GuildError},
+	entities::{BackupCode, Config, Guild, GuildMember, UserSettings},
+	errors::{Error, GuildError, UserError},
+	gateway::SharedRoleUserMap,
+	util::mfa,
 };
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize, FromRow)]
@@ -58,8 +60,10 @@ impl DerefMut for User {
 
 impl User {
 	#[allow(clippy::too_many_arguments)]
+	/// Create an account. Registration runs this in a transaction, see
+	/// [crate::entities::RegistrationToken::consume].
 	pub async fn create(
-		db: &PgPool,
+		conn: &mut PgConnection,
 		cfg: &Config,
 		username: &str,
 		password: Option<String>,
@@ -72,7 +76,7 @@ impl User {
 		// TODO: generate discrim
 
 		// TODO: dynamically figure out locale
-		let user_settings = UserSettings::create(db, "en-US").await?;
+		let user_settings = UserSettings::create(&mut *conn, "en-US").await?;
 
 		let argon2 = Argon2::default();
 		let salt = SaltString::generate(password_hash::rand_core::OsRng);
@@ -119,7 +123,7 @@ impl User {
             .bind( Utc::now().naive_local())
             .bind(  Some(rights))
             .bind( user.settings_index.clone().as_big_decimal().to_owned())
-            .execute(db)
+            .execute(conn)
             .await?;
 
 		Ok(user)
@@ -144,19 +148,22 @@ impl User {
 		after: Option<Snowflake>,
 		limit: PgU32,
 	) -> Result<Vec<Self>, Error> {
+		if ids.is_empty() {
+			return Ok(Vec::new());
+		}
 		let mut query_builder = sqlx::QueryBuilder::new("SELECT * FROM users WHERE id IN (");
 		let mut separated = query_builder.separated(", ");
 		for id in ids {
 			separated.push_bind(id);
 		}
-		separated.push_unseparated(") ");
+		separated.push_unseparated(")");
 
 		if let Some(after) = after {
-			separated.push_unseparated("AND id > $1 ");
-			separated.push_bind_unseparated(after);
+			query_builder.push(" AND id > ");
+			query_builder.push_bind(after);
 		}
-		separated.push_unseparated("LIMIT $2");
-		separated.push_bind_unseparated(limit);
+		query_builder.push(" ORDER BY id LIMIT ");
+		query_builder.push_bind(limit);
 
 		let query = query_builder.build();
 
@@ -195,6 +202,7 @@ impl User {
 	pub async fn add_to_guild(
 		&self,
 		db: &PgPool,
+		role_user_map: &SharedRoleUserMap,
 		guild_id: Snowflake,
 	) -> Result<GuildMember, Error> {
 		let public = self.to_public_user();
@@ -202,7 +210,7 @@ impl User {
 		let guild =
 			Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
 
-		GuildMember::create(db, self, &guild).await
+		GuildMember::create(db, role_user_map, self, &guild).await
 	}
 
 	/// Return the Snowflake IDs of all guilds the user is a member of. Limited
@@ -218,6 +226,135 @@ impl User {
 		Ok(pg_u64s.iter().map(|x| Snowflake::from(x.to_uint())).collect())
 	}
 
+	/// Revoke all tokens issued to this user so far, logging them out on all
+	/// of their devices.
+	pub async fn revoke_tokens(&mut self, db: &PgPool) -> Result<(), Error> {
+		self.data.valid_tokens_since = Utc::now();
+		sqlx::query("UPDATE users SET data = $1 WHERE id = $2")
+			.bind(&self.data)
+			.bind(self.id)
+			.execute(db)
+			.await?;
+		Ok(())
+	}
+
+	/// Check `password` against the password hash of this user.
+	pub fn check_password(&self, password: &str) -> Result<(), Error> {
+		let hash = self.data.hash.as_ref().ok_or(UserError::InvalidPassword)?;
+		let hash = PasswordHash::new(hash)?;
+		Argon2::default()
+			.verify_password(password.as_bytes(), &hash)
+			.map_err(|_| UserError::InvalidPassword.into())
+	}
+
+	/// Replace the password of this user, revoking all tokens issued so far.
+	pub async fn set_password(&mut self, db: &PgPool, password: &str) -> Result<(), Error> {
+		let salt = SaltString::generate(password_hash::rand_core::OsRng);
+		self.data.hash =
+			Some(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string());
+		self.revoke_tokens(db).await
+	}
+
+	/// Mark the email address of this user as verified.
+	pub async fn set_verified(&mut self, db: &PgPool) -> Result<(), Error> {
+		sqlx::query("UPDATE users SET verified = true WHERE id = $1")
+			.bind(self.id)
+			.execute(db)
+			.await?;
+		self.verified = Some(true);
+		Ok(())
+	}
+
+	/// The base32 encoded TOTP secret of this user, if they enabled TOTP.
+	pub async fn totp_secret(&self, db: &PgPool) -> Result<Option<String>, Error> {
+		sqlx::query_scalar("SELECT totp_secret FROM users WHERE id = $1")
+			.bind(self.id)
+			.fetch_one(db)
+			.await
+			.map_err(Error::Sqlx)
+	}
+
+	/// Enable multi-factor authentication with the TOTP secret `secret`.
+	/// `step` is the time step of the code the user confirmed `secret` with,
+	/// which cannot be used again.
+	pub async fn enable_totp(&mut self, db: &PgPool, secret: &str, step: u64) -> Result<(), Error> {
+		sqlx::query(
+			"UPDATE users SET mfa_enabled = true, totp_secret = $1, totp_last_step = $2 WHERE id = $3",
+		)
+		.bind(secret)
+		.bind(step as i64)
+		.bind(self.id)
+		.execute(db)
+		.await?;
+		self.mfa_enabled = Some(true);
+		Ok(())
+	}
+
+	/// Disable multi-factor authentication, expiring all backup codes and
+	/// pending MFA tickets.
+	pub async fn disable_totp(&mut self, db: &PgPool) -> Result<(), Error> {
+		sqlx::query(
+			"UPDATE users SET mfa_enabled = false, totp_secret = NULL, totp_last_step = NULL, totp_last_ticket = NULL WHERE id = $1",
+		)
+		.bind(self.id)
+		.execute(db)
+		.await?;
+		BackupCode::expire_all(db, self.id).await?;
+		self.mfa_enabled = Some(false);
+		Ok(())
+	}
+
+	/// Whether `code` is a current TOTP code or an unused backup code of this
+	/// user. Both kinds of codes are only accepted once: A backup code is
+	/// consumed by a successful check, and a TOTP code is rejected once it or
+	/// a later one was accepted.
+	pub async fn check_mfa_code(&self, db: &PgPool, code: &str) -> Result<bool, Error> {
+		let (secret, last_step): (Option<String>, Option<i64>) =
+			sqlx::query_as("SELECT totp_secret, totp_last_step FROM users WHERE id = $1")
+				.bind(self.id)
+				.fetch_one(db)
+				.await?;
+		let Some(secret) = secret else {
+			return Err(UserError::MfaNotEnabled.into());
+		};
+		if let Some(step) = mfa::verify_totp(&secret, code, last_step.map(|step| step as u64))? {
+			// Of concurrent checks of the same code, only the first one succeeds
+			let accepted = sqlx::query(
+				"UPDATE users SET totp_last_step = $1 WHERE id = $2 AND (totp_last_step IS NULL OR totp_last_step < $1)",
+			)
+			.bind(step as i64)
+			.bind(self.id)
+			.execute(db)
+			.await?;
+			return Ok(accepted.rows_affected() > 0);
+		}
+		BackupCode::consume(db, self.id, code.trim()).await
+	}
+
+	/// Start a login which has to be completed with a second factor, replacing
+	/// any earlier ticket of this user.
+	pub async fn create_mfa_ticket(&self, db: &PgPool) -> Result<String, Error> {
+		let ticket = mfa::generate_mfa_ticket();
+		sqlx::query("UPDATE users SET totp_last_ticket = $1 WHERE id = $2")
+			.bind(&ticket)
+			.bind(self.id)
+			.execute(db)
+			.await?;
+		Ok(ticket)
+	}
+
+	/// Get the user which was issued `ticket`, removing the ticket so that it
+	/// can only be used once. Expired tickets yield no user.
+	pub async fn take_by_mfa_ticket(db: &PgPool, ticket: &str) -> Result<Option<Self>, Error> {
+		let user: Option<Self> = sqlx::query_as(
+			"UPDATE users SET totp_last_ticket = NULL WHERE totp_last_ticket = $1 RETURNING *",
+		)
+		.bind(ticket)
+		.fetch_optional(db)
+		.await?;
+		Ok(user.filter(|_| mfa::mfa_ticket_is_current(ticket)))
+	}
+
 	pub async fn count(db: &PgPool) -> Result<i32, Error> {
 		sqlx::query("SELECT COUNT(*) FROM users")
 			.fetch_one(db)
@@ -226,6 +363,26 @@ impl User {
 			.map(|r| r.get::<i32, _>(0))
 	}
 
+	/// The number of accounts registered after `since`.
+	pub async fn count_created_since(db: &PgPool, since: DateTime<Utc>) -> Result<i64, Error> {
+		sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE created_at > $1")
+			.bind(since.naive_utc())
+			.fetch_one(db)
+			.await
+			.map_err(Error::Sqlx)
+	}
+
+	/// Whether an account was registered from the device `fingerprint`.
+	pub async fn fingerprint_exists(db: &PgPool, fingerprint: &str) -> Result<bool, Error> {
+		sqlx::query_scalar(
+			"SELECT EXISTS (SELECT 1 FROM users WHERE $1 = ANY(string_to_array(fingerprints, ',')))",
+		)
+		.bind(fingerprint)
+		.fetch_one(db)
+		.await
+		.map_err(Error::Sqlx)
+	}
+
 	pub async fn count_guilds(&self, db: &PgPool) -> Result<i32, Error> {
 		GuildMember::count_by_user_id(db, self.id).await
 	}
@@ -5,8 +5,9 @@
 use std::ops::{Deref, DerefMut};
 
 use bigdecimal::BigDecimal;
+use chorus::types::Snowflake;
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
+use sqlx::{FromRow, PgConnection, PgPool};
 use sqlx_pg_uint::PgU64;
 
 use crate::errors::Error;
@@ -60,7 +61,7 @@ impl UserSettings {
 		Self { inner, index: PgU64::from(index) }
 	}
 
-	pub async fn create(db: &PgPool, locale: &str) -> Result<Self, Error> {
+	pub async fn create(conn: &mut PgConnection, locale: &str) -> Result<Self, Error> {
 		let mut settings = Self {
 			inner: chorus::types::UserSettings { locale: locale.to_string(), ..Default::default() },
 			index: PgU64::from(0),
@@ -70,7 +71,7 @@ impl UserSettings {
 			"INSERT INTO user_settings (locale) VALUES ($1) RETURNING index as inner",
 		)
 		.bind(locale)
-		.fetch_one(db)
+		.fetch_one(conn)
 		.await?;
 		let index = res.into_pg_u64()?;
 		settings.index = index;
@@ -85,4 +86,19 @@ impl UserSettings {
 			.await
 			.map_err(Error::Sqlx)
 	}
+
+	/// Retrieve the status a user has chosen in their settings, if any.
+	pub async fn get_status_by_user_id(
+		db: &PgPool,
+		user_id: Snowflake,
+	) -> Result<Option<String>, Error> {
+		sqlx::query_scalar(
+			"SELECT s.status FROM user_settings s JOIN users u ON u.settings_index = s.index WHERE u.id = $1",
+		)
+		.bind(user_id)
+		.fetch_optional(db)
+		.await
+		.map(Option::flatten)
+		.map_err(Error::Sqlx)
+	}
 }
@@ -1,9 +1,11 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use std::{error::Error as StdError, fmt::Display};
+use std::{collections::BTreeMap, error::Error as StdError, fmt::Display};
 
-use chorus::types::{APIError, AuthError, Rights};
+use chorus::types::{APIError, AuthError, PermissionFlags, Rights};
+use serde::Serialize;
+use serde_json::{Value, json};
 use tokio::sync::broadcast::error::SendError;
 
 #[derive(Debug, thiserror::Error)]
@@ -62,9 +64,24 @@ pub enum Error {
 	#[error(transparent)]
 	SqlxPgUint(#[from] sqlx_pg_uint::Error),
 
+	#[error("TLS error: {0}")]
+	Tls(#[from] tokio_rustls::rustls::Error),
+
+	#[error("JWT error: {0}")]
+	Jwt(#[from] jsonwebtoken::errors::Error),
+
+	#[error("WebAuthn error: {0}")]
+	Webauthn(#[from] webauthn_rs::prelude::WebauthnError),
+
+	#[error("SMTP error: {0}")]
+	Smtp(#[from] lettre::transport::smtp::Error),
+
 	#[error("Password hashing error: {0}")]
 	PasswordHash(argon2::password_hash::Error),
 
+	#[error("Invalid Form Body")]
+	InvalidFormBody(FieldErrors),
+
 	#[error("{0}")]
 	Custom(String),
 }
@@ -75,6 +92,54 @@ impl From<argon2::password_hash::Error> for Error {
 	}
 }
 
+/// Errors of individual fields of a request body, keyed by the name of the
+/// field. Rendered like the `Invalid Form Body` errors of Discord.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct FieldErrors(BTreeMap<String, Vec<FieldError>>);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+	pub code: &'static str,
+	pub message: String,
+}
+
+impl FieldErrors {
+	/// The JSON error code of an invalid form body.
+	pub const CODE: u32 = 50035;
+
+	pub fn add(&mut self, field: &str, code: &'static str, message: impl Into<String>) {
+		self.0
+			.entry(field.to_string())
+			.or_default()
+			.push(FieldError { code, message: message.into() });
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	pub fn get(&self, field: &str) -> &[FieldError] {
+		self.0.get(field).map(Vec::as_slice).unwrap_or_default()
+	}
+
+	/// Fail with these errors, unless there are none.
+	pub fn into_result(self) -> Result<(), Error> {
+		if self.is_empty() { Ok(()) } else { Err(Error::InvalidFormBody(self)) }
+	}
+
+	/// The response body, e.g.
+	/// `{"code": 50035, "message": "Invalid Form Body", "errors": {"email":
+	/// {"_errors": [{"code": "EMAIL_INVALID", "message": "..."}]}}}`.
+	pub fn to_json(&self) -> Value {
+		let errors: serde_json::Map<String, Value> = self
+			.0
+			.iter()
+			.map(|(field, errors)| (field.clone(), json!({"_errors": errors})))
+			.collect();
+		json!({"code": Self::CODE, "message": "Invalid Form Body", "errors": errors})
+	}
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum GatewayError {
 	#[error("UNEXPECTED_MESSAGE: {0}")]
@@ -87,6 +152,14 @@ pub enum GatewayError {
 	Closed,
 	#[error("INTERNAL_SERVER_ERROR")]
 	Internal,
+	#[error("INVALID_API_VERSION: {0}")]
+	InvalidApiVersion(String),
+	#[error("INVALID_CONNECTION_PARAMETER: {0}")]
+	InvalidConnectionParameter(String),
+	#[error("INVALID_INTENTS: {0}")]
+	InvalidIntents(i64),
+	#[error("DISALLOWED_INTENTS")]
+	DisallowedIntents,
 }
 
 impl From<SendError<tokio_tungstenite::tungstenite::Message>> for GatewayError {
@@ -111,6 +184,32 @@ pub enum UserError {
 	InvalidUser,
 	#[error("INVALID_TOKEN")]
 	InvalidToken,
+	#[error("TOKEN_EXPIRED")]
+	ExpiredToken,
+	#[error("ACCOUNT_DISABLED")]
+	Disabled,
+	#[error("ACCOUNT_DELETED")]
+	Deleted,
+	#[error("INVALID_PASSWORD")]
+	InvalidPassword,
+	#[error("INVALID_TWO_FACTOR_CODE")]
+	InvalidMfaCode,
+	#[error("INVALID_TWO_FACTOR_SECRET")]
+	InvalidMfaSecret,
+	#[error("INVALID_TWO_FACTOR_TICKET")]
+	InvalidMfaTicket,
+	#[error("TWO_FACTOR_ALREADY_ENABLED")]
+	MfaAlreadyEnabled,
+	#[error("TWO_FACTOR_NOT_ENABLED")]
+	MfaNotEnabled,
+	#[error("WEBAUTHN_DISABLED")]
+	WebauthnDisabled,
+	#[error("WEBAUTHN_CHALLENGE_NOT_FOUND")]
+	WebauthnChallengeNotFound,
+	#[error("SECURITY_KEY_NOT_FOUND")]
+	SecurityKeyNotFound,
+	#[error("SECURITY_KEY_COUNTER_REGRESSED")]
+	SecurityKeyCounterRegressed,
 	#[error("ALREADY_EXISTS")]
 	AlreadyExists,
 	#[error("MISSING_RIGHTS")]
@@ -173,6 +272,8 @@ pub enum ChannelError {
 	MaxWebhooksReached,
 	#[error("User is already a recipient of this channel")]
 	InvalidRecipient,
+	#[error("Missing Permissions: {0:?}")]
+	MissingPermissions(PermissionFlags), // code 50013
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -185,6 +286,8 @@ pub enum InviteError {
 pub enum RateLimitError {
 	#[error("TOO_MANY_MESSAGES")]
 	TooManyMessages,
+	#[error("TOO_MANY_REGISTRATIONS")]
+	TooManyRegistrations,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -210,6 +313,19 @@ mod poem {
 					UserError::InvalidDiscriminator => StatusCode::BAD_REQUEST,
 					UserError::InvalidUser => StatusCode::NOT_FOUND,
 					UserError::InvalidToken => StatusCode::UNAUTHORIZED,
+					UserError::ExpiredToken => StatusCode::UNAUTHORIZED,
+					UserError::Disabled => StatusCode::FORBIDDEN,
+					UserError::Deleted => StatusCode::FORBIDDEN,
+					UserError::InvalidPassword => StatusCode::BAD_REQUEST,
+					UserError::InvalidMfaCode => StatusCode::BAD_REQUEST,
+					UserError::InvalidMfaSecret => StatusCode::BAD_REQUEST,
+					UserError::InvalidMfaTicket => StatusCode::UNAUTHORIZED,
+					UserError::MfaAlreadyEnabled => StatusCode::BAD_REQUEST,
+					UserError::MfaNotEnabled => StatusCode::BAD_REQUEST,
+					UserError::WebauthnDisabled => StatusCode::NOT_IMPLEMENTED,
+					UserError::WebauthnChallengeNotFound => StatusCode::BAD_REQUEST,
+					UserError::SecurityKeyNotFound => StatusCode::NOT_FOUND,
+					UserError::SecurityKeyCounterRegressed => StatusCode::UNAUTHORIZED,
 					UserError::AlreadyExists => StatusCode::BAD_REQUEST,
 					UserError::MissingRights(_) => StatusCode::UNAUTHORIZED,
 				},
@@ -241,12 +357,14 @@ mod poem {
 					ChannelError::MaxPinsReached => StatusCode::BAD_REQUEST,
 					ChannelError::MaxWebhooksReached => StatusCode::BAD_REQUEST,
 					ChannelError::InvalidRecipient => StatusCode::NOT_FOUND,
+					ChannelError::MissingPermissions(_) => StatusCode::FORBIDDEN,
 				},
 				Error::Invite(err) => match err {
 					InviteError::InvalidInvite => StatusCode::NOT_FOUND,
 				},
 				Error::RateLimit(err) => match err {
 					RateLimitError::TooManyMessages => StatusCode::TOO_MANY_REQUESTS,
+					RateLimitError::TooManyRegistrations => StatusCode::TOO_MANY_REQUESTS,
 				},
 				Error::Reaction(err) => match err {
 					ReactionError::Invalid => StatusCode::NOT_FOUND,
@@ -274,13 +392,22 @@ mod poem {
 					GatewayError::Timeout => StatusCode::BAD_REQUEST,
 					GatewayError::Closed => StatusCode::BAD_REQUEST,
 					GatewayError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+					GatewayError::InvalidApiVersion(_) => StatusCode::BAD_REQUEST,
+					GatewayError::InvalidConnectionParameter(_) => StatusCode::BAD_REQUEST,
+					GatewayError::InvalidIntents(_) => StatusCode::BAD_REQUEST,
+					GatewayError::DisallowedIntents => StatusCode::FORBIDDEN,
 				},
 				Error::SqlxPgUint(_) => StatusCode::BAD_REQUEST,
 				Error::Custom(_) => StatusCode::BAD_REQUEST,
 				Error::Toml(_) => unreachable!(
 					"This should never trigger, as toml is only used before the api is started"
 				),
+				Error::Tls(_) => StatusCode::INTERNAL_SERVER_ERROR,
+				Error::Jwt(_) => StatusCode::INTERNAL_SERVER_ERROR,
+				Error::Webauthn(_) => StatusCode::BAD_REQUEST,
+				Error::Smtp(_) => StatusCode::INTERNAL_SERVER_ERROR,
 				Error::PasswordHash(_) => StatusCode::INTERNAL_SERVER_ERROR,
+				Error::InvalidFormBody(_) => StatusCode::BAD_REQUEST,
 			}
 		}
 
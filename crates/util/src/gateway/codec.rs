@@ -0,0 +1,392 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Encodings of gateway payloads.
+//!
+//! The gateway internally works with JSON. A [Codec] translates between JSON
+//! and the encoding a client has chosen when connecting, both for messages
+//! sent to and messages received from that client.
+
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Map, Number, Value};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::GatewayPayload;
+use crate::errors::{Error, GatewayError};
+
+/// Translates between JSON payloads and WebSocket messages of a specific
+/// encoding.
+pub trait Codec: Send + Sync {
+	/// Encode a JSON value into a message.
+	fn encode(&self, value: &Value) -> Result<Message, Error>;
+
+	/// Decode a message into a JSON value.
+	fn decode(&self, message: &Message) -> Result<Value, Error>;
+
+	/// Encode a payload which has already been serialized to JSON.
+	fn encode_json(&self, json: &str) -> Result<Message, Error> {
+		self.encode(&serde_json::from_str(json)?)
+	}
+
+	/// Decode a message into a serialized JSON payload.
+	fn decode_to_json(&self, message: &Message) -> Result<String, Error> {
+		Ok(self.decode(message)?.to_string())
+	}
+}
+
+impl<T: Serialize + DeserializeOwned> GatewayPayload<T> {
+	/// Encode this payload using `codec`.
+	pub fn encode(&self, codec: &dyn Codec) -> Result<Message, Error> {
+		codec.encode(&serde_json::to_value(self)?)
+	}
+
+	/// Decode a payload from a message using `codec`.
+	pub fn decode(codec: &dyn Codec, message: &Message) -> Result<Self, Error> {
+		Ok(serde_json::from_value(codec.decode(message)?)?)
+	}
+}
+
+/// Plain JSON, sent as text messages.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+	fn encode(&self, value: &Value) -> Result<Message, Error> {
+		Ok(Message::Text(value.to_string().into()))
+	}
+
+	fn decode(&self, message: &Message) -> Result<Value, Error> {
+		Ok(serde_json::from_str(&self.decode_to_json(message)?)?)
+	}
+
+	fn encode_json(&self, json: &str) -> Result<Message, Error> {
+		Ok(Message::Text(json.to_string().into()))
+	}
+
+	fn decode_to_json(&self, message: &Message) -> Result<String, Error> {
+		match message {
+			Message::Text(text) => Ok(text.as_str().to_string()),
+			Message::Binary(bytes) => Ok(String::from_utf8(bytes.to_vec())?),
+			_ => Err(GatewayError::UnexpectedMessage("Expected a text message".to_string()).into()),
+		}
+	}
+}
+
+/// Erlang External Term Format, sent as binary messages.
+///
+/// Following the usual conventions, `null` is encoded as the atom `nil`,
+/// booleans as the atoms `true` and `false`, strings and map keys as binaries
+/// and snowflakes as 64-bit integers. A value is considered a snowflake if it
+/// is a numeric string stored under the key `id` or a key ending in `_id` or
+/// `_ids`. When decoding, integers under these keys are turned back into
+/// strings.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EtfCodec;
+
+const FORMAT_VERSION: u8 = 131;
+const NEW_FLOAT_EXT: u8 = 70;
+const SMALL_INTEGER_EXT: u8 = 97;
+const INTEGER_EXT: u8 = 98;
+const ATOM_EXT: u8 = 100;
+const SMALL_TUPLE_EXT: u8 = 104;
+const LARGE_TUPLE_EXT: u8 = 105;
+const NIL_EXT: u8 = 106;
+const STRING_EXT: u8 = 107;
+const LIST_EXT: u8 = 108;
+const BINARY_EXT: u8 = 109;
+const SMALL_BIG_EXT: u8 = 110;
+const LARGE_BIG_EXT: u8 = 111;
+const SMALL_ATOM_EXT: u8 = 115;
+const MAP_EXT: u8 = 116;
+const ATOM_UTF8_EXT: u8 = 118;
+const SMALL_ATOM_UTF8_EXT: u8 = 119;
+
+impl Codec for EtfCodec {
+	fn encode(&self, value: &Value) -> Result<Message, Error> {
+		let mut buffer = vec![FORMAT_VERSION];
+		encode_term(&mut buffer, value, false)?;
+		Ok(Message::Binary(buffer.into()))
+	}
+
+	fn decode(&self, message: &Message) -> Result<Value, Error> {
+		let Message::Binary(bytes) = message else {
+			return Err(
+				GatewayError::UnexpectedMessage("Expected a binary message".to_string()).into()
+			);
+		};
+		let mut reader = Reader { bytes, position: 0 };
+		if reader.u8()? != FORMAT_VERSION {
+			return Err(decode_error("Unsupported ETF version"));
+		}
+		let value = reader.term(false)?;
+		if reader.position != bytes.len() {
+			return Err(decode_error("Trailing bytes after ETF term"));
+		}
+		Ok(value)
+	}
+}
+
+fn is_snowflake_key(key: &str) -> bool {
+	key == "id" || key.ends_with("_id") || key.ends_with("_ids")
+}
+
+fn decode_error(message: &str) -> Error {
+	GatewayError::UnexpectedMessage(format!("ETF: {message}")).into()
+}
+
+fn encode_term(buffer: &mut Vec<u8>, value: &Value, snowflake: bool) -> Result<(), Error> {
+	match value {
+		Value::Null => encode_atom(buffer, "nil"),
+		Value::Bool(boolean) => encode_atom(buffer, if *boolean { "true" } else { "false" }),
+		Value::Number(number) => encode_number(buffer, number)?,
+		Value::String(string) => match string.parse::<u64>() {
+			Ok(id) if snowflake => encode_integer(buffer, id as i128),
+			_ => encode_binary(buffer, string.as_bytes())?,
+		},
+		Value::Array(values) => {
+			if values.is_empty() {
+				buffer.push(NIL_EXT);
+				return Ok(());
+			}
+			buffer.push(LIST_EXT);
+			buffer.extend_from_slice(&length(values.len())?.to_be_bytes());
+			for value in values.iter() {
+				encode_term(buffer, value, snowflake)?;
+			}
+			buffer.push(NIL_EXT);
+		}
+		Value::Object(map) => {
+			buffer.push(MAP_EXT);
+			buffer.extend_from_slice(&length(map.len())?.to_be_bytes());
+			for (key, value) in map.iter() {
+				encode_binary(buffer, key.as_bytes())?;
+				encode_term(buffer, value, is_snowflake_key(key))?;
+			}
+		}
+	}
+	Ok(())
+}
+
+fn length(length: usize) -> Result<u32, Error> {
+	u32::try_from(length).map_err(|_| decode_error("Term too large"))
+}
+
+fn encode_atom(buffer: &mut Vec<u8>, atom: &str) {
+	buffer.push(SMALL_ATOM_UTF8_EXT);
+	buffer.push(atom.len() as u8);
+	buffer.extend_from_slice(atom.as_bytes());
+}
+
+fn encode_binary(buffer: &mut Vec<u8>, bytes: &[u8]) -> Result<(), Error> {
+	buffer.push(BINARY_EXT);
+	buffer.extend_from_slice(&length(bytes.len())?.to_be_bytes());
+	buffer.extend_from_slice(bytes);
+	Ok(())
+}
+
+fn encode_number(buffer: &mut Vec<u8>, number: &Number) -> Result<(), Error> {
+	if let Some(integer) = number.as_i64() {
+		encode_integer(buffer, integer as i128);
+	} else if let Some(integer) = number.as_u64() {
+		encode_integer(buffer, integer as i128);
+	} else if let Some(float) = number.as_f64() {
+		buffer.push(NEW_FLOAT_EXT);
+		buffer.extend_from_slice(&float.to_be_bytes());
+	} else {
+		return Err(decode_error("Unsupported number"));
+	}
+	Ok(())
+}
+
+fn encode_integer(buffer: &mut Vec<u8>, integer: i128) {
+	if (0..=u8::MAX as i128).contains(&integer) {
+		buffer.push(SMALL_INTEGER_EXT);
+		buffer.push(integer as u8);
+	} else if (i32::MIN as i128..=i32::MAX as i128).contains(&integer) {
+		buffer.push(INTEGER_EXT);
+		buffer.extend_from_slice(&(integer as i32).to_be_bytes());
+	} else {
+		let magnitude = integer.unsigned_abs().to_le_bytes();
+		let digits = magnitude.iter().rposition(|byte| *byte != 0).map_or(0, |index| index + 1);
+		buffer.push(SMALL_BIG_EXT);
+		buffer.push(digits as u8);
+		buffer.push(u8::from(integer < 0));
+		buffer.extend_from_slice(&magnitude[..digits]);
+	}
+}
+
+struct Reader<'a> {
+	bytes: &'a [u8],
+	position: usize,
+}
+
+impl Reader<'_> {
+	fn take(&mut self, count: usize) -> Result<&[u8], Error> {
+		let end = self.position.checked_add(count).filter(|end| *end <= self.bytes.len());
+		let Some(end) = end else {
+			return Err(decode_error("Unexpected end of input"));
+		};
+		let slice = &self.bytes[self.position..end];
+		self.position = end;
+		Ok(slice)
+	}
+
+	fn u8(&mut self) -> Result<u8, Error> {
+		Ok(self.take(1)?[0])
+	}
+
+	fn u16(&mut self) -> Result<usize, Error> {
+		Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()) as usize)
+	}
+
+	fn u32(&mut self) -> Result<usize, Error> {
+		Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as usize)
+	}
+
+	fn string(&mut self, length: usize) -> Result<String, Error> {
+		String::from_utf8(self.take(length)?.to_vec()).map_err(Error::from)
+	}
+
+	fn term(&mut self, snowflake: bool) -> Result<Value, Error> {
+		let integer = |integer: i128| -> Result<Value, Error> {
+			if snowflake && integer >= 0 {
+				return Ok(Value::String(integer.to_string()));
+			}
+			if let Ok(integer) = i64::try_from(integer) {
+				Ok(Value::from(integer))
+			} else {
+				u64::try_from(integer)
+					.map(Value::from)
+					.map_err(|_| decode_error("Integer out of range"))
+			}
+		};
+		match self.u8()? {
+			SMALL_INTEGER_EXT => integer(self.u8()? as i128),
+			INTEGER_EXT => integer(i32::from_be_bytes(self.take(4)?.try_into().unwrap()) as i128),
+			tag @ (SMALL_BIG_EXT | LARGE_BIG_EXT) => {
+				let digits = if tag == SMALL_BIG_EXT { self.u8()? as usize } else { self.u32()? };
+				let negative = self.u8()? != 0;
+				if digits > 8 {
+					return Err(decode_error("Integer out of range"));
+				}
+				let mut magnitude = [0u8; 8];
+				magnitude[..digits].copy_from_slice(self.take(digits)?);
+				let magnitude = u64::from_le_bytes(magnitude) as i128;
+				integer(if negative { -magnitude } else { magnitude })
+			}
+			NEW_FLOAT_EXT => {
+				let float = f64::from_be_bytes(self.take(8)?.try_into().unwrap());
+				Number::from_f64(float).map(Value::Number).ok_or(decode_error("Float out of range"))
+			}
+			tag @ (ATOM_EXT | SMALL_ATOM_EXT | ATOM_UTF8_EXT | SMALL_ATOM_UTF8_EXT) => {
+				let length = match tag {
+					ATOM_EXT | ATOM_UTF8_EXT => self.u16()?,
+					_ => self.u8()? as usize,
+				};
+				Ok(match self.string(length)?.as_str() {
+					"nil" | "null" => Value::Null,
+					"true" => Value::Bool(true),
+					"false" => Value::Bool(false),
+					atom => Value::String(atom.to_string()),
+				})
+			}
+			NIL_EXT => Ok(Value::Array(Vec::new())),
+			STRING_EXT => {
+				let length = self.u16()?;
+				Ok(Value::String(self.string(length)?))
+			}
+			BINARY_EXT => {
+				let length = self.u32()?;
+				Ok(Value::String(self.string(length)?))
+			}
+			tag @ (LIST_EXT | SMALL_TUPLE_EXT | LARGE_TUPLE_EXT) => {
+				let length = match tag {
+					SMALL_TUPLE_EXT => self.u8()? as usize,
+					_ => self.u32()?,
+				};
+				let mut values = Vec::with_capacity(length.min(1024));
+				for _ in 0..length {
+					values.push(self.term(snowflake)?);
+				}
+				if tag == LIST_EXT && self.u8()? != NIL_EXT {
+					return Err(decode_error("Improper lists are not supported"));
+				}
+				Ok(Value::Array(values))
+			}
+			MAP_EXT => {
+				let arity = self.u32()?;
+				let mut map = Map::new();
+				for _ in 0..arity {
+					let key = match self.term(false)? {
+						Value::String(key) => key,
+						Value::Number(key) => key.to_string(),
+						_ => return Err(decode_error("Unsupported map key")),
+					};
+					let value = self.term(is_snowflake_key(&key))?;
+					map.insert(key, value);
+				}
+				Ok(Value::Object(map))
+			}
+			tag => Err(decode_error(&format!("Unsupported term tag {tag}"))),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	#[test]
+	fn etf_encodes_payloads_like_erlpack() {
+		let Message::Binary(bytes) = EtfCodec.encode(&json!({"d": null, "op": 1})).unwrap() else {
+			panic!("ETF must be sent as binary messages");
+		};
+		let mut expected = vec![FORMAT_VERSION, MAP_EXT, 0, 0, 0, 2];
+		expected.extend_from_slice(&[BINARY_EXT, 0, 0, 0, 1, b'd', SMALL_ATOM_UTF8_EXT, 3]);
+		expected.extend_from_slice(b"nil");
+		expected.extend_from_slice(&[BINARY_EXT, 0, 0, 0, 2, b'o', b'p', SMALL_INTEGER_EXT, 1]);
+		assert_eq!(bytes.to_vec(), expected);
+	}
+
+	#[test]
+	fn etf_roundtrips_with_snowflakes_as_integers() {
+		let value = json!({
+			"op": 0,
+			"t": "MESSAGE_CREATE",
+			"s": 42,
+			"d": {
+				"id": "1299026005532348416",
+				"channel_id": "1299026005532348417",
+				"mention_ids": ["1299026005532348418"],
+				"content": "1234",
+				"pinned": false,
+				"nonce": -70000,
+				"score": 1.5,
+				"embeds": []
+			}
+		});
+		let message = EtfCodec.encode(&value).unwrap();
+		let Message::Binary(bytes) = &message else {
+			panic!("ETF must be sent as binary messages");
+		};
+		let id = 1299026005532348416u64.to_le_bytes();
+		assert!(
+			bytes
+				.windows(11)
+				.any(|window| window[..3] == [SMALL_BIG_EXT, 8, 0] && window[3..] == id)
+		);
+		assert_eq!(EtfCodec.decode(&message).unwrap(), value);
+	}
+
+	#[test]
+	fn json_codec_passes_text_through() {
+		let json = r#"{"op":11}"#;
+		let message = JsonCodec.encode_json(json).unwrap();
+		assert_eq!(message, Message::Text(json.into()));
+		assert_eq!(JsonCodec.decode_to_json(&message).unwrap(), json);
+		assert!(EtfCodec.decode(&message).is_err());
+	}
+}
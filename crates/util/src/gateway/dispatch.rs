@@ -0,0 +1,90 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Delivery of dispatch events caused by the HTTP API to the connected users
+//! concerned by them.
+
+use std::collections::HashSet;
+
+use chorus::types::Snowflake;
+use sqlx::PgPool;
+use tokio::sync::broadcast::error::RecvError;
+
+use super::{ConnectedUsers, event_bus::BusMessage, recipients::RecipientGroup};
+use crate::{entities::Channel, errors::Error};
+
+impl ConnectedUsers {
+	/// Apply `message` to this node: Send the event of a dispatch to the
+	/// clients of its recipients which are connected to this node, skipping
+	/// recipients which are not connected, or reload the member list of a
+	/// guild. Changes to the state every process keeps are applied by
+	/// [Self::replicate_state] instead.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on `store` per recipient of a dispatch.
+	pub async fn deliver(&self, db: &PgPool, message: BusMessage) {
+		match message {
+			BusMessage::Dispatch { recipients, event } => {
+				for user_id in recipients {
+					if let Some(inbox) = self.inbox(user_id).await {
+						// Sending only fails if no client is listening, which is fine.
+						let _ = inbox.send(event.clone());
+					}
+				}
+			}
+			BusMessage::MemberListChanged { guild_id } => {
+				if let Err(e) = self.refresh_member_list(db, guild_id).await {
+					log::error!(target: "symfonia::gateway::dispatch", "Failed to refresh the member list of guild {guild_id}: {e}");
+				}
+			}
+			BusMessage::SettingsStatusChanged { .. }
+			| BusMessage::RoleUserMapChanged { .. }
+			| BusMessage::ClientPresenceChanged { .. }
+			| BusMessage::ClientDisconnected { .. }
+			| BusMessage::PresencesRequested { .. } => (),
+		}
+	}
+
+	/// [Deliver](Self::deliver) all messages published to the
+	/// [EventBus](super::event_bus::EventBus) from now on, forever. Run by
+	/// every gateway node.
+	pub async fn deliver_bus_messages(self, db: PgPool) {
+		let mut messages = self.event_bus.subscribe();
+		loop {
+			match messages.recv().await {
+				Ok(message) => self.deliver(&db, message).await,
+				Err(RecvError::Lagged(skipped)) => {
+					log::warn!(target: "symfonia::gateway::dispatch", "Delivery is lagging behind, skipped {skipped} events");
+				}
+				Err(RecvError::Closed) => return,
+			}
+		}
+	}
+
+	/// The users who can see the channel `channel`: The recipients of a
+	/// private channel, or the members of a guild with the `VIEW_CHANNEL`
+	/// permission in a guild channel. Resolved through the [RoleUserMap], as
+	/// the viewers may be connected to any gateway node.
+	///
+	/// Events about a deleted channel have to be sent to the viewers the
+	/// channel had before it was deleted.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a lock on `role_user_map` after loading the
+	/// channel from the database.
+	///
+	/// [RoleUserMap]: super::RoleUserMap
+	pub async fn channel_viewers(
+		&self,
+		db: &PgPool,
+		channel: &Channel,
+	) -> Result<Vec<Snowflake>, Error> {
+		let group = RecipientGroup::ChannelViewers(channel.id).load(db).await?;
+		let mut viewers = HashSet::new();
+		group.resolve(&*self.role_user_map.lock().await, &mut viewers);
+		Ok(viewers.into_iter().collect())
+	}
+}
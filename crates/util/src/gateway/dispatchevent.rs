@@ -31,9 +31,9 @@ pub enum DispatchEvent {
 	ChannelDelete(GatewayPayload<ChannelDelete>),
 	ChannelStatuses(GatewayPayload<()>),
 	VoiceChannelStatusUpdate(GatewayPayload<()>),
-	ChannelPinsUpdate(GatewayPayload<()>),
-	ChannelRecipientAdd(GatewayPayload<()>),
-	ChannelRecipientRemove(GatewayPayload<()>),
+	ChannelPinsUpdate(GatewayPayload<ChannelPinsUpdate>),
+	ChannelRecipientAdd(GatewayPayload<ChannelRecipientChange>),
+	ChannelRecipientRemove(GatewayPayload<ChannelRecipientChange>),
 	DmSettingsUpsellShow(GatewayPayload<()>),
 	ThreadCreate(GatewayPayload<ThreadCreate>),
 	ThreadUpdate(GatewayPayload<ThreadUpdate>),
@@ -58,10 +58,11 @@ pub enum DispatchEvent {
 	GuildMemberRemove(GatewayPayload<GuildMemberRemove>),
 	GuildMemberUpdate(GatewayPayload<GuildMemberUpdate>),
 	GuildMembersChunk(GatewayPayload<GuildMembersChunk>),
+	GuildMemberListUpdate(GatewayPayload<GuildMemberListUpdate>),
 	GuildMembersRequest(GatewayPayload<GatewayRequestGuildMembers>),
-	GuildRoleCreate(GatewayPayload<()>),
-	GuildRoleUpdate(GatewayPayload<()>),
-	GuildRoleDelete(GatewayPayload<()>),
+	GuildRoleCreate(GatewayPayload<GuildRoleCreate>),
+	GuildRoleUpdate(GatewayPayload<GuildRoleUpdate>),
+	GuildRoleDelete(GatewayPayload<GuildRoleDelete>),
 	GuildScheduledEventCreate(GatewayPayload<()>),
 	GuildScheduledEventUpdate(GatewayPayload<()>),
 	GuildScheduledEventDelete(GatewayPayload<()>),
@@ -96,6 +97,7 @@ pub enum DispatchEvent {
 	RelationshipAdd(GatewayPayload<()>),
 	RelationshipUpdate(GatewayPayload<()>),
 	RelationshipRemove(GatewayPayload<()>),
+	SessionsReplace(GatewayPayload<Vec<Session>>),
 	StageInstanceCreate(GatewayPayload<StageInstanceCreate>),
 	StageInstanceUpdate(GatewayPayload<StageInstanceUpdate>),
 	StageInstanceDelete(GatewayPayload<StageInstanceDelete>),
@@ -112,6 +114,193 @@ pub enum DispatchEvent {
 	WebhooksUpdate(GatewayPayload<WebhooksUpdate>),
 }
 
+impl DispatchEvent {
+	/// Create a dispatch event of type `event_type`, with `data` as the event
+	/// data. Fails if `data` does not match the data of the event type.
+	pub fn new(event_type: DispatchEventType, data: impl Serialize) -> Result<Self, Error> {
+		let event_name = event_type.to_string();
+		let variant = screaming_snake_to_pascal_case(&event_name);
+		Ok(serde_json::from_value(serde_json::json!({
+			variant: {
+				"op": Opcode::Dispatch as u8,
+				"d": data,
+				"t": event_name,
+			}
+		}))?)
+	}
+
+	/// Convert this event into the payload sent to clients: An op code 0
+	/// [GatewayPayload] with the event name as `t`. The sequence number `s` is
+	/// left empty and has to be assigned by the session sending the payload.
+	pub fn to_payload(&self) -> Result<GatewayPayload<serde_json::Value>, Error> {
+		Ok(GatewayPayload {
+			op_code: Opcode::Dispatch as u8,
+			event_name: Some(self.event_type()?.to_string()),
+			event_data: self.event_data()?,
+			sequence_number: None,
+		})
+	}
+}
+
+/// Implement [DispatchEvent::event_type] and `DispatchEvent::event_data` for
+/// the variants of [DispatchEvent] which are named like their
+/// [DispatchEventType]. The match is exhaustive, so that adding a variant to
+/// [DispatchEvent] without adding it here fails to compile.
+macro_rules! dispatch_event_types {
+	($($variant:ident),* $(,)?) => {
+		impl DispatchEvent {
+			/// The [DispatchEventType] of this event. Fails for requests sent
+			/// by clients, which are not dispatched.
+			pub fn event_type(&self) -> Result<DispatchEventType, Error> {
+				match self {
+					DispatchEvent::GuildMembersRequest(_) => Err(Error::Custom(
+						"GuildMembersRequest is not a dispatch event".to_string(),
+					)),
+					$(DispatchEvent::$variant(_) => Ok(DispatchEventType::$variant),)*
+				}
+			}
+
+			/// The serialized data `d` of this event.
+			fn event_data(&self) -> Result<Option<serde_json::Value>, Error> {
+				let data = match self {
+					DispatchEvent::GuildMembersRequest(payload) => {
+						payload.event_data.as_ref().map(serde_json::to_value)
+					}
+					$(DispatchEvent::$variant(payload) => {
+						payload.event_data.as_ref().map(serde_json::to_value)
+					})*
+				};
+				Ok(data.transpose()?)
+			}
+		}
+	};
+}
+
+dispatch_event_types! {
+	Ready,
+	ReadySupplemental,
+	Resumed,
+	AuthSessionChange,
+	AuthenticatorCreate,
+	AuthenticatorUpdate,
+	AuthenticatorDelete,
+	ApplicationCommandPermissionsUpdate,
+	AutoModerationRuleCreate,
+	AutoModerationRuleUpdate,
+	AutoModerationRuleDelete,
+	AutoModerationActionExecution,
+	AutoModerationMentionRaidDetection,
+	CallCreate,
+	CallUpdate,
+	CallDelete,
+	ChannelCreate,
+	ChannelUpdate,
+	ChannelDelete,
+	ChannelStatuses,
+	VoiceChannelStatusUpdate,
+	ChannelPinsUpdate,
+	ChannelRecipientAdd,
+	ChannelRecipientRemove,
+	DmSettingsUpsellShow,
+	ThreadCreate,
+	ThreadUpdate,
+	ThreadDelete,
+	ThreadListSync,
+	ThreadMemberUpdate,
+	ThreadMembersUpdate,
+	FriendSuggestionCreate,
+	FriendSuggestionDelete,
+	GuildCreate,
+	GuildUpdate,
+	GuildDelete,
+	GuildAuditLogEntryCreate,
+	GuildBanAdd,
+	GuildBanRemove,
+	GuildEmojisUpdate,
+	GuildStickersUpdate,
+	GuildJoinRequestCreate,
+	GuildJoinRequestUpdate,
+	GuildJoinRequestDelete,
+	GuildMemberAdd,
+	GuildMemberRemove,
+	GuildMemberUpdate,
+	GuildMembersChunk,
+	GuildMemberListUpdate,
+	GuildRoleCreate,
+	GuildRoleUpdate,
+	GuildRoleDelete,
+	GuildScheduledEventCreate,
+	GuildScheduledEventUpdate,
+	GuildScheduledEventDelete,
+	GuildScheduledEventUserAdd,
+	GuildScheduledEventUserRemove,
+	GuildSoundboardSoundCreate,
+	GuildSoundboardSoundUpdate,
+	GuildSoundboardSoundDelete,
+	SoundboardSounds,
+	GuildIntegrationsUpdate,
+	IntegrationCreate,
+	IntegrationUpdate,
+	IntegrationDelete,
+	InteractionCreate,
+	InviteCreate,
+	InviteDelete,
+	MessageCreate,
+	MessageUpdate,
+	MessageDelete,
+	MessageDeleteBulk,
+	MessagePollVoteAdd,
+	MessagePollVoteRemove,
+	MessageReactionAdd,
+	MessageReactionAddMany,
+	MessageReactionRemove,
+	MessageReactionRemoveAll,
+	MessageReactionRemoveEmoji,
+	RecentMentionDelete,
+	LastMessages,
+	Oauth2TokenRevoke,
+	PresenceUpdate,
+	RelationshipAdd,
+	RelationshipUpdate,
+	RelationshipRemove,
+	SessionsReplace,
+	StageInstanceCreate,
+	StageInstanceUpdate,
+	StageInstanceDelete,
+	TypingStart,
+	UserUpdate,
+	UserApplicationRemove,
+	UserConnectionsUpdate,
+	UserNoteUpdate,
+	UserRequiredActionUpdate,
+	UserSettingsUpdate,
+	VoiceStateUpdate,
+	VoiceServerUpdate,
+	VoiceChannelEffectSend,
+	WebhooksUpdate,
+}
+
+fn screaming_snake_to_pascal_case(value: &str) -> String {
+	value
+		.split('_')
+		.map(|word| {
+			let mut characters = word.chars();
+			characters
+				.next()
+				.map(|first| first.to_string() + &characters.as_str().to_ascii_lowercase())
+				.unwrap_or_default()
+		})
+		.collect()
+}
+
+/// The data of `CHANNEL_RECIPIENT_ADD` and `CHANNEL_RECIPIENT_REMOVE`: `user`
+/// was added to or removed from the group DM `channel_id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelRecipientChange {
+	pub channel_id: Snowflake,
+	pub user: chorus::types::PublicUser,
+}
+
 impl From<DispatchEvent> for Event {
 	fn from(value: DispatchEvent) -> Self {
 		Self::Dispatch(value)
@@ -172,6 +361,7 @@ pub enum DispatchEventType {
 	GuildMemberRemove,
 	GuildMemberUpdate,
 	GuildMembersChunk,
+	GuildMemberListUpdate,
 	GuildRoleCreate,
 	GuildRoleUpdate,
 	GuildRoleDelete,
@@ -209,6 +399,7 @@ pub enum DispatchEventType {
 	RelationshipAdd,
 	RelationshipUpdate,
 	RelationshipRemove,
+	SessionsReplace,
 	StageInstanceCreate,
 	StageInstanceUpdate,
 	StageInstanceDelete,
@@ -666,6 +857,16 @@ mod dispatch_event_type_tests {
 		assert_eq!(DispatchEventType::try_from("GUILD_MEMBERS_CHUNK".to_string()).unwrap(), event);
 	}
 
+	#[test]
+	fn test_guild_member_list_update() {
+		let event = DispatchEventType::GuildMemberListUpdate;
+		assert_eq!(event.to_string(), "GUILD_MEMBER_LIST_UPDATE");
+		assert_eq!(
+			DispatchEventType::try_from("GUILD_MEMBER_LIST_UPDATE".to_string()).unwrap(),
+			event
+		);
+	}
+
 	#[test]
 	fn test_guild_role_create() {
 		let event = DispatchEventType::GuildRoleCreate;
@@ -891,6 +1092,13 @@ mod dispatch_event_type_tests {
 		);
 	}
 
+	#[test]
+	fn test_sessions_replace() {
+		let event = DispatchEventType::SessionsReplace;
+		assert_eq!(event.to_string(), "SESSIONS_REPLACE");
+		assert_eq!(DispatchEventType::try_from("SESSIONS_REPLACE".to_string()).unwrap(), event);
+	}
+
 	#[test]
 	fn test_presence_update() {
 		let event = DispatchEventType::PresenceUpdate;
@@ -933,3 +1141,60 @@ mod dispatch_event_type_tests {
 		assert_eq!(DispatchEventType::try_from("WEBHOOKS_UPDATE".to_string()).unwrap(), event);
 	}
 }
+
+#[cfg(test)]
+mod dispatch_event_tests {
+	use super::*;
+
+	#[test]
+	fn dispatch_events_are_sent_as_op_code_0_payloads() {
+		let event = DispatchEvent::UserSettingsUpdate(GatewayPayload {
+			op_code: 0,
+			event_data: None,
+			sequence_number: Some(7),
+			event_name: None,
+		});
+		assert_eq!(event.event_type().unwrap(), DispatchEventType::UserSettingsUpdate);
+		let payload = event.to_payload().unwrap();
+		assert_eq!(payload.op_code, 0);
+		assert_eq!(payload.event_name.as_deref(), Some("USER_SETTINGS_UPDATE"));
+		assert_eq!(payload.sequence_number, None);
+		assert_eq!(
+			serde_json::to_value(&payload).unwrap(),
+			serde_json::json!({"op": 0, "t": "USER_SETTINGS_UPDATE"})
+		);
+	}
+
+	#[test]
+	fn dispatch_events_are_created_from_their_type_and_data() {
+		let event = DispatchEvent::new(
+			DispatchEventType::GuildMemberListUpdate,
+			serde_json::json!({
+				"guild_id": "1",
+				"id": "everyone",
+				"member_count": 0,
+				"online_count": 0,
+				"groups": [],
+				"ops": [],
+			}),
+		)
+		.unwrap();
+		assert_eq!(event.event_type().unwrap(), DispatchEventType::GuildMemberListUpdate);
+		let payload = event.to_payload().unwrap();
+		assert_eq!(payload.event_name.as_deref(), Some("GUILD_MEMBER_LIST_UPDATE"));
+		assert_eq!(payload.event_data.unwrap()["id"], "everyone");
+		assert!(DispatchEvent::new(DispatchEventType::GuildMemberListUpdate, "nonsense").is_err());
+	}
+
+	#[test]
+	fn requests_are_not_dispatch_events() {
+		let request = DispatchEvent::GuildMembersRequest(GatewayPayload {
+			op_code: 8,
+			event_data: None,
+			sequence_number: None,
+			event_name: None,
+		});
+		assert!(request.event_type().is_err());
+		assert!(request.to_payload().is_err());
+	}
+}
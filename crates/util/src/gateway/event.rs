@@ -125,14 +125,14 @@ pub enum Event {
 	Identify(GatewayPayload<GatewayIdentifyPayload>),
 	Resume(GatewayPayload<GatewayResume>),
 	InvalidSession(GatewayPayload<GatewayInvalidSession>),
-	PresenceUpdate(GatewayPayload<PresenceUpdate>),
+	PresenceUpdate(GatewayPayload<UpdatePresence>),
 	VoiceStateUpdate(GatewayPayload<VoiceStateUpdate>),
 	VoiceServerPing(GatewayPayload<VoiceServerUpdate>),
 	Reconnect(GatewayPayload<()>),
 	RequestGuildMembers(GatewayPayload<GatewayRequestGuildMembers>),
 	HeartbeatAck(GatewayPayload<GatewayHeartbeatAck>),
 	CallConnect(GatewayPayload<()>),
-	GuildSubscriptions(GatewayPayload<()>),
+	GuildSubscriptions(GatewayPayload<GuildSubscriptionsRequest>),
 	LobbyConnect(GatewayPayload<()>),
 	LobbyDisconnect(GatewayPayload<()>),
 	LobbyVoiceStates(GatewayPayload<()>),
@@ -526,6 +526,10 @@ impl TryFrom<tokio_tungstenite::tungstenite::Message> for Event {
 				convert_to!(DispatchEvent::GuildMembersChunk, message_as_string)
 					.map(Event::Dispatch)
 			}
+			DispatchEventType::GuildMemberListUpdate => {
+				convert_to!(DispatchEvent::GuildMemberListUpdate, message_as_string)
+					.map(Event::Dispatch)
+			}
 			DispatchEventType::GuildRoleCreate => {
 				convert_to!(DispatchEvent::GuildRoleCreate, message_as_string).map(Event::Dispatch)
 			}
@@ -662,6 +666,9 @@ impl TryFrom<tokio_tungstenite::tungstenite::Message> for Event {
 				convert_to!(DispatchEvent::RelationshipRemove, message_as_string)
 					.map(Event::Dispatch)
 			}
+			DispatchEventType::SessionsReplace => {
+				convert_to!(DispatchEvent::SessionsReplace, message_as_string).map(Event::Dispatch)
+			}
 			DispatchEventType::StageInstanceCreate => {
 				convert_to!(DispatchEvent::StageInstanceCreate, message_as_string)
 					.map(Event::Dispatch)
@@ -0,0 +1,143 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Transport of [Event]s from the node causing them to the gateway nodes
+//! delivering them to their connected users.
+//!
+//! Events are published as [BusMessage]s, which already carry the ids of the
+//! users they are meant for: The recipients are resolved by the publishing
+//! node, which is the one that knows about the change causing the event.
+//! Every gateway node subscribes to the bus and delivers each message to those
+//! recipients which are connected to it, see
+//! [ConnectedUsers::deliver_bus_messages](super::ConnectedUsers::deliver_bus_messages).
+//!
+//! Changes to the state gateway nodes keep in memory, such as member lists and
+//! presences, are published to the bus as well, and applied by every gateway
+//! node. The [RoleUserMap](super::RoleUserMap) and the presences are kept by
+//! every process, which applies the changes published by the others, see
+//! [ConnectedUsers::replicate_state](super::ConnectedUsers::replicate_state).
+
+use std::{ops::Deref, sync::Arc};
+
+use chorus::types::{PublicUser, Snowflake};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use super::{
+	event::Event,
+	presence::{ClientPresence, Status},
+	role_user_map::RoleUserMapChange,
+};
+use crate::errors::Error;
+
+pub mod postgres;
+
+pub use postgres::PgEventBus;
+
+/// Number of [BusMessage]s buffered per subscriber before the oldest ones are
+/// dropped.
+pub const BUS_CHANNEL_CAPACITY: usize = 1024;
+
+/// A message published to the [EventBus].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BusMessage {
+	/// An [Event] addressed to a set of users.
+	Dispatch { recipients: Vec<Snowflake>, event: Event },
+	/// The members or roles of the guild `guild_id` changed, so that its member
+	/// list has to be reloaded.
+	MemberListChanged { guild_id: Snowflake },
+	/// The user `user_id` changed the status in their settings, which
+	/// overrides the status of their clients.
+	SettingsStatusChanged { user_id: Snowflake, status: Option<Status> },
+	/// The process `origin` changed roles or role assignments in the database,
+	/// and made `changes` to its Role->User map.
+	RoleUserMapChanged { origin: Origin, changes: Vec<RoleUserMapChange> },
+	/// The client with the session `session_token` of the user `user`, which
+	/// is connected to the process `origin`, connected or changed its
+	/// presence to `client`.
+	ClientPresenceChanged {
+		origin: Origin,
+		user: PublicUser,
+		settings_status: Option<Status>,
+		session_token: String,
+		client: ClientPresence,
+	},
+	/// The client with the session `session_token` of the user `user_id`
+	/// disconnected from the process `origin`.
+	ClientDisconnected { origin: Origin, user_id: Snowflake, session_token: String },
+	/// The process `origin` started, and asks the others for the presences of
+	/// the clients connected to them.
+	PresencesRequested { origin: Origin },
+}
+
+/// The process which published a [BusMessage] about state that every process
+/// keeps in memory. The process itself has already applied the change, and
+/// skips the message once it receives it back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Origin(Snowflake);
+
+impl Default for Origin {
+	fn default() -> Self {
+		Self(Snowflake::generate())
+	}
+}
+
+/// A way of getting [BusMessage]s to the subscribers of all nodes.
+pub trait EventBus: Send + Sync {
+	/// Publish `message` to the subscribers of all nodes, including this one.
+	fn publish(&self, message: BusMessage) -> BoxFuture<'_, Result<(), Error>>;
+
+	/// Receive all messages published from now on.
+	fn subscribe(&self) -> broadcast::Receiver<BusMessage>;
+}
+
+/// The [EventBus] of this node, shared between the HTTP API and the gateway.
+/// Defaults to an [InProcessEventBus].
+#[derive(Clone)]
+pub struct SharedEventBus(Arc<dyn EventBus>);
+
+impl SharedEventBus {
+	pub fn new(event_bus: impl EventBus + 'static) -> Self {
+		Self(Arc::new(event_bus))
+	}
+}
+
+impl Default for SharedEventBus {
+	fn default() -> Self {
+		Self::new(InProcessEventBus::default())
+	}
+}
+
+impl Deref for SharedEventBus {
+	type Target = dyn EventBus;
+
+	fn deref(&self) -> &Self::Target {
+		self.0.as_ref()
+	}
+}
+
+/// An [EventBus] which only reaches the subscribers within this process.
+pub struct InProcessEventBus {
+	sender: broadcast::Sender<BusMessage>,
+}
+
+impl Default for InProcessEventBus {
+	fn default() -> Self {
+		Self { sender: broadcast::channel(BUS_CHANNEL_CAPACITY).0 }
+	}
+}
+
+impl EventBus for InProcessEventBus {
+	fn publish(&self, message: BusMessage) -> BoxFuture<'_, Result<(), Error>> {
+		// Sending only fails if nobody is subscribed, e.g. on API-only nodes.
+		let _ = self.sender.send(message);
+		Box::pin(async { Ok(()) })
+	}
+
+	fn subscribe(&self) -> broadcast::Receiver<BusMessage> {
+		self.sender.subscribe()
+	}
+}
@@ -0,0 +1,211 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An [EventBus] using PostgreSQL `LISTEN`/`NOTIFY`.
+//!
+//! `NOTIFY` payloads are limited to 8000 bytes. Messages which do not fit are
+//! written to the `event_bus_payloads` table, and only their id is sent. The
+//! stored payloads are purged after [PAYLOAD_RETENTION], by every node.
+
+use std::{borrow::Cow, time::Duration};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, postgres::PgListener};
+use tokio::sync::broadcast;
+
+use super::{BUS_CHANNEL_CAPACITY, BusMessage, EventBus};
+use crate::errors::Error;
+
+/// The channel all nodes `LISTEN` on.
+pub const NOTIFY_CHANNEL: &str = "symfonia_events";
+
+/// Largest payload sent with `NOTIFY` directly, leaving some headroom below
+/// the limit of 8000 bytes.
+pub const MAX_NOTIFY_PAYLOAD: usize = 7900;
+
+/// How long payloads too large for `NOTIFY` are kept in the database. Nodes
+/// which did not fetch a payload in this time will miss the event.
+pub const PAYLOAD_RETENTION: Duration = Duration::from_secs(5 * 60);
+
+/// The payload of a `NOTIFY` on [NOTIFY_CHANNEL].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Notification<'a> {
+	Inline { origin: u64, message: Cow<'a, BusMessage> },
+	Stored { origin: u64, payload_id: i64 },
+}
+
+/// An [EventBus] reaching all nodes connected to the same database.
+pub struct PgEventBus {
+	db: PgPool,
+	node_id: u64,
+	sender: broadcast::Sender<BusMessage>,
+}
+
+impl PgEventBus {
+	/// Start listening on [NOTIFY_CHANNEL] as the node `node_id`, and purging
+	/// expired payloads from the database.
+	pub async fn connect(db: PgPool, node_id: u64) -> Result<Self, Error> {
+		let mut listener = PgListener::connect_with(&db).await?;
+		listener.listen(NOTIFY_CHANNEL).await?;
+		let sender = broadcast::channel(BUS_CHANNEL_CAPACITY).0;
+		tokio::spawn(receive_notifications(listener, db.clone(), sender.clone()));
+		tokio::spawn(purge_payloads(db.clone()));
+		log::info!(target: "symfonia::event_bus", "Listening for events of other nodes as node {node_id}");
+		Ok(Self { db, node_id, sender })
+	}
+}
+
+impl EventBus for PgEventBus {
+	fn publish(&self, message: BusMessage) -> BoxFuture<'_, Result<(), Error>> {
+		Box::pin(async move {
+			let payload = match inline_payload(self.node_id, &message)? {
+				Some(payload) => payload,
+				None => {
+					let payload_id: i64 = sqlx::query_scalar(
+						"INSERT INTO event_bus_payloads (payload) VALUES ($1) RETURNING id",
+					)
+					.bind(sqlx::types::Json(&message))
+					.fetch_one(&self.db)
+					.await?;
+					serde_json::to_string(&Notification::Stored {
+						origin: self.node_id,
+						payload_id,
+					})?
+				}
+			};
+			sqlx::query("SELECT pg_notify($1, $2)")
+				.bind(NOTIFY_CHANNEL)
+				.bind(payload)
+				.execute(&self.db)
+				.await?;
+			Ok(())
+		})
+	}
+
+	fn subscribe(&self) -> broadcast::Receiver<BusMessage> {
+		self.sender.subscribe()
+	}
+}
+
+/// The `NOTIFY` payload carrying `message` itself, or [None] if it would be
+/// larger than [MAX_NOTIFY_PAYLOAD].
+fn inline_payload(origin: u64, message: &BusMessage) -> Result<Option<String>, Error> {
+	let payload =
+		serde_json::to_string(&Notification::Inline { origin, message: Cow::Borrowed(message) })?;
+	Ok((payload.len() <= MAX_NOTIFY_PAYLOAD).then_some(payload))
+}
+
+/// Read the [BusMessage] of a `NOTIFY` payload, fetching it from the database
+/// if it has been stored there.
+async fn decode(db: &PgPool, payload: &str) -> Result<BusMessage, Error> {
+	match serde_json::from_str(payload)? {
+		Notification::Inline { message, .. } => Ok(message.into_owned()),
+		Notification::Stored { origin, payload_id } => {
+			let message: Option<sqlx::types::Json<BusMessage>> =
+				sqlx::query_scalar("SELECT payload FROM event_bus_payloads WHERE id = $1")
+					.bind(payload_id)
+					.fetch_optional(db)
+					.await?;
+			message.map(|message| message.0).ok_or_else(|| {
+				Error::Custom(format!(
+					"Payload {payload_id} published by node {origin} has already been purged"
+				))
+			})
+		}
+	}
+}
+
+/// Hand every notification received by `listener` to the subscribers of
+/// `sender`.
+async fn receive_notifications(
+	mut listener: PgListener,
+	db: PgPool,
+	sender: broadcast::Sender<BusMessage>,
+) {
+	loop {
+		// After losing the connection, `recv` reconnects on its next call.
+		// Events notified in the meantime are lost.
+		let notification = match listener.recv().await {
+			Ok(notification) => notification,
+			Err(e) => {
+				log::error!(target: "symfonia::event_bus", "Failed to receive events: {e}");
+				tokio::time::sleep(Duration::from_secs(1)).await;
+				continue;
+			}
+		};
+		match decode(&db, notification.payload()).await {
+			// Sending only fails if nobody is subscribed, e.g. on API-only nodes.
+			Ok(message) => {
+				let _ = sender.send(message);
+			}
+			Err(e) => log::error!(target: "symfonia::event_bus", "Failed to decode an event: {e}"),
+		}
+	}
+}
+
+/// Delete payloads older than [PAYLOAD_RETENTION], every [PAYLOAD_RETENTION].
+async fn purge_payloads(db: PgPool) {
+	let mut interval = tokio::time::interval(PAYLOAD_RETENTION);
+	loop {
+		interval.tick().await;
+		if let Err(e) = sqlx::query(
+			"DELETE FROM event_bus_payloads WHERE created_at < NOW() - make_interval(secs => $1)",
+		)
+		.bind(PAYLOAD_RETENTION.as_secs_f64())
+		.execute(&db)
+		.await
+		{
+			log::error!(target: "symfonia::event_bus", "Failed to purge stored events: {e}");
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chorus::types::Snowflake;
+	use serde_json::json;
+
+	use super::*;
+	use crate::gateway::{
+		dispatchevent::{DispatchEvent, DispatchEventType},
+		event::Event,
+	};
+
+	fn message(recipients: u64) -> BusMessage {
+		BusMessage::Dispatch {
+			recipients: (0..recipients).map(|id| Snowflake(u64::MAX - id)).collect(),
+			event: Event::Dispatch(
+				DispatchEvent::new(
+					DispatchEventType::MessageDelete,
+					json!({"id": "1", "channel_id": "2"}),
+				)
+				.unwrap(),
+			),
+		}
+	}
+
+	#[test]
+	fn only_small_messages_are_notified_inline() {
+		let small = message(3);
+		let payload = inline_payload(1, &small).unwrap().expect("Small message was not inlined");
+		let Notification::Inline { origin, message: inlined } =
+			serde_json::from_str(&payload).unwrap()
+		else {
+			panic!("Inline payload was not parsed as inline");
+		};
+		assert_eq!(origin, 1);
+		let (
+			BusMessage::Dispatch { recipients, .. },
+			BusMessage::Dispatch { recipients: sent, .. },
+		) = (inlined.as_ref(), &small)
+		else {
+			panic!("Inline payload is not a dispatch");
+		};
+		assert_eq!(recipients, sent);
+
+		assert!(inline_payload(1, &message(1000)).unwrap().is_none());
+	}
+}
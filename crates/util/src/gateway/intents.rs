@@ -0,0 +1,273 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Gateway intents, with which clients choose the dispatch events they want to
+//! receive.
+
+use bitflags::bitflags;
+use chorus::types::{ApplicationFlags, Snowflake};
+use serde_json::Value;
+use sqlx::PgPool;
+
+use super::{GatewayPayload, dispatchevent::DispatchEventType};
+use crate::{
+	entities::Application,
+	errors::{Error, GatewayError},
+};
+
+bitflags! {
+	#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+	pub struct GatewayIntents: u64 {
+		const GUILDS = 1 << 0;
+		const GUILD_MEMBERS = 1 << 1;
+		const GUILD_MODERATION = 1 << 2;
+		const GUILD_EXPRESSIONS = 1 << 3;
+		const GUILD_INTEGRATIONS = 1 << 4;
+		const GUILD_WEBHOOKS = 1 << 5;
+		const GUILD_INVITES = 1 << 6;
+		const GUILD_VOICE_STATES = 1 << 7;
+		const GUILD_PRESENCES = 1 << 8;
+		const GUILD_MESSAGES = 1 << 9;
+		const GUILD_MESSAGE_REACTIONS = 1 << 10;
+		const GUILD_MESSAGE_TYPING = 1 << 11;
+		const DIRECT_MESSAGES = 1 << 12;
+		const DIRECT_MESSAGE_REACTIONS = 1 << 13;
+		const DIRECT_MESSAGE_TYPING = 1 << 14;
+		const MESSAGE_CONTENT = 1 << 15;
+		const GUILD_SCHEDULED_EVENTS = 1 << 16;
+		const AUTO_MODERATION_CONFIGURATION = 1 << 20;
+		const AUTO_MODERATION_EXECUTION = 1 << 21;
+		const GUILD_MESSAGE_POLLS = 1 << 24;
+		const DIRECT_MESSAGE_POLLS = 1 << 25;
+	}
+}
+
+impl Default for GatewayIntents {
+	/// All intents, as used by user clients which do not send any.
+	fn default() -> Self {
+		Self::all()
+	}
+}
+
+impl GatewayIntents {
+	/// Intents which a bot may only use if its application has been granted
+	/// access to them.
+	pub const PRIVILEGED: Self =
+		Self::GUILD_MEMBERS.union(Self::GUILD_PRESENCES).union(Self::MESSAGE_CONTENT);
+
+	/// Parse the `intents` of an identify payload. Unknown bits are rejected.
+	pub fn from_identify(intents: i32) -> Result<Self, GatewayError> {
+		u64::try_from(intents)
+			.ok()
+			.and_then(Self::from_bits)
+			.ok_or(GatewayError::InvalidIntents(intents as i64))
+	}
+
+	/// The privileged intents the user `user_id` is allowed to use. Only bots
+	/// are restricted, through the flags of their application.
+	pub async fn allowed_privileged(db: &PgPool, user_id: Snowflake) -> Result<Self, Error> {
+		let Some(application) = Application::get_by_bot_user_id(db, user_id).await? else {
+			return Ok(Self::PRIVILEGED);
+		};
+		let mut allowed = Self::empty();
+		if application.flags.intersects(
+			ApplicationFlags::GATEWAY_GUILD_MEMBERS
+				| ApplicationFlags::GATEWAY_GUILD_MEMBERS_LIMITED,
+		) {
+			allowed |= Self::GUILD_MEMBERS;
+		}
+		if application.flags.intersects(
+			ApplicationFlags::GATEWAY_PRESENCE | ApplicationFlags::GATEWAY_PRESENCE_LIMITED,
+		) {
+			allowed |= Self::GUILD_PRESENCES;
+		}
+		if application.flags.intersects(
+			ApplicationFlags::GATEWAY_MESSAGE_CONTENT
+				| ApplicationFlags::GATEWAY_MESSAGE_CONTENT_LIMITED,
+		) {
+			allowed |= Self::MESSAGE_CONTENT;
+		}
+		Ok(allowed)
+	}
+
+	/// The intent a client needs to receive a dispatch of type `event_type`, or
+	/// `None` if the event is sent regardless of intents. Some events are
+	/// covered by different intents depending on whether they happened in a
+	/// guild or in a private channel.
+	pub fn required_for(event_type: DispatchEventType, in_guild: bool) -> Option<Self> {
+		use DispatchEventType::*;
+		let (guild, direct) = match event_type {
+			GuildCreate | GuildUpdate | GuildDelete | GuildRoleCreate | GuildRoleUpdate
+			| GuildRoleDelete | ChannelCreate | ChannelUpdate | ChannelDelete | ThreadCreate
+			| ThreadUpdate | ThreadDelete | ThreadListSync | ThreadMemberUpdate
+			| StageInstanceCreate | StageInstanceUpdate | StageInstanceDelete => (Self::GUILDS, None),
+			ChannelPinsUpdate => (Self::GUILDS, Some(Self::DIRECT_MESSAGES)),
+			GuildMemberAdd | GuildMemberUpdate | GuildMemberRemove | ThreadMembersUpdate => {
+				(Self::GUILD_MEMBERS, None)
+			}
+			GuildAuditLogEntryCreate | GuildBanAdd | GuildBanRemove => {
+				(Self::GUILD_MODERATION, None)
+			}
+			GuildEmojisUpdate
+			| GuildStickersUpdate
+			| GuildSoundboardSoundCreate
+			| GuildSoundboardSoundUpdate
+			| GuildSoundboardSoundDelete => (Self::GUILD_EXPRESSIONS, None),
+			GuildIntegrationsUpdate | IntegrationCreate | IntegrationUpdate | IntegrationDelete => {
+				(Self::GUILD_INTEGRATIONS, None)
+			}
+			WebhooksUpdate => (Self::GUILD_WEBHOOKS, None),
+			InviteCreate | InviteDelete => (Self::GUILD_INVITES, None),
+			VoiceStateUpdate | VoiceChannelEffectSend => (Self::GUILD_VOICE_STATES, None),
+			PresenceUpdate => (Self::GUILD_PRESENCES, None),
+			MessageCreate | MessageUpdate | MessageDelete | MessageDeleteBulk => {
+				(Self::GUILD_MESSAGES, Some(Self::DIRECT_MESSAGES))
+			}
+			MessageReactionAdd
+			| MessageReactionRemove
+			| MessageReactionRemoveAll
+			| MessageReactionRemoveEmoji => {
+				(Self::GUILD_MESSAGE_REACTIONS, Some(Self::DIRECT_MESSAGE_REACTIONS))
+			}
+			TypingStart => (Self::GUILD_MESSAGE_TYPING, Some(Self::DIRECT_MESSAGE_TYPING)),
+			GuildScheduledEventCreate
+			| GuildScheduledEventUpdate
+			| GuildScheduledEventDelete
+			| GuildScheduledEventUserAdd
+			| GuildScheduledEventUserRemove => (Self::GUILD_SCHEDULED_EVENTS, None),
+			AutoModerationRuleCreate | AutoModerationRuleUpdate | AutoModerationRuleDelete => {
+				(Self::AUTO_MODERATION_CONFIGURATION, None)
+			}
+			AutoModerationActionExecution => (Self::AUTO_MODERATION_EXECUTION, None),
+			MessagePollVoteAdd | MessagePollVoteRemove => {
+				(Self::GUILD_MESSAGE_POLLS, Some(Self::DIRECT_MESSAGE_POLLS))
+			}
+			_ => return None,
+		};
+		if in_guild { Some(guild) } else { direct.or(Some(guild)) }
+	}
+
+	/// Apply these intents to a dispatch sent to the user `user_id`. Returns
+	/// `false` if the dispatch must not be sent to the client at all.
+	///
+	/// Clients without [GatewayIntents::MESSAGE_CONTENT] still receive guild
+	/// messages, but without their content, unless they wrote the message or
+	/// are mentioned in it.
+	pub fn filter(
+		self,
+		event_type: DispatchEventType,
+		payload: &mut GatewayPayload<Value>,
+		user_id: Snowflake,
+	) -> bool {
+		let Some(data) = payload.event_data.as_mut() else {
+			return true;
+		};
+		let in_guild = data.get("guild_id").is_some_and(|guild_id| !guild_id.is_null());
+		if let Some(required) = Self::required_for(event_type, in_guild) {
+			if !self.contains(required) {
+				return false;
+			}
+		}
+		if in_guild
+			&& !self.contains(Self::MESSAGE_CONTENT)
+			&& matches!(
+				event_type,
+				DispatchEventType::MessageCreate | DispatchEventType::MessageUpdate
+			) && !concerns_user(data, user_id)
+		{
+			strip_message_content(data);
+		}
+		true
+	}
+}
+
+/// Whether the message `data` was written by or mentions the user `user_id`.
+fn concerns_user(data: &Value, user_id: Snowflake) -> bool {
+	let user_id = user_id.to_string();
+	let is_user = |user: &Value| user.get("id").and_then(Value::as_str) == Some(user_id.as_str());
+	data.get("author").is_some_and(is_user)
+		|| data
+			.get("mentions")
+			.and_then(Value::as_array)
+			.is_some_and(|mentions| mentions.iter().any(is_user))
+}
+
+fn strip_message_content(data: &mut Value) {
+	let Some(message) = data.as_object_mut() else {
+		return;
+	};
+	if message.contains_key("content") {
+		message.insert("content".to_string(), Value::String(String::new()));
+	}
+	for key in ["embeds", "attachments", "components"] {
+		if message.contains_key(key) {
+			message.insert(key.to_string(), Value::Array(Vec::new()));
+		}
+	}
+	message.remove("poll");
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	fn message_create(data: Value) -> GatewayPayload<Value> {
+		GatewayPayload {
+			op_code: 0,
+			event_data: Some(data),
+			sequence_number: None,
+			event_name: Some("MESSAGE_CREATE".to_string()),
+		}
+	}
+
+	#[test]
+	fn parses_identify_intents() {
+		assert_eq!(
+			GatewayIntents::from_identify(513).unwrap(),
+			GatewayIntents::GUILDS | GatewayIntents::GUILD_MESSAGES
+		);
+		assert!(GatewayIntents::from_identify(1 << 30).is_err());
+		assert!(GatewayIntents::from_identify(-1).is_err());
+	}
+
+	#[test]
+	fn filters_dispatches_by_intent() {
+		let intents = GatewayIntents::GUILDS | GatewayIntents::DIRECT_MESSAGES;
+		let user_id = Snowflake(1);
+		let mut guild_message = message_create(json!({"guild_id": "10", "content": "hi"}));
+		assert!(!intents.filter(DispatchEventType::MessageCreate, &mut guild_message, user_id));
+		let mut direct_message = message_create(json!({"content": "hi"}));
+		assert!(intents.filter(DispatchEventType::MessageCreate, &mut direct_message, user_id));
+		assert_eq!(direct_message.event_data.unwrap()["content"], "hi");
+		assert!(intents.filter(DispatchEventType::Ready, &mut message_create(json!({})), user_id));
+	}
+
+	#[test]
+	fn strips_message_content_without_intent() {
+		let intents = GatewayIntents::GUILD_MESSAGES;
+		let user_id = Snowflake(1);
+		let mut message = message_create(json!({
+			"guild_id": "10",
+			"author": {"id": "2"},
+			"mentions": [],
+			"content": "secret",
+			"embeds": [{"title": "secret"}]
+		}));
+		assert!(intents.filter(DispatchEventType::MessageCreate, &mut message, user_id));
+		let data = message.event_data.unwrap();
+		assert_eq!(data["content"], "");
+		assert_eq!(data["embeds"], json!([]));
+
+		let mut mention = message_create(json!({
+			"guild_id": "10",
+			"author": {"id": "2"},
+			"mentions": [{"id": "1"}],
+			"content": "hey <@1>"
+		}));
+		assert!(intents.filter(DispatchEventType::MessageCreate, &mut mention, user_id));
+		assert_eq!(mention.event_data.unwrap()["content"], "hey <@1>");
+	}
+}
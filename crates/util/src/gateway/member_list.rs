@@ -0,0 +1,725 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Lazily loaded guild member lists, as shown in the member sidebar of clients.
+//!
+//! Instead of receiving every member of a guild, clients subscribe to ranges
+//! of its member list through op 14 Guild Subscriptions. They receive these
+//! ranges, and from then on only the changes to the list, as
+//! `GUILD_MEMBER_LIST_UPDATE` dispatches. Online members are grouped by their
+//! highest hoisted role, all offline members are grouped together.
+//!
+//! A list only contains the members who can view the channels it belongs to.
+//! Channels which overwrite `VIEW_CHANNEL` alike share a list, see
+//! [ChannelVisibility::list_id].
+
+use std::collections::{HashMap, HashSet};
+
+use chorus::types::Snowflake;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use tokio::sync::broadcast::Sender;
+
+use super::{
+	ConnectedUsers, GatewayPayload,
+	dispatchevent::DispatchEvent,
+	event::Event,
+	event_bus::BusMessage,
+	presence::{PresenceStore, Status},
+	recipients::ChannelVisibility,
+};
+use crate::{
+	entities::{Channel, GuildMember, Role},
+	errors::Error,
+};
+
+/// Maximum number of list items a single range may span.
+pub const MAX_RANGE_SIZE: u64 = 100;
+/// Maximum number of ranges a session may subscribe to per member list.
+pub const MAX_RANGES: usize = 5;
+/// Maximum number of channels whose member lists a session may subscribe to
+/// per guild.
+pub const MAX_CHANNELS: usize = 5;
+/// Number of ops in an update above which subscribers are sent their ranges
+/// anew instead.
+const MAX_OPS: usize = 100;
+/// Number of members loaded from the database at once.
+const PAGE_SIZE: u16 = 1000;
+
+/// An op 14 Guild Subscriptions request, as sent by clients. Subscriptions to
+/// typing, activities and threads are not supported and ignored.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GuildSubscriptionsRequest {
+	pub guild_id: Snowflake,
+	/// The ranges of the member list to subscribe to, by channel.
+	#[serde(default)]
+	pub channels: HashMap<Snowflake, Vec<[u64; 2]>>,
+}
+
+impl GuildSubscriptionsRequest {
+	/// The channels with requested ranges, ordered by id. At most
+	/// [MAX_CHANNELS] of them are returned.
+	pub fn channel_ranges(&self) -> Vec<(Snowflake, &[[u64; 2]])> {
+		let mut channels: Vec<(Snowflake, &[[u64; 2]])> = self
+			.channels
+			.iter()
+			.filter(|(_, ranges)| !ranges.is_empty())
+			.map(|(channel_id, ranges)| (*channel_id, ranges.as_slice()))
+			.collect();
+		channels.sort_unstable_by_key(|(channel_id, _)| channel_id.0);
+		channels.truncate(MAX_CHANNELS);
+		channels
+	}
+}
+
+/// Limit `ranges` to [MAX_RANGE_SIZE] items each, and to at most [MAX_RANGES]
+/// of them.
+fn limit_ranges(ranges: impl IntoIterator<Item = [u64; 2]>) -> Vec<[u64; 2]> {
+	let mut ranges: Vec<[u64; 2]> = ranges
+		.into_iter()
+		.map(|[start, end]| {
+			let (start, end) = (start.min(end), start.max(end));
+			[start, end.min(start.saturating_add(MAX_RANGE_SIZE - 1))]
+		})
+		.collect();
+	ranges.sort_unstable();
+	ranges.dedup();
+	ranges.truncate(MAX_RANGES);
+	ranges
+}
+
+/// A group of the member list, followed by its members.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListGroup {
+	/// The id of the hoisted role of the group, `online` or `offline`.
+	pub id: String,
+	pub count: u64,
+}
+
+/// An item of the member list.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListItem {
+	Group(ListGroup),
+	/// A guild member, with its `roles` and `presence`.
+	Member(Value),
+}
+
+/// A change to the member list of a guild. Indices and ranges are positions in
+/// the list of items, which includes the groups.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "UPPERCASE")]
+pub enum ListOp {
+	Sync { range: [u64; 2], items: Vec<ListItem> },
+	Insert { index: u64, item: ListItem },
+	Update { index: u64, item: ListItem },
+	Delete { index: u64 },
+	Invalidate { range: [u64; 2] },
+}
+
+/// The data of a `GUILD_MEMBER_LIST_UPDATE` dispatch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GuildMemberListUpdate {
+	pub guild_id: Snowflake,
+	pub id: String,
+	pub member_count: u64,
+	pub online_count: u64,
+	pub groups: Vec<ListGroup>,
+	pub ops: Vec<ListOp>,
+}
+
+/// A member as shown in the member list.
+#[derive(Debug, Clone)]
+pub struct ListMember {
+	pub user_id: Snowflake,
+	/// The lowercase nickname or username of the member, by which members are
+	/// sorted within their group.
+	pub sort_name: String,
+	pub roles: Vec<Snowflake>,
+	/// The serialized guild member, without its presence.
+	pub member: Value,
+}
+
+impl ListMember {
+	/// Create a list member from a guild member with populated relations and
+	/// the ids of its roles.
+	pub fn from_member(member: GuildMember, roles: Vec<Snowflake>) -> Result<Self, Error> {
+		let user_id = member.id;
+		let sort_name = member.nick.clone().unwrap_or(member.user_data.username.clone());
+		let mut member = member.into_inner();
+		member.roles = roles.clone();
+		Ok(Self {
+			user_id,
+			sort_name: sort_name.to_lowercase(),
+			roles,
+			member: serde_json::to_value(member)?,
+		})
+	}
+
+	fn to_item(&self, presences: &PresenceStore) -> ListItem {
+		let presence = presences.get(self.user_id);
+		let mut member = self.member.clone();
+		if let Some(member) = member.as_object_mut() {
+			member.insert(
+				"presence".to_string(),
+				json!({
+					"user": { "id": self.user_id },
+					"status": presence.map(|presence| presence.status()).unwrap_or(Status::Offline),
+					"activities": presence.map(|presence| presence.activities()).unwrap_or_default(),
+					"client_status": presence.map(|presence| presence.client_status()).unwrap_or(json!({})),
+				}),
+			);
+		}
+		ListItem::Member(member)
+	}
+}
+
+/// Identifies an item across versions of a member list.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ItemKey {
+	Group(String),
+	Member(Snowflake),
+}
+
+/// A member list of a guild.
+#[derive(Debug)]
+pub struct MemberList {
+	guild_id: Snowflake,
+	id: String,
+	members: HashMap<Snowflake, ListMember>,
+	/// Ids of the hoisted roles of the guild, highest role first.
+	hoisted_roles: Vec<Snowflake>,
+	items: Vec<(ItemKey, ListItem)>,
+	groups: Vec<ListGroup>,
+	online_count: u64,
+}
+
+impl MemberList {
+	pub fn new(guild_id: Snowflake, id: String) -> Self {
+		Self {
+			guild_id,
+			id,
+			members: HashMap::new(),
+			hoisted_roles: Vec::new(),
+			items: Vec::new(),
+			groups: Vec::new(),
+			online_count: 0,
+		}
+	}
+
+	/// Load the members who can view the channels of `visibility`, and the
+	/// hoisted roles of their guild, from the database. The list is empty until
+	/// it is [refreshed](Self::refresh).
+	pub(super) async fn load(db: &PgPool, visibility: &ChannelVisibility) -> Result<Self, Error> {
+		let guild_id = visibility.guild_id();
+		let mut roles: HashMap<Snowflake, Vec<Snowflake>> = HashMap::new();
+		for (user_id, role_id) in GuildMember::get_role_ids_by_guild_id(db, guild_id).await? {
+			roles.entry(user_id).or_default().push(role_id);
+		}
+		let mut members = Vec::new();
+		let mut after = None;
+		loop {
+			let page =
+				GuildMember::get_by_guild_id_with_users(db, guild_id, PAGE_SIZE, after).await?;
+			let is_last_page = page.len() < PAGE_SIZE as usize;
+			after = page.last().map(|member| member.id);
+			for member in page {
+				let member_roles = roles.remove(&member.id).unwrap_or_default();
+				if visibility.can_view(member.id, &member_roles) {
+					members.push(ListMember::from_member(member, member_roles)?);
+				}
+			}
+			if is_last_page {
+				break;
+			}
+		}
+		let hoisted_roles = Role::get_by_guild(db, guild_id)
+			.await?
+			.into_iter()
+			.filter(|role| role.hoist)
+			.map(|role| (role.id, role.position.to_uint()))
+			.collect();
+		let mut list = Self::new(guild_id, visibility.list_id());
+		list.set_members(members, hoisted_roles);
+		Ok(list)
+	}
+
+	/// Replace the members and hoisted roles of the list, given as pairs of id
+	/// and position. The items of the list are only updated on the next
+	/// [refresh](Self::refresh).
+	pub fn set_members(
+		&mut self,
+		members: Vec<ListMember>,
+		mut hoisted_roles: Vec<(Snowflake, u16)>,
+	) {
+		hoisted_roles.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.0.cmp(&b.0.0)));
+		self.hoisted_roles = hoisted_roles.into_iter().map(|(id, _)| id).collect();
+		self.members = members.into_iter().map(|member| (member.user_id, member)).collect();
+	}
+
+	pub fn contains(&self, user_id: Snowflake) -> bool {
+		self.members.contains_key(&user_id)
+	}
+
+	/// Rebuild the items of the list. Returns the ops which turn the previous
+	/// items into the new ones, or `None` if there are so many of them that
+	/// subscribers should rather be sent their ranges anew.
+	pub fn refresh(&mut self, presences: &PresenceStore) -> Option<Vec<ListOp>> {
+		let (items, groups, online_count) = self.build(presences);
+		let ops = diff(&self.items, &items);
+		self.items = items;
+		self.groups = groups;
+		self.online_count = online_count;
+		(ops.len() <= MAX_OPS).then_some(ops)
+	}
+
+	/// A `SYNC` of the items within `range`.
+	pub fn sync(&self, [start, end]: [u64; 2]) -> ListOp {
+		let items = self
+			.items
+			.iter()
+			.skip(start as usize)
+			.take((end - start + 1) as usize)
+			.map(|(_, item)| item.clone())
+			.collect();
+		ListOp::Sync { range: [start, end], items }
+	}
+
+	/// A `GUILD_MEMBER_LIST_UPDATE` with the current groups and counts of the
+	/// list.
+	pub fn update(&self, ops: Vec<ListOp>) -> GuildMemberListUpdate {
+		GuildMemberListUpdate {
+			guild_id: self.guild_id,
+			id: self.id.clone(),
+			member_count: self.members.len() as u64,
+			online_count: self.online_count,
+			groups: self.groups.clone(),
+			ops,
+		}
+	}
+
+	fn build(&self, presences: &PresenceStore) -> (Vec<(ItemKey, ListItem)>, Vec<ListGroup>, u64) {
+		let mut grouped: HashMap<String, Vec<&ListMember>> = HashMap::new();
+		let mut online_count = 0;
+		for member in self.members.values() {
+			let group = if presences.is_online(member.user_id) {
+				online_count += 1;
+				self.hoisted_roles
+					.iter()
+					.find(|role_id| member.roles.contains(role_id))
+					.map(|role_id| role_id.to_string())
+					.unwrap_or_else(|| "online".to_string())
+			} else {
+				"offline".to_string()
+			};
+			grouped.entry(group).or_default().push(member);
+		}
+		let mut items = Vec::with_capacity(self.members.len() + grouped.len());
+		let mut groups = Vec::with_capacity(grouped.len());
+		let group_ids = self
+			.hoisted_roles
+			.iter()
+			.map(|role_id| role_id.to_string())
+			.chain(["online".to_string(), "offline".to_string()]);
+		for group_id in group_ids {
+			let Some(mut members) = grouped.remove(&group_id) else {
+				continue;
+			};
+			members
+				.sort_by(|a, b| a.sort_name.cmp(&b.sort_name).then(a.user_id.0.cmp(&b.user_id.0)));
+			let group = ListGroup { id: group_id.clone(), count: members.len() as u64 };
+			items.push((ItemKey::Group(group_id), ListItem::Group(group.clone())));
+			groups.push(group);
+			items.extend(
+				members
+					.into_iter()
+					.map(|member| (ItemKey::Member(member.user_id), member.to_item(presences))),
+			);
+		}
+		(items, groups, online_count)
+	}
+}
+
+/// The ops which turn the items `old` into the items `new`, applied in order.
+/// Removed items are deleted first, from the end of the list. Then, the new
+/// items are walked in order: Items at the right index are updated if they
+/// changed, items further down the list are moved up, and new items inserted.
+fn diff(old: &[(ItemKey, ListItem)], new: &[(ItemKey, ListItem)]) -> Vec<ListOp> {
+	let mut ops = Vec::new();
+	let new_keys: HashSet<&ItemKey> = new.iter().map(|(key, _)| key).collect();
+	let mut current: Vec<(ItemKey, ListItem)> = old.to_vec();
+	for index in (0..current.len()).rev() {
+		if !new_keys.contains(&current[index].0) {
+			current.remove(index);
+			ops.push(ListOp::Delete { index: index as u64 });
+		}
+	}
+	for (index, (key, item)) in new.iter().enumerate() {
+		if current.get(index).is_some_and(|(current_key, _)| current_key == key) {
+			if current[index].1 != *item {
+				current[index].1 = item.clone();
+				ops.push(ListOp::Update { index: index as u64, item: item.clone() });
+			}
+			continue;
+		}
+		if let Some(position) = current.iter().position(|(current_key, _)| current_key == key) {
+			current.remove(position);
+			ops.push(ListOp::Delete { index: position as u64 });
+		}
+		current.insert(index, (key.clone(), item.clone()));
+		ops.push(ListOp::Insert { index: index as u64, item: item.clone() });
+	}
+	ops
+}
+
+/// A session subscribed to the member list of a guild.
+#[derive(Debug)]
+struct ListSubscriber {
+	/// Sends events to this session only.
+	outbox: Sender<Event>,
+	ranges: Vec<[u64; 2]>,
+}
+
+#[derive(Debug)]
+struct SubscribedList {
+	list: MemberList,
+	/// Who can view the channels of the list, to reload it with.
+	visibility: ChannelVisibility,
+	/// Subscribers by session token.
+	subscribers: HashMap<String, ListSubscriber>,
+}
+
+impl SubscribedList {
+	/// Send the result of a [MemberList::refresh] to all subscribers. Updates
+	/// outside of the ranges of a subscriber are left out, as they do not move
+	/// any items it knows of.
+	fn send(&self, ops: Option<Vec<ListOp>>) {
+		for subscriber in self.subscribers.values() {
+			let ops = match &ops {
+				Some(ops) => ops
+					.iter()
+					.filter(|op| match op {
+						ListOp::Update { index, .. } => subscriber
+							.ranges
+							.iter()
+							.any(|[start, end]| (start..=end).contains(&index)),
+						_ => true,
+					})
+					.cloned()
+					.collect(),
+				None => subscriber
+					.ranges
+					.iter()
+					.flat_map(|&range| [ListOp::Invalidate { range }, self.list.sync(range)])
+					.collect(),
+			};
+			send_update(&subscriber.outbox, self.list.update(ops));
+		}
+	}
+}
+
+fn send_update(outbox: &Sender<Event>, update: GuildMemberListUpdate) {
+	let event = Event::Dispatch(DispatchEvent::GuildMemberListUpdate(GatewayPayload {
+		op_code: 0,
+		event_data: Some(update),
+		sequence_number: None,
+		event_name: Some("GUILD_MEMBER_LIST_UPDATE".to_string()),
+	}));
+	// Sending only fails if the session is gone, which is fine.
+	let _ = outbox.send(event);
+}
+
+/// The member lists at least one session is subscribed to, by guild id and
+/// list id.
+#[derive(Debug, Default)]
+pub struct MemberListStore {
+	lists: HashMap<(Snowflake, String), SubscribedList>,
+}
+
+impl MemberListStore {
+	/// Whether a session is subscribed to any member list of the guild
+	/// `guild_id`.
+	pub fn contains(&self, guild_id: Snowflake) -> bool {
+		self.lists.keys().any(|(id, _)| *id == guild_id)
+	}
+}
+
+impl ConnectedUsers {
+	/// Subscribe the session `session_token` of the user `user_id` to the
+	/// requested ranges of the member lists of the channels in `request`, and
+	/// send it these ranges through `outbox`. Channels the user cannot view are
+	/// ignored. The subscriptions replace those of the session to other lists
+	/// of the guild, unless no channel is left to subscribe to.
+	///
+	/// ## Locking
+	///
+	/// This method briefly acquires a lock on `role_user_map` per channel, and
+	/// calls [Self::subscribe_member_list].
+	pub async fn subscribe_member_lists(
+		&self,
+		db: &PgPool,
+		user_id: Snowflake,
+		session_token: &str,
+		outbox: Sender<Event>,
+		request: &GuildSubscriptionsRequest,
+	) -> Result<(), Error> {
+		let guild_id = request.guild_id;
+		let mut lists: HashMap<String, (ChannelVisibility, Vec<[u64; 2]>)> = HashMap::new();
+		for (channel_id, ranges) in request.channel_ranges() {
+			let Some(channel) = Channel::get_by_id(db, channel_id).await? else {
+				continue;
+			};
+			if channel.guild_id != Some(guild_id) {
+				continue;
+			}
+			let Some(visibility) = ChannelVisibility::load(db, &channel).await? else {
+				continue;
+			};
+			if !visibility.member_can_view(&*self.role_user_map.lock().await, user_id) {
+				log::debug!(target: "symfonia::gateway::member_list", "User {user_id} subscribed to the member list of channel {channel_id} without being able to view it");
+				continue;
+			}
+			lists
+				.entry(visibility.list_id())
+				.or_insert_with(|| (visibility, Vec::new()))
+				.1
+				.extend_from_slice(ranges);
+		}
+		if lists.is_empty() {
+			return Ok(());
+		}
+		self.member_lists.write().lists.retain(|(id, list_id), list| {
+			if *id == guild_id && !lists.contains_key(list_id) {
+				list.subscribers.remove(session_token);
+			}
+			!list.subscribers.is_empty()
+		});
+		for (_, (visibility, ranges)) in lists {
+			self.subscribe_member_list(
+				db,
+				visibility,
+				session_token,
+				outbox.clone(),
+				limit_ranges(ranges),
+			)
+			.await?;
+		}
+		Ok(())
+	}
+
+	/// Subscribe the session `session_token` to `ranges` of the member list of
+	/// the channels of `visibility`, replacing its previous ranges, and send it
+	/// these ranges through `outbox`. The member list is loaded from the
+	/// database if no session is subscribed to it yet.
+	///
+	/// ## Locking
+	///
+	/// This method briefly acquires a read lock on `presences` and a lock on
+	/// `member_lists`.
+	async fn subscribe_member_list(
+		&self,
+		db: &PgPool,
+		visibility: ChannelVisibility,
+		session_token: &str,
+		outbox: Sender<Event>,
+		ranges: Vec<[u64; 2]>,
+	) -> Result<(), Error> {
+		let key = (visibility.guild_id(), visibility.list_id());
+		let mut loaded = None;
+		loop {
+			{
+				let mut store = self.member_lists.write();
+				if let Some(list) = loaded.take() {
+					store.lists.entry(key.clone()).or_insert(SubscribedList {
+						list,
+						visibility: visibility.clone(),
+						subscribers: HashMap::new(),
+					});
+				}
+				if let Some(list) = store.lists.get_mut(&key) {
+					let ops = ranges.iter().map(|&range| list.list.sync(range)).collect();
+					send_update(&outbox, list.list.update(ops));
+					list.subscribers
+						.insert(session_token.to_string(), ListSubscriber { outbox, ranges });
+					return Ok(());
+				}
+			}
+			let mut list = MemberList::load(db, &visibility).await?;
+			list.refresh(&self.presences.read());
+			loaded = Some(list);
+		}
+	}
+
+	/// Publish that the members or roles of the guild `guild_id` changed, so
+	/// that every gateway node [refreshes](Self::refresh_member_list) its
+	/// member list. Failing to publish is logged, as the change has already
+	/// been made.
+	pub async fn publish_member_list_change(&self, guild_id: Snowflake) {
+		if let Err(e) = self.event_bus.publish(BusMessage::MemberListChanged { guild_id }).await {
+			log::error!(target: "symfonia::gateway::member_list", "Failed to publish a member list change: {e}");
+		}
+	}
+
+	/// Reload the member lists of the guild `guild_id` from the database and
+	/// send the changes to their subscribers. Called by the gateway nodes for
+	/// every [BusMessage::MemberListChanged]; does nothing if no session of
+	/// this node is subscribed to a list of the guild.
+	///
+	/// ## Locking
+	///
+	/// This method briefly acquires a read lock on `presences` and a lock on
+	/// `member_lists` per list.
+	pub async fn refresh_member_list(&self, db: &PgPool, guild_id: Snowflake) -> Result<(), Error> {
+		let lists: Vec<((Snowflake, String), ChannelVisibility)> = self
+			.member_lists
+			.read()
+			.lists
+			.iter()
+			.filter(|((id, _), _)| *id == guild_id)
+			.map(|(key, list)| (key.clone(), list.visibility.clone()))
+			.collect();
+		for (key, visibility) in lists {
+			let visibility = visibility.reload(db).await?;
+			let MemberList { members, hoisted_roles, .. } =
+				MemberList::load(db, &visibility).await?;
+			let presences = self.presences.read();
+			let mut store = self.member_lists.write();
+			if let Some(list) = store.lists.get_mut(&key) {
+				list.visibility = visibility;
+				list.list.members = members;
+				list.list.hoisted_roles = hoisted_roles;
+				let ops = list.list.refresh(&presences);
+				list.send(ops);
+			}
+		}
+		Ok(())
+	}
+
+	/// Update the presence of the user `user_id` in all member lists they are
+	/// part of, and send the changes to their subscribers.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on `presences` and a lock on
+	/// `member_lists` for the duration of its runtime.
+	pub fn update_member_list_presences(&self, user_id: Snowflake) {
+		let presences = self.presences.read();
+		let mut store = self.member_lists.write();
+		for list in store.lists.values_mut().filter(|list| list.list.contains(user_id)) {
+			let ops = list.list.refresh(&presences);
+			list.send(ops);
+		}
+	}
+
+	/// Remove all member list subscriptions of the session `session_token`.
+	/// Member lists without any subscribers left are dropped.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a lock on `member_lists` for the duration of its
+	/// runtime.
+	pub fn unsubscribe_member_lists(&self, session_token: &str) {
+		self.member_lists.write().lists.retain(|_, list| {
+			list.subscribers.remove(session_token);
+			!list.subscribers.is_empty()
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chorus::types::PublicUser;
+
+	use super::*;
+	use crate::gateway::presence::{ClientPresence, ClientType, UserPresence};
+
+	fn member(id: u64, name: &str, roles: &[u64]) -> ListMember {
+		ListMember {
+			user_id: Snowflake(id),
+			sort_name: name.to_string(),
+			roles: roles.iter().map(|id| Snowflake(*id)).collect(),
+			member: json!({ "user": { "id": id.to_string() } }),
+		}
+	}
+
+	fn go_online(presences: &mut PresenceStore, id: u64) {
+		presences.set_client(
+			UserPresence::new(PublicUser { id: Snowflake(id), ..Default::default() }, None),
+			"session",
+			ClientPresence::from_update(&json!({ "status": "online" }), ClientType::Web),
+		);
+	}
+
+	fn group_ids(list: &MemberList) -> Vec<(String, u64)> {
+		list.groups.iter().map(|group| (group.id.clone(), group.count)).collect()
+	}
+
+	#[test]
+	fn groups_members_by_hoisted_role_and_presence() {
+		let mut presences = PresenceStore::default();
+		go_online(&mut presences, 1);
+		go_online(&mut presences, 2);
+		let mut list = MemberList::new(Snowflake(10), "everyone".to_string());
+		list.set_members(
+			vec![member(1, "b", &[100]), member(2, "a", &[]), member(3, "c", &[100])],
+			vec![(Snowflake(100), 1)],
+		);
+		list.refresh(&presences);
+		assert_eq!(
+			group_ids(&list),
+			[("100".to_string(), 1), ("online".to_string(), 1), ("offline".to_string(), 1)]
+		);
+		assert_eq!(list.online_count, 2);
+		let ListOp::Sync { items, .. } = list.sync([0, 99]) else {
+			panic!("Expected a SYNC op");
+		};
+		assert_eq!(items.len(), 6);
+	}
+
+	#[test]
+	fn diff_turns_old_items_into_new_ones() {
+		let mut presences = PresenceStore::default();
+		let mut list = MemberList::new(Snowflake(10), "everyone".to_string());
+		list.set_members(
+			vec![member(1, "a", &[]), member(2, "b", &[]), member(3, "c", &[])],
+			Vec::new(),
+		);
+		list.refresh(&presences);
+		let mut items: Vec<ListItem> = list.items.iter().map(|(_, item)| item.clone()).collect();
+
+		go_online(&mut presences, 2);
+		let ops = list.refresh(&presences).unwrap();
+		for op in ops {
+			match op {
+				ListOp::Insert { index, item } => items.insert(index as usize, item),
+				ListOp::Update { index, item } => items[index as usize] = item,
+				ListOp::Delete { index } => {
+					items.remove(index as usize);
+				}
+				_ => panic!("Unexpected op"),
+			}
+		}
+		let expected: Vec<ListItem> = list.items.iter().map(|(_, item)| item.clone()).collect();
+		assert_eq!(items, expected);
+	}
+
+	#[test]
+	fn limits_requested_ranges() {
+		assert_eq!(limit_ranges([[0, 99], [500, 100], [0, 99]]), [[0, 99], [100, 199]]);
+	}
+
+	#[test]
+	fn limits_requested_channels() {
+		let request = GuildSubscriptionsRequest {
+			guild_id: Snowflake(10),
+			channels: (20..30)
+				.map(|id| (Snowflake(id), if id == 20 { Vec::new() } else { vec![[0, 99]] }))
+				.collect(),
+		};
+		let channels: Vec<Snowflake> =
+			request.channel_ranges().into_iter().map(|(channel_id, _)| channel_id).collect();
+		assert_eq!(channels, (21..26).map(Snowflake).collect::<Vec<_>>());
+	}
+}
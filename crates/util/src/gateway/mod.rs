@@ -3,7 +3,7 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::{
-	collections::{HashMap, HashSet},
+	collections::{HashMap, HashSet, VecDeque},
 	fmt::Display,
 	ops::{Deref, DerefMut},
 	sync::{Arc, Weak},
@@ -11,38 +11,58 @@ use std::{
 
 use ::serde::{Deserialize, Serialize, de::DeserializeOwned};
 use chorus::types::{
-	ChannelCreate, ChannelDelete, ChannelUpdate, GatewayHeartbeat, GatewayHeartbeatAck,
-	GatewayHello, GatewayIdentifyPayload, GatewayInvalidSession, GatewayReady,
+	ChannelCreate, ChannelDelete, ChannelPinsUpdate, ChannelUpdate, GatewayHeartbeat,
+	GatewayHeartbeatAck, GatewayHello, GatewayIdentifyPayload, GatewayInvalidSession, GatewayReady,
 	GatewayReadySupplemental, GatewayRequestGuildMembers, GatewayResume, GuildBanAdd,
 	GuildBanRemove, GuildCreate, GuildDelete, GuildEmojisUpdate, GuildIntegrationsUpdate,
-	GuildMemberAdd, GuildMemberRemove, GuildMemberUpdate, GuildMembersChunk, GuildUpdate,
-	InteractionCreate, InviteCreate, InviteDelete, MessageCreate, MessageDelete, MessageDeleteBulk,
-	MessageReactionAdd, MessageReactionRemove, MessageReactionRemoveAll,
-	MessageReactionRemoveEmoji, MessageUpdate, Opcode, PresenceUpdate, Snowflake,
-	StageInstanceCreate, StageInstanceDelete, StageInstanceUpdate, ThreadCreate, ThreadDelete,
-	ThreadListSync, ThreadMemberUpdate, ThreadMembersUpdate, ThreadUpdate, TypingStartEvent,
-	UserUpdate, VoiceServerUpdate, VoiceStateUpdate, WebhooksUpdate,
+	GuildMemberAdd, GuildMemberRemove, GuildMemberUpdate, GuildMembersChunk, GuildRoleCreate,
+	GuildRoleDelete, GuildRoleUpdate, GuildUpdate, InteractionCreate, InviteCreate, InviteDelete,
+	MessageCreate, MessageDelete, MessageDeleteBulk, MessageReactionAdd, MessageReactionRemove,
+	MessageReactionRemoveAll, MessageReactionRemoveEmoji, MessageUpdate, Opcode, PresenceUpdate,
+	Session, Snowflake, StageInstanceCreate, StageInstanceDelete, StageInstanceUpdate,
+	ThreadCreate, ThreadDelete, ThreadListSync, ThreadMemberUpdate, ThreadMembersUpdate,
+	ThreadUpdate, TypingStartEvent, UpdatePresence, UserUpdate, VoiceServerUpdate,
+	VoiceStateUpdate, WebhooksUpdate,
 };
 use event::Event;
+use event_bus::{BusMessage, Origin, SharedEventBus};
 use futures::{
 	SinkExt, StreamExt,
 	stream::{SplitSink, SplitStream},
 };
+use intents::GatewayIntents;
+use member_list::{GuildMemberListUpdate, GuildSubscriptionsRequest, MemberListStore};
 use parking_lot::RwLock;
+use presence::{PresenceStore, Status};
 use pubserve::Subscriber;
+use recipients::RecipientGroup;
+use role_user_map::RoleUserMapChange;
 use serde_json::from_str;
 use sqlx::PgPool;
 use sqlx_pg_uint::PgU64;
-use tokio::{net::TcpStream, sync::Mutex};
+use tokio::sync::Mutex;
 use tokio_tungstenite::{WebSocketStream, tungstenite, tungstenite::Message};
+use transport::{Transport, decode_inbound};
 
 use crate::{
 	WebSocketReceive, WebSocketSend,
 	errors::{Error, GatewayError},
+	tls::MaybeTlsStream,
 };
 
+pub mod codec;
+pub mod dispatch;
 pub mod dispatchevent;
 pub mod event;
+pub mod event_bus;
+pub mod intents;
+pub mod member_list;
+pub mod presence;
+pub mod recipients;
+pub mod replication;
+pub mod role_user_map;
+pub mod saved_sessions;
+pub mod transport;
 
 #[derive(Serialize, Clone, PartialEq, Debug)]
 /// A de-/serializable data payload for transmission over the gateway.
@@ -100,17 +120,32 @@ impl<'de, T: DeserializeOwned + Serialize> Deserialize<'de> for GatewayPayload<T
 	}
 }
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct ConnectedUsers {
 	pub store: Arc<RwLock<ConnectedUsersInner>>,
-	pub role_user_map: Arc<Mutex<RoleUserMap>>,
+	pub role_user_map: SharedRoleUserMap,
+	pub presences: Arc<RwLock<PresenceStore>>,
+	pub member_lists: Arc<RwLock<MemberListStore>>,
+	/// The bus which events for connected users are published to. See
+	/// [Self::deliver_bus_messages].
+	pub event_bus: SharedEventBus,
+	/// This process, as the publisher of changes to the state every process
+	/// keeps in memory. See [Self::replicate_state].
+	pub origin: Origin,
+}
+
+impl Default for ConnectedUsers {
+	fn default() -> Self {
+		Self::with_event_bus(SharedEventBus::default())
+	}
 }
 
-/// A map of resumable clients. The key is the session token used
-/// for the connection. The value is a [GatewayClient] that can be resumed.
-// TODO: this is stupid. it should be a map of string and DisconnectInfo. there
-// is no need to store the whole GatewayClient, nor would it make sense to do
-// so.
+/// Number of dispatched payloads retained per session for replaying them to a
+/// resuming client.
+pub const REPLAY_BUFFER_CAPACITY: usize = 500;
+
+/// A map of resumable sessions. The key is the session id of the disconnected
+/// session, the value is the [DisconnectInfo] needed to resume it.
 pub type ResumableClientsStore = HashMap<String, DisconnectInfo>;
 
 /// A mapping of Snowflake IDs to the "inbox" of a [GatewayUser].
@@ -151,6 +186,17 @@ pub struct GatewayUser {
 }
 
 impl GatewayUser {
+	/// Whether one of the clients of this user is connected with the session
+	/// `session_token`.
+	pub fn has_session(&self, session_token: &str) -> bool {
+		self.clients.contains_key(session_token)
+	}
+
+	/// The session tokens of the clients of this user.
+	pub fn session_tokens(&self) -> impl Iterator<Item = &str> {
+		self.clients.keys().map(String::as_str)
+	}
+
 	/// Kills a user by ending all of their clients' sessions.
 	pub async fn kill(&mut self) {
 		for (_, client_mutex) in self.clients.iter() {
@@ -174,6 +220,10 @@ pub struct GatewayClient {
 	/// The last sequence number received from the client. Shared between the
 	/// main task, heartbeat task, and this struct.
 	last_sequence: Arc<Mutex<u64>>,
+	/// Dispatches sent to this session, kept for replaying them on resume.
+	replay_buffer: Arc<Mutex<ReplayBuffer>>,
+	/// The intents the client identified with.
+	pub intents: GatewayIntents,
 }
 
 impl ConnectedUsers {
@@ -182,6 +232,20 @@ impl ConnectedUsers {
 		Self::default()
 	}
 
+	/// Create a new, empty [ConnectedUsers] instance publishing events to
+	/// `event_bus`.
+	pub fn with_event_bus(event_bus: SharedEventBus) -> Self {
+		let origin = Origin::default();
+		Self {
+			store: Default::default(),
+			role_user_map: SharedRoleUserMap::new(event_bus.clone(), origin),
+			presences: Default::default(),
+			member_lists: Default::default(),
+			event_bus,
+			origin,
+		}
+	}
+
 	pub fn bulk_message_builder(&self) -> BulkMessageBuilder {
 		BulkMessageBuilder::default()
 	}
@@ -193,8 +257,8 @@ impl ConnectedUsers {
 	///
 	/// Due to the possibly large number of roles and users returned by the
 	/// database, this method should only be executed once. The [RoleUserMap]
-	/// should be kept synchronized with the database through means that do not
-	/// involve this method.
+	/// is kept synchronized with the database through means that do not
+	/// involve this method, see [RoleUserMap].
 	///
 	/// ## Locking
 	///
@@ -297,12 +361,13 @@ impl ConnectedUsers {
 
 	/// Create a new [GatewayClient] with the given [GatewayUser], [Connection],
 	/// and other data. Also handles appending the new [GatewayClient] to the
-	/// [GatewayUser]'s list of clients.
+	/// [GatewayUser]'s list of clients. If the last other client of the user
+	/// disconnected in the meantime, the user is registered again.
 	///
 	/// ## Locking
 	///
 	/// This method acquires a lock on the [Arc<Mutex<GatewayUser>>] that is
-	/// passed as `user`.
+	/// passed as `user`, and briefly a write lock on `store`.
 	#[allow(clippy::too_many_arguments)]
 	pub async fn new_client(
 		&self,
@@ -312,6 +377,8 @@ impl ConnectedUsers {
 		heartbeat_task_handle: tokio::task::JoinHandle<()>,
 		session_token: &str,
 		last_sequence: Arc<Mutex<u64>>,
+		replay_buffer: Arc<Mutex<ReplayBuffer>>,
+		intents: GatewayIntents,
 	) -> Arc<Mutex<GatewayClient>> {
 		let client = GatewayClient {
 			connection,
@@ -320,14 +387,223 @@ impl ConnectedUsers {
 			heartbeat_task_handle,
 			session_token: session_token.to_string(),
 			last_sequence,
+			replay_buffer,
+			intents,
 		};
 		let arc = Arc::new(Mutex::new(client));
 		log::trace!(target: "symfonia::gateway::ConnectedUsers::new_client", "Acquiring lock on user...");
-		user.lock().await.clients.insert(session_token.to_string(), arc.clone());
+		let mut user_lock = user.lock().await;
 		log::trace!(target: "symfonia::gateway::ConnectedUsers::new_client", "Lock acquired!");
+		user_lock.clients.insert(session_token.to_string(), arc.clone());
+		let mut store = self.store.write();
+		if !store.users.contains_key(&user_lock.id) {
+			store.inboxes.insert(user_lock.id, user_lock.outbox.clone());
+			store.users.insert(user_lock.id, user.clone());
+		}
+		drop(store);
+		drop(user_lock);
 		log::trace!(target: "symfonia::gateway::ConnectedUsers::new_client", "Inserted into map. Done.");
 		arc
 	}
+
+	/// Remove the client with the session `session_token` from the clients of
+	/// the user `user_id`. The user, and with them the inbox shared by all of
+	/// their clients, is only deregistered once none of their clients are
+	/// left. Returns a [Weak] reference to the user, if they were connected.
+	///
+	/// ## Locking
+	///
+	/// This method briefly acquires a read lock on `store`, then a lock on the
+	/// [GatewayUser] and, if it was their last client, a write lock on `store`.
+	pub async fn remove_client(
+		&self,
+		user_id: Snowflake,
+		session_token: &str,
+	) -> Weak<Mutex<GatewayUser>> {
+		let Some(user) = self.store.read().users.get(&user_id).cloned() else {
+			return Weak::new();
+		};
+		let mut user_lock = user.lock().await;
+		user_lock.clients.remove(session_token);
+		if user_lock.clients.is_empty() {
+			let mut store = self.store.write();
+			// A new session of the user may have registered them again already.
+			if store.users.get(&user_id).is_some_and(|registered| Arc::ptr_eq(registered, &user)) {
+				store.users.remove(&user_id);
+				store.inboxes.remove(&user_id);
+			}
+		}
+		drop(user_lock);
+		Arc::downgrade(&user)
+	}
+
+	/// Make a disconnected session resumable.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a write lock on `store` for the duration of its
+	/// runtime.
+	pub fn add_resumable_session(&self, disconnect_info: DisconnectInfo) {
+		self.store
+			.write()
+			.resumeable_clients_store
+			.insert(disconnect_info.session_token.clone(), disconnect_info);
+	}
+
+	/// Remove a resumable session from the store and return it, if it exists.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a write lock on `store` for the duration of its
+	/// runtime.
+	pub fn take_resumable_session(&self, session_token: &str) -> Option<DisconnectInfo> {
+		self.store.write().resumeable_clients_store.remove(session_token)
+	}
+
+	/// Dispatch `SESSIONS_REPLACE` with the current sessions of the user
+	/// `user_id` to all of their connected clients.
+	pub async fn dispatch_sessions_replace(
+		&self,
+		db: &PgPool,
+		user_id: Snowflake,
+	) -> Result<(), Error> {
+		let sessions = crate::entities::Session::get_by_user_id(db, user_id)
+			.await?
+			.iter()
+			.map(|session| session.to_inner())
+			.collect();
+		let event =
+			Event::Dispatch(dispatchevent::DispatchEvent::SessionsReplace(GatewayPayload {
+				op_code: 0,
+				event_data: Some(sessions),
+				sequence_number: None,
+				event_name: Some("SESSIONS_REPLACE".to_string()),
+			}));
+		self.event_bus.publish(BusMessage::Dispatch { recipients: vec![user_id], event }).await
+	}
+
+	/// Dispatch the presence of the user `user_id` as `PRESENCE_UPDATE` to the
+	/// connected members of all guilds shared with them, and to their friends.
+	/// Users without any connected clients are dispatched as offline and
+	/// removed from the presence store afterwards.
+	///
+	/// The member lists containing the user are updated as well.
+	///
+	/// ## Locking
+	///
+	/// This method briefly acquires a read and a write lock on `presences`, and
+	/// calls [Self::update_member_list_presences].
+	pub async fn dispatch_presence_update(
+		&self,
+		db: &PgPool,
+		user_id: Snowflake,
+	) -> Result<(), Error> {
+		let Some(presence) = self.presences.read().get(user_id).cloned() else {
+			return Ok(());
+		};
+		self.presences.write().prune(user_id);
+		self.update_member_list_presences(user_id);
+		let mut guilds: HashMap<Snowflake, Vec<Snowflake>> = HashMap::new();
+		for (guild_id, member_id) in
+			crate::entities::GuildMember::get_co_member_ids(db, user_id).await?
+		{
+			guilds.entry(guild_id).or_default().push(member_id);
+		}
+		let friends = crate::entities::Relationship::get_friend_ids(user_id, db).await?;
+		let recipients = guilds
+			.into_iter()
+			.map(|(guild_id, members)| (Some(guild_id), members))
+			.chain([(None, friends)]);
+		for (guild_id, members) in recipients {
+			let event =
+				Event::Dispatch(dispatchevent::DispatchEvent::PresenceUpdate(GatewayPayload {
+					op_code: 0,
+					event_data: Some(presence.to_presence_update(guild_id)?),
+					sequence_number: None,
+					event_name: Some("PRESENCE_UPDATE".to_string()),
+				}));
+			self.event_bus.publish(BusMessage::Dispatch { recipients: members, event }).await?;
+		}
+		Ok(())
+	}
+
+	/// Set the status in the settings of the user `user_id` to `status`, and
+	/// dispatch their changed presence. The change is published, so that the
+	/// other processes set it as well. Failing to dispatch or publish is
+	/// logged, as the settings have already been changed.
+	///
+	/// ## Locking
+	///
+	/// This method briefly acquires a write lock on `presences`, and calls
+	/// [Self::dispatch_presence_update].
+	pub async fn set_settings_status(
+		&self,
+		db: &PgPool,
+		user_id: Snowflake,
+		status: Option<Status>,
+	) {
+		let changed = self.presences.write().set_settings_status(user_id, status);
+		if changed && let Err(e) = self.dispatch_presence_update(db, user_id).await {
+			log::error!(target: "symfonia::gateway", "Failed to dispatch the presence of user {user_id}: {e}");
+		}
+		let message = BusMessage::SettingsStatusChanged { user_id, status };
+		if let Err(e) = self.event_bus.publish(message).await {
+			log::error!(target: "symfonia::gateway", "Failed to publish a settings status: {e}");
+		}
+	}
+
+	/// The presences of the online members of the guild `guild_id`, as sent in
+	/// `READY` and `GUILD_CREATE`. These include the members connected to
+	/// other gateway nodes, as presences are replicated to every process.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on `presences`.
+	pub async fn guild_presences(
+		&self,
+		db: &PgPool,
+		guild_id: Snowflake,
+	) -> Result<Vec<PresenceUpdate>, Error> {
+		let member_ids = crate::entities::GuildMember::get_member_ids(db, guild_id).await?;
+		self.presences.read().presences_of(member_ids, Some(guild_id))
+	}
+
+	/// [Reconnect](GatewayClient::reconnect) all clients connected to this
+	/// gateway, returning how many there were.
+	///
+	/// ## Locking
+	///
+	/// This method briefly acquires a read lock on `store` and a lock on every
+	/// [GatewayUser], and then a lock on every [GatewayClient] until it has
+	/// been reconnected.
+	pub async fn reconnect_all_clients(&self) -> usize {
+		let users: Vec<_> = self.store.read().users.values().cloned().collect();
+		let mut clients = Vec::new();
+		for user in users {
+			clients.extend(user.lock().await.clients.values().cloned());
+		}
+		let count = clients.len();
+		futures::future::join_all(clients.iter().map(|client| async move {
+			client.lock().await.reconnect().await;
+		}))
+		.await;
+		count
+	}
+
+	/// Whether the session `session_token` of the user `user_id` is still
+	/// connected to the gateway.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on `store` and a lock on the
+	/// [GatewayUser], if it exists.
+	pub async fn is_session_connected(&self, user_id: Snowflake, session_token: &str) -> bool {
+		let user = self.store.read().users.get(&user_id).cloned();
+		match user {
+			Some(user) => user.lock().await.has_session(session_token),
+			None => false,
+		}
+	}
 }
 
 impl std::hash::Hash for GatewayUser {
@@ -345,89 +621,156 @@ impl PartialEq for GatewayUser {
 impl Eq for GatewayUser {}
 
 impl GatewayClient {
+	/// Asks the client to reconnect with a `RECONNECT` and a close code it may
+	/// resume after, and waits for the gateway task of the client to end. The
+	/// gateway task makes the session resumable as it ends.
+	pub async fn reconnect(&mut self) {
+		let reconnect = GatewayPayload::<()> {
+			op_code: 7,
+			event_data: None,
+			sequence_number: None,
+			event_name: None,
+		};
+		// Sending only fails if the connection has already been closed.
+		let _ = self
+			.connection
+			.sender
+			.send(Message::Text(serde_json::json!(reconnect).to_string().into()));
+		let _ =
+			self.connection.sender.send(Message::Close(Some(tungstenite::protocol::CloseFrame {
+				code: tungstenite::protocol::frame::coding::CloseCode::Library(4000),
+				reason: "The server is shutting down. Please reconnect and resume".into(),
+			})));
+		let _ = self.connection.kill_send.send(());
+		if let Err(e) = (&mut self.main_task_handle).await {
+			log::debug!(target: "symfonia::gateway::GatewayClient::reconnect", "Gateway task of session {} died: {e}", self.session_token);
+		}
+	}
+
 	/// Disconnects a [GatewayClient] properly, including un-registering it from
-	/// the memory store and creating a resumeable session.
+	/// the memory store and creating a resumeable session. The [GatewayUser]
+	/// stays registered as long as they have other clients.
 	pub async fn die(&mut self, connected_users: ConnectedUsers) {
 		self.connection.kill_send.send(()).unwrap();
-		let disconnect_info = DisconnectInfo {
-			session_token: self.session_token.clone(),
-			disconnected_at_sequence: *self.last_sequence.lock().await,
-			parent: self.parent.clone(),
+		let Some(parent) = self.parent.upgrade() else {
+			return;
 		};
-		self.parent.upgrade().unwrap().lock().await.clients.remove(&self.session_token);
-		connected_users.deregister(self.parent.upgrade().unwrap().lock().await.deref());
-		connected_users
-			.store
-			.write()
-			.resumeable_clients_store
-			.insert(self.session_token.clone(), disconnect_info);
+		let user_id = parent.lock().await.id;
+		let disconnect_info = DisconnectInfo::new(
+			self.session_token.clone(),
+			user_id,
+			*self.last_sequence.lock().await,
+			self.replay_buffer.clone(),
+			self.intents,
+			self.parent.clone(),
+		);
+		connected_users.remove_client(user_id, &self.session_token).await;
+		connected_users.add_resumable_session(disconnect_info);
 	}
 }
 
 #[derive(Default, Clone)]
 /// `BulkMessageBuilder` can be used to build and send GatewayMessages to the
-/// inboxes of all currently connected [GatewayClients](GatewayClient).
-/// Recipients can be added either via User or Role snowflake IDs.
+/// inboxes of all currently connected [GatewayClients](GatewayClient), through
+/// the [EventBus](event_bus::EventBus).
+/// Recipients can be added via User or Role snowflake IDs, or as a
+/// [RecipientGroup] which is resolved when the message is sent.
 pub struct BulkMessageBuilder {
 	users: Vec<Snowflake>,
 	roles: Vec<Snowflake>,
+	groups: Vec<RecipientGroup>,
 	message: Option<Event>,
 }
 
 impl BulkMessageBuilder {
 	/// Add the given list of user snowflake IDs to the list of recipients.
-	pub async fn add_user_recipients(&mut self, users: &[Snowflake]) {
+	pub fn add_user_recipients(&mut self, users: &[Snowflake]) {
 		self.users.extend_from_slice(users);
 	}
 
 	/// Add all members which have the given role snowflake IDs to the list of
 	/// recipients.
-	pub async fn add_role_recipients(&mut self, roles: &[Snowflake]) {
+	pub fn add_role_recipients(&mut self, roles: &[Snowflake]) {
 		self.roles.extend_from_slice(roles);
 	}
 
+	/// Add everyone who can see the channel `channel_id` to the list of
+	/// recipients: Members with the `VIEW_CHANNEL` permission for guild
+	/// channels, and the recipients for DM channels.
+	pub fn add_channel_viewers(&mut self, channel_id: Snowflake) {
+		self.groups.push(RecipientGroup::ChannelViewers(channel_id));
+	}
+
+	/// Add all members of the guild `guild_id` to the list of recipients.
+	pub fn add_guild_members(&mut self, guild_id: Snowflake) {
+		self.groups.push(RecipientGroup::GuildMembers(guild_id));
+	}
+
+	/// Add all recipients of the DM or group DM channel `channel_id` to the
+	/// list of recipients.
+	pub fn add_dm_recipients(&mut self, channel_id: Snowflake) {
+		self.groups.push(RecipientGroup::DmRecipients(channel_id));
+	}
+
 	/// Set the message to be sent to the recipients.
-	pub async fn set_message(&mut self, message: Event) {
-		self.message = Some(message);
+	pub fn set_message(&mut self, message: impl Into<Event>) {
+		self.message = Some(message.into());
 	}
 
-	/// Send the message to all recipients.
-	pub async fn send(self, connected_users: ConnectedUsers) -> Result<(), Error> {
-		if self.message.is_none() {
+	/// Send the message to all recipients. Failing to publish the message is
+	/// logged, as the change causing it has already been made.
+	///
+	/// ## Locking
+	///
+	/// The [RecipientGroup]s are loaded from the database before the lock on
+	/// `role_user_map` is acquired.
+	pub async fn send(self, db: &PgPool, connected_users: &ConnectedUsers) -> Result<(), Error> {
+		let Some(message) = self.message else {
 			return Err(Error::Custom("No message to send".to_string()));
+		};
+		let mut groups = Vec::with_capacity(self.groups.len());
+		for group in self.groups {
+			groups.push(group.load(db).await?);
 		}
-		let mut recipients = HashSet::new();
+		let mut recipients = self.users.into_iter().collect::<HashSet<_>>();
 		let lock = connected_users.role_user_map.lock().await;
 		for role in self.roles.iter() {
 			if let Some(users) = lock.get(role) {
-				for user in users.iter() {
-					recipients.insert(*user);
-				}
-			}
-			for user in self.users.iter() {
-				recipients.insert(*user);
+				recipients.extend(users.iter().copied());
 			}
 		}
+		for group in groups.iter() {
+			group.resolve(&lock, &mut recipients);
+		}
+		drop(lock);
 		if recipients.is_empty() {
 			return Ok(());
 		}
-		for recipient in recipients.iter() {
-			if let Some(inbox) = connected_users.inbox(*recipient).await {
-				inbox
-					.send(self.message.clone().unwrap())
-					.map_err(|e| Error::Custom(format!("tokio broadcast error: {}", e)))?;
-			}
+		let message =
+			BusMessage::Dispatch { recipients: recipients.into_iter().collect(), event: message };
+		if let Err(e) = connected_users.event_bus.publish(message).await {
+			log::error!(target: "symfonia::gateway", "Failed to publish an event: {e}");
 		}
 		Ok(())
 	}
 }
 
-#[derive(Default)]
+#[derive(Default, Debug, Clone, PartialEq)]
 /// Represents all existing roles on the server and the users that have these
 /// roles.
+///
+/// Entity methods which change roles or role assignments take a
+/// [SharedRoleUserMap] and [apply](SharedRoleUserMap::apply) their changes to
+/// it after they have been committed to the database, locking it only for the
+/// update itself. The changes are published to the other processes, which
+/// apply them to their own maps. Drift which happens regardless, for example
+/// through changes made to the database directly or through messages lost by
+/// the event bus, is corrected by [ConnectedUsers::reconcile_role_user_map].
 pub struct RoleUserMap {
 	/// Map Role Snowflake ID to a list of User Snowflake IDs
 	map: HashMap<Snowflake, HashSet<Snowflake>>,
+	/// Incremented by every change made through the methods of this map.
+	generation: u64,
 }
 
 impl Deref for RoleUserMap {
@@ -447,44 +790,124 @@ impl DerefMut for RoleUserMap {
 impl RoleUserMap {
 	/// Initialize the [RoleUserMap] with data from the database.
 	///
-	/// This method will query the database for all roles and all users that
-	/// have these roles. The data will then populate the map.
-	///
 	/// Due to the possibly large number of roles and users returned by the
 	/// database, this method should only be executed once. The [RoleUserMap]
-	/// should be kept synchronized with the database through means that do not
+	/// is kept synchronized with the database through means that do not
 	/// involve this method.
-	///
-	/// TODO:
-	/// Things that need to be accounted for:
-	/// - Users who delete or deactivate their accounts
-	/// - Users who are deleted from the database
-	/// - Users creating an account while the server is running
-	///
-	/// If any of these are not accounted for, the RoleUserMap could get out of
-	/// sync with the database. This could result in users not receiving events
-	/// or errors when trying to send an event to a user that no longer exists.
 	pub async fn init(&mut self, db: &PgPool) -> Result<(), Error> {
+		let generation = self.generation;
+		*self = Self::load(db).await?;
+		self.generation = generation + 1;
+		Ok(())
+	}
+
+	/// Build a [RoleUserMap] from the database.
+	///
+	/// This method will query the database for all roles and all users that
+	/// have these roles. The data will then populate the map.
+	pub async fn load(db: &PgPool) -> Result<Self, Error> {
+		let mut role_user_map = Self::default();
 		// First, get all role ids from the roles table and insert them into the map
 		let all_role_ids: Vec<PgU64> =
 			sqlx::query_as("SELECT id FROM roles").fetch_all(db).await.map_err(Error::Sqlx)?;
 		for role_id in all_role_ids.iter() {
-			self.map.insert(Snowflake::from(role_id.to_uint()), HashSet::new());
+			role_user_map.insert_role(Snowflake::from(role_id.to_uint()));
 		}
 		// Then, query member_roles and insert the user ids into the map
-		let all_member_roles: Vec<(PgU64, PgU64)> =
-			sqlx::query_as("SELECT index, role_id FROM member_roles")
-				.fetch_all(db)
-				.await
-				.map_err(Error::Sqlx)?;
+		let all_member_roles: Vec<(PgU64, PgU64)> = sqlx::query_as(
+			"SELECT members.id, member_roles.role_id FROM member_roles JOIN members ON members.index = member_roles.index",
+		)
+		.fetch_all(db)
+		.await
+		.map_err(Error::Sqlx)?;
 		for (user_id, role_id) in all_member_roles.iter() {
-			// Unwrapping is fine here, as the member_roles table has a foreign key
-			// constraint which states that role_id must be a valid id in the roles
-			// table.
-			let users_for_role_id = self.map.get_mut(&role_id.to_uint().into()).unwrap();
-			users_for_role_id.insert(user_id.to_uint().into());
+			// The member_roles table has a foreign key constraint which states that
+			// role_id must be a valid id in the roles table.
+			role_user_map.assign(role_id.to_uint().into(), user_id.to_uint().into());
 		}
-		Ok(())
+		Ok(role_user_map)
+	}
+
+	/// Add the role `role_id`, without any users, if it is not in the map yet.
+	pub fn insert_role(&mut self, role_id: Snowflake) {
+		self.generation += 1;
+		self.map.entry(role_id).or_default();
+	}
+
+	/// Remove the role `role_id` and all of its assignments.
+	pub fn remove_role(&mut self, role_id: Snowflake) {
+		self.generation += 1;
+		self.map.remove(&role_id);
+	}
+
+	/// Assign the role `role_id` to the user `user_id`.
+	pub fn assign(&mut self, role_id: Snowflake, user_id: Snowflake) {
+		self.generation += 1;
+		self.map.entry(role_id).or_default().insert(user_id);
+	}
+
+	/// Take the role `role_id` away from the user `user_id`.
+	pub fn unassign(&mut self, role_id: Snowflake, user_id: Snowflake) {
+		self.generation += 1;
+		if let Some(users) = self.map.get_mut(&role_id) {
+			users.remove(&user_id);
+		}
+	}
+
+	/// The number of changes made through the methods of this map. Used to
+	/// detect whether the map changed while a copy of it was loaded from the
+	/// database.
+	pub fn generation(&self) -> u64 {
+		self.generation
+	}
+}
+
+/// A [RoleUserMap] shared between the HTTP API, the gateway and the entity
+/// methods which keep it in sync.
+#[derive(Clone)]
+pub struct SharedRoleUserMap {
+	map: Arc<Mutex<RoleUserMap>>,
+	event_bus: SharedEventBus,
+	origin: Origin,
+}
+
+impl SharedRoleUserMap {
+	/// An empty map of the process `origin`, publishing its changes to
+	/// `event_bus`.
+	pub fn new(event_bus: SharedEventBus, origin: Origin) -> Self {
+		Self { map: Default::default(), event_bus, origin }
+	}
+
+	/// Make `changes` to the map of this process, and publish them so that the
+	/// other processes make them as well. Failing to publish is logged, as the
+	/// database has already been changed; reconciliation corrects the maps of
+	/// the other processes eventually.
+	///
+	/// ## Locking
+	///
+	/// This method acquires the lock on the map while making the changes.
+	pub async fn apply(&self, changes: impl IntoIterator<Item = RoleUserMapChange>) {
+		let changes: Vec<RoleUserMapChange> = changes.into_iter().collect();
+		if changes.is_empty() {
+			return;
+		}
+		let mut map = self.map.lock().await;
+		for change in changes.iter() {
+			map.apply(*change);
+		}
+		drop(map);
+		let message = BusMessage::RoleUserMapChanged { origin: self.origin, changes };
+		if let Err(e) = self.event_bus.publish(message).await {
+			log::error!(target: "symfonia::gateway", "Failed to publish Role->User map changes: {e}");
+		}
+	}
+}
+
+impl Deref for SharedRoleUserMap {
+	type Target = Mutex<RoleUserMap>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.map
 	}
 }
 
@@ -513,7 +936,16 @@ pub struct WebSocketConnection {
 
 impl WebSocketConnection {
 	/// Create a new [WebSocketConnection] from a tungstenite Sink/Stream pair.
-	pub fn new(mut sink: WebSocketSend, mut stream: WebSocketReceive) -> Self {
+	///
+	/// Messages are encoded and compressed according to `transport` on their
+	/// way to the client, and decoded on their way from the client. Inside of
+	/// symfonia, payloads are always JSON text messages.
+	pub fn new(
+		mut sink: WebSocketSend,
+		mut stream: WebSocketReceive,
+		mut transport: Transport,
+	) -> Self {
+		let codec = transport.codec();
 		// "100" is an arbitrary limit. Feel free to adjust this, if you have a good
 		// reason for it. -bitfl0wer
 		let (mut websocketsend_sender, mut websocketsend_receiver) =
@@ -530,6 +962,13 @@ impl WebSocketConnection {
 					websocketsend_receiver.recv().await;
 				match message {
 					Ok(msg) => {
+						let msg = match transport.outbound(msg) {
+							Ok(msg) => msg,
+							Err(e) => {
+								log::debug!(target: "symfonia::gateway::types::WebSocketConnection::sender_task", "Error when encoding message: {e}");
+								break;
+							}
+						};
 						let send_result = sink.send(msg).await;
 						match send_result {
 							Ok(_) => (),
@@ -559,7 +998,10 @@ impl WebSocketConnection {
 						break;
 					}
 				};
-				let web_socket_receive_message = match web_socket_receive_result {
+				let web_socket_receive_message = match web_socket_receive_result
+					.map_err(Error::from)
+					.and_then(|message| decode_inbound(codec.as_ref(), message))
+				{
 					Ok(message) => message,
 					Err(e) => {
 						log::debug!(target: "symfonia::gateway::WebSocketConnection::receiver_task", "Received malformed message, closing channel: {e}");
@@ -601,27 +1043,131 @@ impl Clone for WebSocketConnection {
 	}
 }
 
+/// Everything needed to resume a session after its connection was lost.
 #[derive(Clone)]
 pub struct DisconnectInfo {
 	/// session token that was used for this connection
 	pub session_token: String,
+	/// The user the session belongs to.
+	pub user_id: Snowflake,
+	/// Sequence number of the last dispatch sent to the session.
 	pub disconnected_at_sequence: u64,
+	/// Unix timestamp (in seconds) at which the session was disconnected.
+	pub disconnected_at: u64,
+	/// Dispatches sent to the session, replayed to the client when resuming.
+	pub replay_buffer: Arc<Mutex<ReplayBuffer>>,
+	/// The intents the session identified with, which are kept when resuming.
+	pub intents: GatewayIntents,
 	pub parent: Weak<Mutex<GatewayUser>>,
 }
 
+impl DisconnectInfo {
+	/// Creates a new [DisconnectInfo] for a session that disconnected just now.
+	pub fn new(
+		session_token: String,
+		user_id: Snowflake,
+		disconnected_at_sequence: u64,
+		replay_buffer: Arc<Mutex<ReplayBuffer>>,
+		intents: GatewayIntents,
+		parent: Weak<Mutex<GatewayUser>>,
+	) -> Self {
+		Self {
+			session_token,
+			user_id,
+			disconnected_at_sequence,
+			disconnected_at: unix_timestamp(),
+			replay_buffer,
+			intents,
+			parent,
+		}
+	}
+
+	/// Whether more than `window_seconds` have passed since the disconnect,
+	/// meaning that the session can no longer be resumed.
+	pub fn has_expired(&self, window_seconds: u64) -> bool {
+		unix_timestamp().saturating_sub(self.disconnected_at) > window_seconds
+	}
+}
+
+fn unix_timestamp() -> u64 {
+	std::time::SystemTime::now()
+		.duration_since(std::time::SystemTime::UNIX_EPOCH)
+		.expect("Check the clock/time settings on the host machine")
+		.as_secs()
+}
+
+/// A bounded buffer of the most recent dispatch payloads sent to a session,
+/// together with their sequence numbers. Once the buffer is full, the oldest
+/// payload is dropped for every new one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayBuffer {
+	capacity: usize,
+	entries: VecDeque<(u64, String)>,
+}
+
+impl Default for ReplayBuffer {
+	fn default() -> Self {
+		Self::new(REPLAY_BUFFER_CAPACITY)
+	}
+}
+
+impl ReplayBuffer {
+	pub fn new(capacity: usize) -> Self {
+		Self { capacity, entries: VecDeque::with_capacity(capacity) }
+	}
+
+	/// Record the serialized dispatch payload with the sequence number
+	/// `sequence`.
+	pub fn push(&mut self, sequence: u64, payload: String) {
+		if self.capacity == 0 {
+			return;
+		}
+		while self.entries.len() >= self.capacity {
+			self.entries.pop_front();
+		}
+		self.entries.push_back((sequence, payload));
+	}
+
+	/// Get all payloads a client missed, given that it last received
+	/// `sequence` and the session last dispatched `latest`.
+	///
+	/// Returns `None` if the client claims to have received a dispatch that was
+	/// never sent, or if some of the missed payloads have already been dropped
+	/// from the buffer.
+	pub fn events_after(&self, sequence: u64, latest: u64) -> Option<Vec<String>> {
+		if sequence > latest {
+			return None;
+		}
+		if sequence == latest {
+			return Some(Vec::new());
+		}
+		match self.entries.front() {
+			Some((oldest, _)) if *oldest <= sequence + 1 => (),
+			_ => return None,
+		}
+		Some(
+			self.entries
+				.iter()
+				.filter(|(entry_sequence, _)| *entry_sequence > sequence)
+				.map(|(_, payload)| payload.clone())
+				.collect(),
+		)
+	}
+}
+
 impl
 	From<(
-		SplitSink<WebSocketStream<TcpStream>, tokio_tungstenite::tungstenite::Message>,
-		SplitStream<WebSocketStream<TcpStream>>,
+		SplitSink<WebSocketStream<MaybeTlsStream>, tokio_tungstenite::tungstenite::Message>,
+		SplitStream<WebSocketStream<MaybeTlsStream>>,
 	)> for WebSocketConnection
 {
 	fn from(
 		value: (
-			SplitSink<WebSocketStream<TcpStream>, tokio_tungstenite::tungstenite::Message>,
-			SplitStream<WebSocketStream<TcpStream>>,
+			SplitSink<WebSocketStream<MaybeTlsStream>, tokio_tungstenite::tungstenite::Message>,
+			SplitStream<WebSocketStream<MaybeTlsStream>>,
 		),
 	) -> Self {
-		Self::new(value.0, value.1)
+		Self::new(value.0, value.1, Transport::default())
 	}
 }
 
@@ -632,3 +1178,51 @@ pub struct NewWebSocketConnection {
 	pub user: Arc<Mutex<GatewayUser>>,
 	pub client: Arc<Mutex<GatewayClient>>,
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn filled(capacity: usize, sequences: std::ops::RangeInclusive<u64>) -> ReplayBuffer {
+		let mut buffer = ReplayBuffer::new(capacity);
+		for sequence in sequences {
+			buffer.push(sequence, sequence.to_string());
+		}
+		buffer
+	}
+
+	#[test]
+	fn replays_missed_payloads_in_order() {
+		let buffer = filled(10, 1..=5);
+		assert_eq!(buffer.events_after(2, 5), Some(vec!["3".into(), "4".into(), "5".into()]));
+		assert_eq!(buffer.events_after(5, 5), Some(Vec::new()));
+		assert_eq!(buffer.events_after(0, 5).map(|events| events.len()), Some(5));
+	}
+
+	#[test]
+	fn unknown_or_dropped_sequences_cannot_be_replayed() {
+		let buffer = filled(3, 1..=5);
+		// 1 and 2 have been dropped
+		assert_eq!(buffer.events_after(0, 5), None);
+		assert_eq!(buffer.events_after(1, 5), None);
+		assert_eq!(buffer.events_after(2, 5), Some(vec!["3".into(), "4".into(), "5".into()]));
+		// The client cannot have received more than was sent
+		assert_eq!(buffer.events_after(6, 5), None);
+		assert_eq!(ReplayBuffer::default().events_after(0, 1), None);
+	}
+
+	#[test]
+	fn disconnects_expire_after_the_window() {
+		let mut info = DisconnectInfo::new(
+			"session".to_string(),
+			Snowflake(1),
+			0,
+			Default::default(),
+			GatewayIntents::default(),
+			Weak::new(),
+		);
+		assert!(!info.has_expired(90));
+		info.disconnected_at -= 91;
+		assert!(info.has_expired(90));
+	}
+}
@@ -0,0 +1,347 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! In-memory store of the presences of connected users.
+//!
+//! Every [GatewayClient](super::GatewayClient) of a user has a presence of its
+//! own, set when identifying and updated through op 3. The presence other users
+//! see is the aggregate of these client presences, overridden by the status a
+//! user has chosen in their settings.
+
+use std::collections::HashMap;
+
+use chorus::types::{PresenceUpdate, PublicUser, Snowflake};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::errors::Error;
+
+/// Status of a user or client.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+	Online,
+	Idle,
+	Dnd,
+	Invisible,
+	#[default]
+	Offline,
+}
+
+impl Status {
+	/// Parse a status as sent by clients. Unknown statuses are treated as
+	/// online.
+	pub fn parse(status: &str) -> Self {
+		serde_json::from_value(Value::String(status.to_string())).unwrap_or(Self::Online)
+	}
+
+	/// The status as seen by other users, who cannot tell invisible users from
+	/// offline ones.
+	pub fn visible(self) -> Self {
+		match self {
+			Self::Invisible => Self::Offline,
+			status => status,
+		}
+	}
+
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::Online => "online",
+			Self::Idle => "idle",
+			Self::Dnd => "dnd",
+			Self::Invisible => "invisible",
+			Self::Offline => "offline",
+		}
+	}
+
+	/// Precedence when aggregating the statuses of several clients: A user
+	/// active on one client is online, even if idle on another.
+	fn precedence(self) -> u8 {
+		match self {
+			Self::Dnd => 4,
+			Self::Online => 3,
+			Self::Idle => 2,
+			Self::Invisible => 1,
+			Self::Offline => 0,
+		}
+	}
+}
+
+/// The kind of device a client runs on, as reported in `client_status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientType {
+	Desktop,
+	Mobile,
+	Web,
+}
+
+impl ClientType {
+	/// Determine the type of a client from the connection properties it
+	/// identified with.
+	pub fn from_properties(properties: &Value) -> Self {
+		let property = |key: &str| {
+			properties.get(key).and_then(Value::as_str).unwrap_or_default().to_lowercase()
+		};
+		let (os, browser) = (property("os"), property("browser"));
+		if os == "android" || os == "ios" || browser.contains("mobile") {
+			Self::Mobile
+		} else if browser.contains("client") || browser.contains("desktop") {
+			Self::Desktop
+		} else {
+			Self::Web
+		}
+	}
+
+	fn as_str(self) -> &'static str {
+		match self {
+			Self::Desktop => "desktop",
+			Self::Mobile => "mobile",
+			Self::Web => "web",
+		}
+	}
+}
+
+/// The presence of a single client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientPresence {
+	pub status: Status,
+	pub activities: Vec<Value>,
+	pub client_type: ClientType,
+}
+
+impl ClientPresence {
+	/// Create a client presence from a serialized presence update, as sent in
+	/// identify payloads and op 3.
+	pub fn from_update(update: &Value, client_type: ClientType) -> Self {
+		Self {
+			status: update
+				.get("status")
+				.and_then(Value::as_str)
+				.map(Status::parse)
+				.unwrap_or(Status::Online),
+			activities: update
+				.get("activities")
+				.and_then(Value::as_array)
+				.cloned()
+				.unwrap_or_default(),
+			client_type,
+		}
+	}
+}
+
+/// The presence of a user, aggregated from the presences of their clients.
+#[derive(Debug, Clone)]
+pub struct UserPresence {
+	pub user: PublicUser,
+	/// The status the user has chosen in their settings, if any.
+	pub settings_status: Option<Status>,
+	/// Presences of the clients of the user, by session token.
+	pub clients: HashMap<String, ClientPresence>,
+}
+
+impl UserPresence {
+	pub fn new(user: PublicUser, settings_status: Option<Status>) -> Self {
+		Self { user, settings_status, clients: HashMap::new() }
+	}
+
+	/// The status of the user, as seen by other users.
+	pub fn status(&self) -> Status {
+		let Some(client_status) = self
+			.clients
+			.values()
+			.map(|client| client.status)
+			.max_by_key(|status| status.precedence())
+		else {
+			return Status::Offline;
+		};
+		match self.settings_status {
+			Some(Status::Online) | None => client_status.visible(),
+			Some(settings_status) => settings_status.visible(),
+		}
+	}
+
+	/// The activities of all clients of the user. Offline users have none.
+	pub fn activities(&self) -> Vec<Value> {
+		if self.status() == Status::Offline {
+			return Vec::new();
+		}
+		self.clients.values().flat_map(|client| client.activities.iter().cloned()).collect()
+	}
+
+	/// The status of the user per kind of device.
+	pub fn client_status(&self) -> Value {
+		let status = self.status();
+		let mut client_status = serde_json::Map::new();
+		if status != Status::Offline {
+			for client in self.clients.values() {
+				client_status.insert(
+					client.client_type.as_str().to_string(),
+					Value::String(status.as_str().to_string()),
+				);
+			}
+		}
+		Value::Object(client_status)
+	}
+
+	/// A `PRESENCE_UPDATE` for this presence, sent in the context of the guild
+	/// `guild_id` or, if `None`, to friends of the user.
+	pub fn to_presence_update(&self, guild_id: Option<Snowflake>) -> Result<PresenceUpdate, Error> {
+		Ok(serde_json::from_value(json!({
+			"user": self.user,
+			"guild_id": guild_id,
+			"status": self.status(),
+			"activities": self.activities(),
+			"client_status": self.client_status(),
+		}))?)
+	}
+
+	/// Everything about this presence which other users can see.
+	fn visible(&self) -> (Status, Vec<Value>, Value) {
+		(self.status(), self.activities(), self.client_status())
+	}
+}
+
+/// Presences of all users connected to the gateway.
+#[derive(Debug, Default)]
+pub struct PresenceStore {
+	users: HashMap<Snowflake, UserPresence>,
+}
+
+impl PresenceStore {
+	pub fn get(&self, user_id: Snowflake) -> Option<&UserPresence> {
+		self.users.get(&user_id)
+	}
+
+	/// Whether the user `user_id` is seen as online by other users.
+	pub fn is_online(&self, user_id: Snowflake) -> bool {
+		self.get(user_id).is_some_and(|presence| presence.status() != Status::Offline)
+	}
+
+	/// Set the presence of a client of the user whose presence is `presence`.
+	/// The user and settings status of an existing entry are replaced with
+	/// those of `presence`. Returns whether the presence other users see
+	/// changed.
+	pub fn set_client(
+		&mut self,
+		presence: UserPresence,
+		session_token: &str,
+		client: ClientPresence,
+	) -> bool {
+		let user_id = presence.user.id;
+		let entry = self.users.entry(user_id).or_insert_with(|| presence.clone());
+		let before = entry.visible();
+		entry.user = presence.user;
+		entry.settings_status = presence.settings_status;
+		entry.clients.insert(session_token.to_string(), client);
+		before != entry.visible()
+	}
+
+	/// Update the status and activities of a connected client. Returns whether
+	/// the presence other users see changed.
+	pub fn update_client(
+		&mut self,
+		user_id: Snowflake,
+		session_token: &str,
+		update: &Value,
+	) -> bool {
+		let Some(entry) = self.users.get_mut(&user_id) else {
+			return false;
+		};
+		let before = entry.visible();
+		let Some(client) = entry.clients.get_mut(session_token) else {
+			return false;
+		};
+		*client = ClientPresence::from_update(update, client.client_type);
+		before != entry.visible()
+	}
+
+	/// Change the status a user has chosen in their settings. Returns whether
+	/// the presence other users see changed.
+	pub fn set_settings_status(&mut self, user_id: Snowflake, status: Option<Status>) -> bool {
+		let Some(entry) = self.users.get_mut(&user_id) else {
+			return false;
+		};
+		let before = entry.visible();
+		entry.settings_status = status;
+		before != entry.visible()
+	}
+
+	/// Remove the presence of a disconnected client. Once the last client of a
+	/// user is gone, the user is offline. Returns whether the presence other
+	/// users see changed.
+	pub fn remove_client(&mut self, user_id: Snowflake, session_token: &str) -> bool {
+		let Some(entry) = self.users.get_mut(&user_id) else {
+			return false;
+		};
+		let before = entry.visible();
+		entry.clients.remove(session_token);
+		before != entry.visible()
+	}
+
+	/// Forget users without any connected clients. Their presence has to be
+	/// dispatched as offline before.
+	pub fn prune(&mut self, user_id: Snowflake) {
+		if self.users.get(&user_id).is_some_and(|presence| presence.clients.is_empty()) {
+			self.users.remove(&user_id);
+		}
+	}
+
+	/// `PRESENCE_UPDATE`s for all online users among `user_ids`, in the
+	/// context of the guild `guild_id`.
+	pub fn presences_of(
+		&self,
+		user_ids: impl IntoIterator<Item = Snowflake>,
+		guild_id: Option<Snowflake>,
+	) -> Result<Vec<PresenceUpdate>, Error> {
+		user_ids
+			.into_iter()
+			.filter(|user_id| self.is_online(*user_id))
+			.filter_map(|user_id| self.get(user_id))
+			.map(|presence| presence.to_presence_update(guild_id))
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn user(id: u64) -> UserPresence {
+		UserPresence::new(PublicUser { id: Snowflake(id), ..Default::default() }, None)
+	}
+
+	fn client(status: &str) -> ClientPresence {
+		ClientPresence::from_update(&json!({ "status": status }), ClientType::Web)
+	}
+
+	#[test]
+	fn aggregates_client_statuses() {
+		let mut store = PresenceStore::default();
+		assert!(store.set_client(user(1), "a", client("idle")));
+		assert_eq!(store.get(Snowflake(1)).unwrap().status(), Status::Idle);
+		assert!(store.set_client(user(1), "b", client("online")));
+		assert_eq!(store.get(Snowflake(1)).unwrap().status(), Status::Online);
+		assert!(!store.set_client(user(1), "c", client("invisible")));
+
+		assert!(store.remove_client(Snowflake(1), "b"));
+		assert_eq!(store.get(Snowflake(1)).unwrap().status(), Status::Idle);
+		store.remove_client(Snowflake(1), "a");
+		assert!(!store.remove_client(Snowflake(1), "c"));
+		assert!(!store.is_online(Snowflake(1)));
+		store.prune(Snowflake(1));
+		assert!(store.get(Snowflake(1)).is_none());
+	}
+
+	#[test]
+	fn settings_status_overrides_clients() {
+		let mut store = PresenceStore::default();
+		store.set_client(user(1), "a", client("online"));
+		assert!(store.set_settings_status(Snowflake(1), Some(Status::Dnd)));
+		assert_eq!(store.get(Snowflake(1)).unwrap().status(), Status::Dnd);
+		assert!(store.set_settings_status(Snowflake(1), Some(Status::Invisible)));
+		assert!(!store.is_online(Snowflake(1)));
+		assert!(store.get(Snowflake(1)).unwrap().activities().is_empty());
+	}
+}
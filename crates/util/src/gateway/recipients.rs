@@ -0,0 +1,376 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Resolution of the recipients of a
+//! [BulkMessageBuilder](super::BulkMessageBuilder) which are not given as plain
+//! user ids.
+//!
+//! Guild membership and the roles of members are taken from the
+//! [RoleUserMap], where the `@everyone` role of a guild holds all of its
+//! members. Only the roles, owner and overwrites are read from the database.
+
+use std::{
+	collections::{HashMap, HashSet},
+	hash::{DefaultHasher, Hash, Hasher},
+};
+
+use chorus::types::{PermissionFlags, PermissionOverwrite, Snowflake};
+use sqlx::PgPool;
+
+use super::RoleUserMap;
+use crate::{
+	entities::{Channel, Guild, Recipient, Role},
+	errors::{ChannelError, Error, GuildError},
+	util::permissions::{MemberPermissionContext, compute_base_permissions},
+};
+
+/// A group of users to send a message to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecipientGroup {
+	/// Everyone who has the `VIEW_CHANNEL` permission in the channel. For DM
+	/// channels, these are the recipients of the channel.
+	ChannelViewers(Snowflake),
+	/// All members of the guild.
+	GuildMembers(Snowflake),
+	/// All recipients of the DM or group DM channel.
+	DmRecipients(Snowflake),
+}
+
+/// The data of a guild channel needed to find out who can see it, loaded
+/// from the database ahead of time so that the [RoleUserMap] does not have to
+/// stay locked while waiting for the database.
+#[derive(Debug, Clone)]
+pub(super) struct ChannelVisibility {
+	guild_id: Snowflake,
+	owner_id: Option<Snowflake>,
+	/// `(role id, permissions)` of all roles of the guild, including
+	/// `@everyone`.
+	roles: Vec<(Snowflake, PermissionFlags)>,
+	category_overwrites: Vec<PermissionOverwrite>,
+	channel_overwrites: Vec<PermissionOverwrite>,
+}
+
+/// A [RecipientGroup] with everything loaded from the database that is needed
+/// to resolve it against a [RoleUserMap].
+#[derive(Debug, Clone)]
+pub(super) enum LoadedGroup {
+	Users(Vec<Snowflake>),
+	GuildMembers(Snowflake),
+	ChannelViewers(ChannelVisibility),
+}
+
+impl RecipientGroup {
+	/// Load what is needed to resolve this group from the database.
+	pub(super) async fn load(self, db: &PgPool) -> Result<LoadedGroup, Error> {
+		match self {
+			RecipientGroup::GuildMembers(guild_id) => Ok(LoadedGroup::GuildMembers(guild_id)),
+			RecipientGroup::DmRecipients(channel_id) => {
+				Ok(LoadedGroup::Users(dm_recipients(db, channel_id).await?))
+			}
+			RecipientGroup::ChannelViewers(channel_id) => {
+				let channel = Channel::get_by_id(db, channel_id)
+					.await?
+					.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
+				match ChannelVisibility::load(db, &channel).await? {
+					Some(visibility) => Ok(LoadedGroup::ChannelViewers(visibility)),
+					None => Ok(LoadedGroup::Users(dm_recipients(db, channel.id).await?)),
+				}
+			}
+		}
+	}
+}
+
+impl LoadedGroup {
+	/// Add the users of this group to `recipients`.
+	pub(super) fn resolve(&self, role_user_map: &RoleUserMap, recipients: &mut HashSet<Snowflake>) {
+		match self {
+			LoadedGroup::Users(users) => recipients.extend(users.iter().copied()),
+			LoadedGroup::GuildMembers(guild_id) => {
+				// The id of the `@everyone` role is the id of the guild.
+				if let Some(members) = role_user_map.get(guild_id) {
+					recipients.extend(members.iter().copied());
+				}
+			}
+			LoadedGroup::ChannelViewers(visibility) => {
+				recipients.extend(visibility.viewers(role_user_map))
+			}
+		}
+	}
+}
+
+impl ChannelVisibility {
+	/// Load the visibility of `channel` from the database, or `None` if it is
+	/// not a guild channel.
+	pub(super) async fn load(db: &PgPool, channel: &Channel) -> Result<Option<Self>, Error> {
+		let Some(guild_id) = channel.guild_id else {
+			return Ok(None);
+		};
+		let category_overwrites = match channel.parent_id {
+			Some(parent_id) => Channel::get_by_id(db, parent_id)
+				.await?
+				.and_then(|category| category.permission_overwrites.clone())
+				.map(|overwrites| overwrites.0)
+				.unwrap_or_default(),
+			None => Vec::new(),
+		};
+		let channel_overwrites = channel
+			.permission_overwrites
+			.as_ref()
+			.map(|overwrites| overwrites.0.clone())
+			.unwrap_or_default();
+		Ok(Some(Self::load_guild(db, guild_id, category_overwrites, channel_overwrites).await?))
+	}
+
+	/// Load the roles and owner of the guild `guild_id` from the database, for
+	/// a channel with the given overwrites.
+	pub(super) async fn load_guild(
+		db: &PgPool,
+		guild_id: Snowflake,
+		category_overwrites: Vec<PermissionOverwrite>,
+		channel_overwrites: Vec<PermissionOverwrite>,
+	) -> Result<Self, Error> {
+		let guild =
+			Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
+		let roles = Role::get_by_guild(db, guild.id)
+			.await?
+			.into_iter()
+			.map(|role| (role.id, role.permissions))
+			.collect();
+		Ok(Self {
+			guild_id: guild.id,
+			owner_id: guild.owner_id,
+			roles,
+			category_overwrites,
+			channel_overwrites,
+		})
+	}
+
+	/// Load the roles and owner of the guild anew, keeping the overwrites.
+	pub(super) async fn reload(self, db: &PgPool) -> Result<Self, Error> {
+		Self::load_guild(db, self.guild_id, self.category_overwrites, self.channel_overwrites).await
+	}
+
+	pub(super) fn guild_id(&self) -> Snowflake {
+		self.guild_id
+	}
+
+	/// The id of the member list of the channel. Like on Discord, channels
+	/// without any overwrites of `VIEW_CHANNEL` share the list `everyone`.
+	/// Other channels share a list with the channels which allow and deny
+	/// `VIEW_CHANNEL` to the same roles and members, identified by a hash of
+	/// these overwrites.
+	pub(super) fn list_id(&self) -> String {
+		let mut entries: Vec<(&str, &str, Snowflake)> =
+			[("category", &self.category_overwrites), ("channel", &self.channel_overwrites)]
+				.into_iter()
+				.flat_map(|(scope, overwrites)| {
+					overwrites.iter().flat_map(move |overwrite| {
+						[("allow", overwrite.allow), ("deny", overwrite.deny)]
+							.into_iter()
+							.filter(|(_, flags)| flags.contains(PermissionFlags::VIEW_CHANNEL))
+							.map(move |(kind, _)| (scope, kind, overwrite.id))
+					})
+				})
+				.collect();
+		if entries.is_empty() {
+			return "everyone".to_string();
+		}
+		entries.sort_unstable_by_key(|&(scope, kind, id)| (scope, kind, id.0));
+		let mut hasher = DefaultHasher::new();
+		for (scope, kind, id) in entries {
+			(scope, kind, id.0).hash(&mut hasher);
+		}
+		hasher.finish().to_string()
+	}
+
+	/// All members of the guild with the `VIEW_CHANNEL` permission in the
+	/// channel.
+	fn viewers(&self, role_user_map: &RoleUserMap) -> Vec<Snowflake> {
+		let Some(members) = role_user_map.get(&self.guild_id) else {
+			return Vec::new();
+		};
+		let mut member_roles: HashMap<Snowflake, Vec<Snowflake>> = HashMap::new();
+		for (role_id, _) in self.roles.iter().filter(|(id, _)| *id != self.guild_id) {
+			for user_id in role_user_map.get(role_id).into_iter().flatten() {
+				member_roles.entry(*user_id).or_default().push(*role_id);
+			}
+		}
+		members
+			.iter()
+			.copied()
+			.filter(|member_id| {
+				let roles = member_roles.get(member_id).map(Vec::as_slice).unwrap_or_default();
+				self.can_view(*member_id, roles)
+			})
+			.collect()
+	}
+
+	/// Whether the member `member_id` of the guild can view the channel, with
+	/// the roles of the member taken from `role_user_map`. Returns `false` if
+	/// they are not a member.
+	pub(super) fn member_can_view(
+		&self,
+		role_user_map: &RoleUserMap,
+		member_id: Snowflake,
+	) -> bool {
+		if !role_user_map.get(&self.guild_id).is_some_and(|members| members.contains(&member_id)) {
+			return false;
+		}
+		let roles: Vec<Snowflake> = self
+			.roles
+			.iter()
+			.map(|(id, _)| *id)
+			.filter(|id| role_user_map.get(id).is_some_and(|users| users.contains(&member_id)))
+			.collect();
+		self.can_view(member_id, &roles)
+	}
+
+	/// Whether the member `member_id` with the roles `role_ids` has the
+	/// `VIEW_CHANNEL` permission in the channel. Roles which are not part of
+	/// the guild, including `@everyone`, are ignored in `role_ids`.
+	pub(super) fn can_view(&self, member_id: Snowflake, role_ids: &[Snowflake]) -> bool {
+		let everyone = self
+			.roles
+			.iter()
+			.find(|(id, _)| *id == self.guild_id)
+			.map(|(_, permissions)| *permissions)
+			.unwrap_or_else(PermissionFlags::empty);
+		let roles: Vec<(Snowflake, PermissionFlags)> = self
+			.roles
+			.iter()
+			.filter(|(id, _)| *id != self.guild_id && role_ids.contains(id))
+			.copied()
+			.collect();
+		let is_owner = self.owner_id == Some(member_id);
+		let context = MemberPermissionContext {
+			guild_id: self.guild_id,
+			member_id,
+			is_owner,
+			role_ids: roles.iter().map(|(id, _)| *id).collect(),
+			base: compute_base_permissions(
+				is_owner,
+				everyone,
+				roles.iter().map(|(_, permissions)| *permissions),
+			),
+		};
+		context
+			.channel_permissions(&self.category_overwrites, &self.channel_overwrites)
+			.contains(PermissionFlags::VIEW_CHANNEL)
+	}
+}
+
+async fn dm_recipients(db: &PgPool, channel_id: Snowflake) -> Result<Vec<Snowflake>, Error> {
+	Ok(Recipient::get_by_channel_id(db, channel_id)
+		.await?
+		.into_iter()
+		.map(|recipient| recipient.user_id)
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use chorus::types::PermissionOverwriteType;
+
+	use super::*;
+
+	const GUILD: Snowflake = Snowflake(1);
+	const OWNER: Snowflake = Snowflake(2);
+	const MODERATOR: Snowflake = Snowflake(3);
+	const MEMBER: Snowflake = Snowflake(4);
+	const MODERATORS: Snowflake = Snowflake(10);
+
+	fn role_user_map() -> RoleUserMap {
+		let mut map = RoleUserMap::default();
+		map.insert(GUILD, HashSet::from([OWNER, MODERATOR, MEMBER]));
+		map.insert(MODERATORS, HashSet::from([MODERATOR]));
+		map
+	}
+
+	fn hidden_channel() -> ChannelVisibility {
+		ChannelVisibility {
+			guild_id: GUILD,
+			owner_id: Some(OWNER),
+			roles: vec![
+				(GUILD, PermissionFlags::VIEW_CHANNEL | PermissionFlags::SEND_MESSAGES),
+				(MODERATORS, PermissionFlags::MANAGE_MESSAGES),
+			],
+			category_overwrites: Vec::new(),
+			channel_overwrites: vec![
+				PermissionOverwrite {
+					id: GUILD,
+					overwrite_type: PermissionOverwriteType::Role,
+					allow: PermissionFlags::empty(),
+					deny: PermissionFlags::VIEW_CHANNEL,
+				},
+				PermissionOverwrite {
+					id: MODERATORS,
+					overwrite_type: PermissionOverwriteType::Role,
+					allow: PermissionFlags::VIEW_CHANNEL,
+					deny: PermissionFlags::empty(),
+				},
+			],
+		}
+	}
+
+	fn resolve(group: LoadedGroup) -> HashSet<Snowflake> {
+		let mut recipients = HashSet::new();
+		group.resolve(&role_user_map(), &mut recipients);
+		recipients
+	}
+
+	#[test]
+	fn hidden_channels_are_only_resolved_to_members_who_can_view_them() {
+		let viewers = resolve(LoadedGroup::ChannelViewers(hidden_channel()));
+		assert_eq!(viewers, HashSet::from([OWNER, MODERATOR]));
+
+		let mut public = hidden_channel();
+		public.channel_overwrites.clear();
+		let viewers = resolve(LoadedGroup::ChannelViewers(public));
+		assert_eq!(viewers, HashSet::from([OWNER, MODERATOR, MEMBER]));
+	}
+
+	#[test]
+	fn channels_share_a_member_list_if_they_overwrite_view_channel_alike() {
+		let hidden = hidden_channel();
+		let mut public = hidden_channel();
+		public.channel_overwrites.clear();
+		assert_eq!(public.list_id(), "everyone");
+		assert_ne!(hidden.list_id(), "everyone");
+
+		// Overwrites which do not touch VIEW_CHANNEL do not change the list.
+		let mut moderated = hidden_channel();
+		moderated.channel_overwrites.push(PermissionOverwrite {
+			id: MEMBER,
+			overwrite_type: PermissionOverwriteType::Member,
+			allow: PermissionFlags::empty(),
+			deny: PermissionFlags::SEND_MESSAGES,
+		});
+		moderated.channel_overwrites.reverse();
+		assert_eq!(moderated.list_id(), hidden.list_id());
+
+		// Moving the overwrites to the category makes them a different list.
+		let mut categorized = public.clone();
+		categorized.category_overwrites = hidden.channel_overwrites.clone();
+		assert_ne!(categorized.list_id(), hidden.list_id());
+	}
+
+	#[test]
+	fn members_can_view_hidden_channels_through_their_roles() {
+		let hidden = hidden_channel();
+		let role_user_map = role_user_map();
+		assert!(hidden.member_can_view(&role_user_map, MODERATOR));
+		assert!(!hidden.member_can_view(&role_user_map, MEMBER));
+		assert!(!hidden.member_can_view(&role_user_map, Snowflake(99)));
+		assert!(hidden.can_view(MEMBER, &[MODERATORS]));
+	}
+
+	#[test]
+	fn guild_members_are_resolved_through_the_everyone_role() {
+		assert_eq!(
+			resolve(LoadedGroup::GuildMembers(GUILD)),
+			HashSet::from([OWNER, MODERATOR, MEMBER])
+		);
+		assert!(resolve(LoadedGroup::GuildMembers(Snowflake(99))).is_empty());
+	}
+}
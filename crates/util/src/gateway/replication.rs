@@ -0,0 +1,210 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Replication of the state every process keeps in memory, the [RoleUserMap]
+//! and the [PresenceStore], through the [EventBus](super::event_bus::EventBus).
+//!
+//! A process changes its own state first, and then publishes the change. All
+//! other processes apply it to their state as well, so that recipients and
+//! presences can be resolved on any process.
+//!
+//! [RoleUserMap]: super::RoleUserMap
+//! [PresenceStore]: super::presence::PresenceStore
+
+use chorus::types::Snowflake;
+use tokio::sync::broadcast::error::RecvError;
+
+use super::{
+	ConnectedUsers,
+	event_bus::BusMessage,
+	presence::{ClientPresence, UserPresence},
+};
+
+impl ConnectedUsers {
+	/// Apply the changes published by other processes to the state of this
+	/// process, forever. Run by every process, before it serves any requests.
+	/// The presences of the clients already connected to other processes are
+	/// requested once subscribed.
+	///
+	/// Messages lost by the bus are not recovered. The [RoleUserMap] is
+	/// corrected by reconciliation.
+	///
+	/// [RoleUserMap]: super::RoleUserMap
+	pub async fn replicate_state(self) {
+		let mut messages = self.event_bus.subscribe();
+		let request = BusMessage::PresencesRequested { origin: self.origin };
+		if let Err(e) = self.event_bus.publish(request).await {
+			log::error!(target: "symfonia::gateway::replication", "Failed to request the presences of other processes: {e}");
+		}
+		loop {
+			match messages.recv().await {
+				Ok(message) => self.replicate(message).await,
+				Err(RecvError::Lagged(skipped)) => {
+					log::warn!(target: "symfonia::gateway::replication", "Replication is lagging behind, skipped {skipped} messages");
+				}
+				Err(RecvError::Closed) => return,
+			}
+		}
+	}
+
+	/// Apply `message`, if it is a change to the state of another process.
+	/// Settings statuses carry no origin, and are set again by the process
+	/// which published them, without effect.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a lock on `role_user_map` for changes to it, and a
+	/// write lock on `presences` for changes to presences.
+	async fn replicate(&self, message: BusMessage) {
+		match message {
+			BusMessage::SettingsStatusChanged { user_id, status } => {
+				let changed = self.presences.write().set_settings_status(user_id, status);
+				if changed {
+					self.update_member_list_presences(user_id);
+				}
+			}
+			BusMessage::RoleUserMapChanged { origin, changes } if origin != self.origin => {
+				let mut role_user_map = self.role_user_map.lock().await;
+				for change in changes {
+					role_user_map.apply(change);
+				}
+			}
+			BusMessage::ClientPresenceChanged {
+				origin,
+				user,
+				settings_status,
+				session_token,
+				client,
+			} if origin != self.origin => {
+				let user_id = user.id;
+				let changed = self.presences.write().set_client(
+					UserPresence::new(user, settings_status),
+					&session_token,
+					client,
+				);
+				if changed {
+					self.update_member_list_presences(user_id);
+				}
+			}
+			BusMessage::ClientDisconnected { origin, user_id, session_token }
+				if origin != self.origin =>
+			{
+				let changed = self.presences.write().remove_client(user_id, &session_token);
+				if changed {
+					self.update_member_list_presences(user_id);
+				}
+				self.presences.write().prune(user_id);
+			}
+			BusMessage::PresencesRequested { origin } if origin != self.origin => {
+				for (user_id, session_token) in self.local_sessions().await {
+					self.publish_client_presence(user_id, &session_token).await;
+				}
+			}
+			_ => (),
+		}
+	}
+
+	/// The users and session tokens of the clients connected to this process.
+	///
+	/// ## Locking
+	///
+	/// This method briefly acquires a read lock on `store`, and then a lock on
+	/// every [GatewayUser](super::GatewayUser).
+	async fn local_sessions(&self) -> Vec<(Snowflake, String)> {
+		let users: Vec<_> = self.store.read().users.values().cloned().collect();
+		let mut sessions = Vec::new();
+		for user in users {
+			let user = user.lock().await;
+			sessions.extend(user.session_tokens().map(|token| (user.id, token.to_string())));
+		}
+		sessions
+	}
+
+	/// Publish the presence of the client with the session `session_token` of
+	/// the user `user_id`, as set in the [PresenceStore] of this process, so
+	/// that the other processes set it as well. Failing to publish is logged.
+	///
+	/// ## Locking
+	///
+	/// This method briefly acquires a read lock on `presences`.
+	///
+	/// [PresenceStore]: super::presence::PresenceStore
+	pub async fn publish_client_presence(&self, user_id: Snowflake, session_token: &str) {
+		let Some((user, settings_status, client)) =
+			self.presences.read().get(user_id).and_then(|presence| {
+				let client: &ClientPresence = presence.clients.get(session_token)?;
+				Some((presence.user.clone(), presence.settings_status, client.clone()))
+			})
+		else {
+			return;
+		};
+		let message = BusMessage::ClientPresenceChanged {
+			origin: self.origin,
+			user,
+			settings_status,
+			session_token: session_token.to_string(),
+			client,
+		};
+		if let Err(e) = self.event_bus.publish(message).await {
+			log::error!(target: "symfonia::gateway::replication", "Failed to publish a client presence: {e}");
+		}
+	}
+
+	/// Publish that the client with the session `session_token` of the user
+	/// `user_id` disconnected from this process, so that the other processes
+	/// remove its presence as well. Failing to publish is logged.
+	pub async fn publish_client_disconnected(&self, user_id: Snowflake, session_token: &str) {
+		let message = BusMessage::ClientDisconnected {
+			origin: self.origin,
+			user_id,
+			session_token: session_token.to_string(),
+		};
+		if let Err(e) = self.event_bus.publish(message).await {
+			log::error!(target: "symfonia::gateway::replication", "Failed to publish a client disconnect: {e}");
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::gateway::{
+		event_bus::SharedEventBus,
+		role_user_map::{RoleAssignment, RoleUserMapChange},
+	};
+
+	#[tokio::test]
+	async fn role_user_map_changes_reach_other_processes() {
+		let event_bus = SharedEventBus::default();
+		let api = ConnectedUsers::with_event_bus(event_bus.clone());
+		let gateway = ConnectedUsers::with_event_bus(event_bus);
+		tokio::spawn(gateway.clone().replicate_state());
+		tokio::time::sleep(Duration::from_millis(50)).await;
+
+		let assignment = RoleAssignment { role_id: Snowflake(1), user_id: Snowflake(10) };
+		api.role_user_map
+			.apply([
+				RoleUserMapChange::InsertRole(Snowflake(1)),
+				RoleUserMapChange::Assign(assignment),
+			])
+			.await;
+
+		tokio::time::timeout(Duration::from_secs(5), async {
+			while !gateway
+				.role_user_map
+				.lock()
+				.await
+				.get(&Snowflake(1))
+				.is_some_and(|users| users.contains(&Snowflake(10)))
+			{
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.expect("The change was not replicated");
+		assert_eq!(*api.role_user_map.lock().await, *gateway.role_user_map.lock().await);
+	}
+}
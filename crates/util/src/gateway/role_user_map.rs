@@ -0,0 +1,275 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Changes to the [RoleUserMap], which are shared with the other processes
+//! through the event bus, and detection and correction of drift between the
+//! [RoleUserMap] and the `roles` and `member_roles` tables.
+
+use std::time::Duration;
+
+use chorus::types::Snowflake;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::sync::MutexGuard;
+
+use super::{ConnectedUsers, RoleUserMap};
+use crate::errors::Error;
+
+/// How often [ConnectedUsers::reconcile_role_user_map_periodically] compares
+/// the [RoleUserMap] with the database by default.
+pub const RECONCILIATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The assignment of the role `role_id` to the user `user_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RoleAssignment {
+	pub role_id: Snowflake,
+	pub user_id: Snowflake,
+}
+
+/// A change to the [RoleUserMap], made after the database has been changed
+/// accordingly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoleUserMapChange {
+	InsertRole(Snowflake),
+	RemoveRole(Snowflake),
+	Assign(RoleAssignment),
+	Unassign(RoleAssignment),
+}
+
+/// Differences between a [RoleUserMap] and the database.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct RoleUserMapDrift {
+	/// Roles in the database which are missing from the map.
+	pub missing_roles: Vec<Snowflake>,
+	/// Roles in the map which no longer exist in the database.
+	pub stale_roles: Vec<Snowflake>,
+	/// Role assignments in the database which are missing from the map.
+	pub missing_assignments: Vec<RoleAssignment>,
+	/// Role assignments in the map which no longer exist in the database.
+	pub stale_assignments: Vec<RoleAssignment>,
+}
+
+impl RoleUserMapDrift {
+	/// Whether the map matched the database.
+	pub fn is_empty(&self) -> bool {
+		self.missing_roles.is_empty()
+			&& self.stale_roles.is_empty()
+			&& self.missing_assignments.is_empty()
+			&& self.stale_assignments.is_empty()
+	}
+}
+
+impl RoleUserMap {
+	/// Make the change `change` to this map.
+	pub fn apply(&mut self, change: RoleUserMapChange) {
+		match change {
+			RoleUserMapChange::InsertRole(role_id) => self.insert_role(role_id),
+			RoleUserMapChange::RemoveRole(role_id) => self.remove_role(role_id),
+			RoleUserMapChange::Assign(RoleAssignment { role_id, user_id }) => {
+				self.assign(role_id, user_id)
+			}
+			RoleUserMapChange::Unassign(RoleAssignment { role_id, user_id }) => {
+				self.unassign(role_id, user_id)
+			}
+		}
+	}
+
+	/// Compare this map with `expected`, the map built from the database.
+	pub fn drift(&self, expected: &RoleUserMap) -> RoleUserMapDrift {
+		let mut drift = RoleUserMapDrift::default();
+		for (role_id, users) in expected.iter() {
+			let Some(actual) = self.get(role_id) else {
+				drift.missing_roles.push(*role_id);
+				drift.missing_assignments.extend(
+					users
+						.iter()
+						.map(|user_id| RoleAssignment { role_id: *role_id, user_id: *user_id }),
+				);
+				continue;
+			};
+			drift.missing_assignments.extend(
+				users
+					.difference(actual)
+					.map(|user_id| RoleAssignment { role_id: *role_id, user_id: *user_id }),
+			);
+			drift.stale_assignments.extend(
+				actual
+					.difference(users)
+					.map(|user_id| RoleAssignment { role_id: *role_id, user_id: *user_id }),
+			);
+		}
+		for (role_id, users) in self.iter().filter(|(role_id, _)| !expected.contains_key(role_id)) {
+			drift.stale_roles.push(*role_id);
+			drift.stale_assignments.extend(
+				users.iter().map(|user_id| RoleAssignment { role_id: *role_id, user_id: *user_id }),
+			);
+		}
+		drift.missing_roles.sort();
+		drift.stale_roles.sort();
+		drift.missing_assignments.sort();
+		drift.stale_assignments.sort();
+		drift
+	}
+}
+
+impl ConnectedUsers {
+	/// Load the [RoleUserMap] from the database and lock `role_user_map`,
+	/// unless it was changed while loading. Such a change may or may not be
+	/// part of the loaded map, so comparing the two would report false drift.
+	///
+	/// ## Locking
+	///
+	/// This method briefly acquires a lock on `role_user_map` before loading
+	/// from the database, and returns a lock acquired after loading.
+	async fn load_role_user_map(
+		&self,
+		db: &PgPool,
+	) -> Result<Option<(MutexGuard<'_, RoleUserMap>, RoleUserMap)>, Error> {
+		let generation = self.role_user_map.lock().await.generation();
+		let expected = RoleUserMap::load(db).await?;
+		let role_user_map = self.role_user_map.lock().await;
+		if role_user_map.generation() != generation {
+			return Ok(None);
+		}
+		Ok(Some((role_user_map, expected)))
+	}
+
+	/// Compare the [RoleUserMap] with the database, without changing it.
+	/// Returns `None` if the map was changed during the comparison.
+	///
+	/// ## Locking
+	///
+	/// This method does not hold the lock on `role_user_map` while reading
+	/// from the database.
+	pub async fn role_user_map_drift(
+		&self,
+		db: &PgPool,
+	) -> Result<Option<RoleUserMapDrift>, Error> {
+		Ok(self
+			.load_role_user_map(db)
+			.await?
+			.map(|(role_user_map, expected)| role_user_map.drift(&expected)))
+	}
+
+	/// Replace the [RoleUserMap] with the state of the database, returning the
+	/// drift which has been corrected. Returns `None` without changing the map
+	/// if it was changed while the database was read.
+	///
+	/// ## Locking
+	///
+	/// This method does not hold the lock on `role_user_map` while reading
+	/// from the database.
+	pub async fn reconcile_role_user_map(
+		&self,
+		db: &PgPool,
+	) -> Result<Option<RoleUserMapDrift>, Error> {
+		let Some((mut role_user_map, mut expected)) = self.load_role_user_map(db).await? else {
+			return Ok(None);
+		};
+		let drift = role_user_map.drift(&expected);
+		// Changes made after this point must still be detected.
+		expected.generation = role_user_map.generation() + 1;
+		*role_user_map = expected;
+		Ok(Some(drift))
+	}
+
+	/// Call [Self::reconcile_role_user_map] every `period`, forever. Corrected
+	/// drift is logged, as it means that some change to roles bypassed the
+	/// entity methods keeping the map in sync.
+	pub async fn reconcile_role_user_map_periodically(self, db: PgPool, period: Duration) {
+		let mut interval = tokio::time::interval(period);
+		// The map has just been initialized when this task is started.
+		interval.tick().await;
+		loop {
+			interval.tick().await;
+			match self.reconcile_role_user_map(&db).await {
+				Ok(None) => {
+					log::debug!(target: "symfonia::gateway::role_user_map", "Role->User map changed during reconciliation, retrying next time");
+				}
+				Ok(Some(drift)) if drift.is_empty() => {
+					log::trace!(target: "symfonia::gateway::role_user_map", "Role->User map is in sync with the database");
+				}
+				Ok(Some(drift)) => {
+					log::warn!(target: "symfonia::gateway::role_user_map", "Corrected drift of the Role->User map: {drift:?}");
+				}
+				Err(e) => {
+					log::error!(target: "symfonia::gateway::role_user_map", "Failed to reconcile the Role->User map: {e}");
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+
+	use super::*;
+
+	const ROLE_A: Snowflake = Snowflake(1);
+	const ROLE_B: Snowflake = Snowflake(2);
+	const ROLE_C: Snowflake = Snowflake(3);
+	const ALICE: Snowflake = Snowflake(10);
+	const BOB: Snowflake = Snowflake(11);
+
+	fn map(entries: &[(Snowflake, &[Snowflake])]) -> RoleUserMap {
+		let mut map = RoleUserMap::default();
+		for (role_id, users) in entries {
+			map.insert(*role_id, users.iter().copied().collect::<HashSet<_>>());
+		}
+		map
+	}
+
+	#[test]
+	fn drift_reports_missing_and_stale_roles_and_assignments() {
+		let actual = map(&[(ROLE_A, &[ALICE, BOB]), (ROLE_C, &[BOB])]);
+		let expected = map(&[(ROLE_A, &[ALICE]), (ROLE_B, &[BOB])]);
+
+		assert_eq!(
+			actual.drift(&expected),
+			RoleUserMapDrift {
+				missing_roles: vec![ROLE_B],
+				stale_roles: vec![ROLE_C],
+				missing_assignments: vec![RoleAssignment { role_id: ROLE_B, user_id: BOB }],
+				stale_assignments: vec![
+					RoleAssignment { role_id: ROLE_A, user_id: BOB },
+					RoleAssignment { role_id: ROLE_C, user_id: BOB },
+				],
+			}
+		);
+		assert!(expected.drift(&expected).is_empty());
+	}
+
+	#[test]
+	fn changes_advance_the_generation() {
+		let mut map = RoleUserMap::default();
+		let generation = map.generation();
+		map.assign(ROLE_A, ALICE);
+		map.unassign(ROLE_A, ALICE);
+		map.remove_role(ROLE_A);
+		assert_eq!(map.generation(), generation + 3);
+	}
+
+	#[test]
+	fn changes_survive_the_event_bus() {
+		let changes = [
+			RoleUserMapChange::InsertRole(ROLE_A),
+			RoleUserMapChange::Assign(RoleAssignment { role_id: ROLE_A, user_id: ALICE }),
+			RoleUserMapChange::Assign(RoleAssignment { role_id: ROLE_A, user_id: BOB }),
+			RoleUserMapChange::Unassign(RoleAssignment { role_id: ROLE_A, user_id: BOB }),
+			RoleUserMapChange::InsertRole(ROLE_B),
+			RoleUserMapChange::RemoveRole(ROLE_B),
+		];
+		let received: Vec<RoleUserMapChange> =
+			serde_json::from_str(&serde_json::to_string(&changes).unwrap()).unwrap();
+		assert_eq!(received, changes);
+
+		let mut applied = RoleUserMap::default();
+		for change in received {
+			applied.apply(change);
+		}
+		assert_eq!(*applied, *map(&[(ROLE_A, &[ALICE])]));
+	}
+}
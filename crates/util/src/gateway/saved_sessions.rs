@@ -0,0 +1,117 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Resumable sessions saved to the `resumable_sessions` table by a gateway
+//! which shuts down, so that their clients can resume them on another node.
+
+use std::sync::{Arc, Weak};
+
+use chorus::types::Snowflake;
+use sqlx::{PgPool, types::Json};
+use tokio::sync::Mutex;
+
+use super::{ConnectedUsers, DisconnectInfo, ReplayBuffer, intents::GatewayIntents};
+use crate::errors::Error;
+
+#[derive(sqlx::FromRow)]
+struct SavedSession {
+	session_id: String,
+	user_id: Snowflake,
+	sequence: i64,
+	disconnected_at: i64,
+	intents: i64,
+	replay_buffer: Json<ReplayBuffer>,
+}
+
+impl From<SavedSession> for DisconnectInfo {
+	fn from(value: SavedSession) -> Self {
+		Self {
+			session_token: value.session_id,
+			user_id: value.user_id,
+			disconnected_at_sequence: value.sequence as u64,
+			disconnected_at: value.disconnected_at as u64,
+			replay_buffer: Arc::new(Mutex::new(value.replay_buffer.0)),
+			intents: GatewayIntents::from_bits_retain(value.intents as u64),
+			parent: Weak::new(),
+		}
+	}
+}
+
+const SAVED_SESSION_COLUMNS: &str = "session_id, user_id, sequence, \
+	EXTRACT(EPOCH FROM disconnected_at)::bigint AS disconnected_at, intents, replay_buffer";
+
+impl DisconnectInfo {
+	/// Save this session, so that it can be resumed on any node.
+	pub async fn save(&self, db: &PgPool) -> Result<(), Error> {
+		let replay_buffer = self.replay_buffer.lock().await.clone();
+		sqlx::query(
+			"INSERT INTO resumable_sessions (session_id, user_id, sequence, disconnected_at, intents, replay_buffer) \
+			 VALUES ($1, $2, $3, to_timestamp($4), $5, $6) \
+			 ON CONFLICT (session_id) DO UPDATE SET sequence = $3, disconnected_at = to_timestamp($4), intents = $5, replay_buffer = $6",
+		)
+		.bind(&self.session_token)
+		.bind(self.user_id)
+		.bind(self.disconnected_at_sequence as i64)
+		.bind(self.disconnected_at as f64)
+		.bind(self.intents.bits() as i64)
+		.bind(Json(replay_buffer))
+		.execute(db)
+		.await?;
+		Ok(())
+	}
+
+	/// Remove the saved session `session_token` from the database and return
+	/// it, if it exists.
+	pub async fn take_saved(db: &PgPool, session_token: &str) -> Result<Option<Self>, Error> {
+		let saved: Option<SavedSession> = sqlx::query_as(&format!(
+			"DELETE FROM resumable_sessions WHERE session_id = $1 RETURNING {SAVED_SESSION_COLUMNS}"
+		))
+		.bind(session_token)
+		.fetch_optional(db)
+		.await?;
+		Ok(saved.map(Self::from))
+	}
+
+	/// Remove all saved sessions disconnected more than `window_seconds` ago
+	/// from the database and return them.
+	pub async fn take_expired_saved(db: &PgPool, window_seconds: u64) -> Result<Vec<Self>, Error> {
+		let expired: Vec<SavedSession> = sqlx::query_as(&format!(
+			"DELETE FROM resumable_sessions WHERE disconnected_at < NOW() - make_interval(secs => $1) \
+			 RETURNING {SAVED_SESSION_COLUMNS}"
+		))
+		.bind(window_seconds as f64)
+		.fetch_all(db)
+		.await?;
+		Ok(expired.into_iter().map(Self::from).collect())
+	}
+}
+
+impl ConnectedUsers {
+	/// Save all sessions which can currently be resumed on this node to the
+	/// database, removing them from the store. Returns the number of saved
+	/// sessions; sessions which could not be saved are logged and dropped.
+	///
+	/// ## Locking
+	///
+	/// This method briefly acquires a write lock on `store`.
+	pub async fn save_resumable_sessions(&self, db: &PgPool) -> usize {
+		let sessions: Vec<DisconnectInfo> = self
+			.store
+			.write()
+			.resumeable_clients_store
+			.drain()
+			.map(|(_, session)| session)
+			.collect();
+		let mut saved = 0;
+		for session in sessions.iter() {
+			match session.save(db).await {
+				Ok(()) => saved += 1,
+				Err(e) => {
+					log::error!(target: "symfonia::gateway::saved_sessions", "Failed to save session {}: {e}", session.session_token);
+				}
+			}
+		}
+		saved
+	}
+}
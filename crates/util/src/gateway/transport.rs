@@ -0,0 +1,282 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Transport level options of a gateway connection, which clients choose
+//! through the query string of the gateway URL, e.g.
+//! `wss://gateway.example.com/?v=9&encoding=json&compress=zlib-stream`.
+
+use std::{io::Write, sync::Arc};
+
+use flate2::{Compression, write::ZlibEncoder};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::codec::{Codec, EtfCodec, JsonCodec};
+use crate::errors::{Error, GatewayError};
+
+/// Gateway versions clients may connect with.
+pub const SUPPORTED_GATEWAY_VERSIONS: std::ops::RangeInclusive<u8> = 6..=10;
+/// Gateway version assumed for clients which do not send a `v` parameter.
+pub const DEFAULT_GATEWAY_VERSION: u8 = 9;
+
+/// The encoding of payloads sent and received over a gateway connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GatewayEncoding {
+	#[default]
+	Json,
+	Etf,
+}
+
+impl GatewayEncoding {
+	/// The [Codec] translating between JSON and this encoding.
+	pub fn codec(self) -> Arc<dyn Codec> {
+		match self {
+			Self::Json => Arc::new(JsonCodec),
+			Self::Etf => Arc::new(EtfCodec),
+		}
+	}
+}
+
+/// The transport compression of payloads sent to the client.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GatewayCompression {
+	#[default]
+	None,
+	/// All payloads are compressed using one shared zlib context, each of them
+	/// ending with a `Z_SYNC_FLUSH` suffix (`00 00 ff ff`).
+	ZlibStream,
+	/// All payloads are compressed using one shared zstd context, each of them
+	/// ending with a flushed block.
+	ZstdStream,
+}
+
+/// Options requested by a client through the query parameters of the gateway
+/// URL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionOptions {
+	pub version: u8,
+	pub encoding: GatewayEncoding,
+	pub compression: GatewayCompression,
+}
+
+impl Default for ConnectionOptions {
+	fn default() -> Self {
+		Self {
+			version: DEFAULT_GATEWAY_VERSION,
+			encoding: GatewayEncoding::default(),
+			compression: GatewayCompression::default(),
+		}
+	}
+}
+
+impl ConnectionOptions {
+	/// Parse the `v`, `encoding` and `compress` parameters from the query
+	/// string of the gateway URL. Unknown parameters are ignored.
+	pub fn from_query(query: Option<&str>) -> Result<Self, GatewayError> {
+		let mut options = Self::default();
+		let Some(query) = query else {
+			return Ok(options);
+		};
+		for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+			match key {
+				"v" => {
+					options.version = value
+						.parse::<u8>()
+						.ok()
+						.filter(|version| SUPPORTED_GATEWAY_VERSIONS.contains(version))
+						.ok_or(GatewayError::InvalidApiVersion(value.to_string()))?;
+				}
+				"encoding" => {
+					options.encoding = match value {
+						"json" => GatewayEncoding::Json,
+						"etf" => GatewayEncoding::Etf,
+						_ => {
+							return Err(GatewayError::InvalidConnectionParameter(format!(
+								"Unsupported encoding: {value}"
+							)));
+						}
+					};
+				}
+				"compress" => {
+					options.compression = match value {
+						"zlib-stream" => GatewayCompression::ZlibStream,
+						"zstd-stream" => GatewayCompression::ZstdStream,
+						_ => {
+							return Err(GatewayError::InvalidConnectionParameter(format!(
+								"Unsupported compression: {value}"
+							)));
+						}
+					};
+				}
+				_ => (),
+			}
+		}
+		Ok(options)
+	}
+}
+
+/// The compression context of a single connection. The context is shared
+/// between all payloads sent over the connection, which is what makes the
+/// compression effective for small payloads.
+pub enum TransportCompressor {
+	Zlib(ZlibEncoder<Vec<u8>>),
+	Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl TransportCompressor {
+	/// Create a compression context for `compression`, or `None` if payloads
+	/// are to be sent uncompressed.
+	pub fn new(compression: GatewayCompression) -> Result<Option<Self>, Error> {
+		Ok(match compression {
+			GatewayCompression::None => None,
+			GatewayCompression::ZlibStream => {
+				Some(Self::Zlib(ZlibEncoder::new(Vec::new(), Compression::default())))
+			}
+			GatewayCompression::ZstdStream => {
+				Some(Self::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 0)?))
+			}
+		})
+	}
+
+	/// Compress a single payload. The returned bytes can be decompressed by the
+	/// client as soon as they are received.
+	pub fn compress(&mut self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+		Ok(match self {
+			Self::Zlib(encoder) => {
+				encoder.write_all(payload)?;
+				encoder.flush()?;
+				std::mem::take(encoder.get_mut())
+			}
+			Self::Zstd(encoder) => {
+				encoder.write_all(payload)?;
+				encoder.flush()?;
+				std::mem::take(encoder.get_mut())
+			}
+		})
+	}
+}
+
+/// Encoding and compression of a single connection.
+pub struct Transport {
+	codec: Arc<dyn Codec>,
+	compressor: Option<TransportCompressor>,
+}
+
+impl Default for Transport {
+	fn default() -> Self {
+		Self { codec: Arc::new(JsonCodec), compressor: None }
+	}
+}
+
+impl Transport {
+	pub fn new(options: &ConnectionOptions) -> Result<Self, Error> {
+		Ok(Self {
+			codec: options.encoding.codec(),
+			compressor: TransportCompressor::new(options.compression)?,
+		})
+	}
+
+	/// Prepare a message produced by the gateway for sending it to the client.
+	/// Text messages hold JSON payloads, which are encoded and compressed.
+	/// Other messages are sent as-is.
+	pub fn outbound(&mut self, message: Message) -> Result<Message, Error> {
+		let Message::Text(json) = message else {
+			return Ok(message);
+		};
+		let encoded = self.codec.encode_json(json.as_str())?;
+		let Some(compressor) = self.compressor.as_mut() else {
+			return Ok(encoded);
+		};
+		Ok(Message::Binary(compressor.compress(&encoded.into_data())?.into()))
+	}
+
+	/// The [Codec] of this connection.
+	pub fn codec(&self) -> Arc<dyn Codec> {
+		self.codec.clone()
+	}
+}
+
+/// Turn a message received from the client into a text message holding a JSON
+/// payload, using the `codec` of the connection. Control messages are passed
+/// through as-is.
+pub fn decode_inbound(codec: &dyn Codec, message: Message) -> Result<Message, Error> {
+	match message {
+		Message::Text(_) | Message::Binary(_) => {
+			Ok(Message::Text(codec.decode_to_json(&message)?.into()))
+		}
+		_ => Ok(message),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use flate2::{Decompress, FlushDecompress};
+
+	use super::*;
+
+	#[test]
+	fn parses_connection_options() {
+		assert_eq!(ConnectionOptions::from_query(None).unwrap(), ConnectionOptions::default());
+		let options =
+			ConnectionOptions::from_query(Some("v=10&encoding=json&compress=zstd-stream&foo=bar"))
+				.unwrap();
+		assert_eq!(options.version, 10);
+		assert_eq!(options.encoding, GatewayEncoding::Json);
+		assert_eq!(options.compression, GatewayCompression::ZstdStream);
+
+		assert!(matches!(
+			ConnectionOptions::from_query(Some("v=42")),
+			Err(GatewayError::InvalidApiVersion(_))
+		));
+		assert!(matches!(
+			ConnectionOptions::from_query(Some("compress=gzip")),
+			Err(GatewayError::InvalidConnectionParameter(_))
+		));
+	}
+
+	#[test]
+	fn zlib_stream_shares_one_context() {
+		let mut compressor =
+			TransportCompressor::new(GatewayCompression::ZlibStream).unwrap().unwrap();
+		let mut decompress = Decompress::new(true);
+		for payload in [r#"{"op":10}"#, r#"{"op":11}"#] {
+			let compressed = compressor.compress(payload.as_bytes()).unwrap();
+			assert!(compressed.ends_with(&[0x00, 0x00, 0xff, 0xff]));
+			let mut output = Vec::with_capacity(64);
+			decompress.decompress_vec(&compressed, &mut output, FlushDecompress::Sync).unwrap();
+			assert_eq!(output, payload.as_bytes());
+		}
+	}
+
+	#[test]
+	fn zstd_stream_payloads_are_decodable_one_by_one() {
+		let mut compressor =
+			TransportCompressor::new(GatewayCompression::ZstdStream).unwrap().unwrap();
+		let mut decoder = zstd::stream::raw::Decoder::new().unwrap();
+		for payload in [r#"{"op":10}"#, r#"{"op":11}"#] {
+			let compressed = compressor.compress(payload.as_bytes()).unwrap();
+			let mut output = vec![0u8; 64];
+			let status = zstd::stream::raw::Operation::run_on_buffers(
+				&mut decoder,
+				&compressed,
+				&mut output,
+			)
+			.unwrap();
+			assert_eq!(&output[..status.bytes_written], payload.as_bytes());
+		}
+	}
+
+	#[test]
+	fn etf_connections_are_transcoded_both_ways() {
+		let options = ConnectionOptions::from_query(Some("encoding=etf")).unwrap();
+		let mut transport = Transport::new(&options).unwrap();
+		let outbound = transport.outbound(Message::Text(r#"{"op":11}"#.into())).unwrap();
+		assert!(matches!(outbound, Message::Binary(_)));
+		let inbound = decode_inbound(transport.codec().as_ref(), outbound).unwrap();
+		assert_eq!(inbound, Message::Text(r#"{"op":11}"#.into()));
+		assert_eq!(
+			transport.outbound(Message::Ping(Vec::new().into())).unwrap(),
+			Message::Ping(Vec::new().into())
+		);
+	}
+}
@@ -39,15 +39,19 @@ pub mod entities;
 pub mod errors;
 pub mod events;
 pub mod gateway;
+pub mod mail;
+pub mod shutdown;
+pub mod startup;
+pub mod tls;
 pub mod util;
 
 pub type SharedEventPublisher = Arc<RwLock<Publisher<Event>>>;
 pub type EventPublisherMap = HashMap<Snowflake, SharedEventPublisher>;
 pub type SharedEventPublisherMap = Arc<RwLock<EventPublisherMap>>;
 pub type WebSocketReceive =
-	futures::stream::SplitStream<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>>;
+	futures::stream::SplitStream<tokio_tungstenite::WebSocketStream<tls::MaybeTlsStream>>;
 pub type WebSocketSend = futures::stream::SplitSink<
-	tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
+	tokio_tungstenite::WebSocketStream<tls::MaybeTlsStream>,
 	tokio_tungstenite::tungstenite::Message,
 >;
 
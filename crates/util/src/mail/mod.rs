@@ -0,0 +1,134 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Emails sent to users, e.g. to verify their address or to reset their
+//! password.
+//!
+//! Emails are handed to a [Mailer]: [SmtpMailer] delivers them, while
+//! [SinkMailer] only records them, for development and tests.
+
+use std::{io::Write, ops::Deref, path::PathBuf, sync::Arc};
+
+use futures::future::BoxFuture;
+
+use crate::{configuration::SymfoniaConfiguration, entities::Config, errors::Error};
+
+pub mod smtp;
+
+pub use smtp::SmtpMailer;
+
+/// A plain text email.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mail {
+	pub to: String,
+	pub subject: String,
+	pub body: String,
+}
+
+impl Mail {
+	/// The email asking `username` to confirm that they own `address`.
+	pub fn verification(address: &str, username: &str, link: &str) -> Self {
+		Self {
+			to: address.to_string(),
+			subject: "Verify your email address".to_string(),
+			body: format!(
+				"Hi {username},\n\nplease confirm that this is your email address by opening\n\n{link}\n\nIf you did not create an account, you can ignore this email.\n"
+			),
+		}
+	}
+
+	/// The email allowing `username` to set a new password.
+	pub fn password_reset(address: &str, username: &str, link: &str) -> Self {
+		Self {
+			to: address.to_string(),
+			subject: "Reset your password".to_string(),
+			body: format!(
+				"Hi {username},\n\nyou can choose a new password within the next hour by opening\n\n{link}\n\nIf you did not ask to reset your password, you can ignore this email.\n"
+			),
+		}
+	}
+}
+
+/// A way of getting [Mail]s to their recipients.
+pub trait Mailer: Send + Sync {
+	/// Send `mail` to its recipient.
+	fn send(&self, mail: Mail) -> BoxFuture<'_, Result<(), Error>>;
+}
+
+/// The [Mailer] of this node. Defaults to a [SinkMailer] which only logs.
+#[derive(Clone)]
+pub struct SharedMailer(Arc<dyn Mailer>);
+
+impl SharedMailer {
+	pub fn new(mailer: impl Mailer + 'static) -> Self {
+		Self(Arc::new(mailer))
+	}
+
+	/// The [Mailer] for the `email_provider` of `cfg`: An [SmtpMailer] for
+	/// `smtp`, or a [SinkMailer] for the `sink` of `[general.mail]` if no
+	/// provider is configured. Other providers are not supported.
+	pub fn from_config(cfg: &Config) -> Result<Self, Error> {
+		let mail = &SymfoniaConfiguration::get().general.mail;
+		match cfg.email.provider.as_deref() {
+			Some("smtp") => {
+				let smtp = &cfg.email.smtp;
+				let host = smtp.host.as_ref().ok_or_else(|| {
+					Error::Custom("The smtp email provider requires email_smtp_host".to_string())
+				})?;
+				let credentials = smtp.username.clone().zip(smtp.password.clone());
+				Ok(Self::new(SmtpMailer::new(
+					host,
+					smtp.port,
+					smtp.secure,
+					credentials,
+					&mail.from,
+				)?))
+			}
+			Some(provider) => Err(Error::Custom(format!("Unsupported email provider {provider}"))),
+			None => {
+				log::warn!(target: "symfonia::mail", "No email provider is configured, emails are not sent");
+				Ok(Self::new(SinkMailer::new(mail.sink.clone())))
+			}
+		}
+	}
+}
+
+impl Default for SharedMailer {
+	fn default() -> Self {
+		Self::new(SinkMailer::new(None))
+	}
+}
+
+impl Deref for SharedMailer {
+	type Target = dyn Mailer;
+
+	fn deref(&self) -> &Self::Target {
+		self.0.as_ref()
+	}
+}
+
+/// A [Mailer] which logs emails instead of sending them, and appends them to
+/// a file if it has one.
+pub struct SinkMailer {
+	path: Option<PathBuf>,
+}
+
+impl SinkMailer {
+	pub fn new(path: Option<PathBuf>) -> Self {
+		Self { path }
+	}
+}
+
+impl Mailer for SinkMailer {
+	fn send(&self, mail: Mail) -> BoxFuture<'_, Result<(), Error>> {
+		Box::pin(async move {
+			log::info!(target: "symfonia::mail", "Not sending \"{}\" to {}", mail.subject, mail.to);
+			if let Some(path) = &self.path {
+				let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+				write!(file, "To: {}\nSubject: {}\n\n{}\n", mail.to, mail.subject, mail.body)?;
+			}
+			Ok(())
+		})
+	}
+}
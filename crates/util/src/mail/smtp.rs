@@ -0,0 +1,159 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use futures::future::BoxFuture;
+use lettre::{
+	AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+	message::{Mailbox, header::ContentType},
+	transport::smtp::{
+		authentication::Credentials,
+		client::{Tls, TlsParameters},
+	},
+};
+
+use super::{Mail, Mailer};
+use crate::{
+	errors::{Error, UserError},
+	tls,
+};
+
+/// A [Mailer] delivering emails to an SMTP server.
+pub struct SmtpMailer {
+	transport: AsyncSmtpTransport<Tokio1Executor>,
+	from: Mailbox,
+}
+
+impl SmtpMailer {
+	/// Send through `host`, authenticating with `credentials` if given. If
+	/// `secure` is set, the connection uses TLS from the start, which is what
+	/// port 465 expects. Otherwise it is upgraded with `STARTTLS` if the server
+	/// offers it.
+	pub fn new(
+		host: &str,
+		port: Option<u16>,
+		secure: bool,
+		credentials: Option<(String, String)>,
+		from: &str,
+	) -> Result<Self, Error> {
+		tls::install_crypto_provider();
+		let mut builder = if secure {
+			AsyncSmtpTransport::<Tokio1Executor>::relay(host)?
+		} else {
+			AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+				.tls(Tls::Opportunistic(TlsParameters::new(host.to_string())?))
+		};
+		if let Some(port) = port {
+			builder = builder.port(port);
+		}
+		if let Some((username, password)) = credentials {
+			builder = builder.credentials(Credentials::new(username, password));
+		}
+		let from = from
+			.parse()
+			.map_err(|e| Error::Custom(format!("Invalid sender address {from}: {e}")))?;
+		Ok(Self { transport: builder.build(), from })
+	}
+}
+
+impl Mailer for SmtpMailer {
+	fn send(&self, mail: Mail) -> BoxFuture<'_, Result<(), Error>> {
+		Box::pin(async move {
+			let to: Mailbox = mail.to.parse().map_err(|_| UserError::InvalidEmail)?;
+			let message = Message::builder()
+				.from(self.from.clone())
+				.to(to)
+				.subject(mail.subject)
+				.header(ContentType::TEXT_PLAIN)
+				.body(mail.body)
+				.map_err(|e| Error::Custom(format!("Failed to build email: {e}")))?;
+			self.transport.send(message).await?;
+			Ok(())
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::{
+		io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+		net::TcpListener,
+	};
+
+	use super::*;
+
+	/// Accept a single SMTP session on `listener`, acknowledging every command,
+	/// and return the envelope recipients and the message.
+	async fn smtp_stand_in(listener: TcpListener) -> (Vec<String>, String) {
+		let (stream, _) = listener.accept().await.unwrap();
+		let (reader, mut writer) = stream.into_split();
+		let mut lines = BufReader::new(reader).lines();
+		writer.write_all(b"220 symfonia.test ESMTP\r\n").await.unwrap();
+
+		let mut recipients = Vec::new();
+		let mut message = String::new();
+		let mut in_data = false;
+		while let Some(line) = lines.next_line().await.unwrap() {
+			if in_data {
+				if line == "." {
+					in_data = false;
+					writer.write_all(b"250 Queued\r\n").await.unwrap();
+				} else {
+					message.push_str(&line);
+					message.push('\n');
+				}
+				continue;
+			}
+			let command = line.split([' ', ':']).next().unwrap_or_default().to_uppercase();
+			let reply: &[u8] = match command.as_str() {
+				"EHLO" | "HELO" => b"250 symfonia.test\r\n",
+				"RCPT" => {
+					recipients.push(line.clone());
+					b"250 OK\r\n"
+				}
+				"DATA" => {
+					in_data = true;
+					b"354 End data with <CR><LF>.<CR><LF>\r\n"
+				}
+				"QUIT" => {
+					writer.write_all(b"221 Bye\r\n").await.unwrap();
+					break;
+				}
+				_ => b"250 OK\r\n",
+			};
+			writer.write_all(reply).await.unwrap();
+		}
+		(recipients, message)
+	}
+
+	#[tokio::test]
+	async fn delivers_to_the_smtp_server() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let server = tokio::spawn(smtp_stand_in(listener));
+
+		let mailer = SmtpMailer::new(
+			"127.0.0.1",
+			Some(port),
+			false,
+			None,
+			"Symfonia <noreply@symfonia.test>",
+		)
+		.unwrap();
+		let mail = Mail::verification("user@symfonia.test", "user", "https://symfonia.test/verify");
+		mailer.send(mail).await.unwrap();
+
+		let (recipients, message) = server.await.unwrap();
+		assert_eq!(recipients, vec!["RCPT TO:<user@symfonia.test>".to_string()]);
+		assert!(message.contains("Subject: Verify your email address"));
+		assert!(message.contains("https://symfonia.test/verify"));
+	}
+
+	#[tokio::test]
+	async fn rejects_invalid_recipients() {
+		let mailer =
+			SmtpMailer::new("127.0.0.1", Some(1), false, None, "noreply@symfonia.test").unwrap();
+		let mail = Mail::verification("not an address", "user", "https://symfonia.test/verify");
+		assert!(matches!(mailer.send(mail).await, Err(Error::User(UserError::InvalidEmail))));
+	}
+}
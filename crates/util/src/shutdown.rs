@@ -0,0 +1,147 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Orderly shutdown of the components running in a process.
+//!
+//! Once the process receives a termination [signal], the [Shutdown] is
+//! triggered: The API stops accepting requests and drains the ones in flight,
+//! and the gateway asks its clients to reconnect, saving their sessions so that
+//! they can be resumed on another node. Both are given [timeout] to do so.
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::configuration::SymfoniaConfiguration;
+
+/// Shared by everything which has to wind down when the process shuts down.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+	sender: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+	fn default() -> Self {
+		Self { sender: Arc::new(watch::channel(false).0) }
+	}
+}
+
+impl Shutdown {
+	/// Start shutting down.
+	pub fn trigger(&self) {
+		self.sender.send_replace(true);
+	}
+
+	/// Whether shutting down has been started.
+	pub fn is_triggered(&self) -> bool {
+		*self.sender.borrow()
+	}
+
+	/// Completes once shutting down has been started.
+	pub async fn triggered(&self) {
+		let mut receiver = self.sender.subscribe();
+		// The sender lives as long as `self`, so this cannot fail.
+		let _ = receiver.wait_for(|triggered| *triggered).await;
+	}
+}
+
+/// How long the components are given to shut down, from the
+/// `shutdown_timeout_secs` of the [SymfoniaConfiguration].
+pub fn timeout() -> Duration {
+	Duration::from_secs(SymfoniaConfiguration::get().general.shutdown_timeout_secs)
+}
+
+/// Run until the process receives a termination [signal], or one of the
+/// `components` stops on its own. Then trigger `shutdown`, and give the
+/// remaining components [timeout] to stop.
+pub async fn run_until_signal(shutdown: &Shutdown, mut components: Vec<JoinHandle<()>>) {
+	if components.is_empty() {
+		return;
+	}
+	tokio::select! {
+		_ = signal() => {
+			log::info!(target: "symfonia", "Received a termination signal, shutting down");
+		}
+		_ = futures::future::select_all(components.iter_mut()) => {
+			log::error!(target: "symfonia", "A component stopped unexpectedly, shutting down");
+		}
+	}
+	shutdown.trigger();
+	// Stopped components must not be awaited again.
+	components.retain(|component| !component.is_finished());
+	let timeout = timeout();
+	if tokio::time::timeout(timeout, futures::future::join_all(components)).await.is_err() {
+		log::warn!(target: "symfonia", "Components did not stop within {timeout:?}, exiting regardless");
+	} else {
+		log::info!(target: "symfonia", "Shut down gracefully");
+	}
+}
+
+/// Completes when the operating system asks the process to terminate.
+///
+/// `SIGHUP` is not among these signals, as it is used for reloading TLS
+/// certificates, see [crate::tls].
+pub async fn signal() {
+	#[cfg(unix)]
+	{
+		use tokio::signal::unix::{SignalKind, signal};
+		let mut sig_interrupt =
+			signal(SignalKind::interrupt()).expect("Failed to listen to SIGINT");
+		let mut sig_quit = signal(SignalKind::quit()).expect("Failed to listen to SIGQUIT");
+		let mut sig_terminate =
+			signal(SignalKind::terminate()).expect("Failed to listen to SIGTERM");
+
+		tokio::select! {
+			_ = sig_interrupt.recv() => (),
+			_ = sig_quit.recv() => (),
+			_ = sig_terminate.recv() => (),
+		}
+	}
+
+	#[cfg(windows)]
+	{
+		use tokio::signal::windows::{ctrl_break, ctrl_close, ctrl_logoff, ctrl_shutdown};
+		let mut sig_break = ctrl_break().expect("Failed to listen to CTRL-BREAK");
+		let mut sig_close = ctrl_close().expect("Failed to listen to CTRL-CLOSE");
+		let mut sig_logoff = ctrl_logoff().expect("Failed to listen to CTRL-LOGOFF");
+		let mut sig_shutdown = ctrl_shutdown().expect("Failed to listen to CTRL-SHUTDOWN");
+		let ctrl_c = tokio::signal::ctrl_c();
+
+		tokio::select! {
+			_ = sig_break.recv() => (),
+			event = ctrl_c => event.expect("Failed to listen to CTRL-C"),
+			_ = sig_close.recv() => (),
+			_ = sig_logoff.recv() => (),
+			_ = sig_shutdown.recv() => (),
+		}
+	}
+
+	#[cfg(not(any(unix, windows)))]
+	{
+		tokio::signal::ctrl_c().await.expect("Failed to listen to CTRL-C");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn triggering_wakes_all_waiters() {
+		let shutdown = Shutdown::default();
+		let waiter = tokio::spawn({
+			let shutdown = shutdown.clone();
+			async move { shutdown.triggered().await }
+		});
+		assert!(!shutdown.is_triggered());
+		shutdown.trigger();
+		tokio::time::timeout(Duration::from_secs(1), waiter)
+			.await
+			.expect("The waiter was not woken up")
+			.unwrap();
+		// Waiting after the fact completes immediately
+		shutdown.triggered().await;
+		assert!(shutdown.is_triggered());
+	}
+}
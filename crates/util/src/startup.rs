@@ -0,0 +1,254 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Startup shared by the `symfonia` binary, which runs all enabled components
+//! in one process, and the `symfonia-api` and `symfonia-gateway` binaries,
+//! which run a single component each.
+
+use std::path::PathBuf;
+
+use log::LevelFilter;
+use log4rs::{
+	append::{
+		console::{ConsoleAppender, Target},
+		rolling_file::{
+			RollingFileAppender,
+			policy::compound::{
+				CompoundPolicy, roll::delete::DeleteRoller, trigger::size::SizeTrigger,
+			},
+		},
+	},
+	config::{Appender, Logger, Root},
+	encode::pattern::PatternEncoder,
+};
+use sqlx::{
+	PgPool,
+	postgres::{PgConnectOptions, PgPoolOptions, PgSslMode},
+};
+
+use crate::{
+	LogFilter,
+	configuration::{
+		DatabaseConfigurationOverrides, EventBusKind, LogLevel, SymfoniaConfiguration, TlsConfig,
+	},
+	database::Connection,
+	errors::Error,
+	gateway::{
+		ConnectedUsers,
+		event_bus::{PgEventBus, SharedEventBus},
+		role_user_map::RECONCILIATION_INTERVAL,
+	},
+};
+
+/// Command line arguments accepted by all symfonia binaries.
+#[derive(Debug, clap::Parser, Default)]
+pub struct CliArgs {
+	#[arg(short, long)]
+	/// Path to the symfonia `TOML` configuration file. Will assume
+	/// "./symfonia.toml", if not specified.
+	pub config: Option<PathBuf>,
+}
+
+impl CliArgs {
+	/// Parse the arguments of this process, falling back to the defaults if
+	/// they are invalid.
+	pub fn parse_or_default() -> Self {
+		<Self as clap::Parser>::try_parse().unwrap_or_default()
+	}
+
+	/// Initialize the [SymfoniaConfiguration] from the configuration file.
+	pub fn init_configuration(&self) {
+		SymfoniaConfiguration::init(
+			self.config.as_ref().unwrap_or(&PathBuf::from("./symfonia.toml")),
+		);
+	}
+}
+
+/// A part of symfonia which can be run on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+	Api,
+	Gateway,
+}
+
+impl Component {
+	/// The `application_name` of database connections made by this component.
+	pub fn application_name(self) -> &'static str {
+		match self {
+			Component::Api => "symfonia-api",
+			Component::Gateway => "symfonia-gateway",
+		}
+	}
+
+	/// The settings of this component's database pool, overriding
+	/// `[general.database]`.
+	pub fn database_overrides(self) -> &'static DatabaseConfigurationOverrides {
+		match self {
+			Component::Api => &SymfoniaConfiguration::get().api.cfg.database,
+			Component::Gateway => &SymfoniaConfiguration::get().gateway.cfg.database,
+		}
+	}
+
+	/// Whether this component is enabled in the [SymfoniaConfiguration].
+	pub fn enabled(self) -> bool {
+		match self {
+			Component::Api => SymfoniaConfiguration::get().api.cfg.enabled,
+			Component::Gateway => SymfoniaConfiguration::get().gateway.cfg.enabled,
+		}
+	}
+}
+
+impl From<&LogLevel> for LevelFilter {
+	fn from(value: &LogLevel) -> Self {
+		match value {
+			LogLevel::Error => LevelFilter::Error,
+			LogLevel::Warn => LevelFilter::Warn,
+			LogLevel::Info => LevelFilter::Info,
+			LogLevel::Debug => LevelFilter::Debug,
+			LogLevel::Trace => LevelFilter::Trace,
+		}
+	}
+}
+
+fn rolling_file_appender(path: &str) -> Result<RollingFileAppender, Error> {
+	RollingFileAppender::builder()
+		.encoder(Box::new(PatternEncoder::new("{d} {l} - {m}{n}")))
+		.build(
+			path,
+			Box::new(CompoundPolicy::new(
+				Box::new(SizeTrigger::new(1024 * 1024 * 30)),
+				Box::new(DeleteRoller::new()),
+			)),
+		)
+		.map_err(|e| Error::Custom(format!("Failed to open {path}: {e}")))
+}
+
+/// Log to stdout, and the logs of each component to its own file in `log/`,
+/// at the `log_level` of the [SymfoniaConfiguration].
+pub fn init_logging() -> Result<log4rs::Handle, Error> {
+	let loglevel = LevelFilter::from(&SymfoniaConfiguration::get().general.log_level);
+	let stdout = ConsoleAppender::builder()
+		.target(Target::Stdout)
+		.encoder(Box::new(PatternEncoder::new(
+			"{d(%Y-%m-%d %H:%M:%S)} | {h({l:<6.6})} | {t:<35} | {m}{n}",
+		)))
+		.build();
+
+	let mut config = log4rs::Config::builder().appender(
+		Appender::builder().filter(Box::new(LogFilter)).build("stdout", Box::new(stdout)),
+	);
+	for component in ["api", "cdn", "gateway"] {
+		config = config
+			.appender(Appender::builder().filter(Box::new(LogFilter)).build(
+				component,
+				Box::new(rolling_file_appender(&format!("log/{component}.log"))?),
+			))
+			.logger(
+				Logger::builder()
+					.appender(component)
+					.build(format!("symfonia::{component}"), loglevel),
+			);
+	}
+	let config = config
+		.build(Root::builder().appender("stdout").build(loglevel))
+		.map_err(|e| Error::Custom(format!("Invalid logging configuration: {e}")))?;
+
+	let handle = log4rs::init_config(config)
+		.map_err(|e| Error::Custom(format!("Failed to initialize logging: {e}")))?;
+
+	if matches!(loglevel, LevelFilter::Debug | LevelFilter::Trace) {
+		log::warn!(target: "symfonia", "⚠️⚠️⚠️ WARNING ⚠️⚠️⚠️");
+		log::warn!(target: "symfonia", "⚠️⚠️⚠️ WARNING ⚠️⚠️⚠️");
+		log::warn!(target: "symfonia", "⚠️⚠️⚠️ WARNING ⚠️⚠️⚠️");
+		log::warn!(target: "symfonia", r#"WARNING: Running with the "Debug" or "Trace" log levels will leak sensitive information to the logs. Please set `log_level` in the `[general]` section of your config to "Info" or lower if you are not currently debugging."#);
+		log::warn!(target: "symfonia", "⚠️⚠️⚠️ WARNING ⚠️⚠️⚠️");
+		log::warn!(target: "symfonia", "⚠️⚠️⚠️ WARNING ⚠️⚠️⚠️");
+		log::warn!(target: "symfonia", "⚠️⚠️⚠️ WARNING ⚠️⚠️⚠️");
+	}
+	Ok(handle)
+}
+
+/// Connect to the database of the [SymfoniaConfiguration] with a pool of
+/// `component`'s own, merging its overrides over `[general.database]`.
+pub async fn connect_database(component: Component) -> Result<Connection, Error> {
+	let config = SymfoniaConfiguration::get()
+		.general
+		.database_configuration
+		.with_overrides(component.database_overrides());
+	let mut pg_connect_options = PgConnectOptions::new()
+		.database(&config.database)
+		.application_name(component.application_name())
+		.host(&config.host)
+		.password(&config.password)
+		.port(config.port)
+		.ssl_mode(match config.tls {
+			TlsConfig::Disable => PgSslMode::Disable,
+			TlsConfig::Allow => PgSslMode::Allow,
+			TlsConfig::Prefer => PgSslMode::Prefer,
+			TlsConfig::Require => PgSslMode::Require,
+			TlsConfig::VerifyCa => PgSslMode::VerifyCa,
+			TlsConfig::VerifyFull => PgSslMode::VerifyFull,
+		})
+		.username(&config.username);
+	if let Some(statement_timeout) = config.statement_timeout {
+		pg_connect_options = pg_connect_options
+			.options([("statement_timeout", statement_timeout.as_millis().to_string())]);
+	}
+	let pool_options = PgPoolOptions::new()
+		.max_connections(config.max_connections)
+		.min_connections(config.min_connections)
+		.acquire_timeout(config.acquire_timeout);
+	log::info!(target: "symfonia::db", "Establishing database connection of {}: {config}", component.application_name());
+	Ok(Connection::new(pg_connect_options, pool_options).await?)
+}
+
+/// Run the migrations, and seed the config of a fresh database. Safe to run
+/// concurrently from several processes, as both the migrations and the
+/// seeding are done while holding an advisory lock.
+pub async fn prepare_database(db: &Connection) -> Result<(), Error> {
+	log::info!(target: "symfonia::db", "Running migrations");
+	sqlx::migrate!("./migrations").run(db.pool()).await?;
+
+	if db.seed_config().await? {
+		log::info!(target: "symfonia::db", "Fresh database detected. Seeded database with config data");
+	}
+	Ok(())
+}
+
+/// Create the [ConnectedUsers] of this process, publishing events to the
+/// event bus of the [SymfoniaConfiguration]. The Role->User map is loaded, and
+/// reconciled with the database in the background.
+///
+/// The Role->User map and the presences are replicated between processes over
+/// the event bus, see [ConnectedUsers::replicate_state]. Everything else about
+/// connected clients is local to this process.
+pub async fn connected_users(db: &PgPool) -> Result<ConnectedUsers, Error> {
+	let general = &SymfoniaConfiguration::get().general;
+	let event_bus = match general.event_bus {
+		EventBusKind::InProcess => SharedEventBus::default(),
+		EventBusKind::Postgres => {
+			SharedEventBus::new(PgEventBus::connect(db.to_owned(), general.node_id).await?)
+		}
+	};
+	let connected_users = ConnectedUsers::with_event_bus(event_bus);
+
+	log::debug!(target: "symfonia", "Initializing Role->User map...");
+	connected_users.init_role_user_map(db).await?;
+	log::trace!(target: "symfonia", "Role->User map initialized with {} entries", connected_users.role_user_map.lock().await.len());
+	tokio::spawn(connected_users.clone().replicate_state());
+	tokio::spawn(
+		connected_users
+			.clone()
+			.reconcile_role_user_map_periodically(db.to_owned(), RECONCILIATION_INTERVAL),
+	);
+	Ok(connected_users)
+}
+
+/// Warn if `component` is run on its own, but events are not shared with
+/// other processes.
+pub fn warn_if_events_stay_in_process(component: Component) {
+	if SymfoniaConfiguration::get().general.event_bus == EventBusKind::InProcess {
+		log::warn!(target: "symfonia", "{} is running on its own with `event_bus = \"in_process\"`. Events will not be exchanged with other processes; set `event_bus = \"postgres\"` in the `[general]` section of your config.", component.application_name());
+	}
+}
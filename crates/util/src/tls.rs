@@ -0,0 +1,319 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! TLS termination for the API and gateway listeners, using rustls.
+//!
+//! Certificates are read from PEM files, and read again whenever the process
+//! receives `SIGHUP`, so that renewed certificates are picked up without a
+//! restart.
+
+use std::{
+	io,
+	path::PathBuf,
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll},
+	time::Duration,
+};
+
+use futures::{Stream, StreamExt, stream};
+use tokio::{
+	io::{AsyncRead, AsyncWrite, ReadBuf},
+	net::TcpStream,
+};
+pub use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{
+	ServerConfig,
+	crypto::ring,
+	pki_types::{CertificateDer, PrivateKeyDer},
+};
+
+use crate::errors::Error;
+
+/// How long a client may take to complete the TLS handshake of a connection.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Install the process-wide rustls crypto provider. Must be called before any
+/// [ServerConfig] is built, as more than one provider is compiled in through
+/// the dependencies of symfonia. Calling it more than once has no effect.
+pub fn install_crypto_provider() {
+	let _ = ring::default_provider().install_default();
+}
+
+/// The PEM encoded certificate chain and private key of a listener.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsFiles {
+	pub certificate: PathBuf,
+	pub key: PathBuf,
+}
+
+impl TlsFiles {
+	/// Read the contents of the certificate and key files.
+	pub fn read(&self) -> Result<(Vec<u8>, Vec<u8>), Error> {
+		let certificate = std::fs::read(&self.certificate).map_err(|e| {
+			Error::Custom(format!("Failed to read {}: {e}", self.certificate.display()))
+		})?;
+		let key = std::fs::read(&self.key)
+			.map_err(|e| Error::Custom(format!("Failed to read {}: {e}", self.key.display())))?;
+		Ok((certificate, key))
+	}
+
+	/// Read the certificate chain and key, and build a [ServerConfig] serving
+	/// them.
+	pub fn server_config(&self) -> Result<Arc<ServerConfig>, Error> {
+		let (certificate, key) = self.read()?;
+		let chain = rustls_pemfile::certs(&mut certificate.as_slice())
+			.collect::<Result<Vec<CertificateDer<'static>>, _>>()?;
+		if chain.is_empty() {
+			return Err(Error::Custom(format!(
+				"No certificate found in {}",
+				self.certificate.display()
+			)));
+		}
+		let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut key.as_slice())?
+			.ok_or_else(|| {
+				Error::Custom(format!("No private key found in {}", self.key.display()))
+			})?;
+		let config = ServerConfig::builder().with_no_client_auth().with_single_cert(chain, key)?;
+		Ok(Arc::new(config))
+	}
+
+	/// Load the files with `load`, and again whenever the process receives
+	/// `SIGHUP`. The stream yields the initial result first; reloads which fail
+	/// are logged and skipped, so that the previous certificate stays in use.
+	pub fn reloads<T, F>(self, load: F) -> Result<impl Stream<Item = T> + Send + 'static, Error>
+	where
+		T: Send + 'static,
+		F: Fn(&TlsFiles) -> Result<T, Error> + Send + 'static,
+	{
+		let initial = load(&self)?;
+		let hangups = hangups()?;
+		let reloads = hangups.filter_map(move |()| {
+			let reloaded = match load(&self) {
+				Ok(reloaded) => {
+					log::info!(target: "symfonia::tls", "Reloaded TLS certificate {}", self.certificate.display());
+					Some(reloaded)
+				}
+				Err(e) => {
+					log::error!(target: "symfonia::tls", "Failed to reload TLS certificate {}, keeping the previous one: {e}", self.certificate.display());
+					None
+				}
+			};
+			async move { reloaded }
+		});
+		Ok(stream::once(async move { initial }).chain(reloads))
+	}
+}
+
+/// Yields whenever the process receives `SIGHUP`.
+#[cfg(unix)]
+fn hangups() -> Result<impl Stream<Item = ()> + Send + 'static, Error> {
+	use tokio::signal::unix::{SignalKind, signal};
+
+	let signal = signal(SignalKind::hangup())?;
+	Ok(stream::unfold(
+		signal,
+		|mut signal| async move { signal.recv().await.map(|()| ((), signal)) },
+	))
+}
+
+/// There is no `SIGHUP` outside of unix; certificates are only read on
+/// startup.
+#[cfg(not(unix))]
+fn hangups() -> Result<impl Stream<Item = ()> + Send + 'static, Error> {
+	Ok(stream::pending())
+}
+
+/// Terminate TLS on `stream`, failing if the client does not complete the
+/// handshake within [HANDSHAKE_TIMEOUT].
+pub async fn accept(acceptor: &TlsAcceptor, stream: TcpStream) -> Result<MaybeTlsStream, Error> {
+	accept_within(acceptor, stream, HANDSHAKE_TIMEOUT).await
+}
+
+async fn accept_within(
+	acceptor: &TlsAcceptor,
+	stream: TcpStream,
+	timeout: Duration,
+) -> Result<MaybeTlsStream, Error> {
+	let stream = tokio::time::timeout(timeout, acceptor.accept(stream))
+		.await
+		.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+	Ok(MaybeTlsStream::Tls(Box::new(stream)))
+}
+
+/// A connection accepted by a listener, which is either plain or TLS
+/// terminated.
+pub enum MaybeTlsStream {
+	Plain(TcpStream),
+	Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for MaybeTlsStream {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+			MaybeTlsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+		}
+	}
+}
+
+impl AsyncWrite for MaybeTlsStream {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		match self.get_mut() {
+			MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+			MaybeTlsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+			MaybeTlsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+			MaybeTlsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+	use tokio_rustls::{
+		TlsConnector,
+		rustls::{ClientConfig, RootCertStore, pki_types::ServerName},
+	};
+
+	use super::*;
+
+	/// A freshly generated self-signed certificate for `localhost`, written to
+	/// the temporary directory. The files are removed when it is dropped.
+	struct SelfSigned {
+		files: TlsFiles,
+		certificate: CertificateDer<'static>,
+	}
+
+	impl SelfSigned {
+		fn new(name: &str) -> Self {
+			let dir = std::env::temp_dir();
+			let files = TlsFiles {
+				certificate: dir.join(format!("symfonia-{name}-{}.crt", std::process::id())),
+				key: dir.join(format!("symfonia-{name}-{}.key", std::process::id())),
+			};
+			let mut self_signed = Self { files, certificate: CertificateDer::from(vec![]) };
+			self_signed.renew();
+			self_signed
+		}
+
+		/// Replace the files with a newly generated certificate.
+		fn renew(&mut self) {
+			let generated =
+				rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+			std::fs::write(&self.files.certificate, generated.cert.pem()).unwrap();
+			std::fs::write(&self.files.key, generated.key_pair.serialize_pem()).unwrap();
+			self.certificate = generated.cert.der().clone();
+		}
+	}
+
+	impl Drop for SelfSigned {
+		fn drop(&mut self) {
+			let _ = std::fs::remove_file(&self.files.certificate);
+			let _ = std::fs::remove_file(&self.files.key);
+		}
+	}
+
+	#[tokio::test]
+	async fn serves_the_certificate_of_the_files() {
+		install_crypto_provider();
+		let self_signed = SelfSigned::new("serves");
+		let acceptor = TlsAcceptor::from(self_signed.files.server_config().unwrap());
+
+		let mut roots = RootCertStore::empty();
+		roots.add(self_signed.certificate.clone()).unwrap();
+		let connector = TlsConnector::from(Arc::new(
+			ClientConfig::builder().with_root_certificates(roots).with_no_client_auth(),
+		));
+
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		let server = tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+			let mut stream = accept(&acceptor, stream).await.unwrap();
+			stream.write_all(b"hello").await.unwrap();
+			stream.shutdown().await.unwrap();
+		});
+
+		let stream = TcpStream::connect(address).await.unwrap();
+		let mut stream = connector
+			.connect(ServerName::try_from("localhost").unwrap(), stream)
+			.await
+			.expect("The client did not accept the certificate");
+		let mut received = String::new();
+		stream.read_to_string(&mut received).await.unwrap();
+		assert_eq!(received, "hello");
+		server.await.unwrap();
+	}
+
+	#[test]
+	fn invalid_files_are_rejected() {
+		install_crypto_provider();
+		let self_signed = SelfSigned::new("invalid");
+		let files = &self_signed.files;
+		let swapped = TlsFiles { certificate: files.key.clone(), key: files.certificate.clone() };
+		assert!(swapped.server_config().is_err());
+		let missing = TlsFiles { key: files.key.with_extension("missing"), ..files.clone() };
+		assert!(missing.server_config().is_err());
+	}
+
+	#[tokio::test]
+	async fn stalled_handshakes_time_out() {
+		install_crypto_provider();
+		let self_signed = SelfSigned::new("stalled");
+		let acceptor = TlsAcceptor::from(self_signed.files.server_config().unwrap());
+
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		// The client connects, but never sends a ClientHello
+		let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+		let (stream, _) = listener.accept().await.unwrap();
+		let accepted = accept_within(&acceptor, stream, Duration::from_millis(100)).await;
+		assert!(matches!(accepted, Err(Error::IO(e)) if e.kind() == io::ErrorKind::TimedOut));
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn sighup_reloads_the_certificate() {
+		let mut self_signed = SelfSigned::new("reload");
+		let mut reloads = self_signed
+			.files
+			.clone()
+			.reloads(|files| {
+				let (certificate, _) = files.read()?;
+				Ok(rustls_pemfile::certs(&mut certificate.as_slice()).next().unwrap()?)
+			})
+			.unwrap()
+			.boxed();
+		assert_eq!(reloads.next().await.unwrap(), self_signed.certificate);
+
+		self_signed.renew();
+		// SAFETY: the signal is handled by the stream of `reloads`
+		assert_eq!(unsafe { libc::raise(libc::SIGHUP) }, 0);
+		let reloaded = tokio::time::timeout(Duration::from_secs(5), reloads.next())
+			.await
+			.expect("The certificate was not reloaded")
+			.unwrap();
+		assert_eq!(reloaded, self_signed.certificate);
+	}
+}
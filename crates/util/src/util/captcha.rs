@@ -0,0 +1,67 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Verification of captcha solutions with the captcha service configured for
+//! the instance.
+
+use chorus::types::{APIError, AuthError, CaptchaService};
+use serde::Deserialize;
+
+use crate::{entities::Config, errors::Error};
+
+/// The part of the response of a `siteverify` endpoint we care about. hCaptcha
+/// and reCAPTCHA respond alike.
+#[derive(Debug, Deserialize)]
+struct SiteverifyResponse {
+	success: bool,
+}
+
+/// The endpoint of `service` which checks solutions.
+fn siteverify_url(service: &CaptchaService) -> &'static str {
+	match service {
+		CaptchaService::Recaptcha => "https://www.google.com/recaptcha/api/siteverify",
+		CaptchaService::HCaptcha => "https://api.hcaptcha.com/siteverify",
+	}
+}
+
+/// Check the solution `captcha_key` of a client with the captcha service of
+/// `cfg`. Fails with [AuthError::InvalidCaptcha] if there is no solution or
+/// the service rejects it.
+pub async fn verify_captcha(cfg: &Config, captcha_key: Option<&str>) -> Result<(), Error> {
+	let captcha = &cfg.security.captcha;
+	let key = captcha_key.ok_or(APIError::Auth(AuthError::InvalidCaptcha))?;
+	let secret = captcha
+		.secret
+		.as_deref()
+		.ok_or_else(|| Error::Custom("No captcha secret is configured".to_string()))?;
+
+	let mut form = vec![("secret", secret), ("response", key)];
+	if let Some(sitekey) = captcha.sitekey.as_deref() {
+		form.push(("sitekey", sitekey));
+	}
+	let response = reqwest::Client::new()
+		.post(siteverify_url(&captcha.service))
+		.form(&form)
+		.send()
+		.await?
+		.error_for_status()?
+		.bytes()
+		.await?;
+	let response: SiteverifyResponse = serde_json::from_slice(&response)?;
+	if !response.success {
+		return Err(APIError::Auth(AuthError::InvalidCaptcha).into());
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn missing_solutions_are_invalid() {
+		let err = verify_captcha(&Config::default(), None).await.unwrap_err();
+		assert!(matches!(err, Error::Chorus(APIError::Auth(AuthError::InvalidCaptcha))));
+	}
+}
@@ -0,0 +1,188 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Role hierarchy rules.
+//!
+//! A member can only manage roles which are positioned below their own
+//! highest role, and can only moderate (kick, ban, rename, ...) members whose
+//! highest role is below their own. The guild owner is exempt from these rules
+//! and can never be moderated by anyone else.
+
+use std::collections::HashMap;
+
+use chorus::types::Snowflake;
+use sqlx::{PgPool, QueryBuilder};
+
+use crate::{
+	entities::{Guild, Role},
+	errors::{Error, GuildError},
+};
+
+/// The standing of a guild member in the role hierarchy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemberRank {
+	pub user_id: Snowflake,
+	pub is_owner: bool,
+	/// Position of the member's highest role. Members who only have
+	/// `@everyone` are at position 0.
+	pub highest_position: u16,
+}
+
+impl MemberRank {
+	pub fn new(user_id: Snowflake, is_owner: bool, roles: &[Role]) -> Self {
+		let highest_position = roles.iter().map(|role| role.position.to_uint()).max().unwrap_or(0);
+		Self { user_id, is_owner, highest_position }
+	}
+
+	/// Loads the rank of `user_id` in `guild`. Roles of other guilds the user
+	/// is a member of are ignored.
+	pub async fn load(db: &PgPool, guild: &Guild, user_id: Snowflake) -> Result<Self, Error> {
+		let roles = Role::get_by_user(db, user_id)
+			.await?
+			.into_iter()
+			.filter(|role| role.guild_id == guild.id)
+			.collect::<Vec<_>>();
+		Ok(Self::new(user_id, guild.owner_id == Some(user_id), &roles))
+	}
+
+	/// Loads the ranks of `user_ids` in `guild` with one query, in the order of
+	/// `user_ids`. Users who are not members of `guild` are at position 0.
+	pub async fn load_many(
+		db: &PgPool,
+		guild: &Guild,
+		user_ids: &[Snowflake],
+	) -> Result<Vec<Self>, Error> {
+		if user_ids.is_empty() {
+			return Ok(Vec::new());
+		}
+		let mut builder = QueryBuilder::new(
+			"SELECT m.id, MAX(r.position) FROM members m JOIN member_roles mr ON mr.index = m.index JOIN roles r ON r.id = mr.role_id WHERE m.guild_id = ",
+		);
+		builder.push_bind(guild.id);
+		builder.push(" AND m.id IN (");
+		let mut separated = builder.separated(", ");
+		for user_id in user_ids {
+			separated.push_bind(*user_id);
+		}
+		separated.push_unseparated(") GROUP BY m.id");
+		let positions: HashMap<Snowflake, i32> =
+			builder.build_query_as().fetch_all(db).await?.into_iter().collect();
+
+		Ok(user_ids
+			.iter()
+			.map(|&user_id| Self {
+				user_id,
+				is_owner: guild.owner_id == Some(user_id),
+				highest_position: positions.get(&user_id).map_or(0, |&position| position as u16),
+			})
+			.collect())
+	}
+
+	/// The highest role position this member is allowed to act upon, if any.
+	///
+	/// For the guild owner, this is [u16::MAX].
+	pub fn manageable_below(&self) -> u16 {
+		if self.is_owner { u16::MAX } else { self.highest_position }
+	}
+
+	/// Whether this member may assign, remove, edit or delete a role at
+	/// `position`.
+	pub fn can_manage_role(&self, position: u16) -> bool {
+		self.is_owner || position < self.highest_position
+	}
+
+	/// Whether this member may kick or rename `target`. Members can always act
+	/// upon themselves, see [Self::can_ban] for banning.
+	pub fn can_moderate(&self, target: &MemberRank) -> bool {
+		if self.user_id == target.user_id || self.is_owner {
+			return true;
+		}
+		!target.is_owner && target.highest_position < self.highest_position
+	}
+
+	/// Whether this member may ban `target`. Unlike [Self::can_moderate],
+	/// nobody can ban themselves or the guild owner.
+	pub fn can_ban(&self, target: &MemberRank) -> bool {
+		self.user_id != target.user_id && !target.is_owner && self.can_moderate(target)
+	}
+
+	/// Fails with [GuildError::InsufficientPermissions] unless
+	/// [Self::can_manage_role] holds.
+	pub fn ensure_can_manage_role(&self, position: u16) -> Result<(), Error> {
+		if !self.can_manage_role(position) {
+			return Err(Error::Guild(GuildError::InsufficientPermissions));
+		}
+		Ok(())
+	}
+
+	/// Fails with [GuildError::InsufficientPermissions] unless
+	/// [Self::can_moderate] holds.
+	pub fn ensure_can_moderate(&self, target: &MemberRank) -> Result<(), Error> {
+		if !self.can_moderate(target) {
+			return Err(Error::Guild(GuildError::InsufficientPermissions));
+		}
+		Ok(())
+	}
+
+	/// Fails with [GuildError::InsufficientPermissions] unless [Self::can_ban]
+	/// holds.
+	pub fn ensure_can_ban(&self, target: &MemberRank) -> Result<(), Error> {
+		if !self.can_ban(target) {
+			return Err(Error::Guild(GuildError::InsufficientPermissions));
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rank(id: u64, is_owner: bool, highest_position: u16) -> MemberRank {
+		MemberRank { user_id: Snowflake(id), is_owner, highest_position }
+	}
+
+	#[test]
+	fn roles_at_or_above_own_top_role_cannot_be_managed() {
+		let moderator = rank(1, false, 5);
+		assert!(moderator.can_manage_role(4));
+		assert!(!moderator.can_manage_role(5));
+		assert!(!moderator.can_manage_role(6));
+		assert!(moderator.ensure_can_manage_role(5).is_err());
+	}
+
+	#[test]
+	fn members_cannot_moderate_equal_or_higher_members() {
+		let moderator = rank(1, false, 5);
+		assert!(moderator.can_moderate(&rank(2, false, 4)));
+		assert!(!moderator.can_moderate(&rank(2, false, 5)));
+		assert!(!moderator.can_moderate(&rank(2, false, 9)));
+		assert!(moderator.can_moderate(&moderator));
+		assert!(moderator.ensure_can_moderate(&rank(2, false, 5)).is_err());
+	}
+
+	#[test]
+	fn owner_is_exempt() {
+		let owner = rank(1, true, 0);
+		assert!(owner.can_manage_role(u16::MAX - 1));
+		assert!(owner.can_moderate(&rank(2, false, 100)));
+		assert_eq!(owner.manageable_below(), u16::MAX);
+
+		let admin = rank(2, false, 100);
+		assert!(!admin.can_moderate(&owner));
+	}
+
+	#[test]
+	fn members_cannot_ban_themselves_or_the_owner() {
+		let moderator = rank(1, false, 5);
+		assert!(moderator.can_ban(&rank(2, false, 4)));
+		assert!(!moderator.can_ban(&moderator));
+		assert!(moderator.ensure_can_ban(&moderator).is_err());
+
+		let owner = rank(3, true, 0);
+		assert!(!owner.can_ban(&owner));
+		assert!(owner.can_ban(&rank(2, false, 100)));
+		assert!(!moderator.can_ban(&owner));
+	}
+}
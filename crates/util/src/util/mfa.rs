@@ -0,0 +1,110 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Time-based one-time passwords (RFC 6238), as generated by authenticator
+//! apps, and the tickets which carry a login from the password step to the
+//! second factor.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::errors::{Error, UserError};
+
+/// How long a user has to enter their second factor after their password.
+pub const MFA_TICKET_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// The TOTP generator for the base32 encoded `secret`, using the parameters
+/// every common authenticator app defaults to: SHA-1, 6 digits and 30 second
+/// steps. The skew is handled by [verify_totp].
+fn totp(secret: &str) -> Result<TOTP, Error> {
+	let secret = Secret::Encoded(secret.to_uppercase())
+		.to_bytes()
+		.map_err(|_| UserError::InvalidMfaSecret)?;
+	// Authenticator apps commonly use 80 bit secrets, less than the 128 bits
+	// RFC 4226 recommends
+	Ok(TOTP::new_unchecked(Algorithm::SHA1, 6, 0, 30, secret))
+}
+
+/// Check that `secret` is a base32 encoded TOTP secret.
+pub fn validate_totp_secret(secret: &str) -> Result<(), Error> {
+	totp(secret).map(|_| ())
+}
+
+/// Check `code` against the base32 encoded `secret`, accepting the current
+/// time step and the ones before and after it. Returns the time step of
+/// `code` if it is valid and later than `last_step`, the step of the last
+/// code accepted for `secret`, so that every code is only accepted once.
+pub fn verify_totp(secret: &str, code: &str, last_step: Option<u64>) -> Result<Option<u64>, Error> {
+	let now = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_err(|e| Error::Custom(e.to_string()))?
+		.as_secs();
+	Ok(matching_step(&totp(secret)?, &code.replace(' ', ""), now, last_step))
+}
+
+/// The time step within one step of `time` which `code` belongs to, if it is
+/// later than `last_step`.
+fn matching_step(totp: &TOTP, code: &str, time: u64, last_step: Option<u64>) -> Option<u64> {
+	let current = time / totp.step;
+	(current.saturating_sub(1)..=current + 1)
+		.filter(|step| last_step.is_none_or(|last_step| *step > last_step))
+		.find(|step| totp.check(code, step * totp.step))
+}
+
+/// Generate a new MFA ticket, which expires after [MFA_TICKET_LIFETIME].
+pub fn generate_mfa_ticket() -> String {
+	let expires_at = chrono::Utc::now().timestamp() + MFA_TICKET_LIFETIME.as_secs() as i64;
+	format!("{}.{expires_at}", hex::encode(rand::thread_rng().r#gen::<[u8; 32]>()))
+}
+
+/// Whether `ticket` has not expired yet.
+pub fn mfa_ticket_is_current(ticket: &str) -> bool {
+	ticket
+		.rsplit_once('.')
+		.and_then(|(_, expires_at)| expires_at.parse::<i64>().ok())
+		.is_some_and(|expires_at| chrono::Utc::now().timestamp() < expires_at)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SECRET: &str = "JBSWY3DPEHPK3PXP";
+
+	#[test]
+	fn accepts_only_the_current_code() {
+		let current = totp(SECRET).unwrap().generate_current().unwrap();
+		assert!(verify_totp(SECRET, &current, None).unwrap().is_some());
+		let stale = totp(SECRET).unwrap().generate(0);
+		assert!(verify_totp(SECRET, &stale, None).unwrap().is_none());
+		assert!(verify_totp("not base32!", &current, None).is_err());
+	}
+
+	#[test]
+	fn codes_are_accepted_once() {
+		let totp = totp(SECRET).unwrap();
+		let time = 1_000_000 * totp.step;
+		let code = totp.generate(time);
+
+		let step = matching_step(&totp, &code, time, None).unwrap();
+		assert_eq!(step, 1_000_000);
+		assert_eq!(matching_step(&totp, &code, time, Some(step)), None);
+		// The code of the next step is still accepted after the current one
+		let next = totp.generate(time + totp.step);
+		assert_eq!(matching_step(&totp, &next, time, Some(step)), Some(step + 1));
+		// The code of the previous step is valid until a later code was accepted
+		let previous = totp.generate(time - totp.step);
+		assert_eq!(matching_step(&totp, &previous, time, None), Some(step - 1));
+		assert_eq!(matching_step(&totp, &previous, time, Some(step)), None);
+	}
+
+	#[test]
+	fn tickets_expire() {
+		assert!(mfa_ticket_is_current(&generate_mfa_ticket()));
+		assert!(!mfa_ticket_is_current("abcdef.0"));
+		assert!(!mfa_ticket_is_current("abcdef"));
+	}
+}
@@ -0,0 +1,438 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Resolution of a guild member's effective permissions.
+//!
+//! The algorithm follows the order used by Discord:
+//!
+//! 1. The guild owner always has every permission.
+//! 2. Start with the permissions of the `@everyone` role, then OR in the
+//!    permissions of every role the member has.
+//! 3. If the result contains `ADMINISTRATOR`, the member has every permission.
+//! 4. Apply channel overwrites: `@everyone` first, then all role overwrites of
+//!    the member combined, then the overwrite for the member itself. When a
+//!    channel belongs to a category, the category's overwrites are applied
+//!    before those of the channel.
+//! 5. A channel that cannot be viewed grants nothing, and a channel in which
+//!    messages cannot be sent does not grant the permissions that depend on
+//!    sending messages.
+
+use chorus::types::{PermissionFlags, PermissionOverwrite, PermissionOverwriteType, Snowflake};
+use sqlx::PgPool;
+
+use crate::{
+	entities::{Channel, Guild, Recipient, Role},
+	errors::{ChannelError, Error, GuildError},
+};
+
+/// Permissions every recipient of a DM or group DM channel has.
+pub const DM_PERMISSIONS: PermissionFlags = PermissionFlags::VIEW_CHANNEL
+	.union(PermissionFlags::SEND_MESSAGES)
+	.union(PermissionFlags::SEND_TTS_MESSAGES)
+	.union(PermissionFlags::EMBED_LINKS)
+	.union(PermissionFlags::ATTACH_FILES)
+	.union(PermissionFlags::READ_MESSAGE_HISTORY)
+	.union(PermissionFlags::MENTION_EVERYONE)
+	.union(PermissionFlags::USE_EXTERNAL_EMOJIS)
+	.union(PermissionFlags::ADD_REACTIONS);
+
+/// Computes the guild-level permissions of a member from the permissions of
+/// the `@everyone` role and the permissions of the member's other roles.
+pub fn compute_base_permissions(
+	is_owner: bool,
+	everyone: PermissionFlags,
+	roles: impl IntoIterator<Item = PermissionFlags>,
+) -> PermissionFlags {
+	if is_owner {
+		return PermissionFlags::all();
+	}
+
+	let permissions = roles.into_iter().fold(everyone, |acc, role| acc | role);
+
+	if permissions.contains(PermissionFlags::ADMINISTRATOR) {
+		return PermissionFlags::all();
+	}
+
+	permissions
+}
+
+/// Applies one set of channel overwrites to `base`.
+///
+/// `guild_id` doubles as the id of the `@everyone` role, `role_ids` are the
+/// roles of the member whose permissions are being computed.
+pub fn apply_overwrites(
+	base: PermissionFlags,
+	guild_id: Snowflake,
+	member_id: Snowflake,
+	role_ids: &[Snowflake],
+	overwrites: &[PermissionOverwrite],
+) -> PermissionFlags {
+	if base.contains(PermissionFlags::ADMINISTRATOR) {
+		return PermissionFlags::all();
+	}
+
+	let mut permissions = base;
+
+	if let Some(everyone) = overwrites.iter().find(|overwrite| {
+		overwrite.id == guild_id
+			&& matches!(overwrite.overwrite_type, PermissionOverwriteType::Role)
+	}) {
+		permissions &= !everyone.deny;
+		permissions |= everyone.allow;
+	}
+
+	let (allow, deny) = overwrites
+		.iter()
+		.filter(|overwrite| {
+			matches!(overwrite.overwrite_type, PermissionOverwriteType::Role)
+				&& overwrite.id != guild_id
+				&& role_ids.contains(&overwrite.id)
+		})
+		.fold((PermissionFlags::empty(), PermissionFlags::empty()), |(allow, deny), overwrite| {
+			(allow | overwrite.allow, deny | overwrite.deny)
+		});
+	permissions &= !deny;
+	permissions |= allow;
+
+	if let Some(member) = overwrites.iter().find(|overwrite| {
+		overwrite.id == member_id
+			&& matches!(overwrite.overwrite_type, PermissionOverwriteType::Member)
+	}) {
+		permissions &= !member.deny;
+		permissions |= member.allow;
+	}
+
+	permissions
+}
+
+/// Removes permissions which are implicitly denied in a channel, because the
+/// permissions they depend on are missing.
+pub fn apply_implicit_permissions(permissions: PermissionFlags) -> PermissionFlags {
+	if permissions.contains(PermissionFlags::ADMINISTRATOR) {
+		return permissions;
+	}
+
+	if !permissions.contains(PermissionFlags::VIEW_CHANNEL) {
+		return PermissionFlags::empty();
+	}
+
+	if !permissions.contains(PermissionFlags::SEND_MESSAGES) {
+		return permissions
+			- (PermissionFlags::SEND_TTS_MESSAGES
+				| PermissionFlags::MENTION_EVERYONE
+				| PermissionFlags::ATTACH_FILES
+				| PermissionFlags::EMBED_LINKS);
+	}
+
+	permissions
+}
+
+/// Computes the permissions of a user in a DM or group DM channel. Users who
+/// are not a recipient of the channel cannot see it at all, which is reported
+/// as [ChannelError::InvalidChannel] rather than as missing permissions.
+pub fn compute_dm_permissions(
+	is_recipient: bool,
+	is_owner: bool,
+) -> Result<PermissionFlags, Error> {
+	if !is_recipient {
+		return Err(Error::Channel(ChannelError::InvalidChannel));
+	}
+
+	if is_owner {
+		// The owner of a group DM may rename it and manage its recipients.
+		return Ok(DM_PERMISSIONS | PermissionFlags::MANAGE_CHANNELS);
+	}
+
+	Ok(DM_PERMISSIONS)
+}
+
+/// Everything needed to compute the permissions of a single guild member.
+#[derive(Debug, Clone)]
+pub struct MemberPermissionContext {
+	pub guild_id: Snowflake,
+	pub member_id: Snowflake,
+	pub is_owner: bool,
+	/// Ids of the member's roles, not including `@everyone`.
+	pub role_ids: Vec<Snowflake>,
+	/// Guild-level permissions, see [compute_base_permissions].
+	pub base: PermissionFlags,
+}
+
+impl MemberPermissionContext {
+	/// Loads the guild, its roles and the roles of the member `user_id`.
+	///
+	/// Fails with [GuildError::InvalidGuild] if the guild does not exist and
+	/// with [GuildError::MemberNotFound] if the user is not a member of it.
+	pub async fn load(db: &PgPool, guild_id: Snowflake, user_id: Snowflake) -> Result<Self, Error> {
+		let guild =
+			Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
+		guild.get_member(db, user_id).await?.ok_or(Error::Guild(GuildError::MemberNotFound))?;
+
+		let is_owner = guild.owner_id == Some(user_id);
+		let guild_roles = Role::get_by_guild(db, guild.id).await?;
+		let member_role_ids = Role::get_ids_by_user(db, user_id).await?;
+
+		let everyone = guild_roles
+			.iter()
+			.find(|role| role.id == guild.id)
+			.map(|role| role.permissions)
+			.unwrap_or_else(PermissionFlags::empty);
+		let member_roles = guild_roles
+			.iter()
+			.filter(|role| role.id != guild.id && member_role_ids.contains(&role.id))
+			.collect::<Vec<_>>();
+
+		Ok(Self {
+			guild_id: guild.id,
+			member_id: user_id,
+			is_owner,
+			role_ids: member_roles.iter().map(|role| role.id).collect(),
+			base: compute_base_permissions(
+				is_owner,
+				everyone,
+				member_roles.iter().map(|role| role.permissions),
+			),
+		})
+	}
+
+	/// Computes the permissions of the member in a channel, given the
+	/// overwrites of the channel's category (if any) and of the channel itself.
+	pub fn channel_permissions(
+		&self,
+		category_overwrites: &[PermissionOverwrite],
+		channel_overwrites: &[PermissionOverwrite],
+	) -> PermissionFlags {
+		if self.is_owner {
+			return PermissionFlags::all();
+		}
+
+		let permissions = [category_overwrites, channel_overwrites].into_iter().fold(
+			self.base,
+			|permissions, overwrites| {
+				apply_overwrites(
+					permissions,
+					self.guild_id,
+					self.member_id,
+					&self.role_ids,
+					overwrites,
+				)
+			},
+		);
+
+		apply_implicit_permissions(permissions)
+	}
+}
+
+/// Returns the guild-level permissions of `user_id` in the guild `guild_id`.
+pub async fn get_guild_permissions(
+	db: &PgPool,
+	guild_id: Snowflake,
+	user_id: Snowflake,
+) -> Result<PermissionFlags, Error> {
+	Ok(MemberPermissionContext::load(db, guild_id, user_id).await?.base)
+}
+
+/// Returns the effective permissions of `user_id` in `channel`, taking the
+/// overwrites of the channel and its parent category into account.
+///
+/// Users who are not a member of the channel's guild, or not a recipient of a
+/// DM channel, get [ChannelError::InvalidChannel].
+pub async fn get_channel_permissions(
+	db: &PgPool,
+	channel: &Channel,
+	user_id: Snowflake,
+) -> Result<PermissionFlags, Error> {
+	let Some(guild_id) = channel.guild_id else {
+		let is_recipient =
+			Recipient::get_by_channel_and_user_id(db, channel.id, user_id).await?.is_some();
+		return compute_dm_permissions(is_recipient, channel.owner_id == Some(user_id));
+	};
+
+	let context =
+		MemberPermissionContext::load(db, guild_id, user_id).await.map_err(|e| match e {
+			Error::Guild(GuildError::MemberNotFound) => {
+				Error::Channel(ChannelError::InvalidChannel)
+			}
+			e => e,
+		})?;
+
+	let category_overwrites = match channel.parent_id {
+		Some(parent_id) => Channel::get_by_id(db, parent_id)
+			.await?
+			.and_then(|category| category.permission_overwrites.clone())
+			.map(|overwrites| overwrites.0)
+			.unwrap_or_default(),
+		None => Vec::new(),
+	};
+	let channel_overwrites =
+		channel.permission_overwrites.as_ref().map(|overwrites| overwrites.0.as_slice());
+
+	Ok(context.channel_permissions(&category_overwrites, channel_overwrites.unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const GUILD: Snowflake = Snowflake(1);
+	const MEMBER: Snowflake = Snowflake(2);
+	const ROLE_A: Snowflake = Snowflake(3);
+	const ROLE_B: Snowflake = Snowflake(4);
+
+	fn overwrite(
+		id: Snowflake,
+		overwrite_type: PermissionOverwriteType,
+		allow: PermissionFlags,
+		deny: PermissionFlags,
+	) -> PermissionOverwrite {
+		PermissionOverwrite { id, overwrite_type, allow, deny }
+	}
+
+	fn context(base: PermissionFlags) -> MemberPermissionContext {
+		MemberPermissionContext {
+			guild_id: GUILD,
+			member_id: MEMBER,
+			is_owner: false,
+			role_ids: vec![ROLE_A, ROLE_B],
+			base,
+		}
+	}
+
+	#[test]
+	fn base_permissions_combine_roles() {
+		let permissions = compute_base_permissions(
+			false,
+			PermissionFlags::VIEW_CHANNEL,
+			[PermissionFlags::SEND_MESSAGES, PermissionFlags::KICK_MEMBERS],
+		);
+		assert_eq!(
+			permissions,
+			PermissionFlags::VIEW_CHANNEL
+				| PermissionFlags::SEND_MESSAGES
+				| PermissionFlags::KICK_MEMBERS
+		);
+	}
+
+	#[test]
+	fn owner_and_administrator_have_all_permissions() {
+		assert_eq!(
+			compute_base_permissions(true, PermissionFlags::empty(), []),
+			PermissionFlags::all()
+		);
+		assert_eq!(
+			compute_base_permissions(
+				false,
+				PermissionFlags::empty(),
+				[PermissionFlags::ADMINISTRATOR]
+			),
+			PermissionFlags::all()
+		);
+
+		let owner = MemberPermissionContext { is_owner: true, ..context(PermissionFlags::empty()) };
+		let deny_everything = [overwrite(
+			MEMBER,
+			PermissionOverwriteType::Member,
+			PermissionFlags::empty(),
+			PermissionFlags::all(),
+		)];
+		assert_eq!(owner.channel_permissions(&[], &deny_everything), PermissionFlags::all());
+	}
+
+	#[test]
+	fn overwrites_are_applied_in_order() {
+		let base = PermissionFlags::VIEW_CHANNEL | PermissionFlags::SEND_MESSAGES;
+		let overwrites = [
+			overwrite(
+				GUILD,
+				PermissionOverwriteType::Role,
+				PermissionFlags::empty(),
+				PermissionFlags::SEND_MESSAGES,
+			),
+			overwrite(
+				ROLE_A,
+				PermissionOverwriteType::Role,
+				PermissionFlags::SEND_MESSAGES,
+				PermissionFlags::empty(),
+			),
+			overwrite(
+				ROLE_B,
+				PermissionOverwriteType::Role,
+				PermissionFlags::ADD_REACTIONS,
+				PermissionFlags::SEND_MESSAGES,
+			),
+			overwrite(
+				MEMBER,
+				PermissionOverwriteType::Member,
+				PermissionFlags::empty(),
+				PermissionFlags::ADD_REACTIONS,
+			),
+		];
+
+		// Role overwrites are combined before being applied, so the allow of
+		// ROLE_A wins over the deny of ROLE_B. The member overwrite comes last.
+		assert_eq!(
+			apply_overwrites(base, GUILD, MEMBER, &[ROLE_A, ROLE_B], &overwrites),
+			PermissionFlags::VIEW_CHANNEL | PermissionFlags::SEND_MESSAGES
+		);
+		// Overwrites of roles the member does not have are ignored.
+		assert_eq!(
+			apply_overwrites(base, GUILD, MEMBER, &[], &overwrites),
+			PermissionFlags::VIEW_CHANNEL
+		);
+	}
+
+	#[test]
+	fn channel_overwrites_override_category() {
+		let context = context(PermissionFlags::VIEW_CHANNEL | PermissionFlags::SEND_MESSAGES);
+		let category = [overwrite(
+			GUILD,
+			PermissionOverwriteType::Role,
+			PermissionFlags::empty(),
+			PermissionFlags::VIEW_CHANNEL,
+		)];
+		let channel = [overwrite(
+			ROLE_A,
+			PermissionOverwriteType::Role,
+			PermissionFlags::VIEW_CHANNEL,
+			PermissionFlags::empty(),
+		)];
+
+		assert_eq!(context.channel_permissions(&category, &[]), PermissionFlags::empty());
+		assert_eq!(
+			context.channel_permissions(&category, &channel),
+			PermissionFlags::VIEW_CHANNEL | PermissionFlags::SEND_MESSAGES
+		);
+	}
+
+	#[test]
+	fn dm_permissions_require_recipient() {
+		assert!(matches!(
+			compute_dm_permissions(false, false),
+			Err(Error::Channel(ChannelError::InvalidChannel))
+		));
+		assert_eq!(compute_dm_permissions(true, false).unwrap(), DM_PERMISSIONS);
+		assert!(!DM_PERMISSIONS.contains(PermissionFlags::MANAGE_MESSAGES));
+		assert!(
+			compute_dm_permissions(true, true).unwrap().contains(PermissionFlags::MANAGE_CHANNELS)
+		);
+	}
+
+	#[test]
+	fn implicit_permissions() {
+		assert_eq!(
+			apply_implicit_permissions(
+				PermissionFlags::SEND_MESSAGES | PermissionFlags::ATTACH_FILES
+			),
+			PermissionFlags::empty()
+		);
+		assert_eq!(
+			apply_implicit_permissions(
+				PermissionFlags::VIEW_CHANNEL
+					| PermissionFlags::ATTACH_FILES
+					| PermissionFlags::READ_MESSAGE_HISTORY
+			),
+			PermissionFlags::VIEW_CHANNEL | PermissionFlags::READ_MESSAGE_HISTORY
+		);
+	}
+}
@@ -0,0 +1,217 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks of the fields of a registration against the `register_*` policy of
+//! the instance. Each check adds its failures to a [FieldErrors], so that all
+//! invalid fields are reported at once.
+
+use chrono::NaiveDate;
+
+use crate::{entities::Config, errors::FieldErrors, util::email::adjust_email};
+
+const MIN_USERNAME_LENGTH: usize = 2;
+
+fn required(errors: &mut FieldErrors, field: &str) {
+	errors.add(field, "BASE_TYPE_REQUIRED", "This field is required.");
+}
+
+pub fn check_username(cfg: &Config, username: &str, errors: &mut FieldErrors) {
+	let length = username.trim().chars().count();
+	let max = cfg.limits.user.max_username as usize;
+	if !(MIN_USERNAME_LENGTH..=max).contains(&length) {
+		errors.add(
+			"username",
+			"BASE_TYPE_BAD_LENGTH",
+			format!("Must be between {MIN_USERNAME_LENGTH} and {max} in length."),
+		);
+	}
+}
+
+/// Check `email` and return it normalized, if it is present and valid.
+pub fn check_email(cfg: &Config, email: Option<&str>, errors: &mut FieldErrors) -> Option<String> {
+	let Some(email) = email.filter(|email| !email.is_empty()) else {
+		if cfg.register.email.required {
+			required(errors, "email");
+		}
+		return None;
+	};
+	let Ok(email) = adjust_email(email) else {
+		errors.add("email", "EMAIL_TYPE_INVALID_EMAIL", "Not a well formed email address.");
+		return None;
+	};
+
+	let domain = email.domain().to_lowercase();
+	let listed = cfg.register.email.domains.iter().any(|listed| {
+		let listed = listed.to_lowercase();
+		domain == listed || domain.ends_with(&format!(".{listed}"))
+	});
+	// The allowlist takes precedence, as enabling it makes the blocklist moot
+	let allowed = if cfg.register.email.allowlist {
+		listed
+	} else {
+		!(cfg.register.email.blocklist && listed)
+	};
+	if !allowed {
+		errors.add(
+			"email",
+			"EMAIL_DOMAIN_NOT_ALLOWED",
+			"Registrations from this email domain are not allowed.",
+		);
+		return None;
+	}
+
+	Some(email.to_string())
+}
+
+pub fn check_password(cfg: &Config, password: Option<&str>, errors: &mut FieldErrors) {
+	let rules = &cfg.register.password;
+	let Some(password) = password else {
+		if rules.required {
+			required(errors, "password");
+		}
+		return;
+	};
+
+	let count = |matches: fn(&char) -> bool| password.chars().filter(matches).count();
+	let checks = [
+		(count(|_| true), rules.min_length, "BASE_TYPE_MIN_LENGTH", "characters"),
+		(
+			count(char::is_ascii_digit),
+			rules.min_numbers,
+			"PASSWORD_REQUIREMENTS_NUMBERS",
+			"numbers",
+		),
+		(
+			count(|c| c.is_uppercase()),
+			rules.min_upper_case,
+			"PASSWORD_REQUIREMENTS_UPPERCASE",
+			"uppercase letters",
+		),
+		(
+			count(|c| !c.is_alphanumeric() && !c.is_whitespace()),
+			rules.min_symbols,
+			"PASSWORD_REQUIREMENTS_SYMBOLS",
+			"symbols",
+		),
+	];
+	for (found, min, code, what) in checks {
+		if found < min as usize {
+			errors.add("password", code, format!("Must contain at least {min} {what}."));
+		}
+	}
+}
+
+/// Check that a user born on `date_of_birth` is old enough to register on
+/// `today`.
+pub fn check_date_of_birth(
+	cfg: &Config,
+	date_of_birth: Option<NaiveDate>,
+	today: NaiveDate,
+	errors: &mut FieldErrors,
+) {
+	let rules = &cfg.register.date_of_birth;
+	let Some(date_of_birth) = date_of_birth else {
+		if rules.required {
+			required(errors, "date_of_birth");
+		}
+		return;
+	};
+
+	match today.years_since(date_of_birth) {
+		None => errors.add("date_of_birth", "DATE_OF_BIRTH_INVALID", "Invalid date of birth."),
+		Some(age) if age < rules.minimum as u32 => errors.add(
+			"date_of_birth",
+			"DATE_OF_BIRTH_UNDERAGE",
+			format!("You need to be {} years or older.", rules.minimum),
+		),
+		Some(_) => {}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn config() -> Config {
+		let mut cfg = Config::default();
+		cfg.limits.user.max_username = 32;
+		cfg.register.password.required = true;
+		cfg.register.password.min_length = 8;
+		cfg.register.password.min_numbers = 2;
+		cfg.register.password.min_upper_case = 1;
+		cfg.register.password.min_symbols = 1;
+		cfg.register.date_of_birth.required = true;
+		cfg.register.date_of_birth.minimum = 13;
+		cfg
+	}
+
+	fn codes(errors: &FieldErrors, field: &str) -> Vec<&'static str> {
+		errors.get(field).iter().map(|error| error.code).collect()
+	}
+
+	#[test]
+	fn passwords_must_follow_the_configured_rules() {
+		let cfg = config();
+		let mut errors = FieldErrors::default();
+		check_password(&cfg, Some("Correct-Horse-42"), &mut errors);
+		assert!(errors.is_empty());
+
+		check_password(&cfg, Some("short1"), &mut errors);
+		assert_eq!(
+			codes(&errors, "password"),
+			vec![
+				"BASE_TYPE_MIN_LENGTH",
+				"PASSWORD_REQUIREMENTS_NUMBERS",
+				"PASSWORD_REQUIREMENTS_UPPERCASE",
+				"PASSWORD_REQUIREMENTS_SYMBOLS"
+			]
+		);
+
+		let mut errors = FieldErrors::default();
+		check_password(&cfg, None, &mut errors);
+		assert_eq!(codes(&errors, "password"), vec!["BASE_TYPE_REQUIRED"]);
+	}
+
+	#[test]
+	fn email_domains_are_checked_against_the_lists() {
+		let mut cfg = config();
+		cfg.register.email.blocklist = true;
+		cfg.register.email.domains = vec!["blocked.test".to_string()];
+
+		let mut errors = FieldErrors::default();
+		assert_eq!(
+			check_email(&cfg, Some("user@symfonia.test"), &mut errors).as_deref(),
+			Some("user@symfonia.test")
+		);
+		assert!(check_email(&cfg, Some("user@mail.blocked.test"), &mut errors).is_none());
+		assert_eq!(codes(&errors, "email"), vec!["EMAIL_DOMAIN_NOT_ALLOWED"]);
+
+		cfg.register.email.allowlist = true;
+		let mut errors = FieldErrors::default();
+		assert!(check_email(&cfg, Some("user@symfonia.test"), &mut errors).is_none());
+		assert!(check_email(&cfg, Some("user@blocked.test"), &mut errors).is_some());
+		assert!(check_email(&cfg, Some("not an address"), &mut errors).is_none());
+		assert_eq!(
+			codes(&errors, "email"),
+			vec!["EMAIL_DOMAIN_NOT_ALLOWED", "EMAIL_TYPE_INVALID_EMAIL"]
+		);
+	}
+
+	#[test]
+	fn users_must_be_old_enough() {
+		let cfg = config();
+		let today = NaiveDate::from_ymd_opt(2026, 10, 17).unwrap();
+		let mut errors = FieldErrors::default();
+		check_date_of_birth(&cfg, NaiveDate::from_ymd_opt(2013, 10, 17), today, &mut errors);
+		assert!(errors.is_empty());
+
+		check_date_of_birth(&cfg, NaiveDate::from_ymd_opt(2013, 10, 18), today, &mut errors);
+		check_date_of_birth(&cfg, NaiveDate::from_ymd_opt(2027, 1, 1), today, &mut errors);
+		check_date_of_birth(&cfg, None, today, &mut errors);
+		assert_eq!(
+			codes(&errors, "date_of_birth"),
+			vec!["DATE_OF_BIRTH_UNDERAGE", "DATE_OF_BIRTH_INVALID", "BASE_TYPE_REQUIRED"]
+		);
+	}
+}
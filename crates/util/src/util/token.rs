@@ -1,32 +1,361 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::jwt::Claims;
-use jsonwebtoken::TokenData;
+use std::{
+	sync::{Arc, OnceLock},
+	time::Duration,
+};
+
+use chorus::types::{Snowflake, jwt::Claims};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, errors::ErrorKind};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use sqlx::PgPool;
 
 use crate::{
+	configuration::{SymfoniaConfiguration, TokenConfiguration},
 	entities::User,
 	errors::{Error, UserError},
 };
 
+/// Keys of an asymmetric algorithm, which are read from their files once.
+static ASYMMETRIC_KEYS: OnceLock<Arc<TokenKeys>> = OnceLock::new();
+
+/// The keys and algorithm used to sign and verify tokens.
+pub struct TokenKeys {
+	algorithm: Algorithm,
+	lifetime_secs: u64,
+	encoding: EncodingKey,
+	decoding: DecodingKey,
+}
+
+impl TokenKeys {
+	/// Build the keys described by `config`. `jwt_secret` is used by the `HS*`
+	/// algorithms, the key files of `config` by all others.
+	pub fn new(config: &TokenConfiguration, jwt_secret: &str) -> Result<Self, Error> {
+		let (encoding, decoding) = match config.algorithm {
+			Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => (
+				EncodingKey::from_secret(jwt_secret.as_bytes()),
+				DecodingKey::from_secret(jwt_secret.as_bytes()),
+			),
+			algorithm => {
+				let read = |path: &Option<std::path::PathBuf>, name: &str| {
+					let path = path.as_ref().ok_or_else(|| {
+						Error::Custom(format!("tokens.{name} is required by {algorithm:?}"))
+					})?;
+					std::fs::read(path).map_err(|e| {
+						Error::Custom(format!("Failed to read {}: {e}", path.display()))
+					})
+				};
+				let private_key = read(&config.private_key, "private_key")?;
+				let public_key = read(&config.public_key, "public_key")?;
+				match algorithm {
+					Algorithm::ES256 | Algorithm::ES384 => (
+						EncodingKey::from_ec_pem(&private_key)?,
+						DecodingKey::from_ec_pem(&public_key)?,
+					),
+					Algorithm::EdDSA => (
+						EncodingKey::from_ed_pem(&private_key)?,
+						DecodingKey::from_ed_pem(&public_key)?,
+					),
+					_ => (
+						EncodingKey::from_rsa_pem(&private_key)?,
+						DecodingKey::from_rsa_pem(&public_key)?,
+					),
+				}
+			}
+		};
+		Ok(Self {
+			algorithm: config.algorithm,
+			lifetime_secs: config.lifetime_secs,
+			encoding,
+			decoding,
+		})
+	}
+
+	/// The keys configured in [SymfoniaConfiguration].
+	fn configured(jwt_secret: &str) -> Result<Arc<Self>, Error> {
+		let config = &SymfoniaConfiguration::get().general.tokens;
+		if matches!(config.algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+			return Ok(Arc::new(Self::new(config, jwt_secret)?));
+		}
+		if let Some(keys) = ASYMMETRIC_KEYS.get() {
+			return Ok(keys.clone());
+		}
+		let keys = Arc::new(Self::new(config, jwt_secret)?);
+		Ok(ASYMMETRIC_KEYS.get_or_init(|| keys).clone())
+	}
+
+	/// Sign a token for the user `id` with `email`, which expires after the
+	/// configured lifetime.
+	pub fn issue(&self, id: Snowflake, email: &str) -> Result<String, Error> {
+		let now = Utc::now();
+		let iat = now.timestamp();
+		self.encode(&AccessClaims {
+			claims: Claims {
+				exp: iat + self.lifetime_secs as i64,
+				iat,
+				email: email.to_string(),
+				id,
+			},
+			iat_micros: Some(now.timestamp_micros()),
+			aud: None,
+		})
+	}
+
+	/// Verify the signature and expiry of `token` and return its claims.
+	/// Tokens with an audience, like [EmailClaims], are no access tokens and
+	/// are rejected.
+	pub fn verify(&self, token: &str) -> Result<Claims, Error> {
+		self.verify_access(token).map(|claims| claims.claims)
+	}
+
+	fn verify_access(&self, token: &str) -> Result<AccessClaims, Error> {
+		let mut validation = Validation::new(self.algorithm);
+		validation.validate_aud = false;
+		let claims: AccessClaims = self.decode(token, &validation)?;
+		if claims.aud.is_some() {
+			return Err(UserError::InvalidToken.into());
+		}
+		Ok(claims)
+	}
+
+	/// Sign a token allowing `purpose` for the user `id` with `email`.
+	pub fn issue_email(
+		&self,
+		id: Snowflake,
+		email: &str,
+		purpose: EmailTokenPurpose,
+	) -> Result<String, Error> {
+		let now = Utc::now();
+		let iat = now.timestamp();
+		self.encode(&EmailClaims {
+			id,
+			email: email.to_string(),
+			aud: purpose.audience().to_string(),
+			iat,
+			iat_micros: Some(now.timestamp_micros()),
+			exp: iat + purpose.lifetime().as_secs() as i64,
+		})
+	}
+
+	/// Verify that `token` is a current token allowing `purpose`, and return
+	/// its claims.
+	pub fn verify_email(
+		&self,
+		token: &str,
+		purpose: EmailTokenPurpose,
+	) -> Result<EmailClaims, Error> {
+		let mut validation = Validation::new(self.algorithm);
+		validation.set_audience(&[purpose.audience()]);
+		self.decode(token, &validation)
+	}
+
+	fn encode<T: Serialize>(&self, claims: &T) -> Result<String, Error> {
+		Ok(jsonwebtoken::encode(&Header::new(self.algorithm), claims, &self.encoding)?)
+	}
+
+	fn decode<T: DeserializeOwned>(
+		&self,
+		token: &str,
+		validation: &Validation,
+	) -> Result<T, Error> {
+		jsonwebtoken::decode(token, &self.decoding, validation).map(|token| token.claims).map_err(
+			|e| match e.kind() {
+				ErrorKind::ExpiredSignature => UserError::ExpiredToken.into(),
+				_ => UserError::InvalidToken.into(),
+			},
+		)
+	}
+}
+
+/// The claims of an access token, together with the audience of tokens which
+/// are meant for something else.
+#[derive(Serialize, Deserialize)]
+struct AccessClaims {
+	#[serde(flatten)]
+	claims: Claims,
+	/// When the token was issued, in microseconds since the Unix epoch.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	iat_micros: Option<i64>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	aud: Option<String>,
+}
+
+/// Whether a token issued at `iat`, or more precisely at `iat_micros`, was
+/// revoked by a user who only accepts tokens issued since `valid_tokens_since`.
+/// Tokens without `iat_micros` were issued by older versions; they are only
+/// compared by the second, and thus revoked if issued in the same second as
+/// the revocation.
+fn is_revoked(iat: i64, iat_micros: Option<i64>, valid_tokens_since: DateTime<Utc>) -> bool {
+	match iat_micros {
+		Some(iat_micros) => iat_micros < valid_tokens_since.timestamp_micros(),
+		None => iat <= valid_tokens_since.timestamp(),
+	}
+}
+
+/// What a token sent by email allows its bearer to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmailTokenPurpose {
+	/// Confirm that the user owns their email address.
+	Verify,
+	/// Set a new password without knowing the current one.
+	ResetPassword,
+}
+
+impl EmailTokenPurpose {
+	fn audience(self) -> &'static str {
+		match self {
+			EmailTokenPurpose::Verify => "symfonia:verify_email",
+			EmailTokenPurpose::ResetPassword => "symfonia:reset_password",
+		}
+	}
+
+	fn lifetime(self) -> Duration {
+		match self {
+			EmailTokenPurpose::Verify => Duration::from_secs(60 * 60 * 24),
+			EmailTokenPurpose::ResetPassword => Duration::from_secs(60 * 60),
+		}
+	}
+}
+
+/// The claims of a token sent by email.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailClaims {
+	pub id: Snowflake,
+	/// The address the token was sent to.
+	pub email: String,
+	pub aud: String,
+	pub iat: i64,
+	/// When the token was issued, in microseconds since the Unix epoch.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub iat_micros: Option<i64>,
+	pub exp: i64,
+}
+
+/// Issue a token for the user `id` with `email`, signed with the configured
+/// keys.
+pub fn generate_token(id: Snowflake, email: &str, jwt_secret: &str) -> Result<String, Error> {
+	TokenKeys::configured(jwt_secret)?.issue(id, email)
+}
+
+/// Verify `token` and check that it may still be used: its user must exist,
+/// must be neither disabled nor deleted, and must not have revoked the token
+/// by logging out all devices since it was issued.
 pub async fn check_token(db: &PgPool, token: &str, jwt_secret: &str) -> Result<Claims, Error> {
-	let decoding_key = jsonwebtoken::DecodingKey::from_base64_secret(jwt_secret).unwrap();
-	let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
-	validation.insecure_disable_signature_validation(); // TODO: Remove
-	let token: TokenData<Claims> = jsonwebtoken::decode(token, &decoding_key, &validation).unwrap();
+	let AccessClaims { claims, iat_micros, .. } =
+		TokenKeys::configured(jwt_secret)?.verify_access(token)?;
 
-	let user = User::get_by_id(db, token.claims.id)
-		.await
-		.unwrap()
-		.ok_or(Error::User(UserError::InvalidUser))?;
+	let user = User::get_by_id(db, claims.id).await?.ok_or(UserError::InvalidToken)?;
+
+	if is_revoked(claims.iat, iat_micros, user.data.valid_tokens_since) {
+		return Err(UserError::InvalidToken.into());
+	}
+	if user.disabled.unwrap_or_default() {
+		return Err(UserError::Disabled.into());
+	}
+	if user.deleted {
+		return Err(UserError::Deleted.into());
+	}
+
+	Ok(claims)
+}
+
+/// Issue a token allowing `purpose` to the user `id`, to be sent to `email`.
+pub fn generate_email_token(
+	id: Snowflake,
+	email: &str,
+	purpose: EmailTokenPurpose,
+	jwt_secret: &str,
+) -> Result<String, Error> {
+	TokenKeys::configured(jwt_secret)?.issue_email(id, email, purpose)
+}
 
-	if chrono::DateTime::from_timestamp(token.claims.iat, 0).unwrap() < user.data.valid_tokens_since
+/// Verify the email token `token` for `purpose` and return its user. The token
+/// must have been sent to the current address of the user, and password reset
+/// tokens can only be used once, as resetting the password revokes all tokens
+/// issued before.
+pub async fn check_email_token(
+	db: &PgPool,
+	token: &str,
+	purpose: EmailTokenPurpose,
+	jwt_secret: &str,
+) -> Result<User, Error> {
+	let claims = TokenKeys::configured(jwt_secret)?.verify_email(token, purpose)?;
+
+	let user = User::get_by_id(db, claims.id).await?.ok_or(UserError::InvalidToken)?;
+	if user.email.as_deref() != Some(claims.email.as_str()) {
+		return Err(UserError::InvalidToken.into());
+	}
+	if purpose == EmailTokenPurpose::ResetPassword
+		&& is_revoked(claims.iat, claims.iat_micros, user.data.valid_tokens_since)
 	{
-		return Err(Error::User(UserError::InvalidToken));
+		return Err(UserError::InvalidToken.into());
+	}
+
+	Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn keys(secret: &str) -> TokenKeys {
+		TokenKeys::new(&TokenConfiguration::default(), secret).unwrap()
 	}
 
-	// TODO: Check if user is banned or disabled
+	#[test]
+	fn tokens_signed_with_another_secret_are_rejected() {
+		let token = keys("secret").issue(Snowflake::default(), "user@symfonia.test").unwrap();
+		assert_eq!(keys("secret").verify(&token).unwrap().email, "user@symfonia.test");
+		assert!(matches!(
+			keys("another secret").verify(&token),
+			Err(Error::User(UserError::InvalidToken))
+		));
+	}
 
-	Ok(token.claims)
+	#[test]
+	fn expired_tokens_are_rejected() {
+		let keys = keys("secret");
+		let iat = Utc::now().timestamp() - 7200;
+		let claims = Claims {
+			exp: iat + 3600,
+			iat,
+			email: "user@symfonia.test".to_string(),
+			id: Snowflake::default(),
+		};
+		let token =
+			jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &keys.encoding).unwrap();
+		assert!(matches!(keys.verify(&token), Err(Error::User(UserError::ExpiredToken))));
+	}
+
+	#[test]
+	fn email_tokens_are_bound_to_their_purpose() {
+		let keys = keys("secret");
+		let id = Snowflake::default();
+		let token = keys.issue_email(id, "user@symfonia.test", EmailTokenPurpose::Verify).unwrap();
+		assert_eq!(keys.verify_email(&token, EmailTokenPurpose::Verify).unwrap().id, id);
+		assert!(keys.verify_email(&token, EmailTokenPurpose::ResetPassword).is_err());
+		assert!(keys.verify(&token).is_err());
+
+		let access = keys.issue(id, "user@symfonia.test").unwrap();
+		assert!(keys.verify_email(&access, EmailTokenPurpose::Verify).is_err());
+	}
+
+	#[test]
+	fn revocations_are_compared_below_the_second() {
+		let keys = keys("secret");
+		let token = keys.issue(Snowflake::default(), "user@symfonia.test").unwrap();
+		let claims = keys.verify_access(&token).unwrap();
+		let issued_at = DateTime::from_timestamp_micros(claims.iat_micros.unwrap()).unwrap();
+		assert_eq!(issued_at.timestamp(), claims.claims.iat);
+
+		let revoked_at = issued_at + chrono::Duration::microseconds(1);
+		assert!(is_revoked(claims.claims.iat, claims.iat_micros, revoked_at));
+		assert!(!is_revoked(claims.claims.iat, claims.iat_micros, issued_at));
+		// Without the microseconds, a token is only valid if it was issued in a
+		// later second than the revocation.
+		assert!(is_revoked(claims.claims.iat, None, issued_at));
+		assert!(!is_revoked(claims.claims.iat + 1, None, issued_at));
+	}
 }
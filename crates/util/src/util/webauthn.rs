@@ -0,0 +1,116 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! WebAuthn security keys, which users can register as a second factor
+//! instead of, or in addition to, TOTP.
+
+use std::sync::OnceLock;
+
+use chorus::types::Snowflake;
+use webauthn_rs::{
+	Webauthn, WebauthnBuilder,
+	prelude::{Url, Uuid},
+};
+
+use crate::{
+	configuration::{SymfoniaConfiguration, WebauthnConfiguration},
+	errors::{Error, UserError},
+};
+
+static WEBAUTHN: OnceLock<Webauthn> = OnceLock::new();
+
+/// Build the relying party described by `config`.
+pub fn build(config: &WebauthnConfiguration) -> Result<Webauthn, Error> {
+	let origin = Url::parse(&config.origin)
+		.map_err(|e| Error::Custom(format!("Invalid WebAuthn origin {}: {e}", config.origin)))?;
+	Ok(WebauthnBuilder::new(&config.rp_id, &origin)?.rp_name(&config.rp_name).build()?)
+}
+
+/// The relying party configured in `[api.webauthn]`.
+pub fn webauthn() -> Result<&'static Webauthn, Error> {
+	if let Some(webauthn) = WEBAUTHN.get() {
+		return Ok(webauthn);
+	}
+	let config =
+		SymfoniaConfiguration::get().api.webauthn.as_ref().ok_or(UserError::WebauthnDisabled)?;
+	let webauthn = build(config)?;
+	Ok(WEBAUTHN.get_or_init(|| webauthn))
+}
+
+/// The WebAuthn user handle of the user `id`.
+pub fn user_handle(id: Snowflake) -> Uuid {
+	Uuid::from_u64_pair(0, u64::from(id))
+}
+
+/// Check that the signature counter `reported` by an authenticator advanced
+/// past the `stored` one. A counter which did not advance means the key may
+/// have been cloned. Authenticators without a counter always report `0`.
+pub fn check_counter(stored: u32, reported: u32) -> Result<(), Error> {
+	if reported == 0 && stored == 0 {
+		return Ok(());
+	}
+	if reported <= stored {
+		return Err(UserError::SecurityKeyCounterRegressed.into());
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use webauthn_authenticator_rs::{WebauthnAuthenticator, softpasskey::SoftPasskey};
+
+	use super::*;
+
+	const ORIGIN: &str = "https://symfonia.test";
+
+	fn relying_party() -> Webauthn {
+		build(&WebauthnConfiguration {
+			rp_id: "symfonia.test".to_string(),
+			origin: ORIGIN.to_string(),
+			rp_name: "Symfonia".to_string(),
+		})
+		.unwrap()
+	}
+
+	#[test]
+	fn software_key_registers_and_authenticates() {
+		let webauthn = relying_party();
+		let origin = Url::parse(ORIGIN).unwrap();
+		let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+
+		let (challenge, registration) = webauthn
+			.start_securitykey_registration(
+				user_handle(Snowflake::default()),
+				"user",
+				"user",
+				None,
+				None,
+				None,
+			)
+			.unwrap();
+		let credential = authenticator.do_registration(origin.clone(), challenge).unwrap();
+		let key = webauthn.finish_securitykey_registration(&credential, &registration).unwrap();
+
+		let mut counter = 0;
+		for _ in 0..2 {
+			let (challenge, authentication) =
+				webauthn.start_securitykey_authentication(&[key.clone()]).unwrap();
+			let assertion = authenticator.do_authentication(origin.clone(), challenge).unwrap();
+			let result =
+				webauthn.finish_securitykey_authentication(&assertion, &authentication).unwrap();
+			assert_eq!(result.cred_id(), key.cred_id());
+			check_counter(counter, result.counter()).unwrap();
+			counter = result.counter();
+		}
+	}
+
+	#[test]
+	fn counters_must_advance() {
+		assert!(check_counter(0, 0).is_ok());
+		assert!(check_counter(4, 5).is_ok());
+		assert!(check_counter(5, 5).is_err());
+		assert!(check_counter(5, 0).is_err());
+		assert!(check_counter(5, 3).is_err());
+	}
+}